@@ -0,0 +1,48 @@
+//! Instruction throughput of the bare CPU: runs a fixed CPU-bound loop for a million
+//! instructions and reports instructions per second. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+use z80_emulator::cpu::CPU;
+use z80_emulator::memory::Memory;
+
+const INSTRUCTIONS: u32 = 1_000_000;
+const RUNS: u32 = 20;
+
+#[rustfmt::skip]
+const PROGRAM: &[u8] = &[
+    0xA2, 0x00,             // start: LDX #$00
+    0xBD, 0x00, 0x10,       // loop:  LDA $1000,X
+    0x69, 0x01,             //        ADC #$01
+    0x9D, 0x00, 0x10,       //        STA $1000,X
+    0x20, 0x20, 0x06,       //        JSR sub
+    0xE8,                   //        INX
+    0xD0, 0xF2,             //        BNE loop
+    0x4C, 0x00, 0x06,       //        JMP start
+];
+/// sub: INC $20; RTS
+const SUBROUTINE: &[u8] = &[0xE6, 0x20, 0x60];
+
+fn run_once() -> Duration {
+    let mem = Memory::new_shared();
+    mem.borrow_mut().load(0x0600, PROGRAM);
+    mem.borrow_mut().load(0x0620, SUBROUTINE);
+    let mut cpu = CPU::new(mem);
+    cpu.pc = 0x0600;
+
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        cpu.step();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let best = (0..RUNS).map(|_| run_once()).min().unwrap();
+    println!(
+        "cpu: {} instructions in {:?} (best of {}), {:.1} M instructions/s",
+        INSTRUCTIONS,
+        best,
+        RUNS,
+        INSTRUCTIONS as f64 / best.as_secs_f64() / 1e6
+    );
+}
@@ -0,0 +1,86 @@
+//! Buffers SID output between the emulator, which produces samples as it runs, and a
+//! host audio backend, which drains them at its own pace.
+//!
+//! With cpal, run the machine on the UI thread and move what `ZPC::pull_audio` returns
+//! into a queue the output stream's data callback reads from, filling with silence when
+//! the queue runs dry. The stream should be opened mono, `i16`, at the SID's sample rate.
+
+/// Samples held by default: a quarter of a second at 44.1 kHz.
+pub const DEFAULT_CAPACITY: usize = 11_025;
+
+/// Fixed-size ring of mono samples. When the reader falls behind, the oldest samples
+/// are overwritten so the audio stays close to the emulation.
+pub struct AudioRing {
+    samples: Vec<i16>,
+    /// Index of the oldest buffered sample.
+    head: usize,
+    len: usize,
+}
+
+impl AudioRing {
+    pub fn new(capacity: usize) -> AudioRing {
+        AudioRing {
+            samples: vec![0; capacity.max(1)],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    pub fn push(&mut self, sample: i16) {
+        let capacity = self.samples.len();
+        self.samples[(self.head + self.len) % capacity] = sample;
+        if self.len == capacity {
+            self.head = (self.head + 1) % capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Moves up to `out.len()` of the oldest samples into `out`. Returns how many.
+    pub fn pull(&mut self, out: &mut [i16]) -> usize {
+        let count = out.len().min(self.len);
+        let capacity = self.samples.len();
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = self.samples[(self.head + i) % capacity];
+        }
+        self.head = (self.head + count) % capacity;
+        self.len -= count;
+        count
+    }
+}
+
+impl Default for AudioRing {
+    fn default() -> Self {
+        AudioRing::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_drops_the_oldest_samples() {
+        let mut ring = AudioRing::new(4);
+        for sample in 1..=6 {
+            ring.push(sample);
+        }
+        let mut out = [0; 8];
+        assert_eq!(ring.pull(&mut out), 4);
+        assert_eq!(out[..4], [3, 4, 5, 6]);
+        assert!(ring.is_empty());
+    }
+}
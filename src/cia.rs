@@ -0,0 +1,1038 @@
+//! MOS 6526 Complex Interface Adapter. CIA1 ($DC00) raises IRQs, CIA2 ($DD00) raises NMIs.
+
+use crate::cpu;
+use crate::iec::{IecDevice, IecLines};
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils::Region;
+use crate::vic;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub type CIAShared = Rc<RefCell<CIA>>;
+
+/// Interrupt sources in the ICR.
+pub const ICR_TIMER_A: u8 = 1 << 0;
+pub const ICR_TIMER_B: u8 = 1 << 1;
+pub const ICR_TOD_ALARM: u8 = 1 << 2;
+pub const ICR_SERIAL: u8 = 1 << 3;
+pub const ICR_FLAG: u8 = 1 << 4;
+/// Set in ICR data when any enabled source is active.
+const ICR_IRQ: u8 = 1 << 7;
+
+// Control register bits shared by CRA and CRB.
+const CR_START: u8 = 1 << 0;
+const CR_ONE_SHOT: u8 = 1 << 3;
+const CR_FORCE_LOAD: u8 = 1 << 4;
+/// CRA only: serial port direction, set for output.
+const CRA_SP_OUTPUT: u8 = 1 << 6;
+/// CRB only: TOD register writes set the alarm instead of the clock.
+const CRB_TOD_ALARM: u8 = 1 << 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TimerInput {
+    /// Counts system clock cycles.
+    Phi2,
+    /// Counts positive CNT transitions.
+    Cnt,
+    /// Timer B only: counts timer A underflows.
+    TimerA,
+    /// Timer B only: counts timer A underflows while CNT is high.
+    TimerACnt,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CIATimer {
+    pub latch: u16,
+    pub value: u16,
+    pub is_running: bool,
+    pub one_shot: bool,
+    pub input: TimerInput,
+    /// Underflowed this cycle; the ICR is updated on the next one.
+    pub irq_next_cycle: bool,
+}
+
+impl CIATimer {
+    pub fn new() -> CIATimer {
+        CIATimer {
+            latch: 0xFFFF,
+            value: 0xFFFF,
+            is_running: false,
+            one_shot: false,
+            input: TimerInput::Phi2,
+            irq_next_cycle: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = CIATimer::new();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.latch);
+        w.u16(self.value);
+        w.bool(self.is_running);
+        w.bool(self.one_shot);
+        w.u8(self.input as u8);
+        w.bool(self.irq_next_cycle);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.latch = r.u16()?;
+        self.value = r.u16()?;
+        self.is_running = r.bool()?;
+        self.one_shot = r.bool()?;
+        self.input = match r.u8()? {
+            0 => TimerInput::Phi2,
+            1 => TimerInput::Cnt,
+            2 => TimerInput::TimerA,
+            3 => TimerInput::TimerACnt,
+            other => return Err(format!("invalid CIA timer input {}", other)),
+        };
+        self.irq_next_cycle = r.bool()?;
+        Ok(())
+    }
+
+    /// Counts one input pulse. Returns true if the timer underflowed.
+    fn count(&mut self) -> bool {
+        if !self.is_running {
+            return false;
+        }
+
+        if self.value > 0 {
+            self.value -= 1;
+            return false;
+        }
+
+        self.value = self.latch;
+        self.irq_next_cycle = true;
+        if self.one_shot {
+            self.is_running = false;
+        }
+        true
+    }
+
+    /// Applies the bits of CRA/CRB that both timers share.
+    fn write_control(&mut self, value: u8) {
+        self.is_running = value & CR_START != 0;
+        self.one_shot = value & CR_ONE_SHOT != 0;
+        if value & CR_FORCE_LOAD != 0 {
+            self.value = self.latch;
+        }
+    }
+
+    fn control_bits(&self) -> u8 {
+        let mut value = 0;
+        if self.is_running {
+            value |= CR_START;
+        }
+        if self.one_shot {
+            value |= CR_ONE_SHOT;
+        }
+        value
+    }
+}
+
+impl Default for CIATimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Time-of-day value in BCD, as exposed through registers $08-$0B.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TodTime {
+    pub tenths: u8,
+    pub sec: u8,
+    pub min: u8,
+    /// Hours 1-12, bit 7 set for PM.
+    pub hour: u8,
+}
+
+impl TodTime {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.tenths);
+        w.u8(self.sec);
+        w.u8(self.min);
+        w.u8(self.hour);
+    }
+
+    fn load_state(r: &mut StateReader) -> Result<TodTime, String> {
+        Ok(TodTime {
+            tenths: r.u8()?,
+            sec: r.u8()?,
+            min: r.u8()?,
+            hour: r.u8()?,
+        })
+    }
+}
+
+fn bcd_increment(value: u8) -> u8 {
+    if value & 0x0F == 0x09 {
+        (value & 0xF0) + 0x10
+    } else {
+        value + 1
+    }
+}
+
+/// With the `serde` feature, timers, TOD and registers are serializable. The CPU and
+/// VIC references and IEC devices are not; reconnect them with `set_cpu_reference`,
+/// `set_vic_reference` and `attach_iec_device` after deserializing.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CIA {
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cpu_ref: cpu::CPUShared,
+    /// CIA2 port A bits 0-1 drive the VIC bank select lines, CIA1 port B bit 4 its light
+    /// pen input.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    vic_ref: Option<vic::VICShared>,
+    /// CIA1 drives the CPU's IRQ line, CIA2 its NMI line.
+    is_cia1: bool,
+    pub timer_a: CIATimer,
+    pub timer_b: CIATimer,
+    pub pra: u8,
+    pub prb: u8,
+    pub ddra: u8,
+    pub ddrb: u8,
+    pub sdr: u8,
+    /// Byte being shifted out of the serial port.
+    sdr_shift: u8,
+    /// CNT half-cycles left in the current byte; each bit takes two timer A underflows.
+    sdr_half_bits_left: u8,
+    /// A byte was written to SDR while another was still shifting.
+    sdr_pending: bool,
+    /// Level of the CNT pin, toggled by timer A while shifting out.
+    pub cnt: bool,
+    pub tod: TodTime,
+    pub tod_alarm: TodTime,
+    /// Snapshot taken when the hour register is read, released by reading tenths.
+    tod_latch: Option<TodTime>,
+    /// Writing the hour stops the clock until tenths are written.
+    tod_halted: bool,
+    tod_cycles: u32,
+    /// System cycles per tenth of a second, the TOD counting rate.
+    tod_tick_cycles: u32,
+    /// Keyboard matrix on CIA1, active low: `key_matrix[row]` holds the port B bits
+    /// pulled low when port A bit `row` is driven low, and `rev_matrix[col]` the port A
+    /// bits pulled low when port B bit `col` is.
+    pub key_matrix: [u8; 8],
+    pub rev_matrix: [u8; 8],
+    /// Joystick switches on CIA1, active low: port 1 shares port B with the keyboard
+    /// columns and port 2 shares port A with the rows.
+    pub joystick_1: u8,
+    pub joystick_2: u8,
+    /// Keeps the joysticks out of keyboard scans and the keyboard out of joystick reads,
+    /// instead of letting them pull each other's shared lines as on hardware.
+    joystick_isolation: bool,
+    /// Level of the light pen line at the last cycle, to catch its falling edge.
+    lightpen_high: bool,
+    /// Level of the FLAG pin, to catch its falling edge.
+    flag_high: bool,
+    /// Peripherals on the IEC bus; only CIA2 is wired to it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    iec_devices: Vec<Box<dyn IecDevice>>,
+    /// Latched interrupt sources, bit 7 set when one of them is enabled.
+    pub icr_data: u8,
+    /// Enabled interrupt sources.
+    pub icr_mask: u8,
+    /// CRA/CRB bits not modelled by the timers themselves, kept for read-back.
+    cra_extra: u8,
+    crb_extra: u8,
+}
+
+impl CIA {
+    pub fn new(cpu_ref: cpu::CPUShared, is_cia1: bool) -> CIA {
+        CIA {
+            cpu_ref,
+            vic_ref: None,
+            is_cia1,
+            timer_a: CIATimer::new(),
+            timer_b: CIATimer::new(),
+            pra: 0,
+            prb: 0,
+            ddra: 0,
+            ddrb: 0,
+            sdr: 0,
+            sdr_shift: 0,
+            sdr_half_bits_left: 0,
+            sdr_pending: false,
+            cnt: true,
+            tod: TodTime::default(),
+            tod_alarm: TodTime::default(),
+            tod_latch: None,
+            tod_halted: false,
+            tod_cycles: 0,
+            tod_tick_cycles: tod_tick_cycles(Region::Pal),
+            key_matrix: [0xFF; 8],
+            rev_matrix: [0xFF; 8],
+            joystick_1: 0xFF,
+            joystick_2: 0xFF,
+            joystick_isolation: false,
+            lightpen_high: true,
+            flag_high: true,
+            iec_devices: Vec::new(),
+            icr_data: 0,
+            icr_mask: 0,
+            cra_extra: 0,
+            crb_extra: 0,
+        }
+    }
+
+    pub fn new_shared(cpu_ref: cpu::CPUShared, is_cia1: bool) -> CIAShared {
+        Rc::new(RefCell::new(CIA::new(cpu_ref, is_cia1)))
+    }
+
+    pub fn set_cpu_reference(&mut self, cpu_ref: cpu::CPUShared) {
+        self.cpu_ref = cpu_ref;
+    }
+
+    pub fn set_vic_reference(&mut self, vic_ref: vic::VICShared) {
+        self.vic_ref = Some(vic_ref);
+    }
+
+    /// With isolation on, port A reads only see joystick 2, and port B reads see the
+    /// keyboard while port A drives a row low and joystick 1 otherwise. Programs that
+    /// scan the keyboard backwards, through port A, see no keys.
+    pub fn set_joystick_isolation(&mut self, isolated: bool) {
+        self.joystick_isolation = isolated;
+    }
+
+    /// The TOD input follows the mains frequency, so it counts in real time either way.
+    pub fn set_region(&mut self, region: Region) {
+        self.tod_tick_cycles = tod_tick_cycles(region);
+    }
+
+    pub fn reset(&mut self) {
+        self.timer_a.reset();
+        self.timer_b.reset();
+        self.pra = 0;
+        self.prb = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.sdr = 0;
+        self.sdr_shift = 0;
+        self.sdr_half_bits_left = 0;
+        self.sdr_pending = false;
+        self.cnt = true;
+        self.tod = TodTime::default();
+        self.tod_alarm = TodTime::default();
+        self.tod_latch = None;
+        self.tod_halted = false;
+        self.tod_cycles = 0;
+        self.icr_data = 0;
+        self.icr_mask = 0;
+        self.cra_extra = 0;
+        self.crb_extra = 0;
+        self.key_matrix = [0xFF; 8];
+        self.rev_matrix = [0xFF; 8];
+        self.joystick_1 = 0xFF;
+        self.joystick_2 = 0xFF;
+        self.lightpen_high = true;
+        self.flag_high = true;
+    }
+
+    /// Saves the chip state. Attached IEC devices are not part of it.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.timer_a.save_state(w);
+        self.timer_b.save_state(w);
+        for value in [self.pra, self.prb, self.ddra, self.ddrb] {
+            w.u8(value);
+        }
+        w.u8(self.sdr);
+        w.u8(self.sdr_shift);
+        w.u8(self.sdr_half_bits_left);
+        w.bool(self.sdr_pending);
+        w.bool(self.cnt);
+        self.tod.save_state(w);
+        self.tod_alarm.save_state(w);
+        w.bool(self.tod_latch.is_some());
+        self.tod_latch.unwrap_or_default().save_state(w);
+        w.bool(self.tod_halted);
+        w.u32(self.tod_cycles);
+        w.bytes(&self.key_matrix);
+        w.bytes(&self.rev_matrix);
+        w.u8(self.joystick_1);
+        w.u8(self.joystick_2);
+        w.u8(self.icr_data);
+        w.u8(self.icr_mask);
+        w.u8(self.cra_extra);
+        w.u8(self.crb_extra);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.timer_a.load_state(r)?;
+        self.timer_b.load_state(r)?;
+        self.pra = r.u8()?;
+        self.prb = r.u8()?;
+        self.ddra = r.u8()?;
+        self.ddrb = r.u8()?;
+        self.sdr = r.u8()?;
+        self.sdr_shift = r.u8()?;
+        self.sdr_half_bits_left = r.u8()?;
+        self.sdr_pending = r.bool()?;
+        self.cnt = r.bool()?;
+        self.tod = TodTime::load_state(r)?;
+        self.tod_alarm = TodTime::load_state(r)?;
+        let latched = r.bool()?;
+        let latch = TodTime::load_state(r)?;
+        self.tod_latch = latched.then_some(latch);
+        self.tod_halted = r.bool()?;
+        self.tod_cycles = r.u32()?;
+        r.bytes_into(&mut self.key_matrix)?;
+        r.bytes_into(&mut self.rev_matrix)?;
+        self.joystick_1 = r.u8()?;
+        self.joystick_2 = r.u8()?;
+        self.icr_data = r.u8()?;
+        self.icr_mask = r.u8()?;
+        self.cra_extra = r.u8()?;
+        self.crb_extra = r.u8()?;
+        self.port_a_changed();
+        Ok(())
+    }
+
+    /// Advances the CIA by one clock cycle.
+    pub fn update(&mut self) {
+        self.process_irq();
+        if self.is_cia1 {
+            self.check_lp();
+        }
+
+        let a_underflow = self.timer_a.input == TimerInput::Phi2 && self.timer_a.count();
+        if a_underflow {
+            self.shift_serial_out();
+        }
+        match self.timer_b.input {
+            TimerInput::Phi2 => {
+                self.timer_b.count();
+            }
+            TimerInput::TimerA if a_underflow => {
+                self.timer_b.count();
+            }
+            _ => {}
+        }
+
+        if !self.tod_halted {
+            self.tod_cycles += 1;
+            if self.tod_cycles >= self.tod_tick_cycles {
+                self.tod_cycles = 0;
+                self.count_tod();
+            }
+        }
+
+        let asserted = self.icr_data & ICR_IRQ != 0;
+        let cause = if asserted {
+            self.interrupt_cause()
+        } else {
+            None
+        };
+        let mut cpu = self.cpu_ref.borrow_mut();
+        if self.is_cia1 {
+            if let Some(cause) = cause {
+                cpu.set_irq_cause(cpu::IrqSource::Cia1, cause);
+            }
+            cpu.set_irq(cpu::IrqSource::Cia1, asserted);
+        } else {
+            if let Some(cause) = cause {
+                cpu.set_nmi_cause(cpu::NmiSource::Cia2, cause);
+            }
+            cpu.set_nmi(cpu::NmiSource::Cia2, asserted);
+        }
+    }
+
+    /// The light pen shares port B bit 4 with joystick 1 fire; pulling it low makes the
+    /// VIC latch the beam position.
+    fn check_lp(&mut self) {
+        let high = (self.prb | !self.ddrb) & self.joystick_1 & 0x10 != 0;
+        if self.lightpen_high && !high {
+            if let Some(vic_ref) = &self.vic_ref {
+                vic_ref.borrow_mut().trigger_lp_irq();
+            }
+        }
+        self.lightpen_high = high;
+    }
+
+    /// Moves timer underflows from the previous cycle into the ICR.
+    fn process_irq(&mut self) {
+        if self.timer_a.irq_next_cycle {
+            self.timer_a.irq_next_cycle = false;
+            self.trigger_interrupt(ICR_TIMER_A);
+        }
+
+        if self.timer_b.irq_next_cycle {
+            self.timer_b.irq_next_cycle = false;
+            self.trigger_interrupt(ICR_TIMER_B);
+        }
+    }
+
+    /// Advances the time of day by a tenth of a second and checks the alarm.
+    pub fn count_tod(&mut self) {
+        let tod = &mut self.tod;
+        tod.tenths = bcd_increment(tod.tenths);
+        if tod.tenths == 0x10 {
+            tod.tenths = 0;
+            tod.sec = bcd_increment(tod.sec);
+            if tod.sec == 0x60 {
+                tod.sec = 0;
+                tod.min = bcd_increment(tod.min);
+                if tod.min == 0x60 {
+                    tod.min = 0;
+                    let pm = tod.hour & 0x80;
+                    tod.hour = match tod.hour & 0x1F {
+                        // 11 -> 12 flips AM/PM, 12 -> 1 does not.
+                        0x11 => 0x12 | (pm ^ 0x80),
+                        0x12 => 0x01 | pm,
+                        hour => bcd_increment(hour) | pm,
+                    };
+                }
+            }
+        }
+
+        if self.tod == self.tod_alarm {
+            self.trigger_interrupt(ICR_TOD_ALARM);
+        }
+    }
+
+    /// TOD value as software reads it: the latched copy while one is held.
+    fn tod_view(&self) -> TodTime {
+        self.tod_latch.unwrap_or(self.tod)
+    }
+
+    fn tod_write_target(&mut self) -> &mut TodTime {
+        if self.crb_extra & CRB_TOD_ALARM != 0 {
+            &mut self.tod_alarm
+        } else {
+            &mut self.tod
+        }
+    }
+
+    fn serial_output(&self) -> bool {
+        self.cra_extra & CRA_SP_OUTPUT != 0
+    }
+
+    /// Clocks the serial port on a timer A underflow. In output mode every underflow
+    /// toggles CNT, and a bit goes out on each full CNT cycle.
+    fn shift_serial_out(&mut self) {
+        if !self.serial_output() || self.sdr_half_bits_left == 0 {
+            return;
+        }
+
+        self.cnt = !self.cnt;
+        if self.cnt {
+            self.sdr_shift <<= 1;
+        }
+
+        self.sdr_half_bits_left -= 1;
+        if self.sdr_half_bits_left == 0 {
+            self.trigger_interrupt(ICR_SERIAL);
+            if self.sdr_pending {
+                self.sdr_pending = false;
+                self.start_serial_byte();
+            }
+        }
+    }
+
+    /// Level of the SP pin: the bit currently being shifted out, MSB first.
+    pub fn serial_data_out(&self) -> bool {
+        self.sdr_shift & 0x80 != 0
+    }
+
+    fn start_serial_byte(&mut self) {
+        self.sdr_shift = self.sdr;
+        self.sdr_half_bits_left = 16;
+    }
+
+    pub fn has_iec_devices(&self) -> bool {
+        !self.iec_devices.is_empty()
+    }
+
+    pub fn attach_iec_device(&mut self, device: Box<dyn IecDevice>) {
+        self.iec_devices.push(device);
+        let lines = self.host_iec_lines();
+        if let Some(device) = self.iec_devices.last_mut() {
+            device.host_lines_changed(lines);
+        }
+    }
+
+    /// Lines driven by the computer through CIA2 port A bits 3-5. They go through
+    /// inverting drivers, so a 1 bit pulls the line low.
+    fn host_iec_lines(&self) -> IecLines {
+        let out = self.pra | !self.ddra;
+        IecLines {
+            atn: out & 0x08 != 0,
+            clk: out & 0x10 != 0,
+            data: out & 0x20 != 0,
+        }
+    }
+
+    /// Wired-AND of the computer and every attached device.
+    pub fn iec_bus(&self) -> IecLines {
+        self.iec_devices
+            .iter()
+            .fold(self.host_iec_lines(), |lines, device| {
+                lines.combine(device.device_lines())
+            })
+    }
+
+    fn notify_iec_devices(&mut self) {
+        if self.is_cia1 || self.iec_devices.is_empty() {
+            return;
+        }
+
+        let lines = self.host_iec_lines();
+        for device in self.iec_devices.iter_mut() {
+            device.host_lines_changed(lines);
+        }
+    }
+
+    fn port_a_changed(&mut self) {
+        self.notify_iec_devices();
+
+        // VA14/VA15 are inverted: port A bits 0-1 high select bank 0 at $0000.
+        if let Some(vic_ref) = self.vic_ref.as_ref().filter(|_| !self.is_cia1) {
+            let out = self.pra | !self.ddra;
+            vic_ref.borrow_mut().on_va_change(!out & 0x03);
+        }
+    }
+
+    /// Port A as read on CIA2: bits 6 and 7 sample CLK and DATA, reading 0 while low.
+    fn read_cia2_port_a(&self) -> u8 {
+        let bus = self.iec_bus();
+        let mut value = (self.pra | !self.ddra) & 0x3F;
+        if !bus.clk {
+            value |= 0x40;
+        }
+        if !bus.data {
+            value |= 0x80;
+        }
+        value
+    }
+
+    /// Drives the FLAG pin. A high-to-low transition latches ICR bit 4. On CIA1 it is
+    /// wired to the cassette read line and the serial SRQ line, on CIA2 to the user port.
+    pub fn set_flag_line(&mut self, high: bool) {
+        if self.flag_high && !high {
+            self.trigger_interrupt(ICR_FLAG);
+        }
+        self.flag_high = high;
+    }
+
+    /// The lowest-numbered enabled source latched in the ICR.
+    fn interrupt_cause(&self) -> Option<cpu::InterruptCause> {
+        let pending = self.icr_data & self.icr_mask;
+        [
+            (ICR_TIMER_A, cpu::InterruptCause::TimerA),
+            (ICR_TIMER_B, cpu::InterruptCause::TimerB),
+            (ICR_TOD_ALARM, cpu::InterruptCause::TodAlarm),
+            (ICR_SERIAL, cpu::InterruptCause::Serial),
+            (ICR_FLAG, cpu::InterruptCause::Flag),
+        ]
+        .into_iter()
+        .find(|&(bit, _)| pending & bit != 0)
+        .map(|(_, cause)| cause)
+    }
+
+    pub fn trigger_interrupt(&mut self, mask: u8) {
+        self.icr_data |= mask;
+        if self.icr_mask & mask != 0 {
+            self.icr_data |= ICR_IRQ;
+        }
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr & 0x0F {
+            0x00 if !self.is_cia1 => self.read_cia2_port_a(),
+            0x00 if self.joystick_isolation => (self.pra | !self.ddra) & self.joystick_2,
+            0x00 => {
+                let port_b = (self.prb | !self.ddrb) & self.joystick_1;
+                (self.pra | !self.ddra) & self.joystick_2 & scan_matrix(&self.rev_matrix, port_b)
+            }
+            0x01 if self.is_cia1 && self.joystick_isolation => {
+                let port_a = self.pra | !self.ddra;
+                let input = if port_a == 0xFF {
+                    self.joystick_1
+                } else {
+                    scan_matrix(&self.key_matrix, port_a)
+                };
+                (self.prb | !self.ddrb) & input
+            }
+            0x01 if self.is_cia1 => {
+                let port_a = (self.pra | !self.ddra) & self.joystick_2;
+                (self.prb | !self.ddrb) & self.joystick_1 & scan_matrix(&self.key_matrix, port_a)
+            }
+            0x01 => self.prb | !self.ddrb,
+            0x02 => self.ddra,
+            0x03 => self.ddrb,
+            0x04 => self.timer_a.value as u8,
+            0x05 => (self.timer_a.value >> 8) as u8,
+            0x06 => self.timer_b.value as u8,
+            0x07 => (self.timer_b.value >> 8) as u8,
+            0x08 => {
+                let value = self.tod_view().tenths;
+                self.tod_latch = None;
+                value
+            }
+            0x09 => self.tod_view().sec,
+            0x0A => self.tod_view().min,
+            0x0B => {
+                if self.tod_latch.is_none() {
+                    self.tod_latch = Some(self.tod);
+                }
+                self.tod_view().hour
+            }
+            0x0C => self.sdr,
+            0x0D => {
+                // Reading the ICR acknowledges every pending interrupt. An underflow in
+                // the cycle of the read only reaches the ICR a cycle later, so it
+                // survives the read.
+                let value = self.icr_data;
+                self.icr_data = 0;
+                value
+            }
+            0x0E => self.timer_a.control_bits() | self.cra_extra,
+            0x0F => self.timer_b.control_bits() | self.crb_extra,
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr & 0x0F {
+            0x00 => {
+                self.pra = value;
+                self.port_a_changed();
+            }
+            0x01 => self.prb = value,
+            0x02 => {
+                self.ddra = value;
+                self.port_a_changed();
+            }
+            0x03 => self.ddrb = value,
+            0x04 => self.timer_a.latch = (self.timer_a.latch & 0xFF00) | value as u16,
+            0x05 => {
+                self.timer_a.latch = (self.timer_a.latch & 0x00FF) | ((value as u16) << 8);
+                if !self.timer_a.is_running {
+                    self.timer_a.value = self.timer_a.latch;
+                }
+            }
+            0x06 => self.timer_b.latch = (self.timer_b.latch & 0xFF00) | value as u16,
+            0x07 => {
+                self.timer_b.latch = (self.timer_b.latch & 0x00FF) | ((value as u16) << 8);
+                if !self.timer_b.is_running {
+                    self.timer_b.value = self.timer_b.latch;
+                }
+            }
+            0x08 => {
+                self.tod_write_target().tenths = value & 0x0F;
+                self.tod_halted = false;
+            }
+            0x09 => self.tod_write_target().sec = value & 0x7F,
+            0x0A => self.tod_write_target().min = value & 0x7F,
+            0x0B => {
+                if self.crb_extra & CRB_TOD_ALARM == 0 {
+                    self.tod_halted = true;
+                }
+                self.tod_write_target().hour = value & 0x9F;
+            }
+            0x0C => {
+                self.sdr = value;
+                if self.serial_output() {
+                    if self.sdr_half_bits_left == 0 {
+                        self.start_serial_byte();
+                    } else {
+                        self.sdr_pending = true;
+                    }
+                }
+            }
+            0x0D => {
+                // Bit 7 selects whether the other set bits enable or disable sources.
+                if value & 0x80 != 0 {
+                    self.icr_mask |= value & 0x7F;
+                } else {
+                    self.icr_mask &= !value;
+                }
+
+                if self.icr_data & self.icr_mask & 0x7F != 0 {
+                    self.icr_data |= ICR_IRQ;
+                }
+            }
+            0x0E => {
+                self.timer_a.write_control(value);
+                self.timer_a.input = if value & 0x20 != 0 {
+                    TimerInput::Cnt
+                } else {
+                    TimerInput::Phi2
+                };
+                // Switching the serial port direction abandons any byte in flight.
+                if (self.cra_extra ^ value) & CRA_SP_OUTPUT != 0 {
+                    self.sdr_half_bits_left = 0;
+                    self.sdr_pending = false;
+                    self.cnt = true;
+                }
+                self.cra_extra = value & 0xE6;
+            }
+            0x0F => {
+                self.timer_b.write_control(value);
+                self.timer_b.input = match (value >> 5) & 0x03 {
+                    0 => TimerInput::Phi2,
+                    1 => TimerInput::Cnt,
+                    2 => TimerInput::TimerA,
+                    _ => TimerInput::TimerACnt,
+                };
+                self.crb_extra = value & 0xE6;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Lines pulled low by the keys in the matrix lines that `drive` holds low.
+fn scan_matrix(matrix: &[u8; 8], drive: u8) -> u8 {
+    (0..8)
+        .filter(|line| drive & (1 << line) == 0)
+        .fold(0xFF, |value, line| value & matrix[line])
+}
+
+fn tod_tick_cycles(region: Region) -> u32 {
+    (region.clock_freq() + 5) / 10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn cia1() -> (CIA, cpu::CPUShared) {
+        let cpu_ref = cpu::CPU::new_shared(Memory::new_shared());
+        (CIA::new(cpu_ref.clone(), true), cpu_ref)
+    }
+
+    #[test]
+    fn timer_b_underflow_asserts_irq() {
+        let (mut cia, cpu_ref) = cia1();
+        cia.write_register(0x0D, 0x80 | ICR_TIMER_B);
+        cia.write_register(0x06, 0x03);
+        cia.write_register(0x07, 0x00);
+        // Start, one-shot, force load.
+        cia.write_register(0x0F, 0x19);
+
+        for _ in 0..4 {
+            cia.update();
+        }
+        assert!(!cpu_ref.borrow().irq_line());
+
+        cia.update();
+        assert!(cpu_ref.borrow().irq_line());
+        assert!(!cia.timer_b.is_running);
+        assert_eq!(cia.read_register(0x0D), 0x80 | ICR_TIMER_B);
+
+        cia.update();
+        assert!(!cpu_ref.borrow().irq_line());
+    }
+
+    #[test]
+    fn icr_read_on_the_underflow_cycle_keeps_the_interrupt() {
+        let (mut cia, cpu_ref) = cia1();
+        cia.write_register(0x0D, 0x80 | ICR_TIMER_A);
+        cia.write_register(0x04, 0x01);
+        cia.write_register(0x05, 0x00);
+        // Start, one-shot, force load.
+        cia.write_register(0x0E, 0x19);
+
+        cia.update();
+        cia.update();
+        assert!(!cia.timer_a.is_running);
+        assert_eq!(cia.read_register(0x0D), 0x00);
+
+        cia.update();
+        assert!(cpu_ref.borrow().irq_line());
+        assert_eq!(cia.read_register(0x0D), 0x80 | ICR_TIMER_A);
+    }
+
+    #[test]
+    fn flag_falling_edge_raises_the_interrupt() {
+        let (mut cia, cpu_ref) = cia1();
+        cia.set_flag_line(false);
+        cia.update();
+        assert!(!cpu_ref.borrow().irq_line());
+        assert_eq!(cia.read_register(0x0D), ICR_FLAG);
+
+        // Staying low or going high is not an edge.
+        cia.write_register(0x0D, 0x80 | ICR_FLAG);
+        cia.set_flag_line(false);
+        cia.set_flag_line(true);
+        cia.update();
+        assert_eq!(cia.read_register(0x0D), 0x00);
+
+        cia.set_flag_line(false);
+        cia.set_flag_line(true);
+        cia.update();
+        assert!(cpu_ref.borrow().irq_line());
+        assert_eq!(cia.read_register(0x0D), 0x80 | ICR_FLAG);
+    }
+
+    #[test]
+    fn timer_read_on_the_underflow_cycle_sees_the_latch() {
+        let (mut cia, _cpu_ref) = cia1();
+        cia.write_register(0x04, 0x02);
+        cia.write_register(0x05, 0x00);
+        // Start, continuous, force load.
+        cia.write_register(0x0E, 0x11);
+
+        let mut reads = Vec::new();
+        for _ in 0..4 {
+            cia.update();
+            reads.push(cia.read_register(0x04));
+        }
+        // A latch of N gives a period of N + 1 cycles, reloading as 0 is passed.
+        assert_eq!(reads, [0x01, 0x00, 0x02, 0x01]);
+        assert_eq!(cia.read_register(0x05), 0x00);
+        assert_eq!(cia.icr_data & ICR_TIMER_A, ICR_TIMER_A);
+    }
+
+    #[test]
+    fn sdr_output_interrupts_after_eight_bits() {
+        let (mut cia, _cpu_ref) = cia1();
+        // Timer A latch 0 underflows every cycle.
+        cia.write_register(0x04, 0x00);
+        cia.write_register(0x05, 0x00);
+        cia.write_register(0x0E, CRA_SP_OUTPUT | CR_FORCE_LOAD | CR_START);
+        cia.write_register(0x0C, 0xA5);
+        // Queue a second byte while the first is shifting.
+        cia.write_register(0x0C, 0x5A);
+
+        for _ in 0..15 {
+            cia.update();
+        }
+        assert_eq!(cia.icr_data & ICR_SERIAL, 0);
+
+        cia.update();
+        assert_ne!(cia.icr_data & ICR_SERIAL, 0);
+        cia.read_register(0x0D);
+
+        for _ in 0..15 {
+            cia.update();
+        }
+        assert_eq!(cia.icr_data & ICR_SERIAL, 0);
+        cia.update();
+        assert_ne!(cia.icr_data & ICR_SERIAL, 0);
+
+        // Nothing left queued, so no further interrupt.
+        cia.read_register(0x0D);
+        for _ in 0..32 {
+            cia.update();
+        }
+        assert_eq!(cia.icr_data & ICR_SERIAL, 0);
+    }
+
+    #[test]
+    fn tod_second_is_visible_in_registers() {
+        let (mut cia, _cpu_ref) = cia1();
+        cia.write_register(0x0B, 0x01);
+        cia.write_register(0x0A, 0x59);
+        cia.write_register(0x09, 0x59);
+        cia.write_register(0x08, 0x09);
+
+        for _ in 0..tod_tick_cycles(Region::Pal) {
+            cia.update();
+        }
+        assert_eq!(cia.read_register(0x0B), 0x02);
+        assert_eq!(cia.read_register(0x0A), 0x00);
+        assert_eq!(cia.read_register(0x09), 0x00);
+        assert_eq!(cia.read_register(0x08), 0x00);
+
+        for _ in 0..10 {
+            cia.count_tod();
+        }
+        assert_eq!(cia.read_register(0x09), 0x01);
+    }
+
+    #[test]
+    fn tod_hour_read_latches_until_tenths() {
+        let (mut cia, _cpu_ref) = cia1();
+        cia.write_register(0x0B, 0x11);
+        cia.write_register(0x08, 0x00);
+
+        assert_eq!(cia.read_register(0x0B), 0x11);
+        for _ in 0..10 {
+            cia.count_tod();
+        }
+        assert_eq!(cia.read_register(0x09), 0x00);
+        assert_eq!(cia.read_register(0x08), 0x00);
+        assert_eq!(cia.read_register(0x09), 0x01);
+    }
+
+    #[test]
+    fn tod_alarm_raises_icr() {
+        let (mut cia, _cpu_ref) = cia1();
+        cia.write_register(0x0F, CRB_TOD_ALARM);
+        cia.write_register(0x08, 0x02);
+        cia.write_register(0x0F, 0x00);
+
+        cia.count_tod();
+        assert_eq!(cia.icr_data & ICR_TOD_ALARM, 0);
+        cia.count_tod();
+        assert_ne!(cia.icr_data & ICR_TOD_ALARM, 0);
+    }
+
+    struct MockDrive {
+        host: Rc<RefCell<IecLines>>,
+        pull_data: bool,
+    }
+
+    impl IecDevice for MockDrive {
+        fn host_lines_changed(&mut self, lines: IecLines) {
+            *self.host.borrow_mut() = lines;
+        }
+
+        fn device_lines(&self) -> IecLines {
+            IecLines {
+                data: self.pull_data,
+                ..IecLines::default()
+            }
+        }
+    }
+
+    #[test]
+    fn iec_device_pulling_data_is_read_back() {
+        let cpu_ref = cpu::CPU::new_shared(Memory::new_shared());
+        let mut cia2 = CIA::new(cpu_ref, false);
+        cia2.write_register(0x02, 0x3F);
+        cia2.write_register(0x00, 0x03);
+        assert_eq!(cia2.read_register(0x00) & 0xC0, 0xC0);
+
+        let host = Rc::new(RefCell::new(IecLines::default()));
+        cia2.attach_iec_device(Box::new(MockDrive {
+            host: host.clone(),
+            pull_data: true,
+        }));
+        assert_eq!(cia2.read_register(0x00) & 0xC0, 0x40);
+
+        // ATN out is seen by the device.
+        cia2.write_register(0x00, 0x0B);
+        assert!(host.borrow().atn);
+        assert!(!host.borrow().clk);
+    }
+
+    #[test]
+    fn host_clk_out_reads_back_low() {
+        let cpu_ref = cpu::CPU::new_shared(Memory::new_shared());
+        let mut cia2 = CIA::new(cpu_ref, false);
+        cia2.write_register(0x02, 0x3F);
+        cia2.write_register(0x00, 0x10);
+
+        assert_eq!(cia2.read_register(0x00) & 0x40, 0);
+    }
+
+    #[test]
+    fn masked_timer_a_sets_icr_without_irq() {
+        let (mut cia, cpu_ref) = cia1();
+        cia.write_register(0x04, 0x00);
+        cia.write_register(0x05, 0x00);
+        cia.write_register(0x0E, 0x11);
+
+        cia.update();
+        cia.update();
+        assert!(!cpu_ref.borrow().irq_line());
+        assert_eq!(cia.read_register(0x0D), ICR_TIMER_A);
+    }
+}
@@ -0,0 +1,132 @@
+//! Paces emulation to the system clock in wall-clock time.
+
+use std::time::Instant;
+
+/// Slowest speed multiplier accepted; anything lower would stop the clock for good.
+pub const MIN_SPEED: f64 = 0.01;
+
+pub struct Clock {
+    freq: u32,
+    speed: f64,
+    /// Run as fast as the host allows.
+    warp: bool,
+    /// Pacing restarts from here whenever the speed changes.
+    start: Instant,
+    ticks_at_start: u64,
+    ticks: u64,
+}
+
+impl Clock {
+    pub fn new(freq: u32) -> Clock {
+        Clock {
+            freq,
+            speed: 1.0,
+            warp: false,
+            start: Instant::now(),
+            ticks_at_start: 0,
+            ticks: 0,
+        }
+    }
+
+    /// Returns true when the next cycle is due.
+    pub fn tick(&mut self) -> bool {
+        if !self.warp {
+            let elapsed = self.start.elapsed().as_secs_f64();
+            let due = self.ticks_at_start + (elapsed * self.freq as f64 * self.speed) as u64;
+            if self.ticks >= due {
+                return false;
+            }
+        }
+
+        self.ticks += 1;
+        true
+    }
+
+    /// Cycles handed out so far.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Continues counting from `ticks`, pacing from now.
+    pub fn set_ticks(&mut self, ticks: u64) {
+        self.ticks = ticks;
+        self.restart();
+    }
+
+    /// Scales emulation speed relative to real time; 1.0 is normal speed. Speeds below
+    /// `MIN_SPEED`, zero, negative and NaN included, are raised to it.
+    pub fn set_speed_multiplier(&mut self, speed: f64) {
+        self.speed = speed.max(MIN_SPEED);
+        self.restart();
+    }
+
+    pub fn speed_multiplier(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_warp(&mut self, warp: bool) {
+        self.warp = warp;
+        self.restart();
+    }
+
+    pub fn toggle_warp(&mut self) {
+        self.set_warp(!self.warp);
+    }
+
+    pub fn is_warp(&self) -> bool {
+        self.warp
+    }
+
+    /// Paces from now on, so the clock doesn't try to catch up on time spent at another
+    /// speed or stopped.
+    pub fn restart(&mut self) {
+        self.start = Instant::now();
+        self.ticks_at_start = self.ticks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn double_speed_runs_twice_the_ticks() {
+        let mut normal = Clock::new(10_000);
+        let mut fast = Clock::new(10_000);
+        fast.set_speed_multiplier(2.0);
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while Instant::now() < deadline {
+            normal.tick();
+            fast.tick();
+        }
+
+        let ratio = fast.ticks() as f64 / normal.ticks() as f64;
+        assert!((1.5..2.5).contains(&ratio), "ratio {}", ratio);
+    }
+
+    #[test]
+    fn non_positive_speeds_are_raised_to_the_minimum() {
+        let mut clock = Clock::new(1_000_000);
+        for speed in [0.0, -2.0, f64::NAN] {
+            clock.set_speed_multiplier(speed);
+            assert_eq!(clock.speed_multiplier(), MIN_SPEED);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !clock.tick() {
+            assert!(Instant::now() < deadline, "clock never ticked");
+        }
+    }
+
+    #[test]
+    fn warp_ticks_without_waiting() {
+        let mut clock = Clock::new(1);
+        clock.set_warp(true);
+        assert!((0..1000).all(|_| clock.tick()));
+
+        clock.toggle_warp();
+        assert!(!clock.tick());
+    }
+}
@@ -0,0 +1,2181 @@
+//! NMOS 6502/6510 CPU core.
+
+use crate::cia;
+use crate::debugger::OpDebugger;
+use crate::memory::{MemShared, Memory, WatchHit};
+use crate::opcodes::{self, AddrMode, Instruction, Op};
+use crate::reu;
+use crate::sid;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils;
+use crate::vic;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+pub type CPUShared = Rc<RefCell<CPU>>;
+
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+const STACK_PAGE: u16 = 0x0100;
+
+/// KERNAL vectors in RAM that the ROM handlers jump through.
+pub const SOFT_IRQ_VECTOR: u16 = 0x0314;
+pub const SOFT_BRK_VECTOR: u16 = 0x0316;
+pub const SOFT_NMI_VECTOR: u16 = 0x0318;
+
+/// Interrupt vectors as currently installed: the hardware ones at $FFFA-$FFFF as banked
+/// in, and the KERNAL's soft vectors in RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vectors {
+    pub nmi: u16,
+    pub reset: u16,
+    pub irq: u16,
+    pub soft_irq: u16,
+    pub soft_brk: u16,
+    pub soft_nmi: u16,
+}
+
+/// One executed instruction, with the registers as they were before it ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u8,
+    /// Operand bytes; only the first `operand_len` are meaningful.
+    pub operand: [u8; 2],
+    pub operand_len: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+    /// CPU cycles elapsed before the instruction started.
+    pub cycle: u64,
+}
+
+impl TraceRecord {
+    /// Formats the record as one disassembly line followed by the registers.
+    pub fn format(&self, labels: &HashMap<u16, String>) -> String {
+        let len = self.operand_len as usize;
+        let mut bytes = vec![self.opcode];
+        bytes.extend_from_slice(&self.operand[..len]);
+        let (instruction, _) = utils::disassemble_instruction(self.pc, &bytes, labels);
+
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| if self.p & (0x80 >> i) != 0 { c } else { '.' })
+            .collect();
+
+        format!(
+            "${:04X}: {:<20}  A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{}",
+            self.pc, instruction, self.a, self.x, self.y, self.sp, flags
+        )
+    }
+}
+
+pub type TraceCallback = Box<dyn FnMut(&TraceRecord)>;
+/// Called with the next instruction's address and the target of a write that lands
+/// just ahead of it.
+pub type SmcCallback = Box<dyn FnMut(u16, u16)>;
+
+/// Bytes after PC watched for self-modifying writes.
+const SMC_WINDOW: u16 = 8;
+
+/// Chips sharing the open-collector IRQ line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqSource {
+    Vic = 1 << 0,
+    Cia1 = 1 << 1,
+    Reu = 1 << 2,
+}
+
+/// Sources sharing the open-collector NMI line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NmiSource {
+    Cia2 = 1 << 0,
+    Restore = 1 << 1,
+}
+
+/// What inside a chip raised IRQ or NMI, kept for diagnostics only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterruptCause {
+    TimerA,
+    TimerB,
+    TodAlarm,
+    Serial,
+    Flag,
+    Raster,
+    SpriteBackground,
+    SpriteSprite,
+    Lightpen,
+    Reu,
+    Restore,
+}
+
+/// Index of a source's bit in the interrupt source masks.
+fn source_index(bit: u8) -> usize {
+    bit.trailing_zeros() as usize
+}
+
+/// Registers a breakpoint condition can compare.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+}
+
+/// Predicate a conditional breakpoint must satisfy, checked when its address is
+/// reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    RegisterEquals(Register, u8),
+    /// The byte at the address, as currently banked in, equals the value.
+    MemoryEquals(u16, u8),
+    FlagSet(StatusFlag),
+    FlagClear(StatusFlag),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusFlag {
+    Carry = 1 << 0,
+    Zero = 1 << 1,
+    InterruptDisable = 1 << 2,
+    DecimalMode = 1 << 3,
+    Break = 1 << 4,
+    Unused = 1 << 5,
+    Overflow = 1 << 6,
+    Negative = 1 << 7,
+}
+
+/// With the `serde` feature, registers and interrupt state are serializable. Chip
+/// references are not: a deserialized CPU sits on fresh RAM until
+/// `set_memory_reference` and `set_references` connect it to the machine again.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CPU {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    mem_ref: MemShared,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    vic_ref: Option<vic::VICShared>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cia1_ref: Option<cia::CIAShared>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cia2_ref: Option<cia::CIAShared>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sid_ref: Option<sid::SIDShared>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    reu_ref: Option<reu::ReuShared>,
+    /// Cycles still to elapse before the next instruction is fetched.
+    cycles_left: u8,
+    /// Set by a JAM/KIL opcode; the CPU stops until the next reset.
+    halted: bool,
+    /// IRQ is level-triggered: serviced for as long as any source holds the line.
+    irq_sources: u8,
+    /// NMI is edge-triggered: only the line going from no sources to some latches
+    /// `nmi_pending`.
+    nmi_sources: u8,
+    nmi_pending: bool,
+    /// Latest cause each interrupt source reported, by source bit. The REU and RESTORE
+    /// only have the one.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    irq_causes: [Option<InterruptCause>; 3],
+    #[cfg_attr(feature = "serde", serde(skip))]
+    nmi_causes: [Option<InterruptCause>; 2],
+    /// Cause of the edge latched in `nmi_pending`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pending_nmi_cause: Option<InterruptCause>,
+    /// Causes of the last IRQ and NMI serviced.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_irq_source: Option<InterruptCause>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_nmi_source: Option<InterruptCause>,
+    /// I flag as seen by the next interrupt poll. CLI, SEI and PLP change the flag after
+    /// the poll has happened, so their effect on IRQs is delayed by one instruction.
+    irq_poll_i_flag: Option<bool>,
+    /// RDY input; when low the CPU stalls on its next read cycle.
+    rdy: bool,
+    /// Write cycles at the tail of the current instruction, which RDY cannot stall.
+    trailing_writes: u8,
+    /// Breakpoint addresses, each with the condition it stops on, if any.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    breakpoints: HashMap<u16, Option<Condition>>,
+    /// Instructions still allowed to run, when limited by `set_instruction_limit`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    instructions_left: Option<u64>,
+    /// Symbols shown in place of addresses when printing instructions.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub debugger: OpDebugger,
+    /// Set after stopping at a breakpoint so the next step executes the instruction there.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    resume_from_breakpoint: bool,
+    /// When set, every fetched opcode is counted in `opcode_counts`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    profiling: bool,
+    #[cfg_attr(feature = "serde", serde(skip, default = "empty_histogram"))]
+    opcode_counts: Box<[u64; 256]>,
+    /// Cycles spent in instructions and interrupt sequences since the CPU was created.
+    cycles: u64,
+    /// Called after every instruction with the state it started from.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trace: Option<TraceCallback>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    smc_watch: Option<SmcCallback>,
+}
+
+fn empty_histogram() -> Box<[u64; 256]> {
+    Box::new([0; 256])
+}
+
+/// Decimal-mode ADC as the NMOS 6502 does it. Returns the result, carry out and the N,
+/// V and Z flags. Z comes from the binary sum, N and V from the sum after the low
+/// nibble has been adjusted but before the high nibble is.
+pub fn add_bcd(a: u8, operand: u8, carry: bool) -> (u8, bool, bool, bool, bool) {
+    let (a, b, carry) = (a as u16, operand as u16, carry as u16);
+    let binary = a + b + carry;
+    let mut lo = (a & 0x0F) + (b & 0x0F) + carry;
+    if lo >= 0x0A {
+        lo = ((lo + 0x06) & 0x0F) + 0x10;
+    }
+    let mut result = (a & 0xF0) + (b & 0xF0) + lo;
+    let negative = result & 0x80 != 0;
+    let overflow = (a ^ result) & (b ^ result) & 0x80 != 0;
+    if result >= 0xA0 {
+        result += 0x60;
+    }
+    (
+        result as u8,
+        result >= 0x100,
+        negative,
+        overflow,
+        binary & 0xFF == 0,
+    )
+}
+
+/// Decimal-mode SBC as the NMOS 6502 does it, with the same return values as
+/// `add_bcd`. Every flag follows the binary difference; only the result is adjusted.
+pub fn sub_bcd(a: u8, operand: u8, carry: bool) -> (u8, bool, bool, bool, bool) {
+    let (a, b, borrow) = (a as i16, operand as i16, !carry as i16);
+    let binary = a - b - borrow;
+    let mut lo = (a & 0x0F) - (b & 0x0F) - borrow;
+    if lo < 0 {
+        lo = ((lo - 0x06) & 0x0F) - 0x10;
+    }
+    let mut result = (a & 0xF0) - (b & 0xF0) + lo;
+    if result < 0 {
+        result -= 0x60;
+    }
+    (
+        result as u8,
+        binary >= 0,
+        binary & 0x80 != 0,
+        (a ^ binary) & (a ^ b) & 0x80 != 0,
+        binary & 0xFF == 0,
+    )
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new(Rc::new(RefCell::new(Memory::new())))
+    }
+}
+
+impl CPU {
+    pub fn new(mem_ref: MemShared) -> CPU {
+        CPU {
+            pc: 0,
+            sp: 0xFD,
+            a: 0,
+            x: 0,
+            y: 0,
+            p: StatusFlag::Unused as u8 | StatusFlag::InterruptDisable as u8,
+            mem_ref,
+            vic_ref: None,
+            cia1_ref: None,
+            cia2_ref: None,
+            sid_ref: None,
+            reu_ref: None,
+            cycles_left: 0,
+            halted: false,
+            irq_sources: 0,
+            nmi_sources: 0,
+            nmi_pending: false,
+            irq_causes: [None, None, Some(InterruptCause::Reu)],
+            nmi_causes: [None, Some(InterruptCause::Restore)],
+            pending_nmi_cause: None,
+            last_irq_source: None,
+            last_nmi_source: None,
+            irq_poll_i_flag: None,
+            rdy: true,
+            trailing_writes: 0,
+            breakpoints: HashMap::new(),
+            instructions_left: None,
+            debugger: OpDebugger::new(),
+            resume_from_breakpoint: false,
+            profiling: false,
+            opcode_counts: empty_histogram(),
+            cycles: 0,
+            trace: None,
+            smc_watch: None,
+        }
+    }
+
+    pub fn new_shared(mem_ref: MemShared) -> CPUShared {
+        Rc::new(RefCell::new(CPU::new(mem_ref)))
+    }
+
+    pub fn set_memory_reference(&mut self, mem_ref: MemShared) {
+        self.mem_ref = mem_ref;
+    }
+
+    /// Connects the chips whose registers are mapped into the IO area.
+    pub fn set_references(
+        &mut self,
+        vic_ref: vic::VICShared,
+        cia1_ref: cia::CIAShared,
+        cia2_ref: cia::CIAShared,
+        sid_ref: sid::SIDShared,
+    ) {
+        self.vic_ref = Some(vic_ref);
+        self.cia1_ref = Some(cia1_ref);
+        self.cia2_ref = Some(cia2_ref);
+        self.sid_ref = Some(sid_ref);
+    }
+
+    /// Plugs an REU into the expansion port, or unplugs it with `None`.
+    pub fn set_reu_reference(&mut self, reu_ref: Option<reu::ReuShared>) {
+        self.reu_ref = reu_ref;
+    }
+
+    pub fn reset(&mut self) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sp = 0xFD;
+        self.p = StatusFlag::Unused as u8 | StatusFlag::InterruptDisable as u8;
+        self.pc = self.read_word_le(RESET_VECTOR);
+        self.cycles_left = 6;
+        self.halted = false;
+        self.irq_sources = 0;
+        self.nmi_sources = 0;
+        self.nmi_pending = false;
+        self.pending_nmi_cause = None;
+        self.last_irq_source = None;
+        self.last_nmi_source = None;
+        self.irq_poll_i_flag = None;
+        self.rdy = true;
+        self.trailing_writes = 0;
+        self.resume_from_breakpoint = false;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.pc);
+        w.u8(self.sp);
+        w.u8(self.a);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u8(self.p);
+        w.u8(self.cycles_left);
+        w.bool(self.halted);
+        w.u8(self.irq_sources);
+        w.u8(self.nmi_sources);
+        w.bool(self.nmi_pending);
+        w.u8(match self.irq_poll_i_flag {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        });
+        w.bool(self.rdy);
+        w.u8(self.trailing_writes);
+        w.u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pc = r.u16()?;
+        self.sp = r.u8()?;
+        self.a = r.u8()?;
+        self.x = r.u8()?;
+        self.y = r.u8()?;
+        self.p = r.u8()?;
+        self.cycles_left = r.u8()?;
+        self.halted = r.bool()?;
+        self.irq_sources = r.u8()?;
+        self.nmi_sources = r.u8()?;
+        self.nmi_pending = r.bool()?;
+        self.irq_poll_i_flag = match r.u8()? {
+            0 => None,
+            1 => Some(false),
+            _ => Some(true),
+        };
+        self.rdy = r.bool()?;
+        self.trailing_writes = r.u8()?;
+        self.cycles = r.u64()?;
+        self.resume_from_breakpoint = false;
+        Ok(())
+    }
+
+    /// Advances the CPU by one clock cycle. Returns true if a breakpoint stopped execution
+    /// at the current instruction boundary.
+    pub fn update(&mut self) -> bool {
+        if self.halted {
+            return false;
+        }
+
+        // With RDY low, read cycles are burned without progress; only the write
+        // cycles that finish an instruction still go through.
+        if self.cycles_left > 0 {
+            if !self.rdy && self.cycles_left > self.trailing_writes {
+                return false;
+            }
+            self.cycles_left -= 1;
+            return false;
+        }
+
+        if !self.rdy {
+            return false;
+        }
+
+        match self.step() {
+            0 => true,
+            cycles => {
+                self.cycles_left = cycles - 1;
+                false
+            }
+        }
+    }
+
+    /// Executes one instruction, or services a pending interrupt if one is due at this
+    /// instruction boundary. Returns the number of cycles consumed, or 0 if nothing ran
+    /// because the CPU is halted, out of instructions or stopped at a breakpoint.
+    pub fn step(&mut self) -> u8 {
+        if self.halted || self.instructions_left == Some(0) || self.at_breakpoint() {
+            return 0;
+        }
+
+        self.trailing_writes = 0;
+        let cycles = match self.process_interrupts() {
+            Some(cycles) => cycles,
+            None => {
+                let record = self.trace.is_some().then(|| self.trace_record());
+                let cycles = self.execute_instruction();
+                if let Some(left) = self.instructions_left.as_mut() {
+                    *left -= 1;
+                }
+                if let (Some(record), Some(trace)) = (record, self.trace.as_mut()) {
+                    trace(&record);
+                }
+                cycles
+            }
+        };
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    /// Like `step`, but runs a JSR's whole subroutine as one step. The call is over once
+    /// PC is back after the JSR with the stack pointer where it was, so recursive calls
+    /// returning to the same address don't end it early. Returns the cycles consumed;
+    /// a breakpoint or halt inside the subroutine stops it short.
+    pub fn step_over(&mut self) -> u64 {
+        let is_jsr = opcodes::decode(self.mem_ref.borrow().peek(self.pc))
+            .is_some_and(|instruction| instruction.op == Op::JSR);
+        if !is_jsr {
+            return self.step() as u64;
+        }
+
+        let return_addr = self.pc.wrapping_add(3);
+        let sp = self.sp;
+        let mut total = 0;
+        loop {
+            let cycles = self.step();
+            total += cycles as u64;
+            if cycles == 0 || (self.pc == return_addr && self.sp == sp) {
+                return total;
+            }
+        }
+    }
+
+    /// Total cycles spent executing so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Calls `trace` after every executed instruction. Interrupt sequences aren't traced.
+    pub fn set_trace_callback(&mut self, trace: TraceCallback) {
+        self.trace = Some(trace);
+    }
+
+    pub fn clear_trace_callback(&mut self) {
+        self.trace = None;
+    }
+
+    /// Calls `watch` whenever a write lands in the few bytes from PC on, i.e. code is
+    /// patching the instructions it is about to run. Operands are always fetched from
+    /// memory as they execute, so this is a debugging aid only.
+    pub fn set_smc_callback(&mut self, watch: SmcCallback) {
+        self.smc_watch = Some(watch);
+    }
+
+    pub fn clear_smc_callback(&mut self) {
+        self.smc_watch = None;
+    }
+
+    /// Describes the instruction at PC and the current registers, without touching the
+    /// bus.
+    pub fn trace_record(&self) -> TraceRecord {
+        let mem = self.mem_ref.borrow();
+        let opcode = mem.peek(self.pc);
+        let operand_len = opcodes::decode(opcode).map_or(0, |i| i.mode.operand_len() as u8);
+        TraceRecord {
+            pc: self.pc,
+            opcode,
+            operand: [
+                mem.peek(self.pc.wrapping_add(1)),
+                mem.peek(self.pc.wrapping_add(2)),
+            ],
+            operand_len,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            sp: self.sp,
+            p: self.p,
+            cycle: self.cycles,
+        }
+    }
+
+    /// Starts or stops counting executed opcodes. Counts are kept until
+    /// `reset_profiling`.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+    }
+
+    /// Number of times each opcode byte was fetched while profiling.
+    pub fn opcode_histogram(&self) -> [u64; 256] {
+        *self.opcode_counts
+    }
+
+    pub fn reset_profiling(&mut self) {
+        self.opcode_counts.fill(0);
+    }
+
+    /// Stops the CPU after `limit` more instructions, or lifts the limit with `None`.
+    /// Interrupt sequences don't count. A guard against runaway loops in unattended
+    /// runs; the limit survives resets.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instructions_left = limit;
+    }
+
+    /// True once the limit has run out and the last instruction allowed has finished.
+    pub fn instruction_limit_reached(&self) -> bool {
+        self.instructions_left == Some(0) && self.cycles_left == 0
+    }
+
+    /// True when the next cycle starts a new instruction or interrupt sequence.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cycles_left == 0
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr, None);
+    }
+
+    /// Adds a breakpoint that only stops when `condition` holds as `addr` is reached.
+    /// Replaces any breakpoint already at `addr`.
+    pub fn add_conditional_breakpoint(&mut self, addr: u16, condition: Condition) {
+        self.breakpoints.insert(addr, Some(condition));
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Checks the breakpoint set before an instruction is fetched. After a hit, the next
+    /// check passes so execution can continue from the breakpoint.
+    fn at_breakpoint(&mut self) -> bool {
+        if self.breakpoints.is_empty() {
+            return false;
+        }
+
+        if self.resume_from_breakpoint {
+            self.resume_from_breakpoint = false;
+            return false;
+        }
+
+        self.resume_from_breakpoint = match self.breakpoints.get(&self.pc) {
+            Some(Some(condition)) => self.condition_holds(*condition),
+            Some(None) => true,
+            None => false,
+        };
+        self.resume_from_breakpoint
+    }
+
+    fn condition_holds(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::RegisterEquals(register, value) => {
+                let current = match register {
+                    Register::A => self.a,
+                    Register::X => self.x,
+                    Register::Y => self.y,
+                    Register::Sp => self.sp,
+                    Register::P => self.p,
+                };
+                current == value
+            }
+            Condition::MemoryEquals(addr, value) => self.mem_ref.borrow().peek(addr) == value,
+            Condition::FlagSet(flag) => self.get_status_flag(flag),
+            Condition::FlagClear(flag) => !self.get_status_flag(flag),
+        }
+    }
+
+    /// Returns and clears the most recent memory watchpoint hit.
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.mem_ref.borrow().take_watch_hit()
+    }
+
+    /// Writes the instruction at PC along with the register state as one line.
+    pub fn debug_instruction(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "{}", self.format_state())
+    }
+
+    /// Disassembles `count` instructions from PC as memory is currently banked, using
+    /// the debugger's labels. Addresses wrap from $FFFF to $0000.
+    pub fn disassemble_ahead(&self, count: usize) -> Vec<(u16, String)> {
+        let mem = self.mem_ref.borrow();
+        let mut addr = self.pc;
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let bytes = [0, 1, 2].map(|i| mem.peek(addr.wrapping_add(i)));
+            let (text, len) = utils::disassemble_instruction(addr, &bytes, self.debugger.labels());
+            lines.push((addr, text));
+            addr = addr.wrapping_add(len);
+        }
+        lines
+    }
+
+    pub fn format_state(&self) -> String {
+        self.trace_record().format(self.debugger.labels())
+    }
+
+    pub fn set_irq(&mut self, source: IrqSource, value: bool) {
+        if value {
+            self.irq_sources |= source as u8;
+        } else {
+            self.irq_sources &= !(source as u8);
+        }
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.irq_sources != 0
+    }
+
+    pub fn set_nmi(&mut self, source: NmiSource, value: bool) {
+        if value && self.nmi_sources == 0 {
+            self.nmi_pending = true;
+            self.pending_nmi_cause = self.nmi_causes[source_index(source as u8)];
+        }
+        if value {
+            self.nmi_sources |= source as u8;
+        } else {
+            self.nmi_sources &= !(source as u8);
+        }
+    }
+
+    /// Tells the CPU why `source` is asserting IRQ, for `last_irq_source`. The line
+    /// itself is left alone.
+    pub fn set_irq_cause(&mut self, source: IrqSource, cause: InterruptCause) {
+        self.irq_causes[source_index(source as u8)] = Some(cause);
+    }
+
+    /// As `set_irq_cause`, for NMI. Must come before the `set_nmi` that asserts it.
+    pub fn set_nmi_cause(&mut self, source: NmiSource, cause: InterruptCause) {
+        self.nmi_causes[source_index(source as u8)] = Some(cause);
+    }
+
+    /// Cause of the last IRQ serviced, from the lowest-numbered source holding the line.
+    pub fn last_irq_source(&self) -> Option<InterruptCause> {
+        self.last_irq_source
+    }
+
+    /// Cause of the last NMI serviced.
+    pub fn last_nmi_source(&self) -> Option<InterruptCause> {
+        self.last_nmi_source
+    }
+
+    /// Drives the RDY line. The VIC pulls it low to steal cycles on bad lines.
+    pub fn set_rdy(&mut self, value: bool) {
+        self.rdy = value;
+    }
+
+    pub fn rdy_line(&self) -> bool {
+        self.rdy
+    }
+
+    /// Returns from a subroutine as RTS does, for traps that replace a ROM routine.
+    pub fn return_from_subroutine(&mut self) {
+        self.pc = self.pop_word().wrapping_add(1);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        if !(0xD000..=0xDFFF).contains(&addr) || !self.mem_ref.borrow().io_visible() {
+            return self.mem_ref.borrow().read_byte(addr);
+        }
+
+        match addr {
+            0xD000..=0xD3FF => {
+                if let Some(vic_ref) = &self.vic_ref {
+                    return vic_ref.borrow_mut().read_register(addr);
+                }
+            }
+            0xD400..=0xD7FF => {
+                if let Some(sid_ref) = &self.sid_ref {
+                    return sid_ref.borrow_mut().read_register(addr);
+                }
+            }
+            0xDF00..=0xDFFF => {
+                if let Some(reu_ref) = &self.reu_ref {
+                    return reu_ref.borrow_mut().read_register(addr);
+                }
+            }
+            _ => {
+                if let Some(cia_ref) = self.cia_at(addr) {
+                    return cia_ref.borrow_mut().read_register(addr);
+                }
+            }
+        }
+
+        self.mem_ref.borrow().read_byte(addr)
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        if let Some(watch) = self.smc_watch.as_mut() {
+            if addr.wrapping_sub(self.pc) < SMC_WINDOW {
+                watch(self.pc, addr);
+            }
+        }
+        if !(0xD000..=0xDFFF).contains(&addr) || !self.mem_ref.borrow().io_visible() {
+            self.mem_ref.borrow_mut().write_byte(addr, value);
+            if addr == reu::TRIGGER_ADDRESS {
+                if let Some(reu_ref) = &self.reu_ref {
+                    reu_ref.borrow_mut().on_trigger_write();
+                }
+            }
+            return;
+        }
+
+        match addr {
+            0xD000..=0xD3FF => {
+                if let Some(vic_ref) = &self.vic_ref {
+                    vic_ref.borrow_mut().write_register(addr, value);
+                    return;
+                }
+            }
+            0xD400..=0xD7FF => {
+                if let Some(sid_ref) = &self.sid_ref {
+                    sid_ref.borrow_mut().write_register(addr, value);
+                    return;
+                }
+            }
+            0xDF00..=0xDFFF => {
+                if let Some(reu_ref) = &self.reu_ref {
+                    reu_ref.borrow_mut().write_register(addr, value);
+                    return;
+                }
+            }
+            _ => {
+                if let Some(cia_ref) = self.cia_at(addr) {
+                    cia_ref.borrow_mut().write_register(addr, value);
+                    return;
+                }
+            }
+        }
+
+        self.mem_ref.borrow_mut().write_byte(addr, value);
+    }
+
+    /// Returns the CIA whose registers are mapped at `addr`, if any.
+    fn cia_at(&self, addr: u16) -> Option<&cia::CIAShared> {
+        match addr {
+            0xDC00..=0xDCFF => self.cia1_ref.as_ref(),
+            0xDD00..=0xDDFF => self.cia2_ref.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Reads every interrupt vector without side effects on the bus.
+    pub fn read_vectors(&self) -> Vectors {
+        let mem = self.mem_ref.borrow();
+        let word = |addr: u16| u16::from_le_bytes([mem.peek(addr), mem.peek(addr.wrapping_add(1))]);
+        Vectors {
+            nmi: word(NMI_VECTOR),
+            reset: word(RESET_VECTOR),
+            irq: word(IRQ_VECTOR),
+            soft_irq: word(SOFT_IRQ_VECTOR),
+            soft_brk: word(SOFT_BRK_VECTOR),
+            soft_nmi: word(SOFT_NMI_VECTOR),
+        }
+    }
+
+    /// Reads a little-endian word through the CPU's view of the bus, chips included. A
+    /// word at $FFFF takes its high byte from $0000.
+    pub fn read_word_le(&self, addr: u16) -> u16 {
+        // Only a word touching $D000-$DFFF can reach a chip.
+        if !(0xCFFF..=0xDFFF).contains(&addr) {
+            return self.mem_ref.borrow().read_word_le(addr);
+        }
+        u16::from_le_bytes([self.read_byte(addr), self.read_byte(addr.wrapping_add(1))])
+    }
+
+    /// Reads one status flag, for debuggers and register editors.
+    pub fn flag(&self, flag: StatusFlag) -> bool {
+        self.get_status_flag(flag)
+    }
+
+    /// Sets or clears one status flag. Bit 5 has no latch on the 6510 and always reads
+    /// as 1, so it stays set whatever is asked.
+    pub fn set_flag(&mut self, flag: StatusFlag, value: bool) {
+        self.set_status_flag(flag, value);
+        self.p |= StatusFlag::Unused as u8;
+    }
+
+    fn get_status_flag(&self, flag: StatusFlag) -> bool {
+        self.p & flag as u8 != 0
+    }
+
+    fn set_status_flag(&mut self, flag: StatusFlag, value: bool) {
+        if value {
+            self.p |= flag as u8;
+        } else {
+            self.p &= !(flag as u8);
+        }
+    }
+
+    fn set_zn_flags(&mut self, value: u8) {
+        self.set_status_flag(StatusFlag::Zero, value == 0);
+        self.set_status_flag(StatusFlag::Negative, value & 0x80 != 0);
+    }
+
+    /// The stack lives in page 1; SP wraps within it on overflow and underflow.
+    fn push_byte(&mut self, value: u8) {
+        self.write_byte(STACK_PAGE | self.sp as u16, value);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pop_byte(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.read_byte(STACK_PAGE | self.sp as u16)
+    }
+
+    fn push_word(&mut self, value: u16) {
+        self.push_byte((value >> 8) as u8);
+        self.push_byte(value as u8);
+    }
+
+    fn pop_word(&mut self) -> u16 {
+        let lo = self.pop_byte() as u16;
+        let hi = self.pop_byte() as u16;
+        (hi << 8) | lo
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let value = self.read_byte(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let value = self.read_word_le(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        value
+    }
+
+    /// Reads a word from the zero page, wrapping the high byte fetch within page 0.
+    fn read_zp_word(&self, addr: u8) -> u16 {
+        let lo = self.read_byte(addr as u16) as u16;
+        let hi = self.read_byte(addr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
+    /// Resolves the effective address of the current instruction's operand and reports
+    /// whether indexing crossed a page boundary.
+    fn fetch_operand_addr(&mut self, mode: AddrMode) -> (u16, bool) {
+        match mode {
+            AddrMode::Implied | AddrMode::Accumulator => (0, false),
+            AddrMode::Immediate => {
+                let addr = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                (addr, false)
+            }
+            AddrMode::ZeroPage => (self.fetch_byte() as u16, false),
+            AddrMode::ZeroPageX => (self.fetch_byte().wrapping_add(self.x) as u16, false),
+            AddrMode::ZeroPageY => (self.fetch_byte().wrapping_add(self.y) as u16, false),
+            AddrMode::Relative => {
+                let offset = self.fetch_byte() as i8;
+                let addr = self.pc.wrapping_add(offset as u16);
+                (addr, addr & 0xFF00 != self.pc & 0xFF00)
+            }
+            AddrMode::Absolute => (self.fetch_word(), false),
+            AddrMode::AbsoluteX => {
+                let base = self.fetch_word();
+                let addr = base.wrapping_add(self.x as u16);
+                (addr, addr & 0xFF00 != base & 0xFF00)
+            }
+            AddrMode::AbsoluteY => {
+                let base = self.fetch_word();
+                let addr = base.wrapping_add(self.y as u16);
+                (addr, addr & 0xFF00 != base & 0xFF00)
+            }
+            AddrMode::Indirect => {
+                // NMOS bug: the high byte is fetched without carrying into the pointer's
+                // high byte, so JMP ($xxFF) reads its high byte from $xx00.
+                let ptr = self.fetch_word();
+                let lo = self.read_byte(ptr) as u16;
+                let hi = self.read_byte((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF)) as u16;
+                ((hi << 8) | lo, false)
+            }
+            AddrMode::IndexedIndirectX => {
+                let ptr = self.fetch_byte().wrapping_add(self.x);
+                (self.read_zp_word(ptr), false)
+            }
+            AddrMode::IndirectIndexedY => {
+                let ptr = self.fetch_byte();
+                let base = self.read_zp_word(ptr);
+                let addr = base.wrapping_add(self.y as u16);
+                (addr, addr & 0xFF00 != base & 0xFF00)
+            }
+        }
+    }
+
+    fn read_operand(&self, mode: AddrMode, addr: u16) -> u8 {
+        match mode {
+            AddrMode::Accumulator => self.a,
+            _ => self.read_byte(addr),
+        }
+    }
+
+    fn write_operand(&mut self, mode: AddrMode, addr: u16, value: u8) {
+        match mode {
+            AddrMode::Accumulator => self.a = value,
+            _ => self.write_byte(addr, value),
+        }
+    }
+
+    fn process_interrupts(&mut self) -> Option<u8> {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.last_nmi_source = self.pending_nmi_cause.take();
+            self.interrupt(NMI_VECTOR);
+            return Some(7);
+        }
+
+        let i_flag = match self.irq_poll_i_flag.take() {
+            Some(flag) => flag,
+            None => self.get_status_flag(StatusFlag::InterruptDisable),
+        };
+        if self.irq_line() && !i_flag {
+            self.last_irq_source = self.irq_causes[source_index(self.irq_sources)];
+            self.interrupt(IRQ_VECTOR);
+            return Some(7);
+        }
+
+        None
+    }
+
+    /// Pushes the return state and jumps through `vector`, as hardware interrupts do.
+    fn interrupt(&mut self, vector: u16) {
+        self.push_word(self.pc);
+        let p = (self.p & !(StatusFlag::Break as u8)) | StatusFlag::Unused as u8;
+        self.push_byte(p);
+        self.set_status_flag(StatusFlag::InterruptDisable, true);
+        self.pc = self.read_word_le(vector);
+    }
+
+    /// Fetches, decodes and executes a single instruction, returning the cycles it took.
+    fn execute_instruction(&mut self) -> u8 {
+        let opcode_addr = self.pc;
+        let opcode = self.fetch_byte();
+        if self.profiling {
+            self.opcode_counts[opcode as usize] += 1;
+        }
+        let Some(instruction) = opcodes::decode(opcode) else {
+            // The decode table covers every byte, but lock up rather than panic if not.
+            self.halted = true;
+            self.pc = opcode_addr;
+            return 2;
+        };
+
+        self.trailing_writes = instruction.trailing_writes();
+        let (addr, page_crossed) = self.fetch_operand_addr(instruction.mode);
+        let mut cycles = instruction.cycles;
+        if instruction.page_penalty && page_crossed {
+            cycles += 1;
+        }
+
+        cycles + self.execute_op(instruction, addr, page_crossed)
+    }
+
+    /// Runs the operation itself. Returns any extra cycles spent (taken branches).
+    fn execute_op(&mut self, instruction: Instruction, addr: u16, page_crossed: bool) -> u8 {
+        let mode = instruction.mode;
+        match instruction.op {
+            Op::ADC => {
+                let value = self.read_operand(mode, addr);
+                self.add_with_carry(value);
+            }
+            Op::AND => {
+                self.a &= self.read_operand(mode, addr);
+                self.set_zn_flags(self.a);
+            }
+            Op::ASL => {
+                let value = self.read_operand(mode, addr);
+                let result = self.asl(value);
+                self.write_operand(mode, addr, result);
+            }
+            Op::BCC => return self.branch_if(StatusFlag::Carry, false, addr, page_crossed),
+            Op::BCS => return self.branch_if(StatusFlag::Carry, true, addr, page_crossed),
+            Op::BEQ => return self.branch_if(StatusFlag::Zero, true, addr, page_crossed),
+            Op::BMI => return self.branch_if(StatusFlag::Negative, true, addr, page_crossed),
+            Op::BNE => return self.branch_if(StatusFlag::Zero, false, addr, page_crossed),
+            Op::BPL => return self.branch_if(StatusFlag::Negative, false, addr, page_crossed),
+            Op::BVC => return self.branch_if(StatusFlag::Overflow, false, addr, page_crossed),
+            Op::BVS => return self.branch_if(StatusFlag::Overflow, true, addr, page_crossed),
+            Op::BIT => {
+                let value = self.read_operand(mode, addr);
+                self.set_status_flag(StatusFlag::Zero, self.a & value == 0);
+                self.set_status_flag(StatusFlag::Overflow, value & 0x40 != 0);
+                self.set_status_flag(StatusFlag::Negative, value & 0x80 != 0);
+            }
+            Op::BRK => {
+                // BRK skips a padding byte after the opcode.
+                let ret = self.pc.wrapping_add(1);
+                self.push_word(ret);
+                let p = self.p | StatusFlag::Break as u8 | StatusFlag::Unused as u8;
+                self.push_byte(p);
+                self.set_status_flag(StatusFlag::InterruptDisable, true);
+                self.pc = self.read_word_le(IRQ_VECTOR);
+            }
+            Op::CLC => self.set_status_flag(StatusFlag::Carry, false),
+            Op::CLD => self.set_status_flag(StatusFlag::DecimalMode, false),
+            Op::CLI => {
+                self.delay_irq_poll();
+                self.set_status_flag(StatusFlag::InterruptDisable, false);
+            }
+            Op::CLV => self.set_status_flag(StatusFlag::Overflow, false),
+            Op::CMP => {
+                let value = self.read_operand(mode, addr);
+                self.compare(self.a, value);
+            }
+            Op::CPX => {
+                let value = self.read_operand(mode, addr);
+                self.compare(self.x, value);
+            }
+            Op::CPY => {
+                let value = self.read_operand(mode, addr);
+                self.compare(self.y, value);
+            }
+            Op::DEC => {
+                let result = self.read_operand(mode, addr).wrapping_sub(1);
+                self.set_zn_flags(result);
+                self.write_operand(mode, addr, result);
+            }
+            Op::DEX => {
+                self.x = self.x.wrapping_sub(1);
+                self.set_zn_flags(self.x);
+            }
+            Op::DEY => {
+                self.y = self.y.wrapping_sub(1);
+                self.set_zn_flags(self.y);
+            }
+            Op::EOR => {
+                self.a ^= self.read_operand(mode, addr);
+                self.set_zn_flags(self.a);
+            }
+            Op::INC => {
+                let result = self.read_operand(mode, addr).wrapping_add(1);
+                self.set_zn_flags(result);
+                self.write_operand(mode, addr, result);
+            }
+            Op::INX => {
+                self.x = self.x.wrapping_add(1);
+                self.set_zn_flags(self.x);
+            }
+            Op::INY => {
+                self.y = self.y.wrapping_add(1);
+                self.set_zn_flags(self.y);
+            }
+            Op::JMP => self.pc = addr,
+            Op::JSR => {
+                let ret = self.pc.wrapping_sub(1);
+                self.push_word(ret);
+                self.pc = addr;
+            }
+            Op::LDA => {
+                self.a = self.read_operand(mode, addr);
+                self.set_zn_flags(self.a);
+            }
+            Op::LDX => {
+                self.x = self.read_operand(mode, addr);
+                self.set_zn_flags(self.x);
+            }
+            Op::LDY => {
+                self.y = self.read_operand(mode, addr);
+                self.set_zn_flags(self.y);
+            }
+            Op::LSR => {
+                let value = self.read_operand(mode, addr);
+                let result = self.lsr(value);
+                self.write_operand(mode, addr, result);
+            }
+            Op::NOP => {
+                // The undocumented NOPs with operands still perform their read.
+                if mode != AddrMode::Implied {
+                    self.read_byte(addr);
+                }
+            }
+            Op::ORA => {
+                self.a |= self.read_operand(mode, addr);
+                self.set_zn_flags(self.a);
+            }
+            Op::PHA => self.push_byte(self.a),
+            Op::PHP => {
+                let p = self.p | StatusFlag::Break as u8 | StatusFlag::Unused as u8;
+                self.push_byte(p);
+            }
+            Op::PLA => {
+                self.a = self.pop_byte();
+                self.set_zn_flags(self.a);
+            }
+            Op::PLP => {
+                self.delay_irq_poll();
+                let p = self.pop_byte();
+                self.p = (p & !(StatusFlag::Break as u8)) | StatusFlag::Unused as u8;
+            }
+            Op::ROL => {
+                let value = self.read_operand(mode, addr);
+                let result = self.rol(value);
+                self.write_operand(mode, addr, result);
+            }
+            Op::ROR => {
+                let value = self.read_operand(mode, addr);
+                let result = self.ror(value);
+                self.write_operand(mode, addr, result);
+            }
+            Op::RTI => {
+                let p = self.pop_byte();
+                self.p = (p & !(StatusFlag::Break as u8)) | StatusFlag::Unused as u8;
+                self.pc = self.pop_word();
+            }
+            Op::RTS => self.pc = self.pop_word().wrapping_add(1),
+            Op::SBC => {
+                let value = self.read_operand(mode, addr);
+                self.subtract_with_carry(value);
+            }
+            Op::SEC => self.set_status_flag(StatusFlag::Carry, true),
+            Op::SED => self.set_status_flag(StatusFlag::DecimalMode, true),
+            Op::SEI => {
+                self.delay_irq_poll();
+                self.set_status_flag(StatusFlag::InterruptDisable, true);
+            }
+            Op::STA => self.write_byte(addr, self.a),
+            Op::STX => self.write_byte(addr, self.x),
+            Op::STY => self.write_byte(addr, self.y),
+            Op::TAX => {
+                self.x = self.a;
+                self.set_zn_flags(self.x);
+            }
+            Op::TAY => {
+                self.y = self.a;
+                self.set_zn_flags(self.y);
+            }
+            Op::TSX => {
+                self.x = self.sp;
+                self.set_zn_flags(self.x);
+            }
+            Op::TXA => {
+                self.a = self.x;
+                self.set_zn_flags(self.a);
+            }
+            Op::TXS => self.sp = self.x,
+            Op::TYA => {
+                self.a = self.y;
+                self.set_zn_flags(self.a);
+            }
+
+            // Undocumented NMOS opcodes. The read-modify-write ones write back through
+            // the normal memory path before combining the result with the accumulator.
+            Op::JAM => {
+                // Lock up with the PC parked on the offending opcode.
+                self.halted = true;
+                self.pc = self.pc.wrapping_sub(1);
+            }
+            Op::LAX => {
+                self.a = self.read_operand(mode, addr);
+                self.x = self.a;
+                self.set_zn_flags(self.a);
+            }
+            Op::SAX => self.write_byte(addr, self.a & self.x),
+            Op::DCP => {
+                let result = self.read_byte(addr).wrapping_sub(1);
+                self.write_byte(addr, result);
+                self.compare(self.a, result);
+            }
+            Op::ISC => {
+                let result = self.read_byte(addr).wrapping_add(1);
+                self.write_byte(addr, result);
+                self.subtract_with_carry(result);
+            }
+            Op::SLO => {
+                let value = self.read_byte(addr);
+                let result = self.asl(value);
+                self.write_byte(addr, result);
+                self.a |= result;
+                self.set_zn_flags(self.a);
+            }
+            Op::RLA => {
+                let value = self.read_byte(addr);
+                let result = self.rol(value);
+                self.write_byte(addr, result);
+                self.a &= result;
+                self.set_zn_flags(self.a);
+            }
+            Op::SRE => {
+                let value = self.read_byte(addr);
+                let result = self.lsr(value);
+                self.write_byte(addr, result);
+                self.a ^= result;
+                self.set_zn_flags(self.a);
+            }
+            Op::RRA => {
+                let value = self.read_byte(addr);
+                let result = self.ror(value);
+                self.write_byte(addr, result);
+                self.add_with_carry(result);
+            }
+            Op::ANC => {
+                self.a &= self.read_byte(addr);
+                self.set_zn_flags(self.a);
+                self.set_status_flag(StatusFlag::Carry, self.a & 0x80 != 0);
+            }
+            Op::ALR => {
+                let value = self.a & self.read_byte(addr);
+                self.a = self.lsr(value);
+            }
+            Op::ARR => {
+                let value = self.a & self.read_byte(addr);
+                self.and_rotate_right(value);
+            }
+            Op::SBX => {
+                let value = self.read_byte(addr);
+                let and = self.a & self.x;
+                self.x = and.wrapping_sub(value);
+                self.set_status_flag(StatusFlag::Carry, and >= value);
+                self.set_zn_flags(self.x);
+            }
+            // ANE and LXA mix in a chip-dependent constant; $EE is the common one.
+            Op::ANE => {
+                self.a = (self.a | 0xEE) & self.x & self.read_byte(addr);
+                self.set_zn_flags(self.a);
+            }
+            Op::LXA => {
+                self.a = (self.a | 0xEE) & self.read_byte(addr);
+                self.x = self.a;
+                self.set_zn_flags(self.a);
+            }
+            Op::LAS => {
+                let value = self.read_byte(addr) & self.sp;
+                self.a = value;
+                self.x = value;
+                self.sp = value;
+                self.set_zn_flags(value);
+            }
+            Op::SHA => self.store_high_and(self.a & self.x, addr, self.y, page_crossed),
+            Op::SHX => self.store_high_and(self.x, addr, self.y, page_crossed),
+            Op::SHY => self.store_high_and(self.y, addr, self.x, page_crossed),
+            Op::TAS => {
+                self.sp = self.a & self.x;
+                self.store_high_and(self.sp, addr, self.y, page_crossed);
+            }
+        }
+
+        0
+    }
+
+    /// ARR: ROR of the AND result with flags of its own. In decimal mode the result
+    /// is then BCD-fixed nibble by nibble.
+    fn and_rotate_right(&mut self, value: u8) {
+        let carry = self.get_status_flag(StatusFlag::Carry);
+        let mut result = (value >> 1) | ((carry as u8) << 7);
+        if !self.get_status_flag(StatusFlag::DecimalMode) {
+            self.set_zn_flags(result);
+            self.set_status_flag(StatusFlag::Carry, result & 0x40 != 0);
+            self.set_status_flag(StatusFlag::Overflow, (result ^ (result << 1)) & 0x40 != 0);
+            self.a = result;
+            return;
+        }
+
+        self.set_status_flag(StatusFlag::Negative, carry);
+        self.set_status_flag(StatusFlag::Zero, result == 0);
+        self.set_status_flag(StatusFlag::Overflow, (value ^ result) & 0x40 != 0);
+        if (value & 0x0F) + (value & 0x01) > 0x05 {
+            result = (result & 0xF0) | (result.wrapping_add(0x06) & 0x0F);
+        }
+        let high_fixed = (value as u16 & 0xF0) + (value as u16 & 0x10) > 0x50;
+        self.set_status_flag(StatusFlag::Carry, high_fixed);
+        if high_fixed {
+            result = result.wrapping_add(0x60);
+        }
+        self.a = result;
+    }
+
+    /// SHA, SHX, SHY and TAS: stores `value` ANDed with the high byte of the unindexed
+    /// address plus one. When indexing crosses a page, the stored value also replaces
+    /// the high byte of the address written.
+    fn store_high_and(&mut self, value: u8, addr: u16, index: u8, page_crossed: bool) {
+        let high = (addr.wrapping_sub(index as u16) >> 8) as u8;
+        let value = value & high.wrapping_add(1);
+        let addr = if page_crossed {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.write_byte(addr, value);
+    }
+
+    /// Makes the next interrupt poll see the I flag as it was before this instruction.
+    fn delay_irq_poll(&mut self) {
+        self.irq_poll_i_flag = Some(self.get_status_flag(StatusFlag::InterruptDisable));
+    }
+
+    /// Branches to `addr` if `flag` equals `set`: +1 cycle when taken, +1 more on a page cross.
+    fn branch_if(&mut self, flag: StatusFlag, set: bool, addr: u16, page_crossed: bool) -> u8 {
+        if self.get_status_flag(flag) != set {
+            return 0;
+        }
+
+        self.pc = addr;
+        if page_crossed {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn asl(&mut self, value: u8) -> u8 {
+        let result = value << 1;
+        self.set_status_flag(StatusFlag::Carry, value & 0x80 != 0);
+        self.set_zn_flags(result);
+        result
+    }
+
+    fn lsr(&mut self, value: u8) -> u8 {
+        let result = value >> 1;
+        self.set_status_flag(StatusFlag::Carry, value & 0x01 != 0);
+        self.set_zn_flags(result);
+        result
+    }
+
+    fn rol(&mut self, value: u8) -> u8 {
+        let result = (value << 1) | self.get_status_flag(StatusFlag::Carry) as u8;
+        self.set_status_flag(StatusFlag::Carry, value & 0x80 != 0);
+        self.set_zn_flags(result);
+        result
+    }
+
+    fn ror(&mut self, value: u8) -> u8 {
+        let result = (value >> 1) | ((self.get_status_flag(StatusFlag::Carry) as u8) << 7);
+        self.set_status_flag(StatusFlag::Carry, value & 0x01 != 0);
+        self.set_zn_flags(result);
+        result
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        let result = register.wrapping_sub(value);
+        self.set_status_flag(StatusFlag::Carry, register >= value);
+        self.set_zn_flags(result);
+    }
+
+    fn add_with_carry(&mut self, value: u8) {
+        let carry = self.get_status_flag(StatusFlag::Carry) as u16;
+        let a = self.a as u16;
+        let b = value as u16;
+        let binary = a + b + carry;
+
+        if !self.get_status_flag(StatusFlag::DecimalMode) {
+            self.set_status_flag(StatusFlag::Carry, binary > 0xFF);
+            self.set_status_flag(
+                StatusFlag::Overflow,
+                (a ^ binary) & (b ^ binary) & 0x80 != 0,
+            );
+            self.a = binary as u8;
+            self.set_zn_flags(self.a);
+            return;
+        }
+
+        let (result, carry, negative, overflow, zero) = add_bcd(self.a, value, carry != 0);
+        self.set_status_flag(StatusFlag::Carry, carry);
+        self.set_status_flag(StatusFlag::Negative, negative);
+        self.set_status_flag(StatusFlag::Overflow, overflow);
+        self.set_status_flag(StatusFlag::Zero, zero);
+        self.a = result;
+    }
+
+    fn subtract_with_carry(&mut self, value: u8) {
+        let carry_in = self.get_status_flag(StatusFlag::Carry);
+        let binary = self.a.wrapping_sub(value).wrapping_sub(!carry_in as u8);
+        // On the NMOS part every flag follows the binary result, even in decimal mode.
+        let (decimal, carry, negative, overflow, zero) = sub_bcd(self.a, value, carry_in);
+        self.set_status_flag(StatusFlag::Carry, carry);
+        self.set_status_flag(StatusFlag::Negative, negative);
+        self.set_status_flag(StatusFlag::Overflow, overflow);
+        self.set_status_flag(StatusFlag::Zero, zero);
+        self.a = if self.get_status_flag(StatusFlag::DecimalMode) {
+            decimal
+        } else {
+            binary
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{self, Memory};
+
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().load(0x0600, program);
+        let mut cpu = CPU::new(mem);
+        cpu.pc = 0x0600;
+        cpu
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn registers_round_trip_through_json() {
+        let mut cpu = cpu_with_program(&[0xA2, 0x05, 0x38]);
+        cpu.a = 0x42;
+        cpu.sp = 0xF0;
+        cpu.step();
+        cpu.step();
+
+        let json = serde_json::to_string(&cpu).unwrap();
+        let mut restored: CPU = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            (
+                restored.pc,
+                restored.sp,
+                restored.a,
+                restored.x,
+                restored.y,
+                restored.p
+            ),
+            (cpu.pc, cpu.sp, cpu.a, cpu.x, cpu.y, cpu.p)
+        );
+
+        // Memory is not part of the data until the reference is restored.
+        assert_eq!(restored.read_byte(0x0600), 0x00);
+        restored.set_memory_reference(cpu.mem_ref.clone());
+        assert_eq!(restored.read_byte(0x0600), 0xA2);
+    }
+
+    #[test]
+    fn format_state_names_labelled_operands() {
+        // JSR $0810; BNE $0600; LDA $D020
+        let mut cpu = cpu_with_program(&[0x20, 0x10, 0x08, 0xD0, 0xFB, 0xAD, 0x20, 0xD0]);
+        cpu.debugger
+            .parse_labels("al C:0810 .print_score\nal C:0600 .main\n");
+
+        assert!(cpu
+            .format_state()
+            .starts_with("$0600: 20 10 08  JSR print_score "));
+        cpu.pc = 0x0603;
+        assert!(cpu.format_state().contains("BNE main "));
+        cpu.pc = 0x0605;
+        assert!(cpu.format_state().contains("LDA $D020 "));
+    }
+
+    #[test]
+    fn debug_instruction_writes_one_line_to_the_sink() {
+        // LDA #$42
+        let cpu = cpu_with_program(&[0xA9, 0x42]);
+        let mut out = Vec::new();
+        cpu.debug_instruction(&mut out).unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with("$0600: A9 42     LDA #$42 "));
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.ends_with('\n'));
+    }
+
+    #[test]
+    fn adc_decimal_wraps_and_sets_carry() {
+        // SED; CLC; LDA #$99; ADC #$01
+        let mut cpu = cpu_with_program(&[0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01]);
+        for _ in 0..4 {
+            cpu.execute_instruction();
+        }
+
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.get_status_flag(StatusFlag::Carry));
+        // Z follows the binary sum ($9A) on NMOS parts.
+        assert!(!cpu.get_status_flag(StatusFlag::Zero));
+    }
+
+    #[test]
+    fn adc_decimal_adds_with_carry_in() {
+        // SED; SEC; LDA #$25; ADC #$48
+        let mut cpu = cpu_with_program(&[0xF8, 0x38, 0xA9, 0x25, 0x69, 0x48]);
+        for _ in 0..4 {
+            cpu.execute_instruction();
+        }
+
+        assert_eq!(cpu.a, 0x74);
+        assert!(!cpu.get_status_flag(StatusFlag::Carry));
+    }
+
+    #[test]
+    fn sbc_decimal_borrows() {
+        // SED; SEC; LDA #$00; SBC #$01
+        let mut cpu = cpu_with_program(&[0xF8, 0x38, 0xA9, 0x00, 0xE9, 0x01]);
+        for _ in 0..4 {
+            cpu.execute_instruction();
+        }
+
+        assert_eq!(cpu.a, 0x99);
+        assert!(!cpu.get_status_flag(StatusFlag::Carry));
+        assert!(cpu.get_status_flag(StatusFlag::Negative));
+    }
+
+    #[test]
+    fn bcd_helpers_match_the_nmos_results() {
+        // (a, operand, carry in) -> (result, carry out, N, V, Z)
+        #[rustfmt::skip]
+        let additions = [
+            ((0x25, 0x48, true),  (0x74, false, false, false, false)),
+            ((0x99, 0x01, false), (0x00, true,  true,  false, false)),
+            ((0x50, 0x50, false), (0x00, true,  true,  true,  false)),
+            ((0x80, 0x80, false), (0x60, true,  false, true,  true)),
+            ((0x09, 0x09, true),  (0x19, false, false, false, false)),
+            ((0x00, 0x00, false), (0x00, false, false, false, true)),
+        ];
+        for ((a, operand, carry), expected) in additions {
+            assert_eq!(
+                add_bcd(a, operand, carry),
+                expected,
+                "${:02X} + ${:02X}",
+                a,
+                operand
+            );
+        }
+
+        #[rustfmt::skip]
+        let subtractions = [
+            ((0x46, 0x12, true),  (0x34, true,  false, false, false)),
+            ((0x40, 0x13, true),  (0x27, true,  false, false, false)),
+            ((0x32, 0x02, false), (0x29, true,  false, false, false)),
+            ((0x00, 0x01, true),  (0x99, false, true,  false, false)),
+            ((0x80, 0x01, true),  (0x79, true,  false, true,  false)),
+            ((0x21, 0x21, true),  (0x00, true,  false, false, true)),
+        ];
+        for ((a, operand, carry), expected) in subtractions {
+            assert_eq!(
+                sub_bcd(a, operand, carry),
+                expected,
+                "${:02X} - ${:02X}",
+                a,
+                operand
+            );
+        }
+    }
+
+    #[test]
+    fn lax_loads_a_and_x() {
+        // LAX $10
+        let mut cpu = cpu_with_program(&[0xA7, 0x10]);
+        cpu.write_byte(0x0010, 0x80);
+        let cycles = cpu.execute_instruction();
+
+        assert_eq!(cycles, 3);
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.x, 0x80);
+        assert!(cpu.get_status_flag(StatusFlag::Negative));
+    }
+
+    #[test]
+    fn sax_stores_a_and_x() {
+        // LDA #$F0; LDX #$3C; SAX $20
+        let mut cpu = cpu_with_program(&[0xA9, 0xF0, 0xA2, 0x3C, 0x87, 0x20]);
+        for _ in 0..3 {
+            cpu.execute_instruction();
+        }
+
+        assert_eq!(cpu.read_byte(0x0020), 0x30);
+    }
+
+    #[test]
+    fn dcp_decrements_and_compares() {
+        // LDA #$41; DCP $30
+        let mut cpu = cpu_with_program(&[0xA9, 0x41, 0xC7, 0x30]);
+        cpu.write_byte(0x0030, 0x42);
+        cpu.execute_instruction();
+        cpu.execute_instruction();
+
+        assert_eq!(cpu.read_byte(0x0030), 0x41);
+        assert!(cpu.get_status_flag(StatusFlag::Zero));
+        assert!(cpu.get_status_flag(StatusFlag::Carry));
+    }
+
+    #[test]
+    fn jam_halts_until_reset() {
+        // LDA #$01; JAM; LDA #$02
+        let mut cpu = cpu_with_program(&[0xA9, 0x01, 0x02, 0xA9, 0x02]);
+        for _ in 0..20 {
+            cpu.update();
+        }
+
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.a, 0x01);
+        assert_eq!(cpu.pc, 0x0602);
+
+        cpu.reset();
+        assert!(!cpu.is_halted());
+    }
+
+    #[test]
+    fn every_opcode_but_jam_runs_without_halting() {
+        for opcode in 0..=255u8 {
+            let mut cpu = cpu_with_program(&[opcode, 0x10, 0x02]);
+            cpu.step();
+            let jam = opcodes::decode(opcode).is_some_and(|i| i.op == Op::JAM);
+            assert_eq!(cpu.is_halted(), jam, "opcode ${:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn unstable_opcodes_compute_their_nmos_results() {
+        // ANC #$80
+        let mut cpu = cpu_with_program(&[0x0B, 0x80]);
+        cpu.a = 0xFF;
+        cpu.step();
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.get_status_flag(StatusFlag::Carry));
+
+        // ALR #$03
+        let mut cpu = cpu_with_program(&[0x4B, 0x03]);
+        cpu.a = 0xFF;
+        cpu.step();
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.get_status_flag(StatusFlag::Carry));
+
+        // ARR #$FF: C from bit 6 of the result, V from bits 6 and 5.
+        let mut cpu = cpu_with_program(&[0x6B, 0xFF]);
+        cpu.a = 0xC0;
+        cpu.step();
+        assert_eq!(cpu.a, 0x60);
+        assert!(cpu.get_status_flag(StatusFlag::Carry));
+        assert!(!cpu.get_status_flag(StatusFlag::Overflow));
+
+        // SBX #$01
+        let mut cpu = cpu_with_program(&[0xCB, 0x01]);
+        cpu.a = 0x0F;
+        cpu.x = 0xF3;
+        cpu.step();
+        assert_eq!(cpu.x, 0x02);
+        assert!(cpu.get_status_flag(StatusFlag::Carry));
+
+        // LAS $1000,Y
+        let mut cpu = cpu_with_program(&[0xBB, 0x00, 0x10]);
+        cpu.write_byte(0x1000, 0xF0);
+        cpu.sp = 0x3C;
+        cpu.step();
+        assert_eq!((cpu.a, cpu.x, cpu.sp), (0x30, 0x30, 0x30));
+
+        // SHX $02FF,Y crossing into page $03: stores X & $03 to page X & $03.
+        let mut cpu = cpu_with_program(&[0x9E, 0xFF, 0x02]);
+        cpu.x = 0x11;
+        cpu.y = 0x01;
+        cpu.step();
+        assert_eq!(cpu.read_byte(0x0100), 0x01);
+    }
+
+    #[test]
+    fn jmp_indirect_wraps_within_page() {
+        // JMP ($10FF)
+        let mut cpu = cpu_with_program(&[0x6C, 0xFF, 0x10]);
+        cpu.write_byte(0x10FF, 0x34);
+        cpu.write_byte(0x1000, 0x12);
+        cpu.write_byte(0x1100, 0x56);
+        cpu.execute_instruction();
+
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn step_reports_page_cross_penalty() {
+        // LDX #$01; LDA $06FF,X
+        let mut cpu = cpu_with_program(&[0xA2, 0x01, 0xBD, 0xFF, 0x06]);
+
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.pc, 0x0605);
+    }
+
+    #[test]
+    fn step_services_pending_irq() {
+        // CLI; NOP; NOP
+        let mut cpu = cpu_with_program(&[0x58, 0xEA, 0xEA]);
+        cpu.write_byte(IRQ_VECTOR, 0x00);
+        cpu.write_byte(IRQ_VECTOR + 1, 0x20);
+        cpu.step();
+        cpu.step();
+        cpu.set_irq(IrqSource::Cia1, true);
+
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.pc, 0x2000);
+        assert!(cpu.get_status_flag(StatusFlag::InterruptDisable));
+        assert_eq!(cpu.pop_byte() & StatusFlag::Break as u8, 0);
+        assert_eq!(cpu.pop_word(), 0x0602);
+    }
+
+    #[test]
+    fn held_irq_is_serviced_repeatedly() {
+        // CLI; NOP; NOP with an IRQ handler that is just RTI
+        let mut cpu = cpu_with_program(&[0x58, 0xEA, 0xEA]);
+        cpu.write_byte(0x2000, 0x40);
+        cpu.write_byte(IRQ_VECTOR, 0x00);
+        cpu.write_byte(IRQ_VECTOR + 1, 0x20);
+        cpu.step();
+        cpu.step();
+        cpu.set_irq(IrqSource::Cia1, true);
+
+        for _ in 0..3 {
+            assert_eq!(cpu.step(), 7);
+            assert_eq!(cpu.pc, 0x2000);
+            cpu.step();
+            assert_eq!(cpu.pc, 0x0602);
+        }
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered() {
+        let mut cpu = cpu_with_program(&[0xEA, 0xEA, 0xEA]);
+        cpu.write_byte(0x3000, 0xEA);
+        cpu.write_byte(0x3001, 0xEA);
+        cpu.write_byte(NMI_VECTOR, 0x00);
+        cpu.write_byte(NMI_VECTOR + 1, 0x30);
+
+        cpu.set_nmi(NmiSource::Cia2, true);
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.pc, 0x3000);
+
+        // Re-asserting a line that never went inactive is not a new edge, and neither
+        // is a second source joining in.
+        cpu.set_nmi(NmiSource::Cia2, true);
+        cpu.set_nmi(NmiSource::Restore, true);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x3001);
+
+        cpu.set_nmi(NmiSource::Cia2, false);
+        cpu.set_nmi(NmiSource::Restore, false);
+        cpu.set_nmi(NmiSource::Restore, true);
+        assert_eq!(cpu.step(), 7);
+    }
+
+    #[test]
+    fn cli_delays_irq_by_one_instruction() {
+        // CLI; SEI; NOP
+        let mut cpu = cpu_with_program(&[0x58, 0x78, 0xEA]);
+        cpu.write_byte(IRQ_VECTOR, 0x00);
+        cpu.write_byte(IRQ_VECTOR + 1, 0x20);
+        cpu.set_irq(IrqSource::Cia1, true);
+
+        assert_eq!(cpu.step(), 2);
+        // The poll before SEI still sees I set, so SEI runs instead of the IRQ.
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x0602);
+        assert!(cpu.get_status_flag(StatusFlag::InterruptDisable));
+
+        // SEI polled while I was clear, so hardware takes the IRQ right after it,
+        // pushing a status byte with I already set.
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.pc, 0x2000);
+        assert_ne!(cpu.pop_byte() & StatusFlag::InterruptDisable as u8, 0);
+
+        // From here on I stays set and the still-asserted line is ignored.
+        cpu.write_byte(0x2000, 0xEA);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x2001);
+    }
+
+    #[test]
+    fn plp_delays_irq_enable() {
+        // LDA #$00; PHA; PLP; NOP
+        let mut cpu = cpu_with_program(&[0xA9, 0x00, 0x48, 0x28, 0xEA]);
+        cpu.write_byte(IRQ_VECTOR, 0x00);
+        cpu.write_byte(IRQ_VECTOR + 1, 0x20);
+        cpu.set_irq(IrqSource::Cia1, true);
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        assert!(!cpu.get_status_flag(StatusFlag::InterruptDisable));
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x0605);
+        assert_eq!(cpu.step(), 7);
+    }
+
+    #[test]
+    fn breakpoint_fires_once() {
+        // NOP; NOP; NOP
+        let mut cpu = cpu_with_program(&[0xEA, 0xEA, 0xEA]);
+        cpu.add_breakpoint(0x0601);
+
+        let mut hits = Vec::new();
+        for _ in 0..6 {
+            if cpu.update() {
+                hits.push(cpu.pc);
+            }
+        }
+
+        assert_eq!(hits, vec![0x0601]);
+        assert_eq!(cpu.pc, 0x0603);
+        assert!(cpu.remove_breakpoint(0x0601));
+    }
+
+    #[test]
+    fn conditional_breakpoint_waits_for_its_condition() {
+        // LDX #$00; loop: INX; CPX #$08; BNE loop
+        let mut cpu = cpu_with_program(&[0xA2, 0x00, 0xE8, 0xE0, 0x08, 0xD0, 0xFB]);
+        cpu.add_conditional_breakpoint(0x0603, Condition::RegisterEquals(Register::X, 5));
+
+        let mut passes = 0;
+        while cpu.step() != 0 {
+            if cpu.pc == 0x0603 {
+                passes += 1;
+            }
+        }
+        assert_eq!((cpu.pc, cpu.x), (0x0603, 5));
+        // The first four times round, with X at 1 to 4, it ran straight past.
+        assert_eq!(passes, 5);
+
+        cpu.write_byte(0x10, 0x42);
+        cpu.add_conditional_breakpoint(0x0605, Condition::MemoryEquals(0x10, 0x42));
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.step(), 0);
+        assert_eq!(cpu.pc, 0x0605);
+    }
+
+    #[test]
+    fn rdy_low_stalls_instruction_fetch() {
+        // NOP; NOP
+        let mut cpu = cpu_with_program(&[0xEA, 0xEA]);
+        cpu.update();
+        cpu.update();
+        assert_eq!(cpu.pc, 0x0601);
+
+        cpu.set_rdy(false);
+        for _ in 0..40 {
+            cpu.update();
+            assert_eq!(cpu.pc, 0x0601);
+        }
+
+        cpu.set_rdy(true);
+        cpu.update();
+        assert_eq!(cpu.pc, 0x0602);
+    }
+
+    #[test]
+    fn adc_binary_sets_overflow() {
+        // CLC; LDA #$50; ADC #$50
+        let mut cpu = cpu_with_program(&[0x18, 0xA9, 0x50, 0x69, 0x50]);
+        for _ in 0..3 {
+            cpu.execute_instruction();
+        }
+
+        assert_eq!(cpu.a, 0xA0);
+        assert!(cpu.get_status_flag(StatusFlag::Overflow));
+        assert!(cpu.get_status_flag(StatusFlag::Negative));
+        assert!(!cpu.get_status_flag(StatusFlag::Carry));
+    }
+
+    #[test]
+    fn profiling_counts_fetched_opcodes() {
+        // LDX #$03; loop: DEX; BNE loop; JAM
+        let mut cpu = cpu_with_program(&[0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x02]);
+        cpu.step();
+        assert_eq!(cpu.opcode_histogram()[0xA2], 0);
+
+        cpu.set_profiling(true);
+        while !cpu.is_halted() {
+            cpu.step();
+        }
+        let histogram = cpu.opcode_histogram();
+        assert_eq!(
+            (histogram[0xCA], histogram[0xD0], histogram[0x02]),
+            (3, 3, 1)
+        );
+        assert_eq!(histogram.iter().sum::<u64>(), 7);
+
+        cpu.reset_profiling();
+        assert!(cpu.opcode_histogram().iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn trace_callback_records_each_instruction() {
+        // LDA #$42; TAX; STA $0200
+        let mut cpu = cpu_with_program(&[0xA9, 0x42, 0xAA, 0x8D, 0x00, 0x02]);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let sink = records.clone();
+        cpu.set_trace_callback(Box::new(move |record| {
+            sink.borrow_mut().push(record.clone())
+        }));
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        let records = records.borrow();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.pc, r.opcode, r.operand_len, r.a, r.x, r.cycle))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x0600, 0xA9, 1, 0x00, 0x00, 0),
+                (0x0602, 0xAA, 0, 0x42, 0x00, 2),
+                (0x0603, 0x8D, 2, 0x42, 0x42, 4),
+            ]
+        );
+        assert_eq!(records[2].operand, [0x00, 0x02]);
+        assert_eq!(cpu.cycles(), 8);
+        assert!(records[2]
+            .format(&HashMap::new())
+            .starts_with("$0603: 8D 00 02  STA $0200 "));
+    }
+
+    #[test]
+    fn step_over_runs_a_subroutine_as_one_step() {
+        // JSR $0610; LDA #$07
+        let mut cpu = cpu_with_program(&[0x20, 0x10, 0x06, 0xA9, 0x07]);
+        // LDX #$01; INY; RTS
+        cpu.write_byte(0x0610, 0xA2);
+        cpu.write_byte(0x0611, 0x01);
+        cpu.write_byte(0x0612, 0xC8);
+        cpu.write_byte(0x0613, 0x60);
+        let sp = cpu.sp;
+
+        assert_eq!(cpu.step_over(), 6 + 2 + 2 + 6);
+        assert_eq!(cpu.pc, 0x0603);
+        assert_eq!((cpu.x, cpu.y, cpu.sp), (0x01, 0x01, sp));
+
+        assert_eq!(cpu.step_over(), 2);
+        assert_eq!(cpu.a, 0x07);
+    }
+
+    #[test]
+    fn stack_wraps_within_page_1() {
+        // LDA #$42; PHA; PHA
+        let mut cpu = cpu_with_program(&[0xA9, 0x42, 0x48, 0x48]);
+        cpu.sp = 0x00;
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.read_byte(0x0100), 0x42);
+        assert_eq!(cpu.read_byte(0x01FF), 0x42);
+        assert_eq!(cpu.read_byte(0x0200), 0x00);
+        assert_eq!(cpu.sp, 0xFE);
+
+        // PLA
+        let mut cpu = cpu_with_program(&[0x68]);
+        cpu.write_byte(0x0100, 0x37);
+        cpu.sp = 0xFF;
+        cpu.step();
+        assert_eq!(cpu.a, 0x37);
+        assert_eq!(cpu.sp, 0x00);
+    }
+
+    #[test]
+    fn read_vectors_reports_soft_and_hardware_vectors() {
+        let mut cpu = cpu_with_program(&[]);
+        let mut kernal = vec![0; memory::KERNAL_SIZE];
+        kernal[0x1FFE..].copy_from_slice(&[0x48, 0xFF]);
+        cpu.mem_ref
+            .borrow_mut()
+            .load_rom(memory::MemType::Kernal, &kernal)
+            .unwrap();
+        cpu.write_byte(SOFT_IRQ_VECTOR, 0x00);
+        cpu.write_byte(SOFT_IRQ_VECTOR + 1, 0xC0);
+        // Writes under the KERNAL land in RAM and don't change what the CPU sees.
+        cpu.write_byte(IRQ_VECTOR, 0x34);
+
+        let vectors = cpu.read_vectors();
+        assert_eq!(vectors.irq, 0xFF48);
+        assert_eq!(vectors.soft_irq, 0xC000);
+        assert_eq!(vectors.soft_nmi, 0x0000);
+    }
+
+    #[test]
+    fn operands_wrap_past_ffff() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.write_byte(0xFFFF, 0x34);
+        cpu.write_byte(0x0000, 0x12);
+        assert_eq!(cpu.read_word_le(0xFFFF), 0x1234);
+
+        // LDA $0612, split across $FFFE-$0000.
+        cpu.write_byte(0xFFFE, 0xAD);
+        cpu.write_byte(0xFFFF, 0x12);
+        cpu.write_byte(0x0000, 0x06);
+        cpu.write_byte(0x0612, 0x5A);
+        cpu.pc = 0xFFFE;
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.a, 0x5A);
+        assert_eq!(cpu.pc, 0x0001);
+    }
+
+    #[test]
+    fn flags_set_and_clear_their_own_bit() {
+        let mut cpu = CPU::new(Memory::new_shared());
+        cpu.p = 0;
+        let flags = [
+            (StatusFlag::Carry, 0x01),
+            (StatusFlag::Zero, 0x02),
+            (StatusFlag::InterruptDisable, 0x04),
+            (StatusFlag::DecimalMode, 0x08),
+            (StatusFlag::Break, 0x10),
+            (StatusFlag::Overflow, 0x40),
+            (StatusFlag::Negative, 0x80),
+        ];
+        for (flag, bit) in flags {
+            cpu.set_flag(flag, true);
+            assert!(cpu.flag(flag));
+            assert_eq!(cpu.p, 0x20 | bit);
+            cpu.set_flag(flag, false);
+            assert!(!cpu.flag(flag));
+            assert_eq!(cpu.p, 0x20);
+        }
+        cpu.set_flag(StatusFlag::Unused, false);
+        assert_eq!(cpu.p, 0x20);
+    }
+
+    #[test]
+    fn code_patching_its_next_operand_runs_the_new_value() {
+        // LDA #$99; STA $0606; LDA #$00 (operand at $0606)
+        let mut cpu = cpu_with_program(&[0xA9, 0x99, 0x8D, 0x06, 0x06, 0xA9, 0x00]);
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let sink = hits.clone();
+        cpu.set_smc_callback(Box::new(move |pc, addr| sink.borrow_mut().push((pc, addr))));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.a, 0x99);
+        assert_eq!(*hits.borrow(), [(0x0605, 0x0606)]);
+    }
+
+    #[test]
+    fn every_opcode_takes_its_reference_cycles() {
+        for opcode in 0..=255u8 {
+            let Some(instruction) = opcodes::decode(opcode) else {
+                panic!("${:02X} does not decode", opcode);
+            };
+            assert_eq!(
+                instruction.cycles,
+                opcodes::REFERENCE_CYCLES[opcode as usize],
+                "decode table for ${:02X}",
+                opcode
+            );
+            if instruction.op == Op::JAM {
+                continue;
+            }
+
+            // Operands point at $0210, directly or through $10/$11, and X and Y are 0,
+            // so no page is crossed.
+            let mut cpu = cpu_with_program(&[opcode, 0x10, 0x02]);
+            cpu.mem_ref.borrow_mut().load(0x0010, &[0x10, 0x02]);
+            let cycles = cpu.step();
+            let branch_taken = instruction.mode == AddrMode::Relative && cpu.pc != 0x0602;
+            assert_eq!(
+                cycles,
+                opcodes::REFERENCE_CYCLES[opcode as usize] + branch_taken as u8,
+                "cycles for ${:02X}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn branch_cycles_depend_on_taken_and_page_cross() {
+        // BNE +$10 with Z set: not taken.
+        let mut cpu = cpu_with_program(&[0xD0, 0x10]);
+        cpu.set_flag(StatusFlag::Zero, true);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.pc, 0x0602);
+
+        // Taken, landing in the same page.
+        let mut cpu = cpu_with_program(&[0xD0, 0x10]);
+        assert_eq!(cpu.step(), 3);
+        assert_eq!(cpu.pc, 0x0612);
+
+        // Taken backwards from $0602 into page $05.
+        let mut cpu = cpu_with_program(&[0xD0, 0xFC]);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0x05FE);
+    }
+
+    #[test]
+    fn disassemble_ahead_lists_from_pc() {
+        // LDA #$01; STA $D020; RTS
+        let mut cpu = cpu_with_program(&[0xA9, 0x01, 0x8D, 0x20, 0xD0, 0x60]);
+        assert_eq!(
+            cpu.disassemble_ahead(3),
+            [
+                (0x0600, String::from("A9 01     LDA #$01")),
+                (0x0602, String::from("8D 20 D0  STA $D020")),
+                (0x0605, String::from("60        RTS")),
+            ]
+        );
+
+        cpu.mem_ref.borrow_mut().load(0xFFFF, &[0xEA]);
+        cpu.pc = 0xFFFF;
+        let addrs: Vec<u16> = cpu.disassemble_ahead(2).iter().map(|l| l.0).collect();
+        assert_eq!(addrs, [0xFFFF, 0x0000]);
+    }
+
+    #[test]
+    fn brk_pushes_b_set_and_skips_its_padding_byte() {
+        // SEC; BRK; padding
+        let mut cpu = cpu_with_program(&[0x38, 0x00, 0xFF]);
+        cpu.write_byte(IRQ_VECTOR, 0x00);
+        cpu.write_byte(IRQ_VECTOR + 1, 0x20);
+        cpu.step();
+        assert_eq!(cpu.step(), 7);
+
+        assert_eq!(cpu.pc, 0x2000);
+        assert!(cpu.flag(StatusFlag::InterruptDisable));
+        assert_eq!(cpu.pop_byte(), 0x35);
+        assert_eq!(cpu.pop_word(), 0x0603);
+    }
+
+    #[test]
+    fn rti_ignores_pulled_b_and_bit_5() {
+        // RTI with N, B and C pushed but bit 5 clear.
+        let mut cpu = cpu_with_program(&[0x40]);
+        cpu.push_word(0x1234);
+        cpu.push_byte(0x91);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cpu.p, 0xA1);
+    }
+}
@@ -0,0 +1,421 @@
+//! CRT cartridge images.
+
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils;
+
+const CRT_SIGNATURE: &[u8] = b"C64 CARTRIDGE   ";
+const CHIP_SIGNATURE: &[u8] = b"CHIP";
+const CHIP_HEADER_LEN: usize = 0x10;
+
+/// Hardware type of a plain 8K/16K/Ultimax cartridge without bank switching.
+pub const HW_TYPE_NORMAL: u16 = 0;
+/// Ocean: 8K banks selected by writing the bank number to $DE00.
+pub const HW_TYPE_OCEAN: u16 = 5;
+/// Magic Desk: 8K banks at $8000 selected through $DE00; bit 7 switches the cartridge off.
+pub const HW_TYPE_MAGIC_DESK: u16 = 19;
+
+const ROML_BASE: u16 = 0x8000;
+const ROMH_BASE: u16 = 0xA000;
+/// Where ROMH shows in Ultimax mode, in place of the KERNAL.
+const ROMH_ULTIMAX_BASE: u16 = 0xE000;
+const BANK_SIZE: usize = 0x2000;
+
+/// Bank switching logic of a cartridge, consulted by `Memory` while the cartridge is
+/// plugged in.
+pub trait CartMapper {
+    /// The cartridge ROM byte at `addr` in ROML ($8000-$9FFF) or ROMH ($A000-$BFFF, or
+    /// $E000-$FFFF in Ultimax mode), or `None` where the cartridge currently maps
+    /// nothing.
+    fn read(&self, addr: u16) -> Option<u8>;
+    /// A write to the IO-1 area at $DE00-$DEFF.
+    fn write(&mut self, addr: u16, value: u8);
+    fn current_bank(&self) -> u16;
+    /// True when the cartridge pulls GAME low without EXROM, replacing the KERNAL with
+    /// its ROMH whatever the processor port says.
+    fn ultimax(&self) -> bool {
+        false
+    }
+    /// Writes the banking state. The ROM contents are not part of it.
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+/// The 8K ROM images of each bank loaded at `load_address`, indexed by bank number.
+fn rom_banks(chips: &[Chip], load_address: u16) -> Vec<Vec<u8>> {
+    let mut banks = Vec::new();
+    for chip in chips
+        .iter()
+        .filter(|chip| chip.load_address == load_address)
+    {
+        let bank = chip.bank as usize;
+        if banks.len() <= bank {
+            banks.resize(bank + 1, Vec::new());
+        }
+        banks[bank] = chip.data.clone();
+    }
+    banks
+}
+
+fn bank_byte(banks: &[Vec<u8>], bank: u16, offset: u16) -> Option<u8> {
+    banks
+        .get(bank as usize)
+        .and_then(|rom| rom.get(offset as usize % BANK_SIZE))
+        .copied()
+}
+
+/// Plain cartridges without bank switching. EXROM and GAME stay where the image sets
+/// them: ROML alone for 8K, ROML and ROMH for 16K, ROMH at $E000 for Ultimax.
+pub struct PlainMapper {
+    roml: Vec<u8>,
+    romh: Vec<u8>,
+    exrom: bool,
+    game: bool,
+}
+
+impl PlainMapper {
+    /// Splits the bank 0 chips into ROML and ROMH. A 16K chip at $8000 holds both.
+    fn new(crt: &Crt) -> PlainMapper {
+        let mut roml = Vec::new();
+        let mut romh = Vec::new();
+        for chip in crt.chips.iter().filter(|chip| chip.bank == 0) {
+            match chip.load_address {
+                ROML_BASE => {
+                    let split = chip.data.len().min(BANK_SIZE);
+                    roml = chip.data[..split].to_vec();
+                    if chip.data.len() > BANK_SIZE {
+                        romh = chip.data[split..].to_vec();
+                    }
+                }
+                ROMH_BASE | ROMH_ULTIMAX_BASE => romh = chip.data.clone(),
+                _ => {}
+            }
+        }
+        PlainMapper {
+            roml,
+            romh,
+            exrom: crt.exrom != 0,
+            game: crt.game != 0,
+        }
+    }
+}
+
+impl CartMapper for PlainMapper {
+    fn read(&self, addr: u16) -> Option<u8> {
+        let (rom, offset) = match addr {
+            0x8000..=0x9FFF if !self.exrom || self.ultimax() => (&self.roml, addr - ROML_BASE),
+            0xA000..=0xBFFF if !self.exrom && !self.game => (&self.romh, addr - ROMH_BASE),
+            0xE000..=0xFFFF if self.ultimax() => (&self.romh, addr - ROMH_ULTIMAX_BASE),
+            _ => return None,
+        };
+        rom.get(offset as usize).copied()
+    }
+
+    fn write(&mut self, _addr: u16, _value: u8) {}
+
+    fn current_bank(&self) -> u16 {
+        0
+    }
+
+    fn ultimax(&self) -> bool {
+        self.exrom && !self.game
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Ocean cartridges bank ROML, and ROMH for the 16K variants, through $DE00 bits 0-5.
+pub struct OceanMapper {
+    roml: Vec<Vec<u8>>,
+    romh: Vec<Vec<u8>>,
+    bank: u16,
+}
+
+impl CartMapper for OceanMapper {
+    fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0x9FFF => bank_byte(&self.roml, self.bank, addr - ROML_BASE),
+            0xA000..=0xBFFF => bank_byte(&self.romh, self.bank, addr - ROMH_BASE),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, _addr: u16, value: u8) {
+        self.bank = (value & 0x3F) as u16;
+    }
+
+    fn current_bank(&self) -> u16 {
+        self.bank
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u16()?;
+        Ok(())
+    }
+}
+
+/// Magic Desk cartridges bank ROML through $DE00 bits 0-6. Setting bit 7 releases
+/// EXROM, unmapping the cartridge until the next bank write.
+pub struct MagicDeskMapper {
+    roml: Vec<Vec<u8>>,
+    bank: u16,
+    disabled: bool,
+}
+
+impl CartMapper for MagicDeskMapper {
+    fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0x9FFF if !self.disabled => bank_byte(&self.roml, self.bank, addr - ROML_BASE),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, _addr: u16, value: u8) {
+        self.bank = (value & 0x7F) as u16;
+        self.disabled = value & 0x80 != 0;
+    }
+
+    fn current_bank(&self) -> u16 {
+        self.bank
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.bank);
+        w.bool(self.disabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.bank = r.u16()?;
+        self.disabled = r.bool()?;
+        Ok(())
+    }
+}
+
+/// A ROM chip packet from the image.
+pub struct Chip {
+    pub bank: u16,
+    pub load_address: u16,
+    pub data: Vec<u8>,
+}
+
+pub struct Crt {
+    pub name: String,
+    pub hw_type: u16,
+    /// Levels of the EXROM and GAME lines; together they select 8K, 16K or Ultimax mapping.
+    pub exrom: u8,
+    pub game: u8,
+    pub chips: Vec<Chip>,
+}
+
+fn read_u16_be(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+impl Crt {
+    pub fn from_filename(filename: &str) -> Result<Crt, String> {
+        let data = utils::open_file(filename, 0).map_err(|e| format!("{}: {}", filename, e))?;
+        Crt::from_bytes(&data).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Crt, String> {
+        if data.len() < 0x40 || &data[0..0x10] != CRT_SIGNATURE {
+            return Err(String::from("not a CRT image"));
+        }
+
+        let header_len = read_u32_be(data, 0x10) as usize;
+        let hw_type = read_u16_be(data, 0x16);
+        if ![HW_TYPE_NORMAL, HW_TYPE_OCEAN, HW_TYPE_MAGIC_DESK].contains(&hw_type) {
+            return Err(format!("unsupported cartridge hardware type {}", hw_type));
+        }
+
+        let name_bytes = &data[0x20..0x40];
+        let name_len = name_bytes
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(name_bytes.len());
+        let name = String::from_utf8_lossy(&name_bytes[..name_len]).into_owned();
+
+        let mut chips = Vec::new();
+        let mut offset = header_len;
+        while offset + CHIP_HEADER_LEN <= data.len() {
+            if &data[offset..offset + 4] != CHIP_SIGNATURE {
+                return Err(format!("bad CHIP packet at offset ${:X}", offset));
+            }
+
+            let packet_len = read_u32_be(data, offset + 4) as usize;
+            let bank = read_u16_be(data, offset + 0x0A);
+            let load_address = read_u16_be(data, offset + 0x0C);
+            let size = read_u16_be(data, offset + 0x0E) as usize;
+            let start = offset + CHIP_HEADER_LEN;
+            if start + size > data.len() || packet_len < CHIP_HEADER_LEN {
+                return Err(format!("truncated CHIP packet at offset ${:X}", offset));
+            }
+
+            chips.push(Chip {
+                bank,
+                load_address,
+                data: data[start..start + size].to_vec(),
+            });
+            offset += packet_len;
+        }
+
+        Ok(Crt {
+            name,
+            hw_type,
+            exrom: data[0x18],
+            game: data[0x19],
+            chips,
+        })
+    }
+
+    /// The mapping logic for this cartridge's hardware type, starting in bank 0.
+    pub fn mapper(&self) -> Box<dyn CartMapper> {
+        match self.hw_type {
+            HW_TYPE_OCEAN => Box::new(OceanMapper {
+                roml: rom_banks(&self.chips, ROML_BASE),
+                romh: rom_banks(&self.chips, ROMH_BASE),
+                bank: 0,
+            }),
+            HW_TYPE_MAGIC_DESK => Box::new(MagicDeskMapper {
+                roml: rom_banks(&self.chips, ROML_BASE),
+                bank: 0,
+                disabled: false,
+            }),
+            _ => Box::new(PlainMapper::new(self)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memory::{self, MemType, Memory, BASIC_SIZE, KERNAL_SIZE};
+
+    pub(crate) fn build_crt(
+        hw_type: u16,
+        exrom: u8,
+        game: u8,
+        load_address: u16,
+        rom: &[u8],
+    ) -> Vec<u8> {
+        let mut data = CRT_SIGNATURE.to_vec();
+        data.extend_from_slice(&0x40u32.to_be_bytes());
+        data.extend_from_slice(&0x0100u16.to_be_bytes());
+        data.extend_from_slice(&hw_type.to_be_bytes());
+        data.push(exrom);
+        data.push(game);
+        data.resize(0x20, 0);
+        data.extend_from_slice(b"TEST");
+        data.resize(0x40, 0);
+
+        add_chip(&mut data, 0, load_address, rom);
+        data
+    }
+
+    pub(crate) fn add_chip(data: &mut Vec<u8>, bank: u16, load_address: u16, rom: &[u8]) {
+        data.extend_from_slice(CHIP_SIGNATURE);
+        data.extend_from_slice(&((CHIP_HEADER_LEN + rom.len()) as u32).to_be_bytes());
+        data.extend_from_slice(&0u16.to_be_bytes());
+        data.extend_from_slice(&bank.to_be_bytes());
+        data.extend_from_slice(&load_address.to_be_bytes());
+        data.extend_from_slice(&(rom.len() as u16).to_be_bytes());
+        data.extend_from_slice(rom);
+    }
+
+    /// Memory with a KERNAL whose reset vector points at $FCE2 and a BASIC of $94s.
+    fn memory_with_roms() -> Memory {
+        let mut mem = Memory::new();
+        let mut kernal = vec![0xEA; KERNAL_SIZE];
+        kernal[0x1FFC..].copy_from_slice(&[0xE2, 0xFC, 0x48, 0xFF]);
+        mem.load_rom(MemType::Kernal, &kernal).unwrap();
+        mem.load_rom(MemType::Basic, &[0x94; BASIC_SIZE]).unwrap();
+        mem
+    }
+
+    #[test]
+    fn ultimax_cart_supplies_reset_vector() {
+        let mut rom = vec![0xEA; 0x2000];
+        rom[0x1FFC] = 0x00;
+        rom[0x1FFD] = 0xE0;
+        let crt = Crt::from_bytes(&build_crt(HW_TYPE_NORMAL, 1, 0, 0xE000, &rom)).unwrap();
+        assert_eq!(crt.name, "TEST");
+        assert_eq!(crt.chips.len(), 1);
+
+        let mut mem = memory_with_roms();
+        assert_eq!(mem.read_word_le(0xFFFC), 0xFCE2);
+        mem.attach_cartridge(crt.mapper());
+        assert_eq!(mem.read_word_le(0xFFFC), 0xE000);
+        // Even with HIRAM cleared, which would otherwise leave RAM at $E000.
+        mem.write_byte(memory::PORT_DDR, 0x07);
+        mem.write_byte(memory::PORT_DATA, 0x00);
+        assert_eq!(mem.read_word_le(0xFFFC), 0xE000);
+
+        // The ROM is not writable: writes land in the RAM underneath.
+        mem.write_byte(0xE000, 0x00);
+        assert_eq!(mem.read_byte(0xE000), 0xEA);
+        mem.detach_cartridge();
+        assert_eq!(mem.read_byte(0xE000), 0x00);
+    }
+
+    #[test]
+    fn plain_carts_follow_exrom_and_game() {
+        let mut rom = vec![0x80; BANK_SIZE];
+        rom.extend_from_slice(&[0xA0; BANK_SIZE]);
+
+        // 16K: ROMH replaces BASIC.
+        let crt = Crt::from_bytes(&build_crt(HW_TYPE_NORMAL, 0, 0, ROML_BASE, &rom)).unwrap();
+        let mut mem = memory_with_roms();
+        mem.attach_cartridge(crt.mapper());
+        assert_eq!(mem.read_byte(0x8000), 0x80);
+        assert_eq!(mem.read_byte(0xA000), 0xA0);
+        assert_eq!(mem.read_byte(0xE000), 0xEA);
+
+        // 8K: ROML only, BASIC stays.
+        let crt = Crt::from_bytes(&build_crt(HW_TYPE_NORMAL, 0, 1, ROML_BASE, &rom)).unwrap();
+        let mut mem = memory_with_roms();
+        mem.attach_cartridge(crt.mapper());
+        assert_eq!(mem.read_byte(0x8000), 0x80);
+        assert_eq!(mem.read_byte(0xA000), 0x94);
+    }
+
+    #[test]
+    fn unsupported_hardware_type_is_rejected() {
+        let data = build_crt(32, 0, 0, 0x8000, &[0; 16]);
+        let err = Crt::from_bytes(&data).err().unwrap();
+        assert!(err.contains("hardware type 32"));
+    }
+
+    #[test]
+    fn ocean_bank_register_switches_roml() {
+        let mut data = build_crt(HW_TYPE_OCEAN, 0, 1, ROML_BASE, &[0x10; BANK_SIZE]);
+        add_chip(&mut data, 1, ROML_BASE, &[0x11; BANK_SIZE]);
+        let crt = Crt::from_bytes(&data).unwrap();
+
+        let mut mem = Memory::new();
+        mem.attach_cartridge(crt.mapper());
+        assert_eq!(mem.read_byte(0x8000), 0x10);
+
+        mem.write_byte(0xDE00, 0x01);
+        assert_eq!(mem.cartridge().unwrap().current_bank(), 1);
+        assert_eq!(mem.read_byte(0x8000), 0x11);
+        assert_eq!(mem.read_byte(0x9FFF), 0x11);
+        // ROMH has no chips on this cart, so RAM shows through.
+        assert_eq!(mem.read_byte(0xA000), 0x00);
+    }
+}
@@ -0,0 +1,74 @@
+//! Debugger support: symbol names for addresses.
+
+use crate::utils;
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Default)]
+pub struct OpDebugger {
+    labels: HashMap<u16, String>,
+}
+
+impl OpDebugger {
+    pub fn new() -> OpDebugger {
+        OpDebugger {
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Loads a VICE label file, as written by `-moncommands` or an assembler's
+    /// `.vs` output. Returns the number of labels read.
+    pub fn load_labels(&mut self, filename: &str) -> io::Result<usize> {
+        let data = utils::open_file(filename, 0)?;
+        Ok(self.parse_labels(&String::from_utf8_lossy(&data)))
+    }
+
+    /// Parses `al C:0810 .start` lines, skipping anything else. Returns the number of
+    /// labels read.
+    pub fn parse_labels(&mut self, text: &str) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some("al") {
+                continue;
+            }
+
+            let (Some(addr), Some(name)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let addr = addr.strip_prefix("C:").unwrap_or(addr);
+            if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                self.add_label(addr, name.trim_start_matches('.'));
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn add_label(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    pub fn labels(&self) -> &HashMap<u16, String> {
+        &self.labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_vice_label_lines() {
+        let mut debugger = OpDebugger::new();
+        let count = debugger.parse_labels("al C:0810 .start\nbogus line\nal c000 .loop\n");
+
+        assert_eq!(count, 2);
+        assert_eq!(debugger.label(0x0810), Some("start"));
+        assert_eq!(debugger.label(0xC000), Some("loop"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Where finished frames go and where host input comes from.
+
+use crate::io::Key;
+use std::collections::VecDeque;
+
+/// Zoom for a windowed display: every emulated pixel becomes a `factor()`-sized square.
+/// Frames themselves are always handed over at 1:1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scale {
+    X1,
+    X2,
+    X4,
+    X8,
+}
+
+impl Scale {
+    const ALL: [Scale; 4] = [Scale::X1, Scale::X2, Scale::X4, Scale::X8];
+
+    pub fn factor(self) -> usize {
+        1 << self as usize
+    }
+
+    /// The next scale up, staying at the largest.
+    pub fn larger(self) -> Scale {
+        Scale::ALL[(self as usize + 1).min(Scale::ALL.len() - 1)]
+    }
+
+    /// The next scale down, staying at the smallest.
+    pub fn smaller(self) -> Scale {
+        Scale::ALL[(self as usize).saturating_sub(1)]
+    }
+
+    /// The largest scale up to `self` at which a `width` x `height` frame fits in
+    /// `max_width` x `max_height`, or `X1` if none does.
+    pub fn clamp_to(
+        self,
+        width: usize,
+        height: usize,
+        max_width: usize,
+        max_height: usize,
+    ) -> Scale {
+        Scale::ALL[..=self as usize]
+            .iter()
+            .rev()
+            .copied()
+            .find(|scale| {
+                width * scale.factor() <= max_width && height * scale.factor() <= max_height
+            })
+            .unwrap_or(Scale::X1)
+    }
+}
+
+pub trait Display {
+    /// Presents a finished frame of 0x00RRGGBB pixels, `width` pixels per row.
+    fn update_with_buffer(&mut self, buffer: &[u32], width: usize, height: usize);
+    /// Host keys held down right now.
+    fn pressed_keys(&mut self) -> Vec<Key>;
+    /// False once the user has closed the display.
+    fn is_open(&self) -> bool;
+    /// Zooms the display and returns the scale it settled on. A window resizes, clamped
+    /// to the screen; the default, for displays with nothing to resize, accepts `scale`.
+    fn set_scale(&mut self, scale: Scale) -> Scale {
+        scale
+    }
+}
+
+/// A display without a window: keeps the most recent frames in memory and reports
+/// scripted key presses, one entry per frame.
+pub struct HeadlessDisplay {
+    /// Most recent frames, oldest first.
+    pub frames: VecDeque<Vec<u32>>,
+    capacity: usize,
+    /// Frames presented since the display was created.
+    pub frame_count: u64,
+    script: VecDeque<Vec<Key>>,
+}
+
+impl Default for HeadlessDisplay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeadlessDisplay {
+    /// Keeps only the last frame.
+    pub fn new() -> HeadlessDisplay {
+        HeadlessDisplay::with_capacity(1)
+    }
+
+    /// Keeps the last `capacity` frames.
+    pub fn with_capacity(capacity: usize) -> HeadlessDisplay {
+        HeadlessDisplay {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            frame_count: 0,
+            script: VecDeque::new(),
+        }
+    }
+
+    /// Holds `keys` down for the next `frames` frames, after anything already queued.
+    pub fn hold_keys(&mut self, keys: &[Key], frames: usize) {
+        for _ in 0..frames {
+            self.script.push_back(keys.to_vec());
+        }
+    }
+}
+
+impl Display for HeadlessDisplay {
+    fn update_with_buffer(&mut self, buffer: &[u32], _width: usize, _height: usize) {
+        self.frame_count += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(buffer.to_vec());
+    }
+
+    fn pressed_keys(&mut self) -> Vec<Key> {
+        self.script.pop_front().unwrap_or_default()
+    }
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,419 @@
+//! A 1541 disk drive backed by a `.d64` image. The drive's own 6502 and the serial
+//! protocol aren't emulated: `ZPC` traps the KERNAL LOAD routine and hands the request
+//! to `Drive1541::load`.
+
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils;
+
+/// Image sizes for 35 tracks, without and with the trailing error bytes.
+const D64_SIZE: usize = 174_848;
+const D64_WITH_ERRORS_SIZE: usize = 175_531;
+pub const TRACKS: u8 = 35;
+const SECTOR_SIZE: usize = 256;
+
+/// Directory entries start at track 18, sector 1 and chain from there.
+const DIRECTORY_TRACK: u8 = 18;
+const BAM_SECTOR: u8 = 0;
+const DIRECTORY_SECTOR: u8 = 1;
+const DIR_ENTRY_SIZE: usize = 32;
+/// Shifted space, used to pad names on disk.
+const NAME_PADDING: u8 = 0xA0;
+
+/// File type bits of a directory entry; the top bit marks a properly closed file.
+const FILE_TYPE_MASK: u8 = 0x07;
+const FILE_CLOSED: u8 = 0x80;
+const FILE_TYPE_PRG: u8 = 2;
+
+/// Default device number of the first drive.
+pub const DEFAULT_DEVICE: u8 = 8;
+
+/// KERNAL error codes returned in A with carry set.
+pub const ERROR_FILE_NOT_FOUND: u8 = 4;
+pub const ERROR_DEVICE_NOT_PRESENT: u8 = 5;
+
+/// Sectors on `track`, which zones give 21, 19, 18 or 17.
+fn sectors_per_track(track: u8) -> u8 {
+    match track {
+        1..=17 => 21,
+        18..=24 => 19,
+        25..=30 => 18,
+        _ => 17,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    /// PETSCII name with the padding removed.
+    pub name: Vec<u8>,
+    pub file_type: u8,
+    pub track: u8,
+    pub sector: u8,
+    pub blocks: u16,
+}
+
+impl DirEntry {
+    /// Matches a KERNAL filename pattern: `*` ends the pattern and matches anything,
+    /// `?` matches any single character.
+    pub fn matches(&self, pattern: &[u8]) -> bool {
+        let mut name = self.name.iter();
+        for &p in pattern {
+            match (p, name.next()) {
+                (b'*', _) => return true,
+                (b'?', Some(_)) => {}
+                (p, Some(&c)) if p == c => {}
+                _ => return false,
+            }
+        }
+        name.next().is_none()
+    }
+}
+
+pub struct D64 {
+    data: Vec<u8>,
+}
+
+impl D64 {
+    pub fn from_filename(filename: &str) -> Result<D64, String> {
+        let data = utils::open_file(filename, 0).map_err(|e| format!("{}: {}", filename, e))?;
+        D64::parse(data).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn parse(mut data: Vec<u8>) -> Result<D64, String> {
+        match data.len() {
+            D64_SIZE => {}
+            D64_WITH_ERRORS_SIZE => data.truncate(D64_SIZE),
+            len => return Err(format!("D64 image is {} bytes, expected {}", len, D64_SIZE)),
+        }
+        Ok(D64 { data })
+    }
+
+    /// Returns one 256-byte block, or None for a position that isn't on the disk.
+    pub fn sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        if track == 0 || track > TRACKS || sector >= sectors_per_track(track) {
+            return None;
+        }
+        let index: usize = (1..track)
+            .map(|t| sectors_per_track(t) as usize)
+            .sum::<usize>()
+            + sector as usize;
+        Some(&self.data[index * SECTOR_SIZE..(index + 1) * SECTOR_SIZE])
+    }
+
+    /// Disk name from the BAM.
+    pub fn disk_name(&self) -> Vec<u8> {
+        let bam = self.sector(DIRECTORY_TRACK, BAM_SECTOR).unwrap();
+        trim_name(&bam[0x90..0xA0])
+    }
+
+    /// Follows a sector chain from `track`/`sector`, calling `f` with the used part of
+    /// each block. Stops at the end of the chain, a bad link, or a loop.
+    fn walk_chain(&self, mut track: u8, mut sector: u8, mut f: impl FnMut(&[u8])) {
+        let max_blocks = D64_SIZE / SECTOR_SIZE;
+        for _ in 0..max_blocks {
+            let Some(block) = self.sector(track, sector) else {
+                return;
+            };
+            if block[0] == 0 {
+                let last = (block[1] as usize).max(1);
+                f(&block[2..=last]);
+                return;
+            }
+            f(&block[2..]);
+            (track, sector) = (block[0], block[1]);
+        }
+    }
+
+    /// Lists the files on the disk, skipping deleted entries. Like the 1541, only
+    /// directory blocks on track 18 are read, which caps the list at 144 entries.
+    pub fn directory(&self) -> Vec<DirEntry> {
+        let mut entries = Vec::new();
+        let mut blocks = Vec::new();
+        // Directory blocks use their whole 256 bytes, so walk the links by hand.
+        let (mut track, mut sector) = (DIRECTORY_TRACK, DIRECTORY_SECTOR);
+        while track == DIRECTORY_TRACK {
+            let Some(block) = self.sector(track, sector) else {
+                break;
+            };
+            if blocks.contains(&(track, sector)) {
+                break;
+            }
+            blocks.push((track, sector));
+            for raw in block.chunks(DIR_ENTRY_SIZE) {
+                if raw[2] & FILE_TYPE_MASK == 0 && raw[2] & FILE_CLOSED == 0 {
+                    continue;
+                }
+                entries.push(DirEntry {
+                    name: trim_name(&raw[5..21]),
+                    file_type: raw[2],
+                    track: raw[3],
+                    sector: raw[4],
+                    blocks: u16::from_le_bytes([raw[30], raw[31]]),
+                });
+            }
+            (track, sector) = (block[0], block[1]);
+        }
+        entries
+    }
+
+    /// Finds the first PRG whose name matches `pattern`.
+    pub fn find(&self, pattern: &[u8]) -> Option<DirEntry> {
+        self.directory().into_iter().find(|entry| {
+            entry.file_type & FILE_TYPE_MASK == FILE_TYPE_PRG && entry.matches(pattern)
+        })
+    }
+
+    /// Reads a file's contents, load address included.
+    pub fn read_file(&self, entry: &DirEntry) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.walk_chain(entry.track, entry.sector, |block| {
+            data.extend_from_slice(block)
+        });
+        data
+    }
+
+    /// Builds the BASIC program that `LOAD"$",8` produces: a header line with the disk
+    /// name, one line per file and a BLOCKS FREE line.
+    pub fn directory_listing(&self) -> Vec<u8> {
+        let mut lines: Vec<(u16, Vec<u8>)> = Vec::new();
+        let mut header = vec![0x12, b'"'];
+        let mut name = self.disk_name();
+        name.resize(16, b' ');
+        header.extend_from_slice(&name);
+        header.extend_from_slice(b"\" 00 2A");
+        lines.push((0, header));
+
+        for entry in self.directory() {
+            let mut text = vec![b' '; 4usize.saturating_sub(entry.blocks.to_string().len())];
+            text.push(b'"');
+            text.extend_from_slice(&entry.name);
+            text.push(b'"');
+            text.resize(text.len() + 16 - entry.name.len().min(16), b' ');
+            let kind: &[u8] = match entry.file_type & FILE_TYPE_MASK {
+                0 => b"DEL",
+                1 => b"SEQ",
+                2 => b"PRG",
+                3 => b"USR",
+                _ => b"REL",
+            };
+            text.extend_from_slice(kind);
+            lines.push((entry.blocks, text));
+        }
+        lines.push((self.blocks_free(), b"BLOCKS FREE.".to_vec()));
+
+        // With at most 144 entries of under 40 bytes each, the listing stays far below
+        // $FFFF; the checked sum guards that.
+        let start: u16 = 0x0801;
+        let mut program = start.to_le_bytes().to_vec();
+        let mut addr = start;
+        for (number, text) in lines {
+            addr = u16::try_from(text.len() + 5)
+                .ok()
+                .and_then(|len| addr.checked_add(len))
+                .expect("directory listing fits in memory");
+            program.extend_from_slice(&addr.to_le_bytes());
+            program.extend_from_slice(&number.to_le_bytes());
+            program.extend_from_slice(&text);
+            program.push(0);
+        }
+        program.extend_from_slice(&[0, 0]);
+        program
+    }
+
+    /// Free blocks according to the BAM, leaving out the directory track.
+    pub fn blocks_free(&self) -> u16 {
+        let bam = self.sector(DIRECTORY_TRACK, BAM_SECTOR).unwrap();
+        (1..=TRACKS)
+            .filter(|&track| track != DIRECTORY_TRACK)
+            .map(|track| bam[4 * track as usize] as u16)
+            .sum()
+    }
+}
+
+fn trim_name(raw: &[u8]) -> Vec<u8> {
+    let len = raw
+        .iter()
+        .rposition(|&b| b != NAME_PADDING)
+        .map_or(0, |i| i + 1);
+    raw[..len].to_vec()
+}
+
+pub struct Drive1541 {
+    pub device: u8,
+    disk: Option<D64>,
+}
+
+impl Default for Drive1541 {
+    fn default() -> Self {
+        Drive1541::new(DEFAULT_DEVICE)
+    }
+}
+
+impl Drive1541 {
+    pub fn new(device: u8) -> Drive1541 {
+        Drive1541 { device, disk: None }
+    }
+
+    pub fn insert(&mut self, disk: D64) {
+        self.disk = Some(disk);
+    }
+
+    pub fn eject(&mut self) -> Option<D64> {
+        self.disk.take()
+    }
+
+    pub fn disk(&self) -> Option<&D64> {
+        self.disk.as_ref()
+    }
+
+    /// Writes the device number and the inserted disk image, if any.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.device);
+        w.bool(self.disk.is_some());
+        if let Some(disk) = &self.disk {
+            w.bytes(&disk.data);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.device = r.u8()?;
+        self.disk = if r.bool()? {
+            Some(D64::parse(r.bytes()?.to_vec())?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Serves a LOAD for `filename`: `$` gives the directory listing, anything else the
+    /// first matching PRG. Returns the file with its load address, or a KERNAL error
+    /// code.
+    pub fn load(&self, filename: &[u8]) -> Result<Vec<u8>, u8> {
+        let disk = self.disk.as_ref().ok_or(ERROR_DEVICE_NOT_PRESENT)?;
+        if filename.first() == Some(&b'$') {
+            return Ok(disk.directory_listing());
+        }
+        // A drive number prefix such as "0:" selects the only drive there is.
+        let pattern = match filename {
+            [b'0', b':', rest @ ..] | [b':', rest @ ..] => rest,
+            _ => filename,
+        };
+        let entry = disk.find(pattern).ok_or(ERROR_FILE_NOT_FOUND)?;
+        Ok(disk.read_file(&entry))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Offset of a block in a D64 image.
+    fn offset(track: u8, sector: u8) -> usize {
+        let index: usize = (1..track)
+            .map(|t| sectors_per_track(t) as usize)
+            .sum::<usize>()
+            + sector as usize;
+        index * SECTOR_SIZE
+    }
+
+    /// A disk named TEST holding one PRG, "HELLO", whose data spans two blocks.
+    pub(crate) fn sample_d64() -> Vec<u8> {
+        let mut image = vec![0; D64_SIZE];
+        let bam = offset(18, 0);
+        image[bam..bam + 2].copy_from_slice(&[18, 1]);
+        image[bam + 0x90..bam + 0xA0].fill(NAME_PADDING);
+        image[bam + 0x90..bam + 0x94].copy_from_slice(b"TEST");
+        for track in 1..=TRACKS {
+            image[bam + 4 * track as usize] = 10;
+        }
+
+        let dir = offset(18, 1);
+        image[dir + 1] = 0xFF;
+        image[dir + 2] = FILE_CLOSED | FILE_TYPE_PRG;
+        image[dir + 3..dir + 5].copy_from_slice(&[17, 0]);
+        image[dir + 5..dir + 21].fill(NAME_PADDING);
+        image[dir + 5..dir + 10].copy_from_slice(b"HELLO");
+        image[dir + 30] = 2;
+
+        let first = offset(17, 0);
+        image[first..first + 2].copy_from_slice(&[17, 1]);
+        image[first + 2..first + 4].copy_from_slice(&[0x00, 0xC0]);
+        for i in 4..SECTOR_SIZE {
+            image[first + i] = i as u8;
+        }
+        let second = offset(17, 1);
+        image[second..second + 5].copy_from_slice(&[0, 4, 0xAA, 0xBB, 0xCC]);
+        image
+    }
+
+    #[test]
+    fn directory_lists_the_first_entry() {
+        let disk = D64::parse(sample_d64()).unwrap();
+        let entries = disk.directory();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, b"HELLO");
+        assert_eq!((entries[0].track, entries[0].sector), (17, 0));
+        assert_eq!(disk.disk_name(), b"TEST");
+        assert_eq!(disk.blocks_free(), 340);
+    }
+
+    #[test]
+    fn load_follows_the_sector_chain() {
+        let mut drive = Drive1541::default();
+        assert_eq!(drive.load(b"*"), Err(ERROR_DEVICE_NOT_PRESENT));
+        drive.insert(D64::parse(sample_d64()).unwrap());
+
+        let data = drive.load(b"HE*").unwrap();
+        assert_eq!(data.len(), 254 + 3);
+        assert_eq!(&data[..3], &[0x00, 0xC0, 4]);
+        assert_eq!(&data[254..], &[0xAA, 0xBB, 0xCC]);
+        assert_eq!(drive.load(b"0:HELLO"), Ok(data));
+        assert_eq!(drive.load(b"HELL"), Err(ERROR_FILE_NOT_FOUND));
+
+        let listing = drive.load(b"$").unwrap();
+        assert_eq!(&listing[..2], &[0x01, 0x08]);
+        assert!(listing.windows(7).any(|w| w == b"\"HELLO\""));
+    }
+
+    #[test]
+    fn directory_stays_on_track_18() {
+        // Every block links to the next one on the disk and is full of entries.
+        let mut image = sample_d64();
+        let blocks: Vec<(u8, u8)> = (DIRECTORY_TRACK..=TRACKS)
+            .flat_map(|track| (0..sectors_per_track(track)).map(move |sector| (track, sector)))
+            .filter(|&block| block != (DIRECTORY_TRACK, BAM_SECTOR))
+            .collect();
+        for (i, &(track, sector)) in blocks.iter().enumerate() {
+            let block = offset(track, sector);
+            let next = blocks.get(i + 1).copied().unwrap_or((0, 0xFF));
+            image[block..block + 2].copy_from_slice(&[next.0, next.1]);
+            for entry in (block..block + SECTOR_SIZE).step_by(DIR_ENTRY_SIZE) {
+                image[entry + 2] = FILE_CLOSED | FILE_TYPE_PRG;
+                image[entry + 5..entry + 21].fill(b'X');
+                image[entry + 30] = 0xFF;
+            }
+        }
+
+        let disk = D64::parse(image).unwrap();
+        let sectors = sectors_per_track(DIRECTORY_TRACK) as usize - 1;
+        assert_eq!(disk.directory().len(), sectors * 8);
+        // The header, every entry and BLOCKS FREE, each linked to the next line.
+        let listing = disk.directory_listing();
+        let mut lines = 0;
+        let mut link = 0x0801;
+        while let [lo, hi] = listing[link - 0x0801 + 2..][..2] {
+            if (lo, hi) == (0, 0) {
+                break;
+            }
+            link = u16::from_le_bytes([lo, hi]) as usize;
+            lines += 1;
+        }
+        assert_eq!(lines, sectors * 8 + 2);
+        assert_eq!(link - 0x0801 + 2, listing.len() - 2);
+    }
+
+    #[test]
+    fn parse_rejects_odd_sizes() {
+        assert!(D64::parse(vec![0; 1000]).is_err());
+        assert!(D64::parse(vec![0; D64_WITH_ERRORS_SIZE]).is_ok());
+    }
+}
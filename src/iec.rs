@@ -0,0 +1,28 @@
+//! The IEC serial bus shared by CIA2 and peripherals such as disk drives and printers.
+
+/// State of the open-collector bus lines. `true` means the line is pulled low.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IecLines {
+    pub atn: bool,
+    pub clk: bool,
+    pub data: bool,
+}
+
+impl IecLines {
+    /// Wired-AND of two sets of drivers: a line is low if either side pulls it.
+    pub fn combine(self, other: IecLines) -> IecLines {
+        IecLines {
+            atn: self.atn || other.atn,
+            clk: self.clk || other.clk,
+            data: self.data || other.data,
+        }
+    }
+}
+
+pub trait IecDevice {
+    /// Called whenever the computer changes the lines it drives.
+    fn host_lines_changed(&mut self, lines: IecLines);
+
+    /// Lines the device is currently pulling low.
+    fn device_lines(&self) -> IecLines;
+}
@@ -0,0 +1,519 @@
+//! Host input: turns pressed host keys into C64 keyboard matrix state.
+
+use crate::cia;
+use std::collections::HashMap;
+
+/// Host keys the emulator understands, named after their position on a US keyboard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Key0,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M,
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+    F1,
+    F3,
+    F5,
+    F7,
+    /// Host-only hotkey: save a screenshot.
+    F8,
+    /// Host-only hotkey: pause and resume.
+    F9,
+    /// Host-only hotkey: warp on and off.
+    F10,
+    /// Host-only hotkey: warm reset.
+    F11,
+    /// Host-only hotkey: cold reset.
+    F12,
+    Space,
+    Enter,
+    Backspace,
+    Escape,
+    /// RESTORE, which is wired to NMI rather than to the keyboard matrix.
+    PageUp,
+    Home,
+    Up,
+    Down,
+    Left,
+    Right,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    Tab,
+    Comma,
+    Period,
+    Slash,
+    Semicolon,
+    Apostrophe,
+    Equal,
+    Minus,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    NumPad0,
+    NumPad2,
+    NumPad4,
+    NumPad6,
+    NumPad8,
+    /// Host-only hotkey: zoom the window in.
+    NumPadPlus,
+    /// Host-only hotkey: zoom the window out.
+    NumPadMinus,
+}
+
+/// Joystick switch bits, active low in the CIA port.
+pub const JOY_UP: u8 = 1 << 0;
+pub const JOY_DOWN: u8 = 1 << 1;
+pub const JOY_LEFT: u8 = 1 << 2;
+pub const JOY_RIGHT: u8 = 1 << 3;
+pub const JOY_FIRE: u8 = 1 << 4;
+
+/// Numeric keypad keys that drive the emulated joystick.
+const JOYSTICK_KEYS: &[(Key, u8)] = &[
+    (Key::NumPad8, JOY_UP),
+    (Key::NumPad2, JOY_DOWN),
+    (Key::NumPad4, JOY_LEFT),
+    (Key::NumPad6, JOY_RIGHT),
+    (Key::NumPad0, JOY_FIRE),
+];
+
+/// Converts a host character to the PETSCII code the C64 keyboard would produce in
+/// its default upper case mode. Letters of either case type the unshifted letter.
+pub fn ascii_to_petscii(c: char) -> Option<u8> {
+    match c {
+        '\n' | '\r' => Some(0x0D),
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        ' '..='_' => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// Control port the keypad joystick is plugged into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JoystickPort {
+    Port1,
+    #[default]
+    Port2,
+}
+
+/// Matrix position of a C64 key: the port A row bit and port B column bit.
+pub type MatrixPos = (u8, u8);
+
+/// A scripted press or release of a matrix key, `frame` frames from when it was queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub pos: MatrixPos,
+    pub pressed: bool,
+}
+
+const LEFT_SHIFT: MatrixPos = (1, 7);
+const CURSOR_RIGHT: MatrixPos = (0, 2);
+const CURSOR_DOWN: MatrixPos = (0, 7);
+
+/// Where each host key lands on the C64 keyboard. Host keys with no C64 counterpart
+/// borrow a neighbour: quote is colon, brackets are @ and *, and backslash is pound.
+const SYMBOLIC_KEYS: &[(Key, MatrixPos)] = &[
+    (Key::Backspace, (0, 0)),
+    (Key::Enter, (0, 1)),
+    (Key::Right, CURSOR_RIGHT),
+    (Key::F7, (0, 3)),
+    (Key::F1, (0, 4)),
+    (Key::F3, (0, 5)),
+    (Key::F5, (0, 6)),
+    (Key::Down, CURSOR_DOWN),
+    (Key::Key3, (1, 0)),
+    (Key::W, (1, 1)),
+    (Key::A, (1, 2)),
+    (Key::Key4, (1, 3)),
+    (Key::Z, (1, 4)),
+    (Key::S, (1, 5)),
+    (Key::E, (1, 6)),
+    (Key::LeftShift, LEFT_SHIFT),
+    (Key::Key5, (2, 0)),
+    (Key::R, (2, 1)),
+    (Key::D, (2, 2)),
+    (Key::Key6, (2, 3)),
+    (Key::C, (2, 4)),
+    (Key::F, (2, 5)),
+    (Key::T, (2, 6)),
+    (Key::X, (2, 7)),
+    (Key::Key7, (3, 0)),
+    (Key::Y, (3, 1)),
+    (Key::G, (3, 2)),
+    (Key::Key8, (3, 3)),
+    (Key::B, (3, 4)),
+    (Key::H, (3, 5)),
+    (Key::U, (3, 6)),
+    (Key::V, (3, 7)),
+    (Key::Key9, (4, 0)),
+    (Key::I, (4, 1)),
+    (Key::J, (4, 2)),
+    (Key::Key0, (4, 3)),
+    (Key::M, (4, 4)),
+    (Key::K, (4, 5)),
+    (Key::O, (4, 6)),
+    (Key::N, (4, 7)),
+    (Key::P, (5, 1)),
+    (Key::L, (5, 2)),
+    (Key::Minus, (5, 3)),
+    (Key::Period, (5, 4)),
+    (Key::Apostrophe, (5, 5)),
+    (Key::LeftBracket, (5, 6)),
+    (Key::Comma, (5, 7)),
+    (Key::Backslash, (6, 0)),
+    (Key::RightBracket, (6, 1)),
+    (Key::Semicolon, (6, 2)),
+    (Key::Home, (6, 3)),
+    (Key::RightShift, (6, 4)),
+    (Key::Equal, (6, 5)),
+    (Key::Slash, (6, 7)),
+    (Key::Key1, (7, 0)),
+    (Key::LeftCtrl, (7, 2)),
+    (Key::Key2, (7, 3)),
+    (Key::Space, (7, 4)),
+    (Key::Tab, (7, 5)),
+    (Key::Q, (7, 6)),
+    (Key::Escape, (7, 7)),
+];
+
+/// C64 key names by matrix position, as accepted by `matrix_key_by_name`.
+const MATRIX_NAMES: [[&str; 8]; 8] = [
+    [
+        "DEL",
+        "RETURN",
+        "CRSR_RIGHT",
+        "F7",
+        "F1",
+        "F3",
+        "F5",
+        "CRSR_DOWN",
+    ],
+    ["3", "W", "A", "4", "Z", "S", "E", "LSHIFT"],
+    ["5", "R", "D", "6", "C", "F", "T", "X"],
+    ["7", "Y", "G", "8", "B", "H", "U", "V"],
+    ["9", "I", "J", "0", "M", "K", "O", "N"],
+    ["+", "P", "L", "-", ".", ":", "@", ","],
+    ["POUND", "*", ";", "HOME", "RSHIFT", "=", "UP_ARROW", "/"],
+    [
+        "1",
+        "LEFT_ARROW",
+        "CTRL",
+        "2",
+        "SPACE",
+        "CBM",
+        "Q",
+        "RUNSTOP",
+    ],
+];
+
+/// Matrix position of the C64 key printed as `name`, or named in upper case for keys
+/// without a single-character label (`RETURN`, `CRSR_DOWN`, `RUNSTOP`, ...).
+pub fn matrix_key_by_name(name: &str) -> Option<MatrixPos> {
+    MATRIX_NAMES.iter().enumerate().find_map(|(row, keys)| {
+        keys.iter()
+            .position(|&key| key == name)
+            .map(|bit| (row as u8, bit as u8))
+    })
+}
+
+/// Differences from the symbolic map when host keys follow the C64 key in the same
+/// physical spot.
+const POSITIONAL_KEYS: &[(Key, MatrixPos)] = &[
+    (Key::Minus, (5, 0)),
+    (Key::Equal, (5, 3)),
+    (Key::LeftBracket, (5, 6)),
+    (Key::RightBracket, (6, 1)),
+    (Key::Backslash, (6, 6)),
+    (Key::Semicolon, (5, 5)),
+    (Key::Apostrophe, (6, 2)),
+    (Key::Tab, (7, 2)),
+    (Key::LeftCtrl, (7, 5)),
+];
+
+/// Host key to C64 matrix position table used by `IO::update`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyMap {
+    keys: HashMap<Key, MatrixPos>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::symbolic()
+    }
+}
+
+impl KeyMap {
+    /// An empty map, for embedders that build their own layout.
+    pub fn new() -> KeyMap {
+        KeyMap {
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Host keys type the character printed on them where the C64 has it.
+    pub fn symbolic() -> KeyMap {
+        KeyMap {
+            keys: SYMBOLIC_KEYS.iter().copied().collect(),
+        }
+    }
+
+    /// Host keys press whatever C64 key sits in the same place on the keyboard.
+    pub fn positional() -> KeyMap {
+        let mut map = KeyMap::symbolic();
+        map.keys.extend(POSITIONAL_KEYS.iter().copied());
+        map
+    }
+
+    pub fn insert(&mut self, key: Key, pos: MatrixPos) -> Option<MatrixPos> {
+        self.keys.insert(key, pos)
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<MatrixPos> {
+        self.keys.remove(&key)
+    }
+
+    pub fn get(&self, key: Key) -> Option<MatrixPos> {
+        self.keys.get(&key).copied()
+    }
+}
+
+#[derive(Default)]
+pub struct IO {
+    keymap: KeyMap,
+    joystick_port: JoystickPort,
+    restore_held: bool,
+    /// Matrix keys held by scripted events, pressed on top of the host keys.
+    matrix_keys: Vec<MatrixPos>,
+}
+
+impl IO {
+    pub fn new() -> IO {
+        IO {
+            keymap: KeyMap::symbolic(),
+            joystick_port: JoystickPort::Port2,
+            restore_held: false,
+            matrix_keys: Vec::new(),
+        }
+    }
+
+    pub fn set_joystick_port(&mut self, port: JoystickPort) {
+        self.joystick_port = port;
+    }
+
+    pub fn joystick_port(&self) -> JoystickPort {
+        self.joystick_port
+    }
+
+    pub fn set_keymap(&mut self, keymap: KeyMap) {
+        self.keymap = keymap;
+    }
+
+    pub fn keymap(&self) -> &KeyMap {
+        &self.keymap
+    }
+
+    /// Holds or releases a key by matrix position, independently of the host keys.
+    pub fn set_matrix_key(&mut self, pos: MatrixPos, pressed: bool) {
+        self.matrix_keys.retain(|&held| held != pos);
+        if pressed {
+            self.matrix_keys.push(pos);
+        }
+    }
+
+    /// True on the frame RESTORE goes down. Holding it doesn't signal again, as the real
+    /// key only pulses NMI when pressed.
+    pub fn check_restore_key(&mut self, pressed: &[Key]) -> bool {
+        let held = pressed.contains(&Key::PageUp);
+        let fresh = held && !self.restore_held;
+        self.restore_held = held;
+        fresh
+    }
+
+    /// Rebuilds CIA1's keyboard matrix and joystick state from the host keys currently
+    /// held down.
+    pub fn update(&mut self, pressed: &[Key], cia1: &mut cia::CIA) {
+        let mut key_matrix = [0xFF; 8];
+        let mut rev_matrix = [0xFF; 8];
+        let mut joystick = 0xFF;
+        let mut press = |(row, col): MatrixPos| {
+            key_matrix[row as usize] &= !(1 << col);
+            rev_matrix[col as usize] &= !(1 << row);
+        };
+
+        for &pos in &self.matrix_keys {
+            press(pos);
+        }
+        for &key in pressed {
+            match key {
+                // The C64 only has right and down cursor keys; shift reverses them.
+                Key::Up => {
+                    press(LEFT_SHIFT);
+                    press(CURSOR_DOWN);
+                }
+                Key::Left => {
+                    press(LEFT_SHIFT);
+                    press(CURSOR_RIGHT);
+                }
+                _ => {
+                    if let Some(pos) = self.keymap.get(key) {
+                        press(pos);
+                    } else if let Some((_, bit)) = JOYSTICK_KEYS.iter().find(|(k, _)| *k == key) {
+                        joystick &= !bit;
+                    }
+                }
+            }
+        }
+
+        cia1.key_matrix = key_matrix;
+        cia1.rev_matrix = rev_matrix;
+        match self.joystick_port {
+            JoystickPort::Port1 => {
+                cia1.joystick_1 = joystick;
+                cia1.joystick_2 = 0xFF;
+            }
+            JoystickPort::Port2 => {
+                cia1.joystick_1 = 0xFF;
+                cia1.joystick_2 = joystick;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+    use crate::memory::Memory;
+
+    fn cia1() -> cia::CIA {
+        cia::CIA::new(cpu::CPU::new_shared(Memory::new_shared()), true)
+    }
+
+    #[test]
+    fn a_key_sets_row_1_column_2() {
+        let mut cia = cia1();
+        IO::new().update(&[Key::A], &mut cia);
+
+        assert_eq!(cia.key_matrix[1], !0x04);
+        assert_eq!(cia.rev_matrix[2], !0x02);
+        assert!(cia
+            .key_matrix
+            .iter()
+            .enumerate()
+            .all(|(row, &bits)| row == 1 || bits == 0xFF));
+
+        // The KERNAL scan: drive row 1 low on port A and read port B.
+        cia.write_register(0xDC02, 0xFF);
+        cia.write_register(0xDC00, !0x02);
+        assert_eq!(cia.read_register(0xDC01), !0x04);
+        cia.write_register(0xDC00, !0x01);
+        assert_eq!(cia.read_register(0xDC01), 0xFF);
+    }
+
+    #[test]
+    fn custom_keymap_swaps_keys() {
+        let mut keymap = KeyMap::symbolic();
+        let z = keymap.get(Key::Z).unwrap();
+        let y = keymap.insert(Key::Y, z).unwrap();
+        keymap.insert(Key::Z, y);
+
+        let mut io = IO::new();
+        io.set_keymap(keymap);
+        let mut cia = cia1();
+
+        io.update(&[Key::Y], &mut cia);
+        assert_eq!(cia.key_matrix[1], !0x10);
+        assert_eq!(cia.key_matrix[3], 0xFF);
+
+        io.update(&[Key::Z], &mut cia);
+        assert_eq!(cia.key_matrix[1], 0xFF);
+        assert_eq!(cia.key_matrix[3], !0x02);
+    }
+
+    #[test]
+    fn positional_map_follows_key_positions() {
+        let positional = KeyMap::positional();
+        assert_eq!(positional.get(Key::Minus), Some((5, 0)));
+        assert_eq!(positional.get(Key::A), KeyMap::symbolic().get(Key::A));
+    }
+
+    #[test]
+    fn keypad_fire_and_left_drive_joystick_2() {
+        let mut cia = cia1();
+        let mut io = IO::new();
+        io.update(&[Key::NumPad0, Key::NumPad4], &mut cia);
+
+        assert_eq!(cia.joystick_2, !(JOY_FIRE | JOY_LEFT));
+        assert_eq!(cia.joystick_1, 0xFF);
+        assert_eq!(cia.key_matrix, [0xFF; 8]);
+        cia.write_register(0xDC02, 0xFF);
+        cia.write_register(0xDC00, 0x7F);
+        assert_eq!(cia.read_register(0xDC00), 0x6B);
+
+        io.set_joystick_port(JoystickPort::Port1);
+        io.update(&[Key::NumPad8], &mut cia);
+        assert_eq!(cia.joystick_1, !JOY_UP);
+        assert_eq!(cia.joystick_2, 0xFF);
+    }
+
+    #[test]
+    fn pasted_text_converts_to_petscii() {
+        let petscii: Vec<u8> = "10 print\"a\"\n"
+            .chars()
+            .filter_map(ascii_to_petscii)
+            .collect();
+        assert_eq!(petscii, b"10 PRINT\"A\"\r");
+        assert_eq!(ascii_to_petscii('~'), None);
+    }
+
+    #[test]
+    fn cursor_up_is_shifted_cursor_down() {
+        let mut cia = cia1();
+        IO::new().update(&[Key::Up], &mut cia);
+
+        assert_eq!(cia.key_matrix[0], !0x80);
+        assert_eq!(cia.key_matrix[1], !0x80);
+
+        IO::new().update(&[], &mut cia);
+        assert_eq!(cia.key_matrix, [0xFF; 8]);
+    }
+
+    #[test]
+    fn keys_are_found_by_their_c64_name() {
+        assert_eq!(matrix_key_by_name("A"), Some((1, 2)));
+        assert_eq!(matrix_key_by_name("RUNSTOP"), Some((7, 7)));
+        assert_eq!(matrix_key_by_name("a"), None);
+    }
+}
@@ -0,0 +1,25 @@
+//! A Commodore 64 emulator built around an NMOS 6502/6510 core.
+
+pub mod audio;
+pub mod cia;
+pub mod clock;
+pub mod cpu;
+pub mod crt;
+pub mod debugger;
+pub mod display;
+pub mod drive;
+pub mod iec;
+pub mod io;
+pub mod memory;
+pub mod opcodes;
+pub mod reu;
+pub mod script;
+pub mod sid;
+pub mod snapshot;
+pub mod t64;
+pub mod tape;
+pub mod utils;
+pub mod vic;
+#[cfg(feature = "window")]
+pub mod window;
+pub mod zpc;
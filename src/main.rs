@@ -1,3 +1,106 @@
+use std::env;
+use z80_emulator::display::{Display, HeadlessDisplay};
+use z80_emulator::zpc::{ResetKind, RunUntil, ZpcConfig, ZPC};
+
+/// Frames a headless run lasts unless `--frames` says otherwise.
+const DEFAULT_HEADLESS_FRAMES: u64 = 300;
+
+const USAGE: &str = "usage: z80Emulator [--headless [--frames N]] [--instructions N] \
+                     [--kernal FILE] [--basic FILE] [--chargen FILE] [PROGRAM]";
+
+fn count_arg(flag: &str, value: Option<String>) -> u64 {
+    match value.as_deref().map(str::parse) {
+        Some(Ok(count)) => count,
+        _ => {
+            eprintln!("{} needs a number\n{}", flag, USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut config = ZpcConfig::default();
+    let mut headless = false;
+    let mut frames = DEFAULT_HEADLESS_FRAMES;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let rom = match arg.as_str() {
+            "--kernal" => &mut config.roms.kernal,
+            "--basic" => &mut config.roms.basic,
+            "--chargen" => &mut config.roms.chargen,
+            "--headless" => {
+                headless = true;
+                continue;
+            }
+            "--frames" => {
+                frames = count_arg(&arg, args.next());
+                continue;
+            }
+            "--instructions" => {
+                config.max_instructions = Some(count_arg(&arg, args.next()));
+                continue;
+            }
+            _ => {
+                config.autoload = arg;
+                continue;
+            }
+        };
+        *rom = args.next();
+    }
+
+    if headless {
+        run_headless(config, frames);
+    } else {
+        run_windowed(config);
+    }
+}
+
+fn build<D: Display>(config: ZpcConfig, display: D) -> ZPC<D> {
+    match ZPC::with_config(config, display) {
+        Ok(zpc) => zpc,
+        Err(e) => {
+            eprintln!("Couldn't load ROM: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_headless(config: ZpcConfig, frames: u64) {
+    let cycles = frames * config.region.cycles_per_frame() as u64;
+    let mut zpc = build(config, HeadlessDisplay::new());
+    zpc.reset(ResetKind::Cold);
+    let outcome = zpc.run_until(RunUntil::Cycles(cycles), cycles);
+    eprintln!("Stopped: {:?}", outcome);
+}
+
+#[cfg(feature = "window")]
+fn run_windowed(config: ZpcConfig) {
+    use z80_emulator::{vic, window::WindowDisplay};
+
+    let display = match WindowDisplay::new(
+        "z80Emulator",
+        vic::SCREEN_WIDTH,
+        vic::SCREEN_HEIGHT,
+        config.scale,
+    ) {
+        Ok(display) => display,
+        Err(e) => {
+            eprintln!("Couldn't open window: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut zpc = build(config, display);
+    zpc.reset(ResetKind::Cold);
+    zpc.run();
+}
+
+// Without a window the only display is headless, and it never closes, so a run needs
+// a limit.
+#[cfg(not(feature = "window"))]
+fn run_windowed(_config: ZpcConfig) {
+    eprintln!(
+        "Built without the window feature; run with --headless.\n{}",
+        USAGE
+    );
+    std::process::exit(2);
 }
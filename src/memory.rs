@@ -0,0 +1,778 @@
+//! System memory as seen by the CPU.
+
+use crate::crt::CartMapper;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub type MemShared = Rc<RefCell<Memory>>;
+
+/// Size of the addressable memory space.
+pub const MEM_SIZE: usize = 0x10000;
+
+/// ROM sizes.
+pub const BASIC_SIZE: usize = 0x2000;
+pub const CHARGEN_SIZE: usize = 0x1000;
+pub const KERNAL_SIZE: usize = 0x2000;
+
+pub const BASIC_BASE: u16 = 0xA000;
+pub const CHARGEN_BASE: u16 = 0xD000;
+pub const KERNAL_BASE: u16 = 0xE000;
+
+/// Colour RAM: 1K of 4-bit cells at $D800-$DBFF, in the IO area.
+pub const COLOR_RAM_BASE: u16 = 0xD800;
+pub const COLOR_RAM_SIZE: usize = 0x0400;
+
+/// What RAM holds after a cold reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamPattern {
+    Zeroed,
+    /// The pattern real C64 RAM tends to power up with: alternating 64-byte runs of
+    /// $00 and $FF, inverted in every other 16K.
+    Patterned,
+    /// Pseudo-random bytes drawn from the memory's seed, the same on every reset.
+    Random,
+}
+
+fn power_on_byte(addr: usize) -> u8 {
+    let run = if addr & 0x40 == 0 { 0x00 } else { 0xFF };
+    if addr & 0x4000 == 0 {
+        run
+    } else {
+        !run
+    }
+}
+
+/// Watchpoint mask bits.
+pub const WATCH_READ: u8 = 1 << 0;
+pub const WATCH_WRITE: u8 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub value: u8,
+    /// `WATCH_READ` or `WATCH_WRITE`, depending on the access that triggered.
+    pub access: u8,
+}
+
+/// Observes CPU bus accesses to memory. Accesses to chip registers are dispatched by
+/// the CPU before they reach `Memory`, so hooks don't see them.
+pub trait BusHook {
+    fn on_read(&mut self, addr: u16, value: u8);
+    fn on_write(&mut self, addr: u16, value: u8);
+    /// A VIC fetch from RAM or the character ROM, at its address in the CPU's view of
+    /// the bus.
+    fn on_vic_read(&mut self, _addr: u16, _value: u8) {}
+}
+
+/// 6510 processor port: data direction register and data register.
+pub const PORT_DDR: u16 = 0x0000;
+pub const PORT_DATA: u16 = 0x0001;
+
+/// Processor port bits that select the memory configuration.
+pub const PORT_LORAM: u8 = 1 << 0;
+pub const PORT_HIRAM: u8 = 1 << 1;
+pub const PORT_CHAREN: u8 = 1 << 2;
+/// Input bit pulled low while a datasette button is held down.
+pub const PORT_CASSETTE_SENSE: u8 = 1 << 4;
+
+/// What the CPU sees at an address under the current banking, from `Memory::bank_at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BankSource {
+    Ram,
+    Basic,
+    Kernal,
+    Chargen,
+    /// Chip registers and colour RAM.
+    Io,
+    Cartridge,
+    /// The 6510's own data direction and data registers at $0000/$0001.
+    Port,
+}
+
+/// The system ROMs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemType {
+    Basic,
+    Chargen,
+    Kernal,
+}
+
+impl MemType {
+    pub fn size(self) -> usize {
+        match self {
+            MemType::Basic => BASIC_SIZE,
+            MemType::Chargen => CHARGEN_SIZE,
+            MemType::Kernal => KERNAL_SIZE,
+        }
+    }
+
+    pub fn base(self) -> u16 {
+        match self {
+            MemType::Basic => BASIC_BASE,
+            MemType::Chargen => CHARGEN_BASE,
+            MemType::Kernal => KERNAL_BASE,
+        }
+    }
+}
+
+pub struct Memory {
+    ram: Vec<u8>,
+    /// BASIC and KERNAL ROMs, empty until loaded. Once loaded the CPU reads them at
+    /// $A000 and $E000; writes always land in the RAM underneath.
+    basic: Vec<u8>,
+    kernal: Vec<u8>,
+    /// Character generator ROM.
+    chargen: Vec<u8>,
+    /// Colour RAM, low nibbles only. The CPU reaches it while IO is banked in; the VIC
+    /// always sees it, whatever its bank.
+    color_ram: Vec<u8>,
+    /// Processor port registers at $00 and $01. Writes to them also reach the RAM
+    /// underneath.
+    port_ddr: u8,
+    port_data: u8,
+    /// A datasette button is pressed, grounding the cassette sense input.
+    cassette_switch: bool,
+    /// Watched addresses and their access masks.
+    watchpoints: HashMap<u16, u8>,
+    /// Most recent watchpoint hit, waiting to be collected with `take_watch_hit`.
+    watch_hit: Cell<Option<WatchHit>>,
+    /// Called on every access once installed. Reads only borrow memory, hence the
+    /// `RefCell`.
+    bus_hook: Option<RefCell<Box<dyn BusHook>>>,
+    /// Bank-switched cartridge in the expansion port. It overlays ROML and ROMH and
+    /// takes writes to IO-1.
+    cartridge: Option<Box<dyn CartMapper>>,
+    /// Contents `reset` leaves in RAM.
+    ram_pattern: RamPattern,
+    /// Seeds the generator behind `RamPattern::Random`.
+    seed: u64,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            ram: vec![0; MEM_SIZE],
+            basic: Vec::new(),
+            kernal: Vec::new(),
+            chargen: vec![0; CHARGEN_SIZE],
+            color_ram: vec![0; COLOR_RAM_SIZE],
+            port_ddr: 0,
+            port_data: 0,
+            cassette_switch: false,
+            watchpoints: HashMap::new(),
+            watch_hit: Cell::new(None),
+            bus_hook: None,
+            cartridge: None,
+            ram_pattern: RamPattern::Patterned,
+            seed: utils::DEFAULT_SEED,
+        }
+    }
+
+    /// Puts RAM, colour RAM and the processor port in their power-on state, filling RAM
+    /// according to the chosen `RamPattern`. ROMs stay installed.
+    pub fn reset(&mut self) {
+        match self.ram_pattern {
+            RamPattern::Zeroed => self.ram.fill(0),
+            RamPattern::Patterned => {
+                for (addr, byte) in self.ram.iter_mut().enumerate() {
+                    *byte = power_on_byte(addr);
+                }
+            }
+            RamPattern::Random => {
+                let mut rng = utils::Rng::new(self.seed);
+                for byte in self.ram.iter_mut() {
+                    *byte = rng.next_u8();
+                }
+            }
+        }
+        self.color_ram.fill(0);
+        self.port_ddr = 0;
+        self.port_data = 0;
+    }
+
+    pub fn set_ram_pattern(&mut self, pattern: RamPattern) {
+        self.ram_pattern = pattern;
+    }
+
+    pub fn ram_pattern(&self) -> RamPattern {
+        self.ram_pattern
+    }
+
+    /// Seed for the pseudo-random contents, applied from the next reset on.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn new_shared() -> MemShared {
+        Rc::new(RefCell::new(Memory::new()))
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.bytes(&self.basic);
+        w.bytes(&self.kernal);
+        w.bytes(&self.chargen);
+        w.bytes(&self.color_ram);
+        w.u8(self.port_ddr);
+        w.u8(self.port_data);
+        w.bool(self.cartridge.is_some());
+        if let Some(cart) = &self.cartridge {
+            cart.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.ram)?;
+        self.basic = Self::read_optional_rom(r, MemType::Basic)?;
+        self.kernal = Self::read_optional_rom(r, MemType::Kernal)?;
+        r.bytes_into(&mut self.chargen)?;
+        r.bytes_into(&mut self.color_ram)?;
+        self.port_ddr = r.u8()?;
+        self.port_data = r.u8()?;
+        // Cartridge ROMs aren't saved, so the same cartridge has to be plugged in.
+        if r.bool()? != self.cartridge.is_some() {
+            return Err(String::from(
+                "snapshot and machine differ in whether a cartridge is plugged in",
+            ));
+        }
+        if let Some(cart) = &mut self.cartridge {
+            cart.load_state(r)?;
+        }
+        Ok(())
+    }
+
+    fn read_optional_rom(r: &mut StateReader, rom: MemType) -> Result<Vec<u8>, String> {
+        let data = r.bytes()?;
+        if !data.is_empty() && data.len() != rom.size() {
+            return Err(format!("snapshot {:?} ROM is {} bytes", rom, data.len()));
+        }
+        Ok(data.to_vec())
+    }
+
+    /// Installs a system ROM. `data` must be exactly the ROM's size.
+    pub fn load_rom(&mut self, rom: MemType, data: &[u8]) -> Result<(), String> {
+        if data.len() != rom.size() {
+            return Err(format!(
+                "{:?} ROM is {} bytes, expected {}",
+                rom,
+                data.len(),
+                rom.size()
+            ));
+        }
+        match rom {
+            MemType::Basic => self.basic = data.to_vec(),
+            MemType::Chargen => self.chargen.copy_from_slice(data),
+            MemType::Kernal => self.kernal = data.to_vec(),
+        }
+        Ok(())
+    }
+
+    /// The processor port as the CPU reads it at $01. Pins configured as inputs are
+    /// pulled high unless the datasette grounds the sense line.
+    pub fn port_value(&self) -> u8 {
+        let value = self.port_data | !self.port_ddr;
+        if self.cassette_switch && self.port_ddr & PORT_CASSETTE_SENSE == 0 {
+            value & !PORT_CASSETTE_SENSE
+        } else {
+            value
+        }
+    }
+
+    pub fn set_cassette_sense(&mut self, pressed: bool) {
+        self.cassette_switch = pressed;
+    }
+
+    /// True when the chip registers are banked in at $D000-$DFFF.
+    pub fn io_visible(&self) -> bool {
+        let port = self.port_value();
+        port & (PORT_LORAM | PORT_HIRAM) != 0 && port & PORT_CHAREN != 0
+    }
+
+    /// Reads what the CPU sees at `addr` under the current banking. ROMs that were
+    /// never loaded leave the RAM underneath visible. Chip registers are dispatched by
+    /// the CPU, so with IO banked in this returns the RAM under $D000.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        let value = self.peek(addr);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoint(addr, value, WATCH_READ);
+        }
+        if let Some(hook) = &self.bus_hook {
+            hook.borrow_mut().on_read(addr, value);
+        }
+        value
+    }
+
+    /// Reads like `read_byte` without triggering watchpoints or the bus hook.
+    pub fn peek(&self, addr: u16) -> u8 {
+        // Nothing is ever banked in over these, so skip the port decoding.
+        if (PORT_DATA + 1..0x8000).contains(&addr) || (0xC000..0xD000).contains(&addr) {
+            return self.ram[addr as usize];
+        }
+        let port = self.port_value();
+        if let Some(value) = self.cartridge_read(addr, port) {
+            return value;
+        }
+        match addr {
+            PORT_DDR => self.port_ddr,
+            PORT_DATA => port,
+            0xA000..=0xBFFF
+                if port & (PORT_LORAM | PORT_HIRAM) == PORT_LORAM | PORT_HIRAM
+                    && !self.basic.is_empty() =>
+            {
+                self.basic[(addr - BASIC_BASE) as usize]
+            }
+            0xD800..=0xDBFF if self.io_visible() => {
+                // The upper nibble isn't connected and floats; read it as set.
+                self.color_ram[(addr - COLOR_RAM_BASE) as usize] | 0xF0
+            }
+            0xD000..=0xDFFF if port & (PORT_LORAM | PORT_HIRAM) != 0 && port & PORT_CHAREN == 0 => {
+                self.chargen[(addr - CHARGEN_BASE) as usize]
+            }
+            0xE000..=0xFFFF if port & PORT_HIRAM != 0 && !self.kernal.is_empty() => {
+                self.kernal[(addr - KERNAL_BASE) as usize]
+            }
+            _ => self.ram[addr as usize],
+        }
+    }
+
+    /// Reports which source a CPU read of `addr` would be served from, following the
+    /// same rules as `peek`. ROMs that were never loaded leave RAM visible.
+    pub fn bank_at(&self, addr: u16) -> BankSource {
+        let port = self.port_value();
+        if self.cartridge_read(addr, port).is_some() {
+            return BankSource::Cartridge;
+        }
+        match addr {
+            PORT_DDR | PORT_DATA => BankSource::Port,
+            0xA000..=0xBFFF
+                if port & (PORT_LORAM | PORT_HIRAM) == PORT_LORAM | PORT_HIRAM
+                    && !self.basic.is_empty() =>
+            {
+                BankSource::Basic
+            }
+            0xD000..=0xDFFF if self.io_visible() => BankSource::Io,
+            0xD000..=0xDFFF if port & (PORT_LORAM | PORT_HIRAM) != 0 && port & PORT_CHAREN == 0 => {
+                BankSource::Chargen
+            }
+            0xE000..=0xFFFF if port & PORT_HIRAM != 0 && !self.kernal.is_empty() => {
+                BankSource::Kernal
+            }
+            _ => BankSource::Ram,
+        }
+    }
+
+    /// Cartridge ROML shows while LORAM and HIRAM are both set, ROMH in place of BASIC
+    /// while HIRAM is. In Ultimax mode ROML and ROMH, at $E000, show whatever the port
+    /// says.
+    fn cartridge_read(&self, addr: u16, port: u8) -> Option<u8> {
+        let cart = self.cartridge.as_ref()?;
+        let ultimax = cart.ultimax();
+        let visible = match addr {
+            0x8000..=0x9FFF => {
+                ultimax || port & (PORT_LORAM | PORT_HIRAM) == PORT_LORAM | PORT_HIRAM
+            }
+            0xA000..=0xBFFF => !ultimax && port & PORT_HIRAM != 0,
+            0xE000..=0xFFFF => ultimax,
+            _ => false,
+        };
+        if !visible {
+            return None;
+        }
+        cart.read(addr)
+    }
+
+    /// Writes land in RAM whatever is banked in for reads, except for colour RAM, which
+    /// replaces RAM at $D800-$DBFF while IO is banked in, and IO-1 at $DE00-$DEFF,
+    /// which belongs to the cartridge.
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoint(addr, value, WATCH_WRITE);
+        }
+        if let Some(hook) = &self.bus_hook {
+            hook.borrow_mut().on_write(addr, value);
+        }
+        match addr {
+            PORT_DDR => self.port_ddr = value,
+            PORT_DATA => self.port_data = value,
+            0xD800..=0xDBFF if self.io_visible() => {
+                self.color_ram[(addr - COLOR_RAM_BASE) as usize] = value & 0x0F;
+                return;
+            }
+            0xDE00..=0xDEFF if self.io_visible() => {
+                if let Some(cart) = &mut self.cartridge {
+                    cart.write(addr, value);
+                }
+                return;
+            }
+            _ => {}
+        }
+        self.ram[addr as usize] = value;
+    }
+
+    /// Reads RAM directly, bypassing banking and watchpoints. This is the VIC's view.
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    /// Reads colour RAM as the VIC does, by offset from $D800.
+    pub fn read_color_ram(&self, offset: u16) -> u8 {
+        self.color_ram[offset as usize % COLOR_RAM_SIZE]
+    }
+
+    pub fn read_chargen(&self, offset: u16) -> u8 {
+        self.chargen[offset as usize % CHARGEN_SIZE]
+    }
+
+    /// Plugs in a bank-switched cartridge, replacing any previous one.
+    pub fn attach_cartridge(&mut self, cartridge: Box<dyn CartMapper>) {
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn detach_cartridge(&mut self) -> Option<Box<dyn CartMapper>> {
+        self.cartridge.take()
+    }
+
+    pub fn cartridge(&self) -> Option<&dyn CartMapper> {
+        self.cartridge.as_deref()
+    }
+
+    /// Installs `hook`, replacing any previous one.
+    pub fn set_bus_hook(&mut self, hook: Box<dyn BusHook>) {
+        self.bus_hook = Some(RefCell::new(hook));
+    }
+
+    /// Passes a VIC fetch on to the bus hook.
+    pub fn report_vic_read(&self, addr: u16, value: u8) {
+        if let Some(hook) = &self.bus_hook {
+            hook.borrow_mut().on_vic_read(addr, value);
+        }
+    }
+
+    /// Removes the installed hook and hands it back.
+    pub fn take_bus_hook(&mut self) -> Option<Box<dyn BusHook>> {
+        self.bus_hook.take().map(RefCell::into_inner)
+    }
+
+    /// Watches `addr` for the accesses in `mask` (`WATCH_READ`, `WATCH_WRITE` or both).
+    pub fn add_watchpoint(&mut self, addr: u16, mask: u8) {
+        *self.watchpoints.entry(addr).or_insert(0) |= mask;
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    /// Returns and clears the most recent watchpoint hit.
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoint(&self, addr: u16, value: u8, access: u8) {
+        if let Some(mask) = self.watchpoints.get(&addr) {
+            if mask & access != 0 {
+                self.watch_hit.set(Some(WatchHit {
+                    addr,
+                    value,
+                    access,
+                }));
+            }
+        }
+    }
+
+    /// Reads a little-endian word starting at `addr`.
+    pub fn read_word_le(&self, addr: u16) -> u16 {
+        let lo = self.read_byte(addr) as u16;
+        let hi = self.read_byte(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    /// Copies `len` bytes of raw RAM from `start`, ignoring banking, wrapping at the end
+    /// of the address space.
+    pub fn dump_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.ram[start.wrapping_add(i as u16) as usize])
+            .collect()
+    }
+
+    /// Compares raw RAM with `snapshot`, an earlier `dump_range(0, MEM_SIZE)`, returning
+    /// the address, old value and current value of every byte that changed. A snapshot
+    /// of any other length is rejected.
+    pub fn diff(&self, snapshot: &[u8]) -> Result<Vec<(u16, u8, u8)>, String> {
+        if snapshot.len() != self.ram.len() {
+            return Err(format!(
+                "snapshot holds {} bytes, not the {} of RAM",
+                snapshot.len(),
+                self.ram.len()
+            ));
+        }
+        Ok(snapshot
+            .iter()
+            .zip(&self.ram)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(addr, (&old, &new))| (addr as u16, old, new))
+            .collect())
+    }
+
+    /// Writes `data` straight into RAM from `start`, without the side effects of
+    /// `write_byte` on the processor port, colour RAM or cartridge.
+    pub fn load_range(&mut self, start: u16, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.ram[start.wrapping_add(i as u16) as usize] = byte;
+        }
+    }
+
+    /// Copies `data` into RAM starting at `addr`, wrapping at the end of the address space.
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.write_byte(addr.wrapping_add(i as u16), *byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[derive(Default)]
+    struct CountingHook {
+        counts: Rc<Cell<(usize, usize)>>,
+    }
+
+    impl BusHook for CountingHook {
+        fn on_read(&mut self, _addr: u16, _value: u8) {
+            let (reads, writes) = self.counts.get();
+            self.counts.set((reads + 1, writes));
+        }
+
+        fn on_write(&mut self, _addr: u16, _value: u8) {
+            let (reads, writes) = self.counts.get();
+            self.counts.set((reads, writes + 1));
+        }
+    }
+
+    #[test]
+    fn write_watch_fires_on_screen_write() {
+        let mut mem = Memory::new();
+        mem.add_watchpoint(0x0400, WATCH_WRITE);
+
+        mem.read_byte(0x0400);
+        mem.write_byte(0x0401, 0x01);
+        assert_eq!(mem.take_watch_hit(), None);
+
+        mem.write_byte(0x0400, 0x41);
+        assert_eq!(
+            mem.take_watch_hit(),
+            Some(WatchHit {
+                addr: 0x0400,
+                value: 0x41,
+                access: WATCH_WRITE,
+            })
+        );
+        assert_eq!(mem.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn read_watch_fires_on_read() {
+        let mut mem = Memory::new();
+        mem.write_byte(0xC000, 0x7F);
+        mem.add_watchpoint(0xC000, WATCH_READ);
+
+        assert_eq!(mem.read_byte(0xC000), 0x7F);
+        assert_eq!(mem.take_watch_hit().map(|hit| hit.access), Some(WATCH_READ));
+    }
+
+    #[test]
+    fn roms_overlay_ram_for_reads_only() {
+        let mut mem = Memory::new();
+        mem.write_byte(0xA000, 0x11);
+        assert_eq!(mem.read_byte(0xA000), 0x11);
+
+        let mut basic = vec![0; BASIC_SIZE];
+        basic[0] = 0x94;
+        let mut kernal = vec![0; KERNAL_SIZE];
+        kernal[0x1FFC] = 0xE2;
+        kernal[0x1FFD] = 0xFC;
+        let mut chargen = vec![0; CHARGEN_SIZE];
+        chargen[0x08] = 0x3C;
+        mem.load_rom(MemType::Basic, &basic).unwrap();
+        mem.load_rom(MemType::Kernal, &kernal).unwrap();
+        mem.load_rom(MemType::Chargen, &chargen).unwrap();
+
+        assert_eq!(mem.read_byte(0xA000), 0x94);
+        assert_eq!(mem.read_word_le(0xFFFC), 0xFCE2);
+        assert_eq!(mem.read_chargen(0x08), 0x3C);
+
+        mem.write_byte(0xE000, 0x55);
+        assert_eq!(mem.read_byte(0xE000), 0x00);
+        assert_eq!(mem.ram[0xE000], 0x55);
+    }
+
+    #[test]
+    fn load_rom_rejects_wrong_length() {
+        let mut mem = Memory::new();
+        assert!(mem.load_rom(MemType::Kernal, &[0; 0x1000]).is_err());
+        assert!(mem.load_rom(MemType::Chargen, &[0; 0x2000]).is_err());
+        assert_eq!(mem.read_byte(0xE000), 0);
+    }
+
+    #[test]
+    fn port_bits_switch_basic_and_chargen() {
+        let mut mem = Memory::new();
+        mem.load_rom(MemType::Basic, &[0x94; BASIC_SIZE]).unwrap();
+        mem.load_rom(MemType::Chargen, &[0x3C; CHARGEN_SIZE])
+            .unwrap();
+        mem.write_byte(0xA000, 0x11);
+        mem.write_byte(0xD000, 0x22);
+        mem.write_byte(PORT_DDR, 0x2F);
+
+        mem.write_byte(PORT_DATA, 0x37);
+        assert_eq!(mem.read_byte(0xA000), 0x94);
+        assert!(mem.io_visible());
+
+        mem.write_byte(PORT_DATA, 0x36);
+        assert_eq!(mem.read_byte(0xA000), 0x11);
+        assert!(mem.io_visible());
+
+        mem.write_byte(PORT_DATA, 0x33);
+        assert_eq!(mem.read_byte(0xA000), 0x94);
+        assert_eq!(mem.read_byte(0xD000), 0x3C);
+        assert!(!mem.io_visible());
+
+        mem.write_byte(PORT_DATA, 0x30);
+        assert_eq!(mem.read_byte(0xA000), 0x11);
+        assert_eq!(mem.read_byte(0xD000), 0x22);
+        assert!(!mem.io_visible());
+    }
+
+    #[test]
+    fn bank_at_follows_the_port_bits() {
+        let mut mem = Memory::new();
+        mem.load_rom(MemType::Basic, &[0; BASIC_SIZE]).unwrap();
+        mem.load_rom(MemType::Kernal, &[0; KERNAL_SIZE]).unwrap();
+        mem.load_rom(MemType::Chargen, &[0; CHARGEN_SIZE]).unwrap();
+        mem.write_byte(PORT_DDR, 0x2F);
+
+        let cases = [
+            (0x37, BankSource::Basic, BankSource::Io, BankSource::Kernal),
+            (0x36, BankSource::Ram, BankSource::Io, BankSource::Kernal),
+            (0x35, BankSource::Ram, BankSource::Io, BankSource::Ram),
+            (
+                0x33,
+                BankSource::Basic,
+                BankSource::Chargen,
+                BankSource::Kernal,
+            ),
+            (0x31, BankSource::Ram, BankSource::Chargen, BankSource::Ram),
+            (0x30, BankSource::Ram, BankSource::Ram, BankSource::Ram),
+        ];
+        for (port, basic, io, kernal) in cases {
+            mem.write_byte(PORT_DATA, port);
+            assert_eq!(mem.bank_at(0xA000), basic, "port {:02X}", port);
+            assert_eq!(mem.bank_at(0xD000), io, "port {:02X}", port);
+            assert_eq!(mem.bank_at(0xE000), kernal, "port {:02X}", port);
+        }
+        assert_eq!(mem.bank_at(PORT_DATA), BankSource::Port);
+        assert_eq!(mem.bank_at(0x0400), BankSource::Ram);
+    }
+
+    #[test]
+    fn input_port_pins_read_high() {
+        let mut mem = Memory::new();
+        mem.write_byte(PORT_DATA, 0x00);
+        assert_eq!(mem.read_byte(PORT_DATA), 0xFF);
+        mem.write_byte(PORT_DDR, 0x07);
+        assert_eq!(mem.read_byte(PORT_DATA), 0xF8);
+        assert_eq!(mem.read_byte(PORT_DDR), 0x07);
+    }
+
+    #[test]
+    fn color_ram_keeps_low_nibble_while_io_is_visible() {
+        let mut mem = Memory::new();
+        mem.write_byte(0xD800, 0x5A);
+        assert_eq!(mem.read_byte(0xD800), 0xFA);
+        assert_eq!(mem.read_color_ram(0), 0x0A);
+        assert_eq!(mem.read_ram(0xD800), 0x00);
+
+        // With IO banked out the CPU reaches the RAM underneath instead.
+        mem.write_byte(PORT_DDR, 0x07);
+        mem.write_byte(PORT_DATA, 0x00);
+        mem.write_byte(0xD800, 0x33);
+        assert_eq!(mem.read_byte(0xD800), 0x33);
+        assert_eq!(mem.read_color_ram(0), 0x0A);
+    }
+
+    #[test]
+    fn reset_fills_ram_with_the_chosen_pattern() {
+        let mut mem = Memory::new();
+        mem.reset();
+        assert_eq!(mem.read_ram(0x0002), 0x00);
+        assert_eq!(mem.read_ram(0x0040), 0xFF);
+        assert_eq!(mem.read_ram(0x4000), 0xFF);
+        assert_eq!(mem.read_ram(0x4040), 0x00);
+
+        mem.set_ram_pattern(RamPattern::Random);
+        mem.set_seed(7);
+        mem.reset();
+        let first = mem.dump_range(0x1000, 16);
+        mem.reset();
+        assert_eq!(mem.dump_range(0x1000, 16), first);
+        mem.set_seed(8);
+        mem.reset();
+        assert_ne!(mem.dump_range(0x1000, 16), first);
+
+        mem.set_ram_pattern(RamPattern::Zeroed);
+        mem.reset();
+        assert_eq!(mem.read_ram(0x0040), 0x00);
+    }
+
+    #[test]
+    fn bus_hook_sees_every_memory_access() {
+        let mem = Memory::new_shared();
+        // LDA $10; STA $11
+        mem.borrow_mut().load(0x0600, &[0xA5, 0x10, 0x85, 0x11]);
+        let hook = CountingHook::default();
+        let counts = hook.counts.clone();
+        mem.borrow_mut().set_bus_hook(Box::new(hook));
+
+        let mut cpu = CPU::new(mem.clone());
+        cpu.pc = 0x0600;
+        cpu.step();
+        cpu.step();
+        assert_eq!(counts.get(), (5, 1));
+
+        assert!(mem.borrow_mut().take_bus_hook().is_some());
+        mem.borrow().read_byte(0x0600);
+        assert_eq!(counts.get(), (5, 1));
+    }
+
+    #[test]
+    fn diff_needs_a_snapshot_of_all_of_ram() {
+        let mut mem = Memory::new();
+        let mut snapshot = mem.dump_range(0, MEM_SIZE);
+        mem.load_range(0xFFFF, &[snapshot[0xFFFF] ^ 1]);
+        assert_eq!(
+            mem.diff(&snapshot),
+            Ok(vec![(0xFFFF, snapshot[0xFFFF], snapshot[0xFFFF] ^ 1)])
+        );
+
+        // A prefix would hide the change at $FFFF.
+        snapshot.pop();
+        assert!(mem.diff(&snapshot).is_err());
+        assert!(mem.diff(&[]).is_err());
+    }
+}
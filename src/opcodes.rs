@@ -0,0 +1,466 @@
+//! 6502 instruction set: mnemonics, addressing modes and the opcode decode table.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    ADC,
+    AND,
+    ASL,
+    BCC,
+    BCS,
+    BEQ,
+    BIT,
+    BMI,
+    BNE,
+    BPL,
+    BRK,
+    BVC,
+    BVS,
+    CLC,
+    CLD,
+    CLI,
+    CLV,
+    CMP,
+    CPX,
+    CPY,
+    DEC,
+    DEX,
+    DEY,
+    EOR,
+    INC,
+    INX,
+    INY,
+    JMP,
+    JSR,
+    LDA,
+    LDX,
+    LDY,
+    LSR,
+    NOP,
+    ORA,
+    PHA,
+    PHP,
+    PLA,
+    PLP,
+    ROL,
+    ROR,
+    RTI,
+    RTS,
+    SBC,
+    SEC,
+    SED,
+    SEI,
+    STA,
+    STX,
+    STY,
+    TAX,
+    TAY,
+    TSX,
+    TXA,
+    TXS,
+    TYA,
+    // Undocumented opcodes
+    ALR,
+    ANC,
+    ANE,
+    ARR,
+    DCP,
+    ISC,
+    JAM,
+    LAS,
+    LAX,
+    LXA,
+    RLA,
+    RRA,
+    SAX,
+    SBX,
+    SHA,
+    SHX,
+    SHY,
+    SLO,
+    SRE,
+    TAS,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Relative,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirectX,
+    IndirectIndexedY,
+}
+
+impl AddrMode {
+    /// Number of operand bytes following the opcode.
+    pub fn operand_len(self) -> u16 {
+        match self {
+            AddrMode::Implied | AddrMode::Accumulator => 0,
+            AddrMode::Absolute | AddrMode::AbsoluteX | AddrMode::AbsoluteY | AddrMode::Indirect => {
+                2
+            }
+            _ => 1,
+        }
+    }
+
+    /// Formats an operand in assembler syntax, naming addresses found in `labels`.
+    /// `addr` is the address of the opcode, which branch targets are relative to.
+    pub fn format_operand(self, addr: u16, operand: u16, labels: &HashMap<u16, String>) -> String {
+        let name = |value: u16, digits: usize| match labels.get(&value) {
+            Some(label) => label.clone(),
+            None => format!("${:0digits$X}", value),
+        };
+
+        match self {
+            AddrMode::Implied => String::new(),
+            AddrMode::Accumulator => String::from("A"),
+            AddrMode::Immediate => format!("#${:02X}", operand),
+            AddrMode::ZeroPage => name(operand, 2),
+            AddrMode::ZeroPageX => format!("{},X", name(operand, 2)),
+            AddrMode::ZeroPageY => format!("{},Y", name(operand, 2)),
+            AddrMode::Relative => name(
+                addr.wrapping_add(2)
+                    .wrapping_add(operand as u8 as i8 as u16),
+                4,
+            ),
+            AddrMode::Absolute => name(operand, 4),
+            AddrMode::AbsoluteX => format!("{},X", name(operand, 4)),
+            AddrMode::AbsoluteY => format!("{},Y", name(operand, 4)),
+            AddrMode::Indirect => format!("({})", name(operand, 4)),
+            AddrMode::IndexedIndirectX => format!("({},X)", name(operand, 2)),
+            AddrMode::IndirectIndexedY => format!("({}),Y", name(operand, 2)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: Op,
+    pub mode: AddrMode,
+    /// Base cycle count, excluding page-crossing and branch penalties.
+    pub cycles: u8,
+    /// Whether crossing a page boundary while indexing costs an extra cycle.
+    pub page_penalty: bool,
+}
+
+impl Instruction {
+    /// Number of write cycles that end the instruction. These complete even while
+    /// RDY is held low.
+    pub fn trailing_writes(&self) -> u8 {
+        match self.op {
+            Op::ASL | Op::LSR | Op::ROL | Op::ROR if self.mode == AddrMode::Accumulator => 0,
+            Op::ASL | Op::LSR | Op::ROL | Op::ROR | Op::INC | Op::DEC => 2,
+            Op::DCP | Op::ISC | Op::SLO | Op::RLA | Op::SRE | Op::RRA => 2,
+            Op::STA | Op::STX | Op::STY | Op::SAX | Op::PHA | Op::PHP => 1,
+            Op::SHA | Op::SHX | Op::SHY | Op::TAS => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Published NMOS 6510 cycle counts for every opcode byte, undocumented ones included,
+/// before page-crossing and taken-branch penalties. `decode` must agree with it.
+#[rustfmt::skip]
+pub const REFERENCE_CYCLES: [u8; 256] = [
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+];
+
+const fn ins(op: Op, mode: AddrMode, cycles: u8, page_penalty: bool) -> Option<Instruction> {
+    Some(Instruction {
+        op,
+        mode,
+        cycles,
+        page_penalty,
+    })
+}
+
+/// Decodes an opcode byte. Every byte decodes, undocumented ones included; the unstable
+/// ones behave as they do on most NMOS parts.
+#[rustfmt::skip]
+pub fn decode(opcode: u8) -> Option<Instruction> {
+    use self::AddrMode::*;
+    use self::Op::*;
+
+    match opcode {
+        0x00 => ins(BRK, Implied, 7, false),
+        0x01 => ins(ORA, IndexedIndirectX, 6, false),
+        0x02 => ins(JAM, Implied, 2, false),
+        0x03 => ins(SLO, IndexedIndirectX, 8, false),
+        0x04 => ins(NOP, ZeroPage, 3, false),
+        0x05 => ins(ORA, ZeroPage, 3, false),
+        0x06 => ins(ASL, ZeroPage, 5, false),
+        0x07 => ins(SLO, ZeroPage, 5, false),
+        0x08 => ins(PHP, Implied, 3, false),
+        0x09 => ins(ORA, Immediate, 2, false),
+        0x0A => ins(ASL, Accumulator, 2, false),
+        0x0B => ins(ANC, Immediate, 2, false),
+        0x0C => ins(NOP, Absolute, 4, false),
+        0x0D => ins(ORA, Absolute, 4, false),
+        0x0E => ins(ASL, Absolute, 6, false),
+        0x0F => ins(SLO, Absolute, 6, false),
+        0x10 => ins(BPL, Relative, 2, false),
+        0x11 => ins(ORA, IndirectIndexedY, 5, true),
+        0x12 => ins(JAM, Implied, 2, false),
+        0x13 => ins(SLO, IndirectIndexedY, 8, false),
+        0x14 => ins(NOP, ZeroPageX, 4, false),
+        0x15 => ins(ORA, ZeroPageX, 4, false),
+        0x16 => ins(ASL, ZeroPageX, 6, false),
+        0x17 => ins(SLO, ZeroPageX, 6, false),
+        0x18 => ins(CLC, Implied, 2, false),
+        0x19 => ins(ORA, AbsoluteY, 4, true),
+        0x1A => ins(NOP, Implied, 2, false),
+        0x1B => ins(SLO, AbsoluteY, 7, false),
+        0x1C => ins(NOP, AbsoluteX, 4, true),
+        0x1D => ins(ORA, AbsoluteX, 4, true),
+        0x1E => ins(ASL, AbsoluteX, 7, false),
+        0x1F => ins(SLO, AbsoluteX, 7, false),
+        0x20 => ins(JSR, Absolute, 6, false),
+        0x21 => ins(AND, IndexedIndirectX, 6, false),
+        0x22 => ins(JAM, Implied, 2, false),
+        0x23 => ins(RLA, IndexedIndirectX, 8, false),
+        0x24 => ins(BIT, ZeroPage, 3, false),
+        0x25 => ins(AND, ZeroPage, 3, false),
+        0x26 => ins(ROL, ZeroPage, 5, false),
+        0x27 => ins(RLA, ZeroPage, 5, false),
+        0x28 => ins(PLP, Implied, 4, false),
+        0x29 => ins(AND, Immediate, 2, false),
+        0x2A => ins(ROL, Accumulator, 2, false),
+        0x2B => ins(ANC, Immediate, 2, false),
+        0x2C => ins(BIT, Absolute, 4, false),
+        0x2D => ins(AND, Absolute, 4, false),
+        0x2E => ins(ROL, Absolute, 6, false),
+        0x2F => ins(RLA, Absolute, 6, false),
+        0x30 => ins(BMI, Relative, 2, false),
+        0x31 => ins(AND, IndirectIndexedY, 5, true),
+        0x32 => ins(JAM, Implied, 2, false),
+        0x33 => ins(RLA, IndirectIndexedY, 8, false),
+        0x34 => ins(NOP, ZeroPageX, 4, false),
+        0x35 => ins(AND, ZeroPageX, 4, false),
+        0x36 => ins(ROL, ZeroPageX, 6, false),
+        0x37 => ins(RLA, ZeroPageX, 6, false),
+        0x38 => ins(SEC, Implied, 2, false),
+        0x39 => ins(AND, AbsoluteY, 4, true),
+        0x3A => ins(NOP, Implied, 2, false),
+        0x3B => ins(RLA, AbsoluteY, 7, false),
+        0x3C => ins(NOP, AbsoluteX, 4, true),
+        0x3D => ins(AND, AbsoluteX, 4, true),
+        0x3E => ins(ROL, AbsoluteX, 7, false),
+        0x3F => ins(RLA, AbsoluteX, 7, false),
+        0x40 => ins(RTI, Implied, 6, false),
+        0x41 => ins(EOR, IndexedIndirectX, 6, false),
+        0x42 => ins(JAM, Implied, 2, false),
+        0x43 => ins(SRE, IndexedIndirectX, 8, false),
+        0x44 => ins(NOP, ZeroPage, 3, false),
+        0x45 => ins(EOR, ZeroPage, 3, false),
+        0x46 => ins(LSR, ZeroPage, 5, false),
+        0x47 => ins(SRE, ZeroPage, 5, false),
+        0x48 => ins(PHA, Implied, 3, false),
+        0x49 => ins(EOR, Immediate, 2, false),
+        0x4A => ins(LSR, Accumulator, 2, false),
+        0x4B => ins(ALR, Immediate, 2, false),
+        0x4C => ins(JMP, Absolute, 3, false),
+        0x4D => ins(EOR, Absolute, 4, false),
+        0x4E => ins(LSR, Absolute, 6, false),
+        0x4F => ins(SRE, Absolute, 6, false),
+        0x50 => ins(BVC, Relative, 2, false),
+        0x51 => ins(EOR, IndirectIndexedY, 5, true),
+        0x52 => ins(JAM, Implied, 2, false),
+        0x53 => ins(SRE, IndirectIndexedY, 8, false),
+        0x54 => ins(NOP, ZeroPageX, 4, false),
+        0x55 => ins(EOR, ZeroPageX, 4, false),
+        0x56 => ins(LSR, ZeroPageX, 6, false),
+        0x57 => ins(SRE, ZeroPageX, 6, false),
+        0x58 => ins(CLI, Implied, 2, false),
+        0x59 => ins(EOR, AbsoluteY, 4, true),
+        0x5A => ins(NOP, Implied, 2, false),
+        0x5B => ins(SRE, AbsoluteY, 7, false),
+        0x5C => ins(NOP, AbsoluteX, 4, true),
+        0x5D => ins(EOR, AbsoluteX, 4, true),
+        0x5E => ins(LSR, AbsoluteX, 7, false),
+        0x5F => ins(SRE, AbsoluteX, 7, false),
+        0x60 => ins(RTS, Implied, 6, false),
+        0x61 => ins(ADC, IndexedIndirectX, 6, false),
+        0x62 => ins(JAM, Implied, 2, false),
+        0x63 => ins(RRA, IndexedIndirectX, 8, false),
+        0x64 => ins(NOP, ZeroPage, 3, false),
+        0x65 => ins(ADC, ZeroPage, 3, false),
+        0x66 => ins(ROR, ZeroPage, 5, false),
+        0x67 => ins(RRA, ZeroPage, 5, false),
+        0x68 => ins(PLA, Implied, 4, false),
+        0x69 => ins(ADC, Immediate, 2, false),
+        0x6A => ins(ROR, Accumulator, 2, false),
+        0x6B => ins(ARR, Immediate, 2, false),
+        0x6C => ins(JMP, Indirect, 5, false),
+        0x6D => ins(ADC, Absolute, 4, false),
+        0x6E => ins(ROR, Absolute, 6, false),
+        0x6F => ins(RRA, Absolute, 6, false),
+        0x70 => ins(BVS, Relative, 2, false),
+        0x71 => ins(ADC, IndirectIndexedY, 5, true),
+        0x72 => ins(JAM, Implied, 2, false),
+        0x73 => ins(RRA, IndirectIndexedY, 8, false),
+        0x74 => ins(NOP, ZeroPageX, 4, false),
+        0x75 => ins(ADC, ZeroPageX, 4, false),
+        0x76 => ins(ROR, ZeroPageX, 6, false),
+        0x77 => ins(RRA, ZeroPageX, 6, false),
+        0x78 => ins(SEI, Implied, 2, false),
+        0x79 => ins(ADC, AbsoluteY, 4, true),
+        0x7A => ins(NOP, Implied, 2, false),
+        0x7B => ins(RRA, AbsoluteY, 7, false),
+        0x7C => ins(NOP, AbsoluteX, 4, true),
+        0x7D => ins(ADC, AbsoluteX, 4, true),
+        0x7E => ins(ROR, AbsoluteX, 7, false),
+        0x7F => ins(RRA, AbsoluteX, 7, false),
+        0x80 => ins(NOP, Immediate, 2, false),
+        0x81 => ins(STA, IndexedIndirectX, 6, false),
+        0x82 => ins(NOP, Immediate, 2, false),
+        0x83 => ins(SAX, IndexedIndirectX, 6, false),
+        0x84 => ins(STY, ZeroPage, 3, false),
+        0x85 => ins(STA, ZeroPage, 3, false),
+        0x86 => ins(STX, ZeroPage, 3, false),
+        0x87 => ins(SAX, ZeroPage, 3, false),
+        0x88 => ins(DEY, Implied, 2, false),
+        0x89 => ins(NOP, Immediate, 2, false),
+        0x8A => ins(TXA, Implied, 2, false),
+        0x8B => ins(ANE, Immediate, 2, false),
+        0x8C => ins(STY, Absolute, 4, false),
+        0x8D => ins(STA, Absolute, 4, false),
+        0x8E => ins(STX, Absolute, 4, false),
+        0x8F => ins(SAX, Absolute, 4, false),
+        0x90 => ins(BCC, Relative, 2, false),
+        0x91 => ins(STA, IndirectIndexedY, 6, false),
+        0x92 => ins(JAM, Implied, 2, false),
+        0x93 => ins(SHA, IndirectIndexedY, 6, false),
+        0x94 => ins(STY, ZeroPageX, 4, false),
+        0x95 => ins(STA, ZeroPageX, 4, false),
+        0x96 => ins(STX, ZeroPageY, 4, false),
+        0x97 => ins(SAX, ZeroPageY, 4, false),
+        0x98 => ins(TYA, Implied, 2, false),
+        0x99 => ins(STA, AbsoluteY, 5, false),
+        0x9A => ins(TXS, Implied, 2, false),
+        0x9B => ins(TAS, AbsoluteY, 5, false),
+        0x9C => ins(SHY, AbsoluteX, 5, false),
+        0x9D => ins(STA, AbsoluteX, 5, false),
+        0x9E => ins(SHX, AbsoluteY, 5, false),
+        0x9F => ins(SHA, AbsoluteY, 5, false),
+        0xA0 => ins(LDY, Immediate, 2, false),
+        0xA1 => ins(LDA, IndexedIndirectX, 6, false),
+        0xA2 => ins(LDX, Immediate, 2, false),
+        0xA3 => ins(LAX, IndexedIndirectX, 6, false),
+        0xA4 => ins(LDY, ZeroPage, 3, false),
+        0xA5 => ins(LDA, ZeroPage, 3, false),
+        0xA6 => ins(LDX, ZeroPage, 3, false),
+        0xA7 => ins(LAX, ZeroPage, 3, false),
+        0xA8 => ins(TAY, Implied, 2, false),
+        0xA9 => ins(LDA, Immediate, 2, false),
+        0xAA => ins(TAX, Implied, 2, false),
+        0xAB => ins(LXA, Immediate, 2, false),
+        0xAC => ins(LDY, Absolute, 4, false),
+        0xAD => ins(LDA, Absolute, 4, false),
+        0xAE => ins(LDX, Absolute, 4, false),
+        0xAF => ins(LAX, Absolute, 4, false),
+        0xB0 => ins(BCS, Relative, 2, false),
+        0xB1 => ins(LDA, IndirectIndexedY, 5, true),
+        0xB2 => ins(JAM, Implied, 2, false),
+        0xB3 => ins(LAX, IndirectIndexedY, 5, true),
+        0xB4 => ins(LDY, ZeroPageX, 4, false),
+        0xB5 => ins(LDA, ZeroPageX, 4, false),
+        0xB6 => ins(LDX, ZeroPageY, 4, false),
+        0xB7 => ins(LAX, ZeroPageY, 4, false),
+        0xB8 => ins(CLV, Implied, 2, false),
+        0xB9 => ins(LDA, AbsoluteY, 4, true),
+        0xBA => ins(TSX, Implied, 2, false),
+        0xBB => ins(LAS, AbsoluteY, 4, true),
+        0xBC => ins(LDY, AbsoluteX, 4, true),
+        0xBD => ins(LDA, AbsoluteX, 4, true),
+        0xBE => ins(LDX, AbsoluteY, 4, true),
+        0xBF => ins(LAX, AbsoluteY, 4, true),
+        0xC0 => ins(CPY, Immediate, 2, false),
+        0xC1 => ins(CMP, IndexedIndirectX, 6, false),
+        0xC2 => ins(NOP, Immediate, 2, false),
+        0xC3 => ins(DCP, IndexedIndirectX, 8, false),
+        0xC4 => ins(CPY, ZeroPage, 3, false),
+        0xC5 => ins(CMP, ZeroPage, 3, false),
+        0xC6 => ins(DEC, ZeroPage, 5, false),
+        0xC7 => ins(DCP, ZeroPage, 5, false),
+        0xC8 => ins(INY, Implied, 2, false),
+        0xC9 => ins(CMP, Immediate, 2, false),
+        0xCA => ins(DEX, Implied, 2, false),
+        0xCB => ins(SBX, Immediate, 2, false),
+        0xCC => ins(CPY, Absolute, 4, false),
+        0xCD => ins(CMP, Absolute, 4, false),
+        0xCE => ins(DEC, Absolute, 6, false),
+        0xCF => ins(DCP, Absolute, 6, false),
+        0xD0 => ins(BNE, Relative, 2, false),
+        0xD1 => ins(CMP, IndirectIndexedY, 5, true),
+        0xD2 => ins(JAM, Implied, 2, false),
+        0xD3 => ins(DCP, IndirectIndexedY, 8, false),
+        0xD4 => ins(NOP, ZeroPageX, 4, false),
+        0xD5 => ins(CMP, ZeroPageX, 4, false),
+        0xD6 => ins(DEC, ZeroPageX, 6, false),
+        0xD7 => ins(DCP, ZeroPageX, 6, false),
+        0xD8 => ins(CLD, Implied, 2, false),
+        0xD9 => ins(CMP, AbsoluteY, 4, true),
+        0xDA => ins(NOP, Implied, 2, false),
+        0xDB => ins(DCP, AbsoluteY, 7, false),
+        0xDC => ins(NOP, AbsoluteX, 4, true),
+        0xDD => ins(CMP, AbsoluteX, 4, true),
+        0xDE => ins(DEC, AbsoluteX, 7, false),
+        0xDF => ins(DCP, AbsoluteX, 7, false),
+        0xE0 => ins(CPX, Immediate, 2, false),
+        0xE1 => ins(SBC, IndexedIndirectX, 6, false),
+        0xE2 => ins(NOP, Immediate, 2, false),
+        0xE3 => ins(ISC, IndexedIndirectX, 8, false),
+        0xE4 => ins(CPX, ZeroPage, 3, false),
+        0xE5 => ins(SBC, ZeroPage, 3, false),
+        0xE6 => ins(INC, ZeroPage, 5, false),
+        0xE7 => ins(ISC, ZeroPage, 5, false),
+        0xE8 => ins(INX, Implied, 2, false),
+        0xE9 => ins(SBC, Immediate, 2, false),
+        0xEA => ins(NOP, Implied, 2, false),
+        0xEB => ins(SBC, Immediate, 2, false),
+        0xEC => ins(CPX, Absolute, 4, false),
+        0xED => ins(SBC, Absolute, 4, false),
+        0xEE => ins(INC, Absolute, 6, false),
+        0xEF => ins(ISC, Absolute, 6, false),
+        0xF0 => ins(BEQ, Relative, 2, false),
+        0xF1 => ins(SBC, IndirectIndexedY, 5, true),
+        0xF2 => ins(JAM, Implied, 2, false),
+        0xF3 => ins(ISC, IndirectIndexedY, 8, false),
+        0xF4 => ins(NOP, ZeroPageX, 4, false),
+        0xF5 => ins(SBC, ZeroPageX, 4, false),
+        0xF6 => ins(INC, ZeroPageX, 6, false),
+        0xF7 => ins(ISC, ZeroPageX, 6, false),
+        0xF8 => ins(SED, Implied, 2, false),
+        0xF9 => ins(SBC, AbsoluteY, 4, true),
+        0xFA => ins(NOP, Implied, 2, false),
+        0xFB => ins(ISC, AbsoluteY, 7, false),
+        0xFC => ins(NOP, AbsoluteX, 4, true),
+        0xFD => ins(SBC, AbsoluteX, 4, true),
+        0xFE => ins(INC, AbsoluteX, 7, false),
+        0xFF => ins(ISC, AbsoluteX, 7, false),
+    }
+}
@@ -0,0 +1,410 @@
+//! Commodore 1750 RAM Expansion Unit: extra RAM behind a DMA controller at $DF00. While
+//! a transfer runs the REU moves one byte per cycle and holds the CPU off with RDY.
+
+use crate::cpu;
+use crate::memory;
+use crate::snapshot::{StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub type ReuShared = Rc<RefCell<Reu>>;
+
+/// Size of a stock 1750.
+pub const DEFAULT_SIZE: usize = 512 * 1024;
+/// Largest REU the 24-bit address register can reach.
+pub const MAX_SIZE: usize = 1 << 24;
+
+/// Status register ($DF00) bits. The top three are cleared by reading it.
+const STATUS_IRQ: u8 = 1 << 7;
+const STATUS_END_OF_BLOCK: u8 = 1 << 6;
+const STATUS_FAULT: u8 = 1 << 5;
+/// Set on units with 256K chips, i.e. anything bigger than a 1700.
+const STATUS_SIZE: u8 = 1 << 4;
+
+/// Command register ($DF01) bits.
+const COMMAND_EXECUTE: u8 = 1 << 7;
+const COMMAND_AUTOLOAD: u8 = 1 << 5;
+/// Start right away instead of waiting for a write to $FF00.
+const COMMAND_FF00_DISABLED: u8 = 1 << 4;
+const COMMAND_TYPE_MASK: u8 = 0x03;
+
+/// Interrupt mask register ($DF09) bits.
+const IRQ_ENABLE: u8 = 1 << 7;
+const IRQ_END_OF_BLOCK: u8 = 1 << 6;
+const IRQ_FAULT: u8 = 1 << 5;
+
+/// Address control register ($DF0A) bits.
+const FIX_C64_ADDRESS: u8 = 1 << 7;
+const FIX_REU_ADDRESS: u8 = 1 << 6;
+
+/// Writing here starts a transfer armed without `COMMAND_FF00_DISABLED`.
+pub const TRIGGER_ADDRESS: u16 = 0xFF00;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    /// C64 to REU.
+    Stash,
+    /// REU to C64.
+    Fetch,
+    Swap,
+    /// Compare, stopping with a fault at the first difference.
+    Verify,
+}
+
+pub struct Reu {
+    mem_ref: memory::MemShared,
+    cpu_ref: cpu::CPUShared,
+    ram: Vec<u8>,
+    status: u8,
+    command: u8,
+    c64_address: u16,
+    reu_address: u32,
+    length: u16,
+    /// Values last written to the address and length registers, restored after a
+    /// transfer when autoload is on.
+    c64_address_latch: u16,
+    reu_address_latch: u32,
+    length_latch: u16,
+    irq_mask: u8,
+    address_control: u8,
+    /// Waiting for a write to $FF00.
+    armed: bool,
+    active: bool,
+}
+
+impl Reu {
+    /// Creates an REU with `size` bytes of RAM, a power of two up to 16M.
+    pub fn new(mem_ref: memory::MemShared, cpu_ref: cpu::CPUShared, size: usize) -> Reu {
+        assert!(size.is_power_of_two() && size <= MAX_SIZE);
+        let mut reu = Reu {
+            mem_ref,
+            cpu_ref,
+            ram: vec![0; size],
+            status: 0,
+            command: 0,
+            c64_address: 0,
+            reu_address: 0,
+            length: 0,
+            c64_address_latch: 0,
+            reu_address_latch: 0,
+            length_latch: 0,
+            irq_mask: 0,
+            address_control: 0,
+            armed: false,
+            active: false,
+        };
+        reu.reset();
+        reu
+    }
+
+    pub fn new_shared(
+        mem_ref: memory::MemShared,
+        cpu_ref: cpu::CPUShared,
+        size: usize,
+    ) -> ReuShared {
+        Rc::new(RefCell::new(Reu::new(mem_ref, cpu_ref, size)))
+    }
+
+    /// Resets the registers; the RAM keeps its contents.
+    pub fn reset(&mut self) {
+        self.status = if self.ram.len() > 128 * 1024 {
+            STATUS_SIZE
+        } else {
+            0
+        };
+        self.command = COMMAND_FF00_DISABLED;
+        self.c64_address = 0;
+        self.reu_address = 0;
+        self.length = 0xFFFF;
+        self.c64_address_latch = 0;
+        self.reu_address_latch = 0;
+        self.length_latch = 0xFFFF;
+        self.irq_mask = 0;
+        self.address_control = 0;
+        self.armed = false;
+        self.active = false;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.status);
+        w.u8(self.command);
+        w.u16(self.c64_address);
+        w.u32(self.reu_address);
+        w.u16(self.length);
+        w.u16(self.c64_address_latch);
+        w.u32(self.reu_address_latch);
+        w.u16(self.length_latch);
+        w.u8(self.irq_mask);
+        w.u8(self.address_control);
+        w.bool(self.armed);
+        w.bool(self.active);
+    }
+
+    /// Restores a state saved by an REU of the same size.
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.ram)?;
+        self.status = r.u8()?;
+        self.command = r.u8()?;
+        self.c64_address = r.u16()?;
+        self.reu_address = r.u32()?;
+        self.length = r.u16()?;
+        self.c64_address_latch = r.u16()?;
+        self.reu_address_latch = r.u32()?;
+        self.length_latch = r.u16()?;
+        self.irq_mask = r.u8()?;
+        self.address_control = r.u8()?;
+        self.armed = r.bool()?;
+        self.active = r.bool()?;
+        Ok(())
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn transfer(&self) -> Transfer {
+        match self.command & COMMAND_TYPE_MASK {
+            0 => Transfer::Stash,
+            1 => Transfer::Fetch,
+            2 => Transfer::Swap,
+            _ => Transfer::Verify,
+        }
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr & 0x1F {
+            0x00 => {
+                let value = self.status;
+                self.status &= !(STATUS_IRQ | STATUS_END_OF_BLOCK | STATUS_FAULT);
+                value
+            }
+            0x01 => self.command,
+            0x02 => self.c64_address as u8,
+            0x03 => (self.c64_address >> 8) as u8,
+            0x04 => self.reu_address as u8,
+            0x05 => (self.reu_address >> 8) as u8,
+            // Unused bank bits read back high.
+            0x06 => (self.reu_address >> 16) as u8 | 0xF8,
+            0x07 => self.length as u8,
+            0x08 => (self.length >> 8) as u8,
+            0x09 => self.irq_mask | 0x1F,
+            0x0A => self.address_control | 0x3F,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr & 0x1F {
+            0x01 => {
+                self.command = value;
+                if value & COMMAND_EXECUTE != 0 {
+                    if value & COMMAND_FF00_DISABLED != 0 {
+                        self.active = true;
+                    } else {
+                        self.armed = true;
+                    }
+                }
+            }
+            0x02 => {
+                self.c64_address_latch = (self.c64_address_latch & 0xFF00) | value as u16;
+                self.c64_address = self.c64_address_latch;
+            }
+            0x03 => {
+                self.c64_address_latch = (self.c64_address_latch & 0x00FF) | (value as u16) << 8;
+                self.c64_address = self.c64_address_latch;
+            }
+            0x04 => {
+                self.reu_address_latch = (self.reu_address_latch & 0xFF_FF00) | value as u32;
+                self.reu_address = self.reu_address_latch;
+            }
+            0x05 => {
+                self.reu_address_latch = (self.reu_address_latch & 0xFF_00FF) | (value as u32) << 8;
+                self.reu_address = self.reu_address_latch;
+            }
+            0x06 => {
+                self.reu_address_latch =
+                    (self.reu_address_latch & 0x00_FFFF) | (value as u32) << 16;
+                self.reu_address = self.reu_address_latch;
+            }
+            0x07 => {
+                self.length_latch = (self.length_latch & 0xFF00) | value as u16;
+                self.length = self.length_latch;
+            }
+            0x08 => {
+                self.length_latch = (self.length_latch & 0x00FF) | (value as u16) << 8;
+                self.length = self.length_latch;
+            }
+            0x09 => self.irq_mask = value & (IRQ_ENABLE | IRQ_END_OF_BLOCK | IRQ_FAULT),
+            0x0A => self.address_control = value & (FIX_C64_ADDRESS | FIX_REU_ADDRESS),
+            _ => {}
+        }
+    }
+
+    /// Called by the CPU on every write to $FF00, which starts an armed transfer.
+    pub fn on_trigger_write(&mut self) {
+        if self.armed {
+            self.armed = false;
+            self.active = true;
+        }
+    }
+
+    /// Advances the REU by one clock cycle: moves one byte while a transfer runs and
+    /// drives the IRQ line.
+    pub fn update(&mut self) {
+        if self.active {
+            self.cpu_ref.borrow_mut().set_rdy(false);
+            self.transfer_byte();
+        }
+        self.cpu_ref
+            .borrow_mut()
+            .set_irq(cpu::IrqSource::Reu, self.status & STATUS_IRQ != 0);
+    }
+
+    fn transfer_byte(&mut self) {
+        let reu_index = self.reu_address as usize & (self.ram.len() - 1);
+        let mut mem = self.mem_ref.borrow_mut();
+        let mut fault = false;
+        match self.transfer() {
+            Transfer::Stash => self.ram[reu_index] = mem.read_byte(self.c64_address),
+            Transfer::Fetch => mem.write_byte(self.c64_address, self.ram[reu_index]),
+            Transfer::Swap => {
+                let c64 = mem.read_byte(self.c64_address);
+                mem.write_byte(self.c64_address, self.ram[reu_index]);
+                self.ram[reu_index] = c64;
+            }
+            Transfer::Verify => fault = mem.read_byte(self.c64_address) != self.ram[reu_index],
+        }
+        drop(mem);
+
+        if self.address_control & FIX_C64_ADDRESS == 0 {
+            self.c64_address = self.c64_address.wrapping_add(1);
+        }
+        if self.address_control & FIX_REU_ADDRESS == 0 {
+            self.reu_address = (self.reu_address + 1) & 0xFF_FFFF;
+        }
+        if fault {
+            self.status |= STATUS_FAULT;
+            self.finish();
+        } else if self.length == 1 {
+            self.status |= STATUS_END_OF_BLOCK;
+            self.finish();
+        } else {
+            self.length = self.length.wrapping_sub(1);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.active = false;
+        self.command = (self.command & !COMMAND_EXECUTE) | COMMAND_FF00_DISABLED;
+        if self.command & COMMAND_AUTOLOAD != 0 {
+            self.c64_address = self.c64_address_latch;
+            self.reu_address = self.reu_address_latch;
+            self.length = self.length_latch;
+        }
+        let raised = (self.status & STATUS_END_OF_BLOCK != 0
+            && self.irq_mask & IRQ_END_OF_BLOCK != 0)
+            || (self.status & STATUS_FAULT != 0 && self.irq_mask & IRQ_FAULT != 0);
+        if raised && self.irq_mask & IRQ_ENABLE != 0 {
+            self.status |= STATUS_IRQ;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    const BASE: u16 = 0xDF00;
+
+    fn new_reu() -> (Reu, memory::MemShared, cpu::CPUShared) {
+        let mem = Memory::new_shared();
+        let cpu = cpu::CPU::new_shared(mem.clone());
+        (Reu::new(mem.clone(), cpu.clone(), DEFAULT_SIZE), mem, cpu)
+    }
+
+    /// Programs a transfer between $C000 and REU bank 1 and runs it to the end.
+    fn run_transfer(reu: &mut Reu, command: u8, len: u16) -> u32 {
+        for (reg, value) in [
+            (0x02, 0x00),
+            (0x03, 0xC0),
+            (0x04, 0x00),
+            (0x05, 0x00),
+            (0x06, 0x01),
+            (0x07, len as u8),
+            (0x08, (len >> 8) as u8),
+        ] {
+            reu.write_register(BASE + reg, value);
+        }
+        reu.write_register(BASE + 1, command);
+        let mut cycles = 0;
+        while reu.is_active() {
+            reu.update();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn stash_and_fetch_round_trip() {
+        let (mut reu, mem, cpu) = new_reu();
+        let data: Vec<u8> = (0..=255).collect();
+        mem.borrow_mut().load(0xC000, &data);
+
+        assert_eq!(run_transfer(&mut reu, 0x90, 256), 256);
+        assert!(!cpu.borrow().rdy_line());
+        assert_eq!(&reu.ram()[0x10000..0x10100], &data[..]);
+        assert_eq!(
+            reu.read_register(BASE) & STATUS_END_OF_BLOCK,
+            STATUS_END_OF_BLOCK
+        );
+        assert_eq!(reu.read_register(BASE) & STATUS_END_OF_BLOCK, 0);
+
+        mem.borrow_mut().load(0xC000, &[0; 256]);
+        run_transfer(&mut reu, 0x91, 256);
+        let fetched: Vec<u8> = (0..256)
+            .map(|i| mem.borrow().read_byte(0xC000 + i))
+            .collect();
+        assert_eq!(fetched, data);
+
+        // Verify passes on identical data and faults on the first difference.
+        run_transfer(&mut reu, 0x93, 256);
+        assert_eq!(reu.read_register(BASE) & STATUS_FAULT, 0);
+        mem.borrow_mut().write_byte(0xC010, 0xEE);
+        assert_eq!(run_transfer(&mut reu, 0x93, 256), 0x11);
+        assert_eq!(reu.read_register(BASE) & STATUS_FAULT, STATUS_FAULT);
+    }
+
+    #[test]
+    fn armed_transfer_waits_for_ff00_and_raises_irq() {
+        let (mut reu, mem, cpu) = new_reu();
+        mem.borrow_mut().write_byte(0xC000, 0x42);
+        reu.write_register(BASE + 0x09, IRQ_ENABLE | IRQ_END_OF_BLOCK);
+        reu.write_register(BASE + 0x07, 1);
+        reu.write_register(BASE + 0x08, 0);
+        reu.write_register(BASE + 0x03, 0xC0);
+        reu.write_register(BASE + 1, COMMAND_EXECUTE | COMMAND_AUTOLOAD);
+        reu.update();
+        assert_eq!(reu.ram()[0], 0);
+
+        reu.on_trigger_write();
+        reu.update();
+        assert_eq!(reu.ram()[0], 0x42);
+        assert!(cpu.borrow().irq_line());
+        // Autoload put the registers back for the next transfer.
+        assert_eq!(reu.read_register(BASE + 0x03), 0xC0);
+
+        assert_ne!(reu.read_register(BASE) & STATUS_IRQ, 0);
+        reu.update();
+        assert!(!cpu.borrow().irq_line());
+    }
+}
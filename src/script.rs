@@ -0,0 +1,146 @@
+//! Line-based scripts for driving a machine unattended, e.g. to record demos.
+//!
+//! One command per line; blank lines and lines starting with `#` are skipped:
+//!
+//! - `load FILE`: copies a PRG into memory without running it.
+//! - `wait N`: runs N frames.
+//!
+//! `wait` and `key` fail if the machine stops before its frames are up, at a breakpoint,
+//! a halt or the end of an instruction limit.
+//! - `key NAME`: taps a C64 key, named as for `io::matrix_key_by_name`, and runs the
+//!   frames it is held for.
+//! - `type TEXT`: queues the rest of the line to be typed, like `ZPC::paste_text`.
+//! - `screenshot FILE`: saves the current frame as a PNG.
+//! - `reset`: cold reset.
+
+use crate::display::Display;
+use crate::io;
+use crate::zpc::{ResetKind, RunOutcome, RunUntil, ZPC};
+
+/// Frames a `key` command holds its key down, long enough for the KERNAL scan to see it.
+const KEY_HOLD_FRAMES: u64 = 2;
+
+/// Runs each line of `script`, stopping at the first one that fails.
+pub fn run<D: Display>(zpc: &mut ZPC<D>, script: &str) -> Result<(), String> {
+    for (index, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        run_command(zpc, line).map_err(|e| format!("line {}: {}", index + 1, e))?;
+    }
+    Ok(())
+}
+
+fn run_command<D: Display>(zpc: &mut ZPC<D>, line: &str) -> Result<(), String> {
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    match command {
+        "load" => {
+            let data = crate::utils::open_file(arg, 0).map_err(|e| format!("{}: {}", arg, e))?;
+            zpc.load_prg_data(&data)
+                .map_err(|e| format!("{}: {}", arg, e))?;
+        }
+        "wait" => {
+            let frames = arg
+                .parse()
+                .map_err(|_| format!("bad frame count '{}'", arg))?;
+            run_frames(zpc, frames)?;
+        }
+        "key" => {
+            let pos =
+                io::matrix_key_by_name(arg).ok_or_else(|| format!("unknown key '{}'", arg))?;
+            zpc.type_matrix(vec![
+                io::KeyEvent {
+                    frame: 0,
+                    pos,
+                    pressed: true,
+                },
+                io::KeyEvent {
+                    frame: KEY_HOLD_FRAMES,
+                    pos,
+                    pressed: false,
+                },
+            ]);
+            run_frames(zpc, KEY_HOLD_FRAMES + 1)?;
+        }
+        "type" => zpc.paste_text(arg),
+        "screenshot" => zpc
+            .save_screenshot(arg)
+            .map_err(|e| format!("{}: {}", arg, e))?,
+        "reset" => zpc.reset(ResetKind::Cold),
+        _ => return Err(format!("unknown command '{}'", command)),
+    }
+    Ok(())
+}
+
+fn run_frames<D: Display>(zpc: &mut ZPC<D>, frames: u64) -> Result<(), String> {
+    let cycles = frames
+        .checked_mul(zpc.region.cycles_per_frame() as u64)
+        .ok_or_else(|| format!("bad frame count '{}'", frames))?;
+    let reason = match zpc.run_until(RunUntil::Cycles(cycles), cycles) {
+        RunOutcome::CyclesElapsed | RunOutcome::ReachedPc => return Ok(()),
+        RunOutcome::Breakpoint => "stopped at a breakpoint",
+        RunOutcome::Halted => "CPU halted",
+        RunOutcome::InstructionLimit => "instruction limit reached",
+    };
+    Err(format!("{} at ${:04X}", reason, zpc.cpu_ref.borrow().pc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::HeadlessDisplay;
+    use crate::utils::Region;
+
+    #[test]
+    fn script_loads_a_prg_and_waits() {
+        let path = std::env::temp_dir().join(format!("zpc-script-{}.prg", std::process::id()));
+        std::fs::write(&path, [0x00, 0xC0, 0xA9, 0x07]).unwrap();
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let frame = zpc.vic_ref.borrow().frame_count;
+
+        let script = format!("# demo\nload {}\n\nwait 3\nkey RETURN\n", path.display());
+        let result = run(&mut zpc, &script);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(zpc.mem_ref.borrow().dump_range(0xC000, 2), [0xA9, 0x07]);
+        assert_eq!(zpc.vic_ref.borrow().frame_count, frame + 6);
+
+        assert_eq!(
+            run(&mut zpc, "wait 1\njump $C000"),
+            Err(String::from("line 2: unknown command 'jump'"))
+        );
+        assert_eq!(
+            run(&mut zpc, "key ENTER"),
+            Err(String::from("line 1: unknown key 'ENTER'"))
+        );
+        assert_eq!(
+            run(&mut zpc, &format!("wait {}", u64::MAX)),
+            Err(format!("line 1: bad frame count '{}'", u64::MAX))
+        );
+    }
+
+    #[test]
+    fn script_fails_when_the_machine_stops() {
+        // NOP; JAM
+        let mut zpc = ZPC::new_bare(&[0xEA, 0x02], 0xC000);
+        assert_eq!(
+            run(
+                &mut zpc,
+                "# halts
+wait 1
+wait 1"
+            ),
+            Err(String::from("line 2: CPU halted at $C001"))
+        );
+
+        // NOP; JMP $C000
+        let mut zpc = ZPC::new_bare(&[0xEA, 0x4C, 0x00, 0xC0], 0xC000);
+        zpc.cpu_ref.borrow_mut().add_breakpoint(0xC001);
+        assert_eq!(
+            run(&mut zpc, "key SPACE"),
+            Err(String::from("line 1: stopped at a breakpoint at $C001"))
+        );
+    }
+}
@@ -0,0 +1,410 @@
+//! MOS 6581 Sound Interface Device: three oscillators with linear envelopes, mixed
+//! into signed samples on demand. The filter is not emulated.
+
+use crate::snapshot::{StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub type SIDShared = Rc<RefCell<SID>>;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// Voice control register bits.
+const CTRL_GATE: u8 = 1 << 0;
+const CTRL_SYNC: u8 = 1 << 1;
+const CTRL_RING: u8 = 1 << 2;
+const CTRL_TEST: u8 = 1 << 3;
+const CTRL_TRIANGLE: u8 = 1 << 4;
+const CTRL_SAWTOOTH: u8 = 1 << 5;
+const CTRL_PULSE: u8 = 1 << 6;
+const CTRL_NOISE: u8 = 1 << 7;
+
+const ACCUMULATOR_MASK: u32 = 0x00FF_FFFF;
+const NOISE_SEED: u32 = 0x7F_FFF8;
+
+/// Cycles between envelope steps for each attack/decay/release nibble. Decay and
+/// release use the same table but the real chip curves them exponentially.
+const RATE_PERIODS: [u16; 16] = [
+    9, 32, 63, 95, 149, 220, 267, 313, 392, 977, 1954, 3126, 3907, 11720, 19532, 31251,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EnvelopeState {
+    Attack,
+    DecaySustain,
+    Release,
+}
+
+struct Voice {
+    frequency: u16,
+    pulse_width: u16,
+    control: u8,
+    attack_decay: u8,
+    sustain_release: u8,
+    /// 24-bit phase accumulator.
+    accumulator: u32,
+    /// 23-bit noise LFSR, clocked when accumulator bit 19 rises.
+    noise: u32,
+    envelope: u8,
+    envelope_state: EnvelopeState,
+    rate_counter: u16,
+}
+
+impl Voice {
+    fn new() -> Voice {
+        Voice {
+            frequency: 0,
+            pulse_width: 0,
+            control: 0,
+            attack_decay: 0,
+            sustain_release: 0,
+            accumulator: 0,
+            noise: NOISE_SEED,
+            envelope: 0,
+            envelope_state: EnvelopeState::Release,
+            rate_counter: 0,
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let gate_on = value & CTRL_GATE != 0 && self.control & CTRL_GATE == 0;
+        let gate_off = value & CTRL_GATE == 0 && self.control & CTRL_GATE != 0;
+        if gate_on {
+            self.envelope_state = EnvelopeState::Attack;
+        } else if gate_off {
+            self.envelope_state = EnvelopeState::Release;
+        }
+        if value & CTRL_TEST != 0 {
+            self.accumulator = 0;
+            self.noise = NOISE_SEED;
+        }
+        self.control = value;
+    }
+
+    /// Advances the oscillator by one cycle. Returns true when the accumulator MSB
+    /// rose, which hard-syncs the next voice.
+    fn clock_oscillator(&mut self) -> bool {
+        if self.control & CTRL_TEST != 0 {
+            return false;
+        }
+        let prev = self.accumulator;
+        self.accumulator = (self.accumulator + self.frequency as u32) & ACCUMULATOR_MASK;
+        if prev & 0x08_0000 == 0 && self.accumulator & 0x08_0000 != 0 {
+            let bit = ((self.noise >> 22) ^ (self.noise >> 17)) & 1;
+            self.noise = ((self.noise << 1) | bit) & 0x7F_FFFF;
+        }
+        prev & 0x80_0000 == 0 && self.accumulator & 0x80_0000 != 0
+    }
+
+    fn clock_envelope(&mut self) {
+        let rate = match self.envelope_state {
+            EnvelopeState::Attack => self.attack_decay >> 4,
+            EnvelopeState::DecaySustain => self.attack_decay & 0x0F,
+            EnvelopeState::Release => self.sustain_release & 0x0F,
+        };
+        self.rate_counter += 1;
+        if self.rate_counter < RATE_PERIODS[rate as usize] {
+            return;
+        }
+        self.rate_counter = 0;
+
+        match self.envelope_state {
+            EnvelopeState::Attack => {
+                self.envelope = self.envelope.saturating_add(1);
+                if self.envelope == 0xFF {
+                    self.envelope_state = EnvelopeState::DecaySustain;
+                }
+            }
+            EnvelopeState::DecaySustain => {
+                let sustain = (self.sustain_release >> 4) * 0x11;
+                if self.envelope > sustain {
+                    self.envelope -= 1;
+                }
+            }
+            EnvelopeState::Release => self.envelope = self.envelope.saturating_sub(1),
+        }
+    }
+
+    /// 12-bit waveform output. `ring_msb` is the previous voice's accumulator MSB.
+    /// Combined waveforms are approximated by ANDing the selected ones together.
+    fn waveform(&self, ring_msb: bool) -> u16 {
+        let mut output = 0x0FFF;
+        let mut selected = false;
+        if self.control & CTRL_TRIANGLE != 0 {
+            let mut msb = self.accumulator & 0x80_0000 != 0;
+            if self.control & CTRL_RING != 0 {
+                msb ^= ring_msb;
+            }
+            let folded = if msb {
+                !self.accumulator & 0x7F_FFFF
+            } else {
+                self.accumulator & 0x7F_FFFF
+            };
+            output &= (folded >> 11) as u16;
+            selected = true;
+        }
+        if self.control & CTRL_SAWTOOTH != 0 {
+            output &= (self.accumulator >> 12) as u16;
+            selected = true;
+        }
+        if self.control & CTRL_PULSE != 0 {
+            let high = self.control & CTRL_TEST != 0
+                || (self.accumulator >> 12) as u16 >= self.pulse_width;
+            output &= if high { 0x0FFF } else { 0 };
+            selected = true;
+        }
+        if self.control & CTRL_NOISE != 0 {
+            let n = self.noise;
+            let bits = ((n >> 20) & 1) << 11
+                | ((n >> 18) & 1) << 10
+                | ((n >> 14) & 1) << 9
+                | ((n >> 11) & 1) << 8
+                | ((n >> 9) & 1) << 7
+                | ((n >> 5) & 1) << 6
+                | ((n >> 2) & 1) << 5
+                | (n & 1) << 4;
+            output &= bits as u16;
+            selected = true;
+        }
+        if selected {
+            output
+        } else {
+            0
+        }
+    }
+
+    /// Waveform centred on zero and scaled by the envelope, roughly ±2048.
+    fn output(&self, ring_msb: bool) -> i32 {
+        (self.waveform(ring_msb) as i32 - 0x800) * self.envelope as i32 / 0xFF
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.frequency);
+        w.u16(self.pulse_width);
+        w.u8(self.control);
+        w.u8(self.attack_decay);
+        w.u8(self.sustain_release);
+        w.u32(self.accumulator);
+        w.u32(self.noise);
+        w.u8(self.envelope);
+        w.u8(self.envelope_state as u8);
+        w.u16(self.rate_counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.frequency = r.u16()?;
+        self.pulse_width = r.u16()?;
+        self.control = r.u8()?;
+        self.attack_decay = r.u8()?;
+        self.sustain_release = r.u8()?;
+        self.accumulator = r.u32()? & ACCUMULATOR_MASK;
+        self.noise = r.u32()? & 0x7F_FFFF;
+        self.envelope = r.u8()?;
+        self.envelope_state = match r.u8()? {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::DecaySustain,
+            2 => EnvelopeState::Release,
+            state => return Err(format!("invalid SID envelope state {}", state)),
+        };
+        self.rate_counter = r.u16()?;
+        Ok(())
+    }
+}
+
+pub struct SID {
+    voices: [Voice; 3],
+    /// Low nibble of $D418.
+    volume: u8,
+    /// Filter registers are stored but have no effect on the output.
+    filter_regs: [u8; 4],
+    clock_freq: u32,
+    sample_rate: u32,
+    /// Fractional cycles carried over between samples, in units of 1/sample_rate.
+    cycle_remainder: u32,
+}
+
+impl SID {
+    pub fn new(clock_freq: u32, sample_rate: u32) -> SID {
+        SID {
+            voices: [Voice::new(), Voice::new(), Voice::new()],
+            volume: 0,
+            filter_regs: [0; 4],
+            clock_freq,
+            sample_rate,
+            cycle_remainder: 0,
+        }
+    }
+
+    pub fn new_shared(clock_freq: u32, sample_rate: u32) -> SIDShared {
+        Rc::new(RefCell::new(SID::new(clock_freq, sample_rate)))
+    }
+
+    pub fn reset(&mut self) {
+        *self = SID::new(self.clock_freq, self.sample_rate);
+    }
+
+    pub fn set_clock_freq(&mut self, clock_freq: u32) {
+        self.clock_freq = clock_freq;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.cycle_remainder = 0;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr & 0x1F {
+            0x1B => {
+                (self.voices[2].waveform(self.voices[1].accumulator & 0x80_0000 != 0) >> 4) as u8
+            }
+            0x1C => self.voices[2].envelope,
+            // Paddles are not connected and the write-only registers read back as zero.
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        let reg = (addr & 0x1F) as usize;
+        if reg < 0x15 {
+            let voice = &mut self.voices[reg / 7];
+            match reg % 7 {
+                0 => voice.frequency = (voice.frequency & 0xFF00) | value as u16,
+                1 => voice.frequency = (voice.frequency & 0x00FF) | (value as u16) << 8,
+                2 => voice.pulse_width = (voice.pulse_width & 0x0F00) | value as u16,
+                3 => {
+                    voice.pulse_width = (voice.pulse_width & 0x00FF) | ((value & 0x0F) as u16) << 8
+                }
+                4 => voice.write_control(value),
+                5 => voice.attack_decay = value,
+                _ => voice.sustain_release = value,
+            }
+            return;
+        }
+        match reg {
+            0x15..=0x17 => self.filter_regs[reg - 0x15] = value,
+            0x18 => {
+                self.filter_regs[3] = value & 0xF0;
+                self.volume = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        let mut msb_rose = [false; 3];
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            msb_rose[i] = voice.clock_oscillator();
+            voice.clock_envelope();
+        }
+        for i in 0..3 {
+            let source = (i + 2) % 3;
+            if msb_rose[source] && self.voices[i].control & CTRL_SYNC != 0 {
+                self.voices[i].accumulator = 0;
+            }
+        }
+    }
+
+    fn mix(&self) -> i16 {
+        let sum: i32 = (0..3)
+            .map(|i| {
+                let source = &self.voices[(i + 2) % 3];
+                self.voices[i].output(source.accumulator & 0x80_0000 != 0)
+            })
+            .sum();
+        (sum * self.volume as i32 * 5 / 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+
+    /// Fills `samples` with mono output at the configured sample rate, running the chip
+    /// for as many cycles as that much audio spans.
+    pub fn render(&mut self, samples: &mut [i16]) {
+        for sample in samples.iter_mut() {
+            self.cycle_remainder += self.clock_freq;
+            let cycles = self.cycle_remainder / self.sample_rate;
+            self.cycle_remainder %= self.sample_rate;
+            for _ in 0..cycles {
+                self.clock();
+            }
+            *sample = self.mix();
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for voice in self.voices.iter() {
+            voice.save_state(w);
+        }
+        w.u8(self.volume);
+        for &reg in self.filter_regs.iter() {
+            w.u8(reg);
+        }
+        w.u32(self.cycle_remainder);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for voice in self.voices.iter_mut() {
+            voice.load_state(r)?;
+        }
+        self.volume = r.u8()?;
+        for reg in self.filter_regs.iter_mut() {
+            *reg = r.u8()?;
+        }
+        self.cycle_remainder = r.u32()? % self.sample_rate;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Region;
+
+    const BASE: u16 = 0xD400;
+
+    fn program_voice1(sid: &mut SID, frequency: u16, control: u8) {
+        sid.write_register(BASE, frequency as u8);
+        sid.write_register(BASE + 1, (frequency >> 8) as u8);
+        sid.write_register(BASE + 5, 0x00);
+        sid.write_register(BASE + 6, 0xF0);
+        sid.write_register(BASE + 0x18, 0x0F);
+        sid.write_register(BASE + 4, control);
+    }
+
+    #[test]
+    fn triangle_oscillates_at_programmed_frequency() {
+        let clock = Region::Pal.clock_freq();
+        let mut sid = SID::new(clock, DEFAULT_SAMPLE_RATE);
+        let hz = 440;
+        let frequency = ((hz as u64) << 24) / clock as u64;
+        program_voice1(&mut sid, frequency as u16, CTRL_TRIANGLE | CTRL_GATE);
+
+        // Let the attack finish before measuring.
+        let mut samples = vec![0; DEFAULT_SAMPLE_RATE as usize / 10];
+        sid.render(&mut samples);
+        assert_eq!(sid.read_register(BASE + 0x1C), 0);
+        assert_eq!(sid.voices[0].envelope, 0xFF);
+
+        let mut samples = vec![0; DEFAULT_SAMPLE_RATE as usize];
+        sid.render(&mut samples);
+        let rising = samples.windows(2).filter(|w| w[0] < 0 && w[1] >= 0).count();
+        assert!((hz - 2..=hz + 2).contains(&rising), "{} crossings", rising);
+        let peak = samples.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!(peak > 10_000);
+    }
+
+    #[test]
+    fn gate_off_releases_to_silence() {
+        let mut sid = SID::new(Region::Pal.clock_freq(), DEFAULT_SAMPLE_RATE);
+        program_voice1(&mut sid, 0x1000, CTRL_SAWTOOTH | CTRL_GATE);
+        let mut samples = vec![0; 4410];
+        sid.render(&mut samples);
+        assert!(samples.iter().any(|&s| s != 0));
+
+        sid.write_register(BASE + 4, CTRL_SAWTOOTH);
+        sid.render(&mut samples);
+        assert_eq!(sid.voices[0].envelope, 0);
+        assert!(samples[samples.len() - 100..].iter().all(|&s| s == 0));
+    }
+}
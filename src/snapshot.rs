@@ -0,0 +1,155 @@
+//! Binary machine snapshots: a magic header and version byte followed by one
+//! length-prefixed section per component.
+
+/// Identifies a snapshot blob.
+pub const MAGIC: &[u8; 4] = b"ZPCS";
+/// Bumped whenever the layout of any section changes; older blobs are rejected.
+pub const VERSION: u8 = 10;
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length-prefixed byte string.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    /// Writes whatever `f` produces as one length-prefixed section.
+    pub fn section(&mut self, f: impl FnOnce(&mut StateWriter)) {
+        let mut section = StateWriter::new();
+        f(&mut section);
+        self.bytes(&section.data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(String::from("snapshot is truncated"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a byte string that must be exactly `dest.len()` long into `dest`.
+    pub fn bytes_into(&mut self, dest: &mut [u8]) -> Result<(), String> {
+        let bytes = self.bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(format!(
+                "snapshot block is {} bytes, expected {}",
+                bytes.len(),
+                dest.len()
+            ));
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Reads one section written by `StateWriter::section` and hands it to `f`, which
+    /// must consume all of it.
+    pub fn section(
+        &mut self,
+        f: impl FnOnce(&mut StateReader<'a>) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut section = StateReader::new(self.bytes()?);
+        f(&mut section)?;
+        if section.pos != section.data.len() {
+            return Err(String::from("snapshot section has trailing data"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.section(|w| {
+            w.u16(0xBEEF);
+            w.bool(true);
+        });
+        writer.u64(42);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        reader
+            .section(|r| {
+                assert_eq!(r.u16()?, 0xBEEF);
+                assert!(r.bool()?);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(reader.u64(), Ok(42));
+        assert!(reader.u8().is_err());
+    }
+}
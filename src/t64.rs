@@ -0,0 +1,149 @@
+//! T64 tape archives: a header, a directory of entries, and the entries' program data.
+
+use crate::utils;
+
+const HEADER_SIZE: usize = 0x40;
+const DIR_ENTRY_SIZE: usize = 0x20;
+/// Every T64 signature starts this way ("C64S tape file", "C64 tape image file", ...).
+const SIGNATURE_PREFIX: &[u8] = b"C64";
+/// Directory entry type of a normal tape file; 0 marks an unused slot.
+const ENTRY_NORMAL: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct T64Entry {
+    /// Name with trailing padding removed.
+    pub name: Vec<u8>,
+    pub load_address: u16,
+    /// Program bytes without a load address.
+    pub data: Vec<u8>,
+}
+
+impl T64Entry {
+    /// The entry as PRG data: the load address followed by the program.
+    pub fn to_prg(&self) -> Vec<u8> {
+        let mut prg = self.load_address.to_le_bytes().to_vec();
+        prg.extend_from_slice(&self.data);
+        prg
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct T64 {
+    pub name: Vec<u8>,
+    pub entries: Vec<T64Entry>,
+}
+
+impl T64 {
+    pub fn from_filename(filename: &str) -> Result<T64, String> {
+        let data = utils::open_file(filename, 0).map_err(|e| format!("{}: {}", filename, e))?;
+        T64::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<T64, String> {
+        if data.len() < HEADER_SIZE || !data.starts_with(SIGNATURE_PREFIX) {
+            return Err(String::from("not a T64 archive"));
+        }
+        let max_entries = u16::from_le_bytes([data[0x22], data[0x23]]) as usize;
+        let name = trim_name(&data[0x28..0x40]);
+        let dir_end = HEADER_SIZE + max_entries * DIR_ENTRY_SIZE;
+        if data.len() < dir_end {
+            return Err(String::from("T64 directory is truncated"));
+        }
+
+        let mut raw_entries: Vec<(&[u8], usize)> = data[HEADER_SIZE..dir_end]
+            .chunks(DIR_ENTRY_SIZE)
+            .filter(|raw| raw[0] == ENTRY_NORMAL)
+            .map(|raw| {
+                (
+                    raw,
+                    u32::from_le_bytes(raw[8..12].try_into().unwrap()) as usize,
+                )
+            })
+            .collect();
+        // Data sizes come from the gap to the next entry's data, so work in file order.
+        raw_entries.sort_by_key(|&(_, offset)| offset);
+
+        let mut entries = Vec::new();
+        for (i, &(raw, offset)) in raw_entries.iter().enumerate() {
+            let next = raw_entries
+                .get(i + 1)
+                .map_or(data.len(), |&(_, next)| next.min(data.len()));
+            if offset > next {
+                return Err(format!("T64 entry data at ${:X} is past the end", offset));
+            }
+            let load_address = u16::from_le_bytes([raw[2], raw[3]]);
+            let end_address = u16::from_le_bytes([raw[4], raw[5]]);
+            // Many archivers wrote a bogus end address; trust it only when the data
+            // it describes is actually there.
+            let available = next - offset;
+            let declared = end_address.wrapping_sub(load_address) as usize;
+            let len = if declared > 0 && declared <= available {
+                declared
+            } else {
+                available
+            };
+            entries.push(T64Entry {
+                name: trim_name(&raw[0x10..0x20]),
+                load_address,
+                data: data[offset..offset + len].to_vec(),
+            });
+        }
+        Ok(T64 { name, entries })
+    }
+}
+
+fn trim_name(raw: &[u8]) -> Vec<u8> {
+    let len = raw
+        .iter()
+        .rposition(|&b| b != b' ' && b != 0xA0 && b != 0)
+        .map_or(0, |i| i + 1);
+    raw[..len].to_vec()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An archive with one entry, "DEMO", whose header claims `end_address`.
+    pub(crate) fn sample_t64(end_address: u16, data: &[u8]) -> Vec<u8> {
+        let mut image = vec![0x20; HEADER_SIZE + DIR_ENTRY_SIZE];
+        image[..19].copy_from_slice(b"C64 tape image file");
+        image[0x20..0x28].copy_from_slice(&[0x00, 0x01, 1, 0, 1, 0, 0, 0]);
+        image[0x28..0x2C].copy_from_slice(b"TEST");
+
+        let entry = &mut image[HEADER_SIZE..];
+        entry[..0x10].fill(0);
+        entry[0] = ENTRY_NORMAL;
+        entry[1] = 0x82;
+        entry[2..4].copy_from_slice(&0xC000u16.to_le_bytes());
+        entry[4..6].copy_from_slice(&end_address.to_le_bytes());
+        entry[8..12].copy_from_slice(&((HEADER_SIZE + DIR_ENTRY_SIZE) as u32).to_le_bytes());
+        entry[0x10..0x14].copy_from_slice(b"DEMO");
+        image.extend_from_slice(data);
+        image
+    }
+
+    #[test]
+    fn parse_reads_the_directory() {
+        let t64 = T64::parse(&sample_t64(0xC003, &[1, 2, 3])).unwrap();
+        assert_eq!(t64.name, b"TEST");
+        assert_eq!(t64.entries.len(), 1);
+        assert_eq!(t64.entries[0].name, b"DEMO");
+        assert_eq!(t64.entries[0].to_prg(), [0x00, 0xC0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn bogus_end_address_is_recomputed_from_the_data() {
+        let t64 = T64::parse(&sample_t64(0xC3C6, &[1, 2, 3])).unwrap();
+        assert_eq!(t64.entries[0].data, [1, 2, 3]);
+
+        // A correct end address keeps trailing padding out of the program.
+        let t64 = T64::parse(&sample_t64(0xC002, &[1, 2, 0, 0])).unwrap();
+        assert_eq!(t64.entries[0].data, [1, 2]);
+    }
+
+    #[test]
+    fn parse_rejects_other_files() {
+        assert!(T64::parse(&[0; 0x40]).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Datasette playing `.tap` images. Each pulse pulls the read line low once, which
+//! reaches the KERNAL as a FLAG interrupt on CIA1.
+
+use crate::cia::CIA;
+use crate::memory::Memory;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils;
+
+const TAP_MAGIC: &[u8; 12] = b"C64-TAPE-RAW";
+const TAP_HEADER_SIZE: usize = 20;
+/// TAP pulse bytes count units of 8 cycles.
+const TAP_CYCLES_PER_UNIT: u32 = 8;
+/// Version 0 marks overlong pulses with a zero byte but doesn't say how long they
+/// were.
+const TAP_V0_OVERFLOW_CYCLES: u32 = 256 * TAP_CYCLES_PER_UNIT;
+
+/// Processor port bit driving the datasette motor, active low.
+const PORT_CASSETTE_MOTOR: u8 = 1 << 5;
+
+/// A parsed tape image: the cycle length of every pulse, in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tap {
+    pub version: u8,
+    pub pulses: Vec<u32>,
+}
+
+impl Tap {
+    pub fn from_filename(filename: &str) -> Result<Tap, String> {
+        let data = utils::open_file(filename, 0).map_err(|e| format!("{}: {}", filename, e))?;
+        Tap::parse(&data).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Tap, String> {
+        if data.len() < TAP_HEADER_SIZE || &data[..12] != TAP_MAGIC {
+            return Err(String::from("not a TAP image"));
+        }
+        let version = data[12];
+        if version > 1 {
+            return Err(format!("unsupported TAP version {}", version));
+        }
+        let len = u32::from_le_bytes(data[16..20].try_into().unwrap()) as usize;
+        let body = &data[TAP_HEADER_SIZE..];
+        if body.len() < len {
+            return Err(String::from("TAP image is truncated"));
+        }
+
+        let mut pulses = Vec::new();
+        let mut bytes = body[..len].iter();
+        while let Some(&byte) = bytes.next() {
+            let cycles = match byte {
+                0 if version == 0 => TAP_V0_OVERFLOW_CYCLES,
+                0 => {
+                    let mut long = [0; 4];
+                    for b in long.iter_mut().take(3) {
+                        *b = *bytes.next().ok_or("TAP image ends inside a long pulse")?;
+                    }
+                    u32::from_le_bytes(long)
+                }
+                _ => byte as u32 * TAP_CYCLES_PER_UNIT,
+            };
+            pulses.push(cycles);
+        }
+        Ok(Tap { version, pulses })
+    }
+}
+
+/// The tape deck. While PLAY is down it holds the cassette sense line low, and while
+/// the KERNAL runs the motor it plays pulses into CIA1's FLAG input.
+#[derive(Default)]
+pub struct Datasette {
+    tap: Option<Tap>,
+    /// Index of the pulse being played.
+    position: usize,
+    /// Cycles until the current pulse ends.
+    cycles_left: u32,
+    playing: bool,
+}
+
+impl Datasette {
+    pub fn new() -> Datasette {
+        Datasette::default()
+    }
+
+    /// Inserts a tape, rewound to the start.
+    pub fn insert(&mut self, tap: Tap) {
+        self.tap = Some(tap);
+        self.rewind();
+    }
+
+    pub fn eject(&mut self) -> Option<Tap> {
+        self.playing = false;
+        self.tap.take()
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0;
+        self.cycles_left = self.current_pulse();
+    }
+
+    pub fn press_play(&mut self) {
+        self.playing = self.tap.is_some();
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Writes the tape, if any, and how far it has played.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.tap.is_some());
+        if let Some(tap) = &self.tap {
+            w.u8(tap.version);
+            w.u32(tap.pulses.len() as u32);
+            for &pulse in &tap.pulses {
+                w.u32(pulse);
+            }
+        }
+        w.u32(self.position as u32);
+        w.u32(self.cycles_left);
+        w.bool(self.playing);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.tap = if r.bool()? {
+            let version = r.u8()?;
+            let len = r.u32()?;
+            let pulses = (0..len).map(|_| r.u32()).collect::<Result<_, _>>()?;
+            Some(Tap { version, pulses })
+        } else {
+            None
+        };
+        self.position = r.u32()? as usize;
+        self.cycles_left = r.u32()?;
+        self.playing = r.bool()?;
+        Ok(())
+    }
+
+    fn current_pulse(&self) -> u32 {
+        self.tap
+            .as_ref()
+            .and_then(|tap| tap.pulses.get(self.position))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Advances the deck by one cycle, reading the motor line from the processor port
+    /// and signalling pulse ends on CIA1's FLAG. The tape stops by itself at its end.
+    pub fn update(&mut self, mem: &mut Memory, cia1: &mut CIA) {
+        mem.set_cassette_sense(self.playing);
+        if !self.playing || mem.port_value() & PORT_CASSETTE_MOTOR != 0 {
+            return;
+        }
+
+        self.cycles_left = self.cycles_left.saturating_sub(1);
+        if self.cycles_left > 0 {
+            return;
+        }
+        // The read line pulses low at the end of each pulse.
+        cia1.set_flag_line(false);
+        cia1.set_flag_line(true);
+        self.position += 1;
+        self.cycles_left = self.current_pulse();
+        if self.cycles_left == 0 {
+            self.playing = false;
+            mem.set_cassette_sense(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cia;
+    use crate::cpu;
+    use crate::memory;
+
+    /// Builds a TAP image around raw pulse data.
+    fn tap_image(version: u8, data: &[u8]) -> Vec<u8> {
+        let mut image = TAP_MAGIC.to_vec();
+        image.extend_from_slice(&[version, 0, 0, 0]);
+        image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        image.extend_from_slice(data);
+        image
+    }
+
+    #[test]
+    fn parse_reads_short_and_long_pulses() {
+        let tap = Tap::parse(&tap_image(1, &[0x30, 0x00, 0x00, 0x10, 0x00, 0x2B])).unwrap();
+        assert_eq!(tap.pulses, [0x180, 0x1000, 0x158]);
+
+        let tap = Tap::parse(&tap_image(0, &[0x00, 0x2B])).unwrap();
+        assert_eq!(tap.pulses, [0x800, 0x158]);
+
+        assert!(Tap::parse(&tap_image(1, &[0x00, 0x01])).is_err());
+        assert!(Tap::parse(b"C64-TAPE-RAW").is_err());
+    }
+
+    #[test]
+    fn pulses_raise_flag_at_their_spacing() {
+        let mut mem = Memory::new();
+        let mut cia1 = CIA::new(cpu::CPU::new_shared(Memory::new_shared()), true);
+        let mut deck = Datasette::new();
+        deck.insert(Tap::parse(&tap_image(1, &[0x30, 0x40, 0x30])).unwrap());
+
+        // Motor off: nothing plays, but the sense line shows PLAY is down.
+        mem.write_byte(memory::PORT_DDR, 0x2F);
+        mem.write_byte(memory::PORT_DATA, 0x37);
+        deck.press_play();
+        for _ in 0..1000 {
+            deck.update(&mut mem, &mut cia1);
+        }
+        assert_eq!(cia1.icr_data & cia::ICR_FLAG, 0);
+        assert_eq!(mem.read_byte(memory::PORT_DATA) & 0x10, 0);
+
+        mem.write_byte(memory::PORT_DATA, 0x17);
+        let mut flags = Vec::new();
+        for cycle in 1..=2000 {
+            deck.update(&mut mem, &mut cia1);
+            if cia1.read_register(0xDC0D) & cia::ICR_FLAG != 0 {
+                flags.push(cycle);
+            }
+        }
+        assert_eq!(flags, [0x180, 0x180 + 0x200, 0x180 + 0x200 + 0x180]);
+        assert!(!deck.is_playing());
+        assert_ne!(mem.read_byte(memory::PORT_DATA) & 0x10, 0);
+    }
+}
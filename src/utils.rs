@@ -0,0 +1,240 @@
+//! Miscellaneous helpers shared across the emulator.
+
+use crate::memory;
+use crate::opcodes;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Seed for `Rng` unless another is configured.
+pub const DEFAULT_SEED: u64 = 0x5EED_C064;
+
+/// xorshift64 generator. Every pseudo-random value the emulator produces comes from one
+/// of these, seeded explicitly, so runs can be reproduced.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // A zero state would only ever produce zeros.
+        let state = if seed == 0 { DEFAULT_SEED } else { seed };
+        Rng { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 32) as u8
+    }
+}
+
+/// Reads a whole file into memory, skipping the first `offset` bytes.
+pub fn open_file(filename: &str, offset: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(filename)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Writes 0x00RRGGBB pixels, row by row, to an opaque RGBA PNG.
+pub fn write_png(filename: &str, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    let file = File::create(filename)?;
+    let mut encoder = png::Encoder::new(io::BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let rgba: Vec<u8> = pixels
+        .iter()
+        .flat_map(|&pixel| {
+            let [_, r, g, b] = pixel.to_be_bytes();
+            [r, g, b, 0xFF]
+        })
+        .collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&rgba))
+        .map_err(io::Error::other)
+}
+
+/// Disassembles the instruction at the start of `bytes`, located at `addr`. Returns
+/// the hex dump and assembler text, and the instruction length. Opcodes that don't
+/// decode, or whose operand is cut off by the end of `bytes`, become a `.BYTE`.
+pub fn disassemble_instruction(
+    addr: u16,
+    bytes: &[u8],
+    labels: &HashMap<u16, String>,
+) -> (String, u16) {
+    let Some(&opcode) = bytes.first() else {
+        return (String::new(), 0);
+    };
+
+    let instruction = match opcodes::decode(opcode) {
+        Some(instruction) if bytes.len() > instruction.mode.operand_len() as usize => instruction,
+        _ => return (format!("{:02X}        .BYTE ${:02X}", opcode, opcode), 1),
+    };
+
+    let len = 1 + instruction.mode.operand_len();
+    let operand = match len {
+        2 => bytes[1] as u16,
+        3 => u16::from_le_bytes([bytes[1], bytes[2]]),
+        _ => 0,
+    };
+    let hex: Vec<String> = bytes[..len as usize]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    let text = format!(
+        "{:<9} {:?} {}",
+        hex.join(" "),
+        instruction.op,
+        instruction.mode.format_operand(addr, operand, labels)
+    );
+    (text.trim_end().to_string(), len)
+}
+
+/// Disassembles memory from `start` to `end` inclusive, one line per instruction.
+pub fn disassemble(mem: &memory::Memory, start: u16, end: u16) -> Vec<(u16, String)> {
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let bytes: Vec<u8> = (addr..=(addr + 2).min(end as u32))
+            .map(|a| mem.read_byte(a as u16))
+            .collect();
+        let (text, len) = disassemble_instruction(addr as u16, &bytes, &HashMap::new());
+        lines.push((addr as u16, text));
+        addr += len as u32;
+    }
+    lines
+}
+
+/// Video standard, which sets the system clock and the VIC raster geometry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Pal,
+    /// The 6567R8 VIC-II.
+    Ntsc,
+}
+
+impl Region {
+    /// System clock in Hz.
+    pub fn clock_freq(self) -> u32 {
+        match self {
+            Region::Pal => 985_248,
+            Region::Ntsc => 1_022_727,
+        }
+    }
+
+    pub fn raster_lines(self) -> u16 {
+        match self {
+            Region::Pal => 312,
+            Region::Ntsc => 263,
+        }
+    }
+
+    pub fn cycles_per_line(self) -> u16 {
+        match self {
+            Region::Pal => 63,
+            Region::Ntsc => 65,
+        }
+    }
+
+    pub fn cycles_per_frame(self) -> u32 {
+        self.raster_lines() as u32 * self.cycles_per_line() as u32
+    }
+}
+
+/// RGB values used for the 16 VIC-II colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Palette {
+    /// Philip Timmermann's 2001 measurements of a PAL VIC-II.
+    #[default]
+    Pepto,
+    /// The classic VICE palette.
+    Vice,
+    /// Pepto's 2017 revision, as used by the Colodore site.
+    Colodore,
+}
+
+impl Palette {
+    fn colors(self) -> &'static [u32; 16] {
+        match self {
+            Palette::Pepto => &PEPTO_PALETTE,
+            Palette::Vice => &VICE_PALETTE,
+            Palette::Colodore => &COLODORE_PALETTE,
+        }
+    }
+}
+
+/// The 16 VIC-II colors as 0x00RRGGBB, per palette.
+const PEPTO_PALETTE: [u32; 16] = [
+    0x000000, 0xFFFFFF, 0x68372B, 0x70A4B2, 0x6F3D86, 0x588D43, 0x352879, 0xB8C76F, 0x6F4F25,
+    0x433900, 0x9A6759, 0x444444, 0x6C6C6C, 0x9AD284, 0x6C5EB5, 0x959595,
+];
+const VICE_PALETTE: [u32; 16] = [
+    0x000000, 0xFDFEFC, 0xBE1A24, 0x30E6C6, 0xB41AE2, 0x1FD21E, 0x211BAE, 0xDFF60A, 0xB84104,
+    0x6A3304, 0xFE4A57, 0x424540, 0x70746F, 0x59FE59, 0x5F53FE, 0xA4A7A2,
+];
+const COLODORE_PALETTE: [u32; 16] = [
+    0x000000, 0xFFFFFF, 0x813338, 0x75CEC8, 0x8E3C97, 0x56AC4D, 0x2E2C9B, 0xEDF171, 0x8E5029,
+    0x553800, 0xC46C71, 0x4A4A4A, 0x7B7B7B, 0xA9FF9F, 0x706DEB, 0xB2B2B2,
+];
+
+/// Converts a VIC color index (only the low nibble is used) to a 0x00RRGGBB pixel in
+/// `palette`.
+pub fn fetch_color(palette: Palette, index: u8) -> u32 {
+    palette.colors()[(index & 0x0F) as usize]
+}
+
+/// Like `fetch_color` with the default palette.
+pub fn fetch_c64_color_rgba(index: u8) -> u32 {
+    fetch_color(Palette::default(), index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palettes_differ_for_the_same_index() {
+        assert_eq!(fetch_color(Palette::Pepto, 2), 0x68372B);
+        assert_eq!(fetch_color(Palette::Colodore, 2), 0x813338);
+        assert_eq!(fetch_color(Palette::Vice, 0x12), 0xBE1A24);
+        assert_eq!(fetch_c64_color_rgba(2), fetch_color(Palette::Pepto, 2));
+    }
+
+    #[test]
+    fn disassembles_a_small_program() {
+        let mut mem = memory::Memory::new();
+        // LDA #$01; STA $D020,X; BNE -5; JMP ($0300); then a cut-off LDA abs.
+        mem.load(
+            0xC000,
+            &[
+                0xA9, 0x01, 0x9D, 0x20, 0xD0, 0xD0, 0xF9, 0x6C, 0x00, 0x03, 0xAD, 0x00,
+            ],
+        );
+
+        let lines = disassemble(&mem, 0xC000, 0xC00B);
+        let text: Vec<(u16, &str)> = lines.iter().map(|(a, t)| (*a, t.as_str())).collect();
+        assert_eq!(
+            text,
+            [
+                (0xC000, "A9 01     LDA #$01"),
+                (0xC002, "9D 20 D0  STA $D020,X"),
+                (0xC005, "D0 F9     BNE $C000"),
+                (0xC007, "6C 00 03  JMP ($0300)"),
+                (0xC00A, "AD        .BYTE $AD"),
+                (0xC00B, "00        BRK"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1336 @@
+//! MOS 6569 VIC-II video chip. Each raster line is composed once the beam reaches its
+//! end, and the part of it inside the chosen window copied to the window buffer.
+
+use crate::cpu;
+use crate::memory;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils;
+use crate::utils::Region;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub type VICShared = Rc<RefCell<VIC>>;
+
+/// Size of the window buffer with the standard border: the 320x200 display plus the
+/// border a TV shows.
+pub const SCREEN_WIDTH: usize = 384;
+pub const SCREEN_HEIGHT: usize = 272;
+
+/// Raster line shown on the first row of the standard window.
+const FIRST_VISIBLE_LINE: u16 = 15;
+/// Standard window column of sprite X coordinate 0.
+const SPRITE_X_OFFSET: u16 = 8;
+/// Sprite X coordinates wrap around at this value.
+const SPRITE_X_WRAP: u16 = 504;
+/// A composed line holds every X coordinate, starting 24 before X 0 so the left border
+/// comes first.
+const LINE_WIDTH: usize = SPRITE_X_WRAP as usize;
+const LINE_X_OFFSET: u16 = 24;
+
+/// Display window in raster lines and sprite X coordinates (25 rows, 40 columns).
+const DISPLAY_FIRST_LINE: u16 = 51;
+const DISPLAY_FIRST_X: u16 = 24;
+const DISPLAY_LAST_X: u16 = 343;
+
+/// Lines on which the vertical border opens and closes, for 25 rows (RSEL set) and 24.
+const BORDER_TOP_25_ROWS: u16 = 51;
+const BORDER_BOTTOM_25_ROWS: u16 = 251;
+const BORDER_TOP_24_ROWS: u16 = 55;
+const BORDER_BOTTOM_24_ROWS: u16 = 247;
+/// Sprite X coordinates where the side borders end and start, for 40 columns (CSEL set)
+/// and 38.
+const BORDER_LEFT_40_COLUMNS: u16 = 24;
+const BORDER_RIGHT_40_COLUMNS: u16 = 344;
+const BORDER_LEFT_38_COLUMNS: u16 = 31;
+const BORDER_RIGHT_38_COLUMNS: u16 = 335;
+
+/// Sprite data counter value at which a sprite's DMA stops: 21 rows of 3 bytes.
+const SPRITE_DATA_END: u8 = 63;
+/// Cycle at which clearing a sprite's Y-expansion bit mid-pair crunches its data counter.
+const SPRITE_CRUNCH_CYCLE: u16 = 15;
+
+/// Raster lines on which bad lines can occur.
+const BAD_LINE_FIRST: u16 = 0x30;
+const BAD_LINE_LAST: u16 = 0xF7;
+/// Cycles of a bad line during which RDY is held low: three cycles of BA warning
+/// followed by the 40 character pointer fetches.
+const BAD_LINE_STALL_START: u16 = 11;
+const BAD_LINE_STALL_END: u16 = 54;
+
+/// Cycle of a raster line at which the beam is at sprite X coordinate 0.
+const RASTER_X_ZERO_CYCLE: u16 = 13;
+
+/// Interrupt sources in $D019/$D01A.
+pub const IRQ_RASTER: u8 = 1 << 0;
+pub const IRQ_SPRITE_BACKGROUND: u8 = 1 << 1;
+pub const IRQ_SPRITE_SPRITE: u8 = 1 << 2;
+pub const IRQ_LIGHTPEN: u8 = 1 << 3;
+/// Set in $D019 when any enabled source is active.
+const IRQ_ANY: u8 = 1 << 7;
+
+/// How much of the raster the window buffer shows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BorderSize {
+    /// Only the 320x200 display window.
+    None,
+    /// The border as a TV shows it, `SCREEN_WIDTH` x `SCREEN_HEIGHT`.
+    #[default]
+    Standard,
+    /// Every raster line and every X coordinate, for border effects.
+    Full,
+}
+
+/// Part of the composed raster lines copied to the window buffer.
+struct Window {
+    first_line: u16,
+    height: usize,
+    first_column: usize,
+    width: usize,
+}
+
+impl BorderSize {
+    fn window(self, region: Region) -> Window {
+        match self {
+            BorderSize::None => Window {
+                first_line: DISPLAY_FIRST_LINE,
+                height: 200,
+                first_column: (DISPLAY_FIRST_X + LINE_X_OFFSET) as usize,
+                width: 320,
+            },
+            BorderSize::Standard => Window {
+                first_line: FIRST_VISIBLE_LINE,
+                height: SCREEN_HEIGHT,
+                first_column: (LINE_X_OFFSET - SPRITE_X_OFFSET) as usize,
+                width: SCREEN_WIDTH,
+            },
+            BorderSize::Full => Window {
+                first_line: 0,
+                height: region.raster_lines() as usize,
+                first_column: 0,
+                width: LINE_WIDTH,
+            },
+        }
+    }
+}
+
+/// A sprite pixel that won sprite-to-sprite priority at one column of a line.
+#[derive(Clone, Copy)]
+struct SpritePixel {
+    color: u8,
+    /// Sprite-to-background priority bit: drawn behind foreground graphics.
+    behind: bool,
+}
+
+pub struct VIC {
+    mem_ref: memory::MemShared,
+    cpu_ref: cpu::CPUShared,
+    registers: [u8; 0x40],
+    region: Region,
+    palette: utils::Palette,
+    border_size: BorderSize,
+    pub raster_line: u16,
+    raster_cycle: u16,
+    /// Start of the 16K bank the VIC fetches from, selected through CIA2.
+    bank_base: u16,
+    pub window_buffer: Vec<u32>,
+    /// Pixels of the line being drawn, by column.
+    line_pixels: Vec<u32>,
+    /// Foreground graphics pixels on the line being drawn.
+    line_foreground: Vec<bool>,
+    line_sprites: Vec<Option<SpritePixel>>,
+    /// Sprites with an opaque pixel at each column of the line being drawn.
+    line_sprite_mask: Vec<u8>,
+    /// Set when the raster wraps past the last line, i.e. once per frame, meaning the
+    /// window buffer holds a finished frame. Cleared by whoever presents it.
+    pub frame_ready: bool,
+    /// Frames completed since the VIC was created.
+    pub frame_count: u64,
+    /// The light pen already latched a position this frame.
+    lightpen_latched: bool,
+    /// Vertical border flip-flop: set on the bottom compare line, cleared on the top one
+    /// if DEN is set. Missing the bottom compare by switching RSEL keeps it open.
+    vertical_border: bool,
+    /// Sprites whose data is being fetched. A sprite starts when its Y coordinate
+    /// matches the raster line and then runs to its last line whatever Y does.
+    sprite_dma: u8,
+    /// Offset of each sprite's current row in its 64-byte data block (MCBASE).
+    sprite_mcbase: [u8; 8],
+    /// Offset of each sprite's next row (MC), which MCBASE takes when the sprite moves on.
+    sprite_mc: [u8; 8],
+    /// Expansion flip-flops: a sprite moves on to its next row after a line only when
+    /// its bit is set. Always set without Y-expansion, toggled every line with it.
+    sprite_expand_ff: u8,
+}
+
+impl VIC {
+    pub fn new(mem_ref: memory::MemShared, cpu_ref: cpu::CPUShared) -> VIC {
+        VIC {
+            mem_ref,
+            cpu_ref,
+            registers: [0; 0x40],
+            region: Region::Pal,
+            palette: utils::Palette::default(),
+            border_size: BorderSize::default(),
+            raster_line: 0,
+            raster_cycle: 0,
+            bank_base: 0,
+            window_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            line_pixels: vec![0; LINE_WIDTH],
+            line_foreground: vec![false; LINE_WIDTH],
+            line_sprites: vec![None; LINE_WIDTH],
+            line_sprite_mask: vec![0; LINE_WIDTH],
+            frame_ready: false,
+            frame_count: 0,
+            lightpen_latched: false,
+            vertical_border: true,
+            sprite_dma: 0,
+            sprite_mcbase: [0; 8],
+            sprite_mc: [0; 8],
+            sprite_expand_ff: 0xFF,
+        }
+    }
+
+    pub fn new_shared(mem_ref: memory::MemShared, cpu_ref: cpu::CPUShared) -> VICShared {
+        Rc::new(RefCell::new(VIC::new(mem_ref, cpu_ref)))
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.raster_line %= region.raster_lines();
+        self.raster_cycle %= region.cycles_per_line();
+        self.resize_window();
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Colors used from the next line drawn on.
+    pub fn set_palette(&mut self, palette: utils::Palette) {
+        self.palette = palette;
+    }
+
+    pub fn palette(&self) -> utils::Palette {
+        self.palette
+    }
+
+    /// Changes how much of the raster the window buffer shows. The buffer is cleared if
+    /// its size changes.
+    pub fn set_border_size(&mut self, border_size: BorderSize) {
+        self.border_size = border_size;
+        self.resize_window();
+    }
+
+    pub fn border_size(&self) -> BorderSize {
+        self.border_size
+    }
+
+    /// Width and height of the window buffer.
+    pub fn frame_size(&self) -> (usize, usize) {
+        let window = self.border_size.window(self.region);
+        (window.width, window.height)
+    }
+
+    fn resize_window(&mut self) {
+        let (width, height) = self.frame_size();
+        if self.window_buffer.len() != width * height {
+            self.window_buffer = vec![0; width * height];
+        }
+    }
+
+    /// Raster line the beam is on. Lines run from 0 to the region's line count.
+    pub fn raster_line(&self) -> u16 {
+        self.raster_line
+    }
+
+    /// Cycle within the current raster line, advancing once per CPU cycle.
+    pub fn raster_cycle(&self) -> u8 {
+        self.raster_cycle as u8
+    }
+
+    /// Start of the 16K bank the VIC fetches from, as selected through CIA2.
+    pub fn bank_base(&self) -> u16 {
+        self.bank_base
+    }
+
+    pub fn reset(&mut self) {
+        self.registers = [0; 0x40];
+        self.raster_line = 0;
+        self.raster_cycle = 0;
+        self.bank_base = 0;
+        self.frame_ready = false;
+        self.lightpen_latched = false;
+        self.vertical_border = true;
+        self.sprite_dma = 0;
+        self.sprite_mcbase = [0; 8];
+        self.sprite_mc = [0; 8];
+        self.sprite_expand_ff = 0xFF;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.registers);
+        w.u16(self.raster_line);
+        w.u16(self.raster_cycle);
+        w.u16(self.bank_base);
+        w.bool(self.frame_ready);
+        w.bool(self.vertical_border);
+        w.u8(self.sprite_dma);
+        w.bytes(&self.sprite_mcbase);
+        w.bytes(&self.sprite_mc);
+        w.u8(self.sprite_expand_ff);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.registers)?;
+        self.raster_line = r.u16()?;
+        self.raster_cycle = r.u16()?;
+        self.bank_base = r.u16()?;
+        self.frame_ready = r.bool()?;
+        self.vertical_border = r.bool()?;
+        self.sprite_dma = r.u8()?;
+        r.bytes_into(&mut self.sprite_mcbase)?;
+        r.bytes_into(&mut self.sprite_mc)?;
+        self.sprite_expand_ff = r.u8()?;
+        if self.raster_line >= self.region.raster_lines()
+            || self.raster_cycle >= self.region.cycles_per_line()
+        {
+            return Err(String::from("snapshot raster position is out of range"));
+        }
+        Ok(())
+    }
+
+    /// Advances the VIC by one clock cycle.
+    pub fn update(&mut self) {
+        self.raster_cycle += 1;
+        if self.raster_cycle == self.region.cycles_per_line() {
+            self.raster_cycle = 0;
+            self.draw_line(self.raster_line);
+            self.raster_line += 1;
+            if self.raster_line == self.region.raster_lines() {
+                self.raster_line = 0;
+                self.frame_ready = true;
+                self.frame_count += 1;
+                self.lightpen_latched = false;
+            }
+
+            if self.raster_line == self.raster_compare() {
+                self.trigger_irq(IRQ_RASTER);
+            }
+        }
+
+        let asserted = self.registers[0x19] & IRQ_ANY != 0;
+        let stalling = self.is_bad_line()
+            && (BAD_LINE_STALL_START..BAD_LINE_STALL_END).contains(&self.raster_cycle);
+        let mut cpu = self.cpu_ref.borrow_mut();
+        if asserted {
+            if let Some(cause) = self.interrupt_cause() {
+                cpu.set_irq_cause(cpu::IrqSource::Vic, cause);
+            }
+        }
+        cpu.set_irq(cpu::IrqSource::Vic, asserted);
+        cpu.set_rdy(!stalling);
+    }
+
+    /// True when the current line is a bad line: the display is enabled and the low
+    /// three bits of the raster line match YSCROLL, so the VIC fetches a new row of
+    /// character pointers and steals the bus from the CPU.
+    pub fn is_bad_line(&self) -> bool {
+        let control = self.registers[0x11];
+        control & 0x10 != 0
+            && (BAD_LINE_FIRST..=BAD_LINE_LAST).contains(&self.raster_line)
+            && (self.raster_line & 0x07) as u8 == control & 0x07
+    }
+
+    /// Raster line from $D012 plus bit 8 from $D011 bit 7.
+    fn raster_compare(&self) -> u16 {
+        self.registers[0x12] as u16 | (((self.registers[0x11] & 0x80) as u16) << 1)
+    }
+
+    pub fn trigger_irq(&mut self, mask: u8) {
+        self.registers[0x19] |= mask;
+        self.update_irq_flag();
+    }
+
+    /// Beam X position in sprite coordinates.
+    pub fn raster_x(&self) -> u16 {
+        let cycles = self.region.cycles_per_line();
+        (self.raster_cycle + cycles - RASTER_X_ZERO_CYCLE) % cycles * 8
+    }
+
+    /// Light pen input pulled low: latches the beam position into $D013/$D014 and raises
+    /// the light pen interrupt. Only the first trigger in a frame counts.
+    pub fn trigger_lp_irq(&mut self) {
+        if self.lightpen_latched {
+            return;
+        }
+        self.lightpen_latched = true;
+        self.registers[0x13] = (self.raster_x() >> 1) as u8;
+        self.registers[0x14] = self.raster_line as u8;
+        self.trigger_irq(IRQ_LIGHTPEN);
+    }
+
+    /// The lowest-numbered enabled source latched in $D019.
+    fn interrupt_cause(&self) -> Option<cpu::InterruptCause> {
+        let pending = self.registers[0x19] & self.registers[0x1A];
+        [
+            (IRQ_RASTER, cpu::InterruptCause::Raster),
+            (IRQ_SPRITE_BACKGROUND, cpu::InterruptCause::SpriteBackground),
+            (IRQ_SPRITE_SPRITE, cpu::InterruptCause::SpriteSprite),
+            (IRQ_LIGHTPEN, cpu::InterruptCause::Lightpen),
+        ]
+        .into_iter()
+        .find(|&(bit, _)| pending & bit != 0)
+        .map(|(_, cause)| cause)
+    }
+
+    /// Recomputes $D019 bit 7 from the latched and enabled sources.
+    fn update_irq_flag(&mut self) {
+        if self.registers[0x19] & self.registers[0x1A] & 0x0F != 0 {
+            self.registers[0x19] |= IRQ_ANY;
+        } else {
+            self.registers[0x19] &= !IRQ_ANY;
+        }
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        let reg = (addr & 0x3F) as usize;
+        match reg {
+            0x11 => (self.registers[reg] & 0x7F) | ((self.raster_line >> 1) as u8 & 0x80),
+            0x12 => self.raster_line as u8,
+            // Collision latches clear when read.
+            0x1E | 0x1F => std::mem::take(&mut self.registers[reg]),
+            0x16 => self.registers[reg] | 0xC0,
+            0x18 => self.registers[reg] | 0x01,
+            0x19 => self.registers[reg] | 0x70,
+            0x1A => self.registers[reg] | 0xF0,
+            0x20..=0x2E => self.registers[reg] | 0xF0,
+            0x2F..=0x3F => 0xFF,
+            _ => self.registers[reg],
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        let reg = (addr & 0x3F) as usize;
+        match reg {
+            // Writing 1 bits acknowledges the matching interrupt sources.
+            0x19 => {
+                self.registers[reg] &= !(value & 0x0F);
+                self.update_irq_flag();
+            }
+            0x1A => {
+                self.registers[reg] = value & 0x0F;
+                self.update_irq_flag();
+            }
+            0x17 => {
+                self.clear_sprite_expansion(self.registers[reg] & !value);
+                self.registers[reg] = value;
+            }
+            _ => self.registers[reg] = value,
+        }
+    }
+
+    /// Called by CIA2 when the VA14/VA15 bank select lines change.
+    pub fn on_va_change(&mut self, va: u8) {
+        self.bank_base = ((va & 0x03) as u16) << 14;
+    }
+
+    /// Reads a byte from the VIC's 14-bit address space in the current bank. Banks 0
+    /// and 2 see the character ROM at $1000-$1FFF instead of RAM.
+    fn read_vic_byte(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        let mem = self.mem_ref.borrow();
+        let value = if self.bank_base & 0x4000 == 0 && addr & 0x3000 == 0x1000 {
+            mem.read_chargen(addr & 0x0FFF)
+        } else {
+            mem.read_ram(self.bank_base | addr)
+        };
+        mem.report_vic_read(self.bank_base | addr, value);
+        value
+    }
+
+    fn video_matrix_base(&self) -> u16 {
+        ((self.registers[0x18] >> 4) as u16) << 10
+    }
+
+    fn bitmap_base(&self) -> u16 {
+        ((self.registers[0x18] & 0x08) as u16) << 10
+    }
+
+    fn charset_base(&self) -> u16 {
+        ((self.registers[0x18] & 0x0E) as u16) << 10
+    }
+
+    fn draw_line(&mut self, line: u16) {
+        self.update_vertical_border(line);
+        self.start_sprite_dma(line);
+
+        let background = utils::fetch_color(self.palette, self.registers[0x21]);
+        self.line_pixels.fill(background);
+        self.line_foreground.fill(false);
+        self.draw_graphics(line);
+        self.draw_sprites();
+        self.advance_sprite_dma();
+        self.draw_border();
+
+        let window = self.border_size.window(self.region);
+        if (window.first_line..window.first_line + window.height as u16).contains(&line) {
+            let row_start = (line - window.first_line) as usize * window.width;
+            self.window_buffer[row_start..row_start + window.width].copy_from_slice(
+                &self.line_pixels[window.first_column..window.first_column + window.width],
+            );
+        }
+    }
+
+    /// Starts the sprites that are enabled and whose Y coordinate matches the low eight
+    /// bits of `line`, so a multiplexer can move a finished sprite further down.
+    fn start_sprite_dma(&mut self, line: u16) {
+        for sprite in 0..8 {
+            let bit = 1 << sprite;
+            if self.sprite_dma & bit == 0
+                && self.registers[0x15] & bit != 0
+                && self.registers[1 + 2 * sprite] == line as u8
+            {
+                self.sprite_dma |= bit;
+                self.sprite_mcbase[sprite] = 0;
+                self.sprite_mc[sprite] = 3;
+                if self.registers[0x17] & bit != 0 {
+                    self.sprite_expand_ff &= !bit;
+                }
+            }
+        }
+    }
+
+    /// Ends a line for the active sprites: those whose expansion flip-flop is set move
+    /// on to the row in their MC, and the flip-flops of Y-expanded sprites toggle so each
+    /// row is shown twice. A sprite stops once its data counter reaches the end.
+    fn advance_sprite_dma(&mut self) {
+        for sprite in 0..8 {
+            let bit = 1 << sprite;
+            if self.sprite_dma & bit == 0 {
+                continue;
+            }
+            if self.sprite_expand_ff & bit != 0 {
+                self.sprite_mcbase[sprite] = self.sprite_mc[sprite];
+                if self.sprite_mcbase[sprite] == SPRITE_DATA_END {
+                    self.sprite_dma &= !bit;
+                }
+            }
+            self.sprite_mc[sprite] = (self.sprite_mcbase[sprite] + 3) & 0x3F;
+            if self.registers[0x17] & bit != 0 {
+                self.sprite_expand_ff ^= bit;
+            } else {
+                self.sprite_expand_ff |= bit;
+            }
+        }
+    }
+
+    /// Sets the expansion flip-flops of the sprites in `cleared`, whose Y-expansion bits
+    /// are being cleared. Doing so at the crunch cycle to a sprite halfway through a
+    /// doubled row mixes the current and next row offsets into its MC, which becomes
+    /// MCBASE at the end of the line. The sprite then skips rows and can miss the end of
+    /// its data, running on for more lines than usual.
+    fn clear_sprite_expansion(&mut self, cleared: u8) {
+        for sprite in 0..8 {
+            let bit = 1 << sprite;
+            if cleared & bit == 0 {
+                continue;
+            }
+            if self.raster_cycle == SPRITE_CRUNCH_CYCLE
+                && self.sprite_dma & bit != 0
+                && self.sprite_expand_ff & bit == 0
+            {
+                let (mcbase, mc) = (self.sprite_mcbase[sprite], self.sprite_mc[sprite]);
+                self.sprite_mc[sprite] = (0x2A & mcbase & mc) | (0x15 & (mcbase | mc));
+            }
+            self.sprite_expand_ff |= bit;
+        }
+    }
+
+    /// Runs the vertical border flip-flop for the start of `line`.
+    fn update_vertical_border(&mut self, line: u16) {
+        let control = self.registers[0x11];
+        let (top, bottom) = if control & 0x08 != 0 {
+            (BORDER_TOP_25_ROWS, BORDER_BOTTOM_25_ROWS)
+        } else {
+            (BORDER_TOP_24_ROWS, BORDER_BOTTOM_24_ROWS)
+        };
+        if line == bottom {
+            self.vertical_border = true;
+        } else if line == top && control & 0x10 != 0 {
+            self.vertical_border = false;
+        }
+    }
+
+    /// Covers everything outside the display window with the border color: the whole
+    /// line while the vertical border is closed, else the sides selected by CSEL.
+    fn draw_border(&mut self) {
+        let border = utils::fetch_color(self.palette, self.registers[0x20]);
+        let row = &mut self.line_pixels;
+        if self.vertical_border {
+            row.fill(border);
+            return;
+        }
+        let (left, right) = if self.registers[0x16] & 0x08 != 0 {
+            (BORDER_LEFT_40_COLUMNS, BORDER_RIGHT_40_COLUMNS)
+        } else {
+            (BORDER_LEFT_38_COLUMNS, BORDER_RIGHT_38_COLUMNS)
+        };
+        row[..(left + LINE_X_OFFSET) as usize].fill(border);
+        row[(right + LINE_X_OFFSET) as usize..].fill(border);
+    }
+
+    /// Draws the 320x200 graphics of `line` in the mode selected by $D011 and $D016,
+    /// shifted by the fine scroll registers.
+    fn draw_graphics(&mut self, line: u16) {
+        let control = self.registers[0x11];
+        let y = line as i32 - DISPLAY_FIRST_LINE as i32 + 3 - (control & 0x07) as i32;
+        if !(0..200).contains(&y) {
+            self.draw_idle();
+            return;
+        }
+
+        let bitmap_mode = control & 0x20 != 0;
+        let multicolor = self.registers[0x16] & 0x10 != 0;
+        let extended_color = control & 0x40 != 0;
+        // Extended color combined with bitmap or multicolor mode is invalid and only
+        // shows black, though the graphics still count for sprite collisions.
+        let invalid_mode = extended_color && (bitmap_mode || multicolor);
+        let x_scroll = (self.registers[0x16] & 0x07) as usize;
+        let row_offset = (y as u16 / 8) * 40;
+        let char_line = y as u16 % 8;
+        let video_matrix = self.video_matrix_base();
+        let bitmap = self.bitmap_base();
+        let charset = self.charset_base();
+        let display_x =
+            (DISPLAY_FIRST_X + LINE_X_OFFSET) as usize..=(DISPLAY_LAST_X + LINE_X_OFFSET) as usize;
+
+        for column in 0..40u16 {
+            let offset = row_offset + column;
+            let screen = self.read_vic_byte(video_matrix + offset);
+            let color = self.mem_ref.borrow().read_color_ram(offset);
+            // In extended color mode the top two bits of the screen code pick one of four
+            // background colors, leaving 64 glyphs.
+            let (glyph, background) = if extended_color {
+                (screen & 0x3F, self.registers[0x21 + (screen >> 6) as usize])
+            } else {
+                (screen, self.registers[0x21])
+            };
+            let data = if bitmap_mode {
+                self.read_vic_byte(bitmap + offset * 8 + char_line)
+            } else {
+                self.read_vic_byte(charset + glyph as u16 * 8 + char_line)
+            };
+
+            // Each pixel is a color index and whether it counts as foreground for
+            // sprite priority and collisions.
+            let mut pixels = [(background, false); 8];
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let pair = (data >> (6 - (i & !1))) & 0x03;
+                let bit = (data >> (7 - i)) & 0x01 != 0;
+                *pixel = match (bitmap_mode, multicolor) {
+                    (true, true) => match pair {
+                        0 => (background, false),
+                        1 => (screen >> 4, false),
+                        2 => (screen & 0x0F, true),
+                        _ => (color, true),
+                    },
+                    (true, false) if bit => (screen >> 4, true),
+                    (true, false) => (screen & 0x0F, false),
+                    // Multicolor text only applies to characters whose color has bit 3 set.
+                    (false, true) if color & 0x08 != 0 => match pair {
+                        0 => (background, false),
+                        1 => (self.registers[0x22], false),
+                        2 => (self.registers[0x23], true),
+                        _ => (color & 0x07, true),
+                    },
+                    (false, _) if bit => (color, true),
+                    (false, _) => (background, false),
+                };
+                if invalid_mode {
+                    pixel.0 = 0;
+                }
+            }
+
+            let x0 = display_x.start() + column as usize * 8 + x_scroll;
+            for (i, (color, foreground)) in pixels.into_iter().enumerate() {
+                let x = x0 + i;
+                if display_x.contains(&x) {
+                    self.line_pixels[x] = utils::fetch_color(self.palette, color);
+                    self.line_foreground[x] = foreground;
+                }
+            }
+        }
+    }
+
+    /// Address the VIC reads graphics from in idle state, outside the character rows.
+    /// ECM forces address bits 9 and 10 low.
+    fn idle_fetch_address(&self) -> u16 {
+        if self.registers[0x11] & 0x40 != 0 {
+            0x39FF
+        } else {
+            0x3FFF
+        }
+    }
+
+    /// Draws an idle-state line, visible only where the border has been opened: the
+    /// idle byte repeated across the display window, as if the screen code and color
+    /// were both 0. Set bits are black; clear bits show the background color except in
+    /// hires bitmap and the invalid modes, where they are black too.
+    fn draw_idle(&mut self) {
+        let data = self.read_vic_byte(self.idle_fetch_address());
+        let control = self.registers[0x11];
+        let bitmap_mode = control & 0x20 != 0;
+        let multicolor = self.registers[0x16] & 0x10 != 0;
+        let extended_color = control & 0x40 != 0;
+        let invalid_mode = extended_color && (bitmap_mode || multicolor);
+        let background = if invalid_mode || (bitmap_mode && !multicolor) {
+            0
+        } else {
+            self.registers[0x21]
+        };
+        let x_scroll = (self.registers[0x16] & 0x07) as usize;
+        let first = (DISPLAY_FIRST_X + LINE_X_OFFSET) as usize;
+        for x in first..=(DISPLAY_LAST_X + LINE_X_OFFSET) as usize {
+            let i = (x + 8 - first - x_scroll) % 8;
+            let foreground = if bitmap_mode && multicolor {
+                (data >> (6 - (i & !1))) & 0x03 != 0
+            } else {
+                (data >> (7 - i)) & 0x01 != 0
+            };
+            let color = if foreground { 0 } else { background };
+            self.line_pixels[x] = utils::fetch_color(self.palette, color);
+            self.line_foreground[x] = foreground;
+        }
+    }
+
+    /// Composites the sprites active on the current line. The sprite pointers are read
+    /// afresh on every line. Lower-numbered sprites win overlaps; the winner's priority
+    /// bit then decides whether it shows over foreground graphics.
+    fn draw_sprites(&mut self) {
+        let active = self.sprite_dma;
+        if active == 0 {
+            return;
+        }
+
+        self.line_sprites.fill(None);
+        self.line_sprite_mask.fill(0);
+        let video_matrix = self.video_matrix_base();
+        for sprite in 0..8 {
+            let bit = 1 << sprite;
+            if active & bit == 0 {
+                continue;
+            }
+
+            let pointer = self.read_vic_byte(video_matrix + 0x3F8 + sprite as u16) as u16;
+            let data_addr =
+                |offset: u8| pointer * 64 + ((self.sprite_mcbase[sprite] + offset) & 0x3F) as u16;
+            let data = ((self.read_vic_byte(data_addr(0)) as u32) << 16)
+                | ((self.read_vic_byte(data_addr(1)) as u32) << 8)
+                | self.read_vic_byte(data_addr(2)) as u32;
+            if data == 0 {
+                continue;
+            }
+
+            let x = self.registers[2 * sprite] as u16
+                | if self.registers[0x10] & bit != 0 {
+                    0x100
+                } else {
+                    0
+                };
+            let x_expand = self.registers[0x1D] & bit != 0;
+            let multicolor = self.registers[0x1C] & bit != 0;
+            let behind = self.registers[0x1B] & bit != 0;
+            let width = if x_expand { 2 } else { 1 };
+
+            for px in 0..24u16 {
+                let color = if multicolor {
+                    match (data >> (22 - (px & !1))) & 0x03 {
+                        0 => continue,
+                        1 => self.registers[0x25],
+                        2 => self.registers[0x27 + sprite],
+                        _ => self.registers[0x26],
+                    }
+                } else if (data >> (23 - px)) & 0x01 != 0 {
+                    self.registers[0x27 + sprite]
+                } else {
+                    continue;
+                };
+
+                for sub in 0..width {
+                    let column = ((x + px * width + sub + LINE_X_OFFSET) % SPRITE_X_WRAP) as usize;
+                    self.line_sprite_mask[column] |= bit;
+                    if self.line_sprites[column].is_none() {
+                        self.line_sprites[column] = Some(SpritePixel { color, behind });
+                    }
+                }
+            }
+        }
+
+        self.detect_collisions();
+        for column in 0..LINE_WIDTH {
+            if let Some(pixel) = self.line_sprites[column] {
+                if !(pixel.behind && self.line_foreground[column]) {
+                    self.line_pixels[column] = utils::fetch_color(self.palette, pixel.color);
+                }
+            }
+        }
+    }
+
+    /// Latches the sprites that overlapped another sprite or foreground graphics on
+    /// the line just composited. The interrupt only fires when a latch goes from
+    /// empty to non-empty.
+    fn detect_collisions(&mut self) {
+        let mut sprite_sprite = 0;
+        let mut sprite_background = 0;
+        for (column, &mask) in self.line_sprite_mask.iter().enumerate() {
+            if mask.count_ones() > 1 {
+                sprite_sprite |= mask;
+            }
+            if self.line_foreground[column] {
+                sprite_background |= mask;
+            }
+        }
+
+        if sprite_sprite != 0 {
+            if self.registers[0x1E] == 0 {
+                self.trigger_irq(IRQ_SPRITE_SPRITE);
+            }
+            self.registers[0x1E] |= sprite_sprite;
+        }
+        if sprite_background != 0 {
+            if self.registers[0x1F] == 0 {
+                self.trigger_irq(IRQ_SPRITE_BACKGROUND);
+            }
+            self.registers[0x1F] |= sprite_background;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn new_vic(mem: memory::MemShared) -> (VIC, cpu::CPUShared) {
+        let cpu_ref = cpu::CPU::new_shared(mem.clone());
+        let mut vic = VIC::new(mem, cpu_ref.clone());
+        // Display on, 25 rows and 40 columns, as the KERNAL sets it up.
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD016, 0x08);
+        (vic, cpu_ref)
+    }
+
+    fn run_frame(vic: &mut VIC) {
+        for _ in 0..vic.region().cycles_per_frame() {
+            vic.update();
+        }
+    }
+
+    fn pixel(vic: &VIC, line: u16, x: u16) -> u32 {
+        vic.window_buffer
+            [(line - FIRST_VISIBLE_LINE) as usize * SCREEN_WIDTH + (x + SPRITE_X_OFFSET) as usize]
+    }
+
+    #[test]
+    fn sprite_0_is_drawn_at_its_position() {
+        let mem = Memory::new_shared();
+        // Video matrix at $0400, sprite 0 data at $2000.
+        mem.borrow_mut().write_byte(0x07F8, 0x80);
+        mem.borrow_mut().load(0x2000, &[0xFF, 0x00, 0x01]);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD015, 0x01);
+        vic.write_register(0xD000, 100);
+        vic.write_register(0xD001, 60);
+        vic.write_register(0xD027, 0x01);
+        vic.write_register(0xD021, 0x06);
+        run_frame(&mut vic);
+
+        let white = utils::fetch_c64_color_rgba(1);
+        let blue = utils::fetch_c64_color_rgba(6);
+        for x in 100..108 {
+            assert_eq!(pixel(&vic, 60, x), white);
+        }
+        assert_eq!(pixel(&vic, 60, 108), blue);
+        assert_eq!(pixel(&vic, 60, 123), white);
+        assert_eq!(pixel(&vic, 61, 100), blue);
+    }
+
+    #[test]
+    fn sprite_moved_below_the_raster_is_shown_again() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().write_byte(0x07F8, 0x80);
+        mem.borrow_mut().load(0x2000, &[0xFF; 63]);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD015, 0x01);
+        vic.write_register(0xD000, 100);
+        vic.write_register(0xD001, 60);
+        vic.write_register(0xD027, 0x01);
+
+        // Moving the sprite while it is being drawn does not cut it short.
+        while vic.raster_line != 70 {
+            vic.update();
+        }
+        vic.write_register(0xD001, 200);
+        while vic.raster_line != 100 {
+            vic.update();
+        }
+        vic.write_register(0xD001, 150);
+        while !vic.frame_ready {
+            vic.update();
+        }
+
+        let white = utils::fetch_c64_color_rgba(1);
+        assert_eq!(pixel(&vic, 60, 100), white);
+        assert_eq!(pixel(&vic, 80, 100), white);
+        assert_ne!(pixel(&vic, 81, 100), white);
+        assert_eq!(pixel(&vic, 150, 100), white);
+        assert_eq!(pixel(&vic, 170, 100), white);
+        assert_ne!(pixel(&vic, 171, 100), white);
+    }
+
+    #[test]
+    fn raster_position_counts_cycles_from_reset() {
+        let (mut vic, _cpu_ref) = new_vic(Memory::new_shared());
+        for _ in 0..63 * 100 + 17 {
+            vic.update();
+        }
+        assert_eq!((vic.raster_line(), vic.raster_cycle()), (100, 17));
+
+        vic.reset();
+        vic.set_region(Region::Ntsc);
+        for _ in 0..65 * 263 - 1 {
+            vic.update();
+        }
+        assert_eq!((vic.raster_line(), vic.raster_cycle()), (262, 64));
+        vic.update();
+        assert_eq!((vic.raster_line(), vic.raster_cycle()), (0, 0));
+    }
+
+    #[test]
+    fn idle_fetch_moves_with_ecm() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().write_byte(0x3FFF, 0xF0);
+        mem.borrow_mut().write_byte(0x39FF, 0x0F);
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        assert_eq!(vic.idle_fetch_address(), 0x3FFF);
+        vic.write_register(0xD011, 0x5B);
+        assert_eq!(vic.idle_fetch_address(), 0x39FF);
+
+        // Line 30 is above the character rows; draw it as though the border were open.
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD021, 0x06);
+        vic.draw_graphics(30);
+        let black = utils::fetch_c64_color_rgba(0);
+        let blue = utils::fetch_c64_color_rgba(6);
+        let first = (DISPLAY_FIRST_X + LINE_X_OFFSET) as usize;
+        assert_eq!(vic.line_pixels[first], black);
+        assert_eq!(vic.line_pixels[first + 4], blue);
+        vic.write_register(0xD011, 0x5B);
+        vic.draw_graphics(30);
+        assert_eq!(vic.line_pixels[first], blue);
+        assert_eq!(pixel(&vic, 30, DISPLAY_FIRST_X + 4), black);
+    }
+
+    #[test]
+    fn raster_irq_fires_on_compare_line() {
+        let (mut vic, cpu_ref) = new_vic(Memory::new_shared());
+        vic.write_register(0xD012, 100);
+        vic.write_register(0xD01A, IRQ_RASTER);
+
+        let mut fired_on = None;
+        for _ in 0..Region::Pal.cycles_per_frame() {
+            vic.update();
+            if cpu_ref.borrow().irq_line() {
+                fired_on = Some(vic.raster_line);
+                break;
+            }
+        }
+        assert_eq!(fired_on, Some(100));
+        assert_eq!(vic.read_register(0xD019), 0xF1);
+
+        vic.write_register(0xD019, IRQ_RASTER);
+        vic.update();
+        assert!(!cpu_ref.borrow().irq_line());
+        assert_eq!(vic.read_register(0xD019), 0x70);
+    }
+
+    #[test]
+    fn raster_compare_uses_d011_msb() {
+        let (mut vic, cpu_ref) = new_vic(Memory::new_shared());
+        vic.write_register(0xD011, 0x80);
+        vic.write_register(0xD012, 0x05);
+        vic.write_register(0xD01A, IRQ_RASTER);
+
+        while !cpu_ref.borrow().irq_line() {
+            vic.update();
+        }
+        assert_eq!(vic.raster_line, 0x105);
+        assert_eq!(vic.read_register(0xD011) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn bad_lines_follow_yscroll_and_steal_cycles() {
+        let (mut vic, cpu_ref) = new_vic(Memory::new_shared());
+        vic.write_register(0xD011, 0x13);
+
+        let mut bad_lines = Vec::new();
+        let mut stolen = 0;
+        for _ in 0..Region::Pal.cycles_per_frame() {
+            vic.update();
+            if vic.is_bad_line() && bad_lines.last() != Some(&vic.raster_line) {
+                bad_lines.push(vic.raster_line);
+            }
+            if !cpu_ref.borrow().rdy_line() {
+                stolen += 1;
+            }
+        }
+
+        let expected: Vec<u16> = (0x33..=0xF3).step_by(8).collect();
+        assert_eq!(bad_lines, expected);
+        assert_eq!(stolen, expected.len() as u32 * 43);
+
+        vic.write_register(0xD011, 0x03);
+        run_frame(&mut vic);
+        assert!(!vic.is_bad_line());
+        assert!(cpu_ref.borrow().rdy_line());
+    }
+
+    #[test]
+    fn overlapping_sprites_collide() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().load(0x07F8, &[0x80, 0x80, 0x81]);
+        mem.borrow_mut().load(0x2000, &[0xF0, 0x00, 0x00]);
+        // Sprite 2 shares the same position but is transparent where the others are opaque.
+        mem.borrow_mut().load(0x2040, &[0x0F, 0x00, 0x00]);
+
+        let (mut vic, cpu_ref) = new_vic(mem);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD015, 0x07);
+        vic.write_register(0xD01A, IRQ_SPRITE_SPRITE);
+        for sprite in 0..3 {
+            vic.write_register(0xD000 + 2 * sprite, 100);
+            vic.write_register(0xD001 + 2 * sprite, 80);
+        }
+        run_frame(&mut vic);
+
+        assert!(cpu_ref.borrow().irq_line());
+        assert_eq!(
+            vic.read_register(0xD019) & (IRQ_SPRITE_SPRITE | IRQ_SPRITE_BACKGROUND),
+            IRQ_SPRITE_SPRITE
+        );
+        assert_eq!(vic.read_register(0xD01E), 0x03);
+        assert_eq!(vic.read_register(0xD01E), 0x00);
+        assert_eq!(vic.read_register(0xD01F), 0x00);
+    }
+
+    #[test]
+    fn hires_bitmap_uses_screen_ram_colors() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().load(0x0400, &[0x12, 0x70]);
+        mem.borrow_mut().write_byte(0x2000, 0xF0);
+        mem.borrow_mut().write_byte(0x2009, 0x80);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD011, 0x3B);
+        vic.write_register(0xD018, 0x18);
+        run_frame(&mut vic);
+
+        let white = utils::fetch_c64_color_rgba(1);
+        let red = utils::fetch_c64_color_rgba(2);
+        for x in 24..28 {
+            assert_eq!(pixel(&vic, 51, x), white);
+        }
+        assert_eq!(pixel(&vic, 51, 28), red);
+        assert_eq!(pixel(&vic, 52, 32), utils::fetch_c64_color_rgba(7));
+        assert_eq!(pixel(&vic, 52, 33), utils::fetch_c64_color_rgba(0));
+    }
+
+    #[test]
+    fn multicolor_bitmap_in_bank_1() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().write_byte(0x4401, 0x34);
+        mem.borrow_mut().write_byte(0xD801, 0x05);
+        mem.borrow_mut().write_byte(0x6008, 0b00_01_10_11);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.on_va_change(1);
+        vic.write_register(0xD011, 0x3B);
+        vic.write_register(0xD016, 0x18);
+        vic.write_register(0xD018, 0x18);
+        vic.write_register(0xD021, 0x06);
+        run_frame(&mut vic);
+
+        let expected = [6, 6, 3, 3, 4, 4, 5, 5];
+        for (i, color) in expected.into_iter().enumerate() {
+            assert_eq!(
+                pixel(&vic, 51, 32 + i as u16),
+                utils::fetch_c64_color_rgba(color)
+            );
+        }
+    }
+
+    #[test]
+    fn text_mode_draws_chargen_glyphs() {
+        let mem = Memory::new_shared();
+        let mut chargen = vec![0; memory::CHARGEN_SIZE];
+        chargen[8 * 8..9 * 8].copy_from_slice(&[0x66, 0x66, 0x66, 0x7E, 0x66, 0x66, 0x66, 0x00]);
+        mem.borrow_mut()
+            .load_rom(memory::MemType::Chargen, &chargen)
+            .unwrap();
+        // "HELLO" in screen codes, the H in light green.
+        mem.borrow_mut().load(0x0400, &[8, 5, 12, 12, 15]);
+        mem.borrow_mut().write_byte(0xD800, 0x0D);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD021, 0x06);
+        run_frame(&mut vic);
+
+        let green = utils::fetch_c64_color_rgba(0x0D);
+        let blue = utils::fetch_c64_color_rgba(6);
+        let top_row: Vec<u32> = (24..32).map(|x| pixel(&vic, 51, x)).collect();
+        assert_eq!(
+            top_row,
+            [blue, green, green, blue, blue, green, green, blue]
+        );
+        assert_eq!(pixel(&vic, 54, 27), green);
+        assert_eq!(pixel(&vic, 58, 25), blue);
+    }
+
+    #[test]
+    fn text_color_comes_from_color_ram() {
+        let mem = Memory::new_shared();
+        let mut chargen = vec![0; memory::CHARGEN_SIZE];
+        chargen[0] = 0xFF;
+        mem.borrow_mut()
+            .load_rom(memory::MemType::Chargen, &chargen)
+            .unwrap();
+        mem.borrow_mut().write_byte(0xD800, 0x07);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD011, 0x1B);
+        vic.write_register(0xD018, 0x14);
+        run_frame(&mut vic);
+
+        let yellow = utils::fetch_c64_color_rgba(0x07);
+        assert!((24..32).all(|x| pixel(&vic, 51, x) == yellow));
+        assert_ne!(pixel(&vic, 51, 32), yellow);
+    }
+
+    #[test]
+    fn ntsc_frame_wraps_after_263_lines() {
+        let (mut vic, _cpu_ref) = new_vic(Memory::new_shared());
+        vic.set_region(Region::Ntsc);
+
+        for _ in 0..Region::Ntsc.cycles_per_frame() - 1 {
+            vic.update();
+        }
+        assert_eq!(vic.raster_line, 262);
+        assert!(!vic.frame_ready);
+
+        vic.update();
+        assert_eq!(vic.raster_line, 0);
+        assert!(vic.frame_ready);
+    }
+
+    #[test]
+    fn sprite_x_msb_and_expansion() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().write_byte(0x07F8, 0x80);
+        mem.borrow_mut().load(0x2000, &[0x80, 0x00, 0x00]);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD015, 0x01);
+        vic.write_register(0xD010, 0x01);
+        vic.write_register(0xD000, 0x04);
+        vic.write_register(0xD001, 100);
+        vic.write_register(0xD017, 0x01);
+        vic.write_register(0xD01D, 0x01);
+        vic.write_register(0xD027, 0x02);
+        run_frame(&mut vic);
+
+        let red = utils::fetch_c64_color_rgba(2);
+        assert_eq!(pixel(&vic, 100, 260), red);
+        assert_eq!(pixel(&vic, 100, 261), red);
+        assert_ne!(pixel(&vic, 100, 262), red);
+        assert_eq!(pixel(&vic, 101, 260), red);
+        assert_ne!(pixel(&vic, 102, 260), red);
+    }
+
+    #[test]
+    fn x_expansion_doubles_every_pixel() {
+        let mem = Memory::new_shared();
+        mem.borrow_mut().write_byte(0x07F8, 0x80);
+        mem.borrow_mut().load(0x2000, &[0b1011_0010, 0x00, 0x01]);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD015, 0x01);
+        vic.write_register(0xD000, 100);
+        vic.write_register(0xD001, 100);
+        vic.write_register(0xD01D, 0x01);
+        vic.write_register(0xD027, 0x02);
+        run_frame(&mut vic);
+
+        let red = utils::fetch_c64_color_rgba(2);
+        let data = 0b1011_0010_0000_0000_0000_0001u32;
+        for px in 0..24 {
+            let set = (data >> (23 - px)) & 1 != 0;
+            for column in [100 + 2 * px, 101 + 2 * px] {
+                assert_eq!(pixel(&vic, 100, column) == red, set, "pixel {}", px);
+            }
+        }
+        assert_ne!(pixel(&vic, 100, 148), red);
+    }
+
+    #[test]
+    fn clearing_y_expansion_at_the_crunch_cycle_stretches_the_sprite() {
+        // Counts the lines sprite 0 is shown on when $D017 is cleared at `line` and `cycle`.
+        let shown_lines = |line: u16, cycle: u16| {
+            let mem = Memory::new_shared();
+            mem.borrow_mut().write_byte(0x07F8, 0x80);
+            mem.borrow_mut().load(0x2000, &[0xFF; 64]);
+            let (mut vic, _cpu_ref) = new_vic(mem);
+            vic.write_register(0xD018, 0x14);
+            vic.write_register(0xD015, 0x01);
+            vic.write_register(0xD000, 100);
+            vic.write_register(0xD001, 100);
+            vic.write_register(0xD017, 0x01);
+            vic.write_register(0xD027, 0x02);
+            while (vic.raster_line, vic.raster_cycle) != (line, cycle) {
+                vic.update();
+            }
+            vic.write_register(0xD017, 0x00);
+            while vic.raster_line != 250 {
+                vic.update();
+            }
+
+            let red = utils::fetch_c64_color_rgba(2);
+            (90..250).filter(|&l| pixel(&vic, l, 100) == red).count()
+        };
+
+        // Off the crunch cycle the sprite finishes its rows unexpanded: two lines for
+        // row 0, then one for each of the 20 rows left.
+        assert_eq!(shown_lines(102, SPRITE_CRUNCH_CYCLE + 1), 22);
+        // Clearing on the second line of a doubled row is no crunch either.
+        assert_eq!(shown_lines(103, SPRITE_CRUNCH_CYCLE), 23);
+        // On the first line of row 1 the MC becomes 7 instead of 6, so the sprite misses
+        // the end of its data and wraps around once.
+        assert_eq!(shown_lines(102, SPRITE_CRUNCH_CYCLE), 43);
+        // On row 2 it becomes 5 and passes 63 twice before landing on it.
+        assert_eq!(shown_lines(104, SPRITE_CRUNCH_CYCLE), 67);
+    }
+
+    #[test]
+    fn lightpen_latches_the_beam_once_per_frame() {
+        let (mut vic, cpu_ref) = new_vic(Memory::new_shared());
+        vic.write_register(0xD01A, IRQ_LIGHTPEN);
+        while (vic.raster_line, vic.raster_cycle) != (100, 20) {
+            vic.update();
+        }
+        vic.trigger_lp_irq();
+        vic.update();
+        assert_eq!(
+            vic.read_register(0xD013),
+            (20 - RASTER_X_ZERO_CYCLE) as u8 * 4
+        );
+        assert_eq!(vic.read_register(0xD014), 100);
+        assert!(cpu_ref.borrow().irq_line());
+
+        // A second trigger in the same frame is ignored.
+        vic.write_register(0xD019, IRQ_LIGHTPEN);
+        vic.update();
+        vic.trigger_lp_irq();
+        assert_eq!(vic.read_register(0xD014), 100);
+        assert_eq!(vic.read_register(0xD019) & IRQ_LIGHTPEN, 0);
+
+        run_frame(&mut vic);
+        vic.trigger_lp_irq();
+        assert_eq!(vic.read_register(0xD019) & IRQ_LIGHTPEN, IRQ_LIGHTPEN);
+    }
+
+    #[test]
+    fn extended_color_mode_picks_background_from_screen_code() {
+        let mem = Memory::new_shared();
+        let mut chargen = vec![0; memory::CHARGEN_SIZE];
+        chargen[8 * 8..9 * 8].copy_from_slice(&[0x66, 0x66, 0x66, 0x7E, 0x66, 0x66, 0x66, 0x00]);
+        mem.borrow_mut()
+            .load_rom(memory::MemType::Chargen, &chargen)
+            .unwrap();
+        // Glyph 8 ("H") with background color register 2.
+        mem.borrow_mut().write_byte(0x0400, 0x88);
+        mem.borrow_mut().write_byte(0xD800, 0x0D);
+
+        let (mut vic, _cpu_ref) = new_vic(mem);
+        vic.write_register(0xD011, 0x5B);
+        vic.write_register(0xD018, 0x14);
+        vic.write_register(0xD021, 0x06);
+        vic.write_register(0xD022, 0x05);
+        vic.write_register(0xD023, 0x02);
+        run_frame(&mut vic);
+
+        let green = utils::fetch_c64_color_rgba(0x0D);
+        let red = utils::fetch_c64_color_rgba(2);
+        let top_row: Vec<u32> = (24..32).map(|x| pixel(&vic, 51, x)).collect();
+        assert_eq!(top_row, [red, green, green, red, red, green, green, red]);
+
+        // ECM with bitmap mode is invalid and blanks the display window to black.
+        vic.write_register(0xD011, 0x7B);
+        run_frame(&mut vic);
+        let black = utils::fetch_c64_color_rgba(0);
+        assert!((24..32).all(|x| pixel(&vic, 51, x) == black));
+    }
+
+    #[test]
+    fn display_disabled_shows_only_border() {
+        let (mut vic, _cpu_ref) = new_vic(Memory::new_shared());
+        vic.write_register(0xD011, 0x0B);
+        vic.write_register(0xD020, 0x0E);
+        vic.write_register(0xD021, 0x06);
+        run_frame(&mut vic);
+
+        let light_blue = utils::fetch_c64_color_rgba(0x0E);
+        assert!(vic.window_buffer.iter().all(|&pixel| pixel == light_blue));
+    }
+
+    #[test]
+    fn switching_to_24_rows_at_the_bottom_opens_the_border() {
+        let (mut vic, _cpu_ref) = new_vic(Memory::new_shared());
+        vic.write_register(0xD020, 0x0E);
+        vic.write_register(0xD021, 0x06);
+        let blue = utils::fetch_c64_color_rgba(6);
+        let light_blue = utils::fetch_c64_color_rgba(0x0E);
+        run_frame(&mut vic);
+        assert_eq!(pixel(&vic, 260, 100), light_blue);
+        // 38 columns narrow the display window.
+        vic.write_register(0xD016, 0x00);
+        run_frame(&mut vic);
+        assert_eq!(pixel(&vic, 100, 26), light_blue);
+        assert_eq!(pixel(&vic, 100, 31), blue);
+
+        // Past the 24-row compare line but before the 25-row one: neither matches.
+        while vic.raster_line != 249 {
+            vic.update();
+        }
+        vic.write_register(0xD011, 0x13);
+        while vic.raster_line != 255 {
+            vic.update();
+        }
+        vic.write_register(0xD011, 0x1B);
+        while vic.raster_line != 0 {
+            vic.update();
+        }
+        assert_eq!(pixel(&vic, 260, 100), blue);
+        // The border stays open through the top of the next frame.
+        while vic.raster_line != 40 {
+            vic.update();
+        }
+        assert_eq!(pixel(&vic, 20, 100), blue);
+    }
+}
@@ -0,0 +1,192 @@
+//! A desktop window for finished frames and host input, built on minifb.
+
+use crate::display::{Display, Scale};
+use crate::io::Key;
+use minifb::{Window, WindowOptions};
+
+/// A minifb window. Changing the scale reopens the window where it was, showing the
+/// last frame, at the largest scale up to the one asked for that fits the screen.
+pub struct WindowDisplay {
+    window: Window,
+    title: String,
+    scale: Scale,
+    /// Largest window, in pixels, the screen takes.
+    screen: (usize, usize),
+    /// Last frame presented, shown again when the window is reopened.
+    frame: Vec<u32>,
+    width: usize,
+    height: usize,
+}
+
+impl WindowDisplay {
+    /// Opens a window for `width` x `height` frames at `scale`, or smaller if the
+    /// screen can't take it.
+    pub fn new(
+        title: &str,
+        width: usize,
+        height: usize,
+        scale: Scale,
+    ) -> Result<WindowDisplay, String> {
+        // minifb doesn't report the screen size, but sizes a screen-fitting window by it.
+        let fitted = open(title, width, height, minifb::Scale::FitScreen)?;
+        let screen = fitted.get_size();
+        let scale = scale.clamp_to(width, height, screen.0, screen.1);
+        let window = if screen == (width * scale.factor(), height * scale.factor()) {
+            fitted
+        } else {
+            drop(fitted);
+            open(title, width, height, minifb_scale(scale))?
+        };
+        Ok(WindowDisplay {
+            window,
+            title: title.to_string(),
+            scale,
+            screen,
+            frame: vec![0; width * height],
+            width,
+            height,
+        })
+    }
+}
+
+fn open(title: &str, width: usize, height: usize, scale: minifb::Scale) -> Result<Window, String> {
+    let options = WindowOptions {
+        scale,
+        ..WindowOptions::default()
+    };
+    Window::new(title, width, height, options).map_err(|e| e.to_string())
+}
+
+fn minifb_scale(scale: Scale) -> minifb::Scale {
+    match scale {
+        Scale::X1 => minifb::Scale::X1,
+        Scale::X2 => minifb::Scale::X2,
+        Scale::X4 => minifb::Scale::X4,
+        Scale::X8 => minifb::Scale::X8,
+    }
+}
+
+fn host_key(key: minifb::Key) -> Option<Key> {
+    Some(match key {
+        minifb::Key::Key0 => Key::Key0,
+        minifb::Key::Key1 => Key::Key1,
+        minifb::Key::Key2 => Key::Key2,
+        minifb::Key::Key3 => Key::Key3,
+        minifb::Key::Key4 => Key::Key4,
+        minifb::Key::Key5 => Key::Key5,
+        minifb::Key::Key6 => Key::Key6,
+        minifb::Key::Key7 => Key::Key7,
+        minifb::Key::Key8 => Key::Key8,
+        minifb::Key::Key9 => Key::Key9,
+        minifb::Key::A => Key::A,
+        minifb::Key::B => Key::B,
+        minifb::Key::C => Key::C,
+        minifb::Key::D => Key::D,
+        minifb::Key::E => Key::E,
+        minifb::Key::F => Key::F,
+        minifb::Key::G => Key::G,
+        minifb::Key::H => Key::H,
+        minifb::Key::I => Key::I,
+        minifb::Key::J => Key::J,
+        minifb::Key::K => Key::K,
+        minifb::Key::L => Key::L,
+        minifb::Key::M => Key::M,
+        minifb::Key::N => Key::N,
+        minifb::Key::O => Key::O,
+        minifb::Key::P => Key::P,
+        minifb::Key::Q => Key::Q,
+        minifb::Key::R => Key::R,
+        minifb::Key::S => Key::S,
+        minifb::Key::T => Key::T,
+        minifb::Key::U => Key::U,
+        minifb::Key::V => Key::V,
+        minifb::Key::W => Key::W,
+        minifb::Key::X => Key::X,
+        minifb::Key::Y => Key::Y,
+        minifb::Key::Z => Key::Z,
+        minifb::Key::F1 => Key::F1,
+        minifb::Key::F3 => Key::F3,
+        minifb::Key::F5 => Key::F5,
+        minifb::Key::F7 => Key::F7,
+        minifb::Key::F8 => Key::F8,
+        minifb::Key::F9 => Key::F9,
+        minifb::Key::F10 => Key::F10,
+        minifb::Key::F11 => Key::F11,
+        minifb::Key::F12 => Key::F12,
+        minifb::Key::Space => Key::Space,
+        minifb::Key::Enter => Key::Enter,
+        minifb::Key::Backspace => Key::Backspace,
+        minifb::Key::Escape => Key::Escape,
+        minifb::Key::PageUp => Key::PageUp,
+        minifb::Key::Home => Key::Home,
+        minifb::Key::Up => Key::Up,
+        minifb::Key::Down => Key::Down,
+        minifb::Key::Left => Key::Left,
+        minifb::Key::Right => Key::Right,
+        minifb::Key::LeftShift => Key::LeftShift,
+        minifb::Key::RightShift => Key::RightShift,
+        minifb::Key::LeftCtrl => Key::LeftCtrl,
+        minifb::Key::Tab => Key::Tab,
+        minifb::Key::Comma => Key::Comma,
+        minifb::Key::Period => Key::Period,
+        minifb::Key::Slash => Key::Slash,
+        minifb::Key::Semicolon => Key::Semicolon,
+        minifb::Key::Apostrophe => Key::Apostrophe,
+        minifb::Key::Equal => Key::Equal,
+        minifb::Key::Minus => Key::Minus,
+        minifb::Key::LeftBracket => Key::LeftBracket,
+        minifb::Key::RightBracket => Key::RightBracket,
+        minifb::Key::Backslash => Key::Backslash,
+        minifb::Key::NumPad0 => Key::NumPad0,
+        minifb::Key::NumPad2 => Key::NumPad2,
+        minifb::Key::NumPad4 => Key::NumPad4,
+        minifb::Key::NumPad6 => Key::NumPad6,
+        minifb::Key::NumPad8 => Key::NumPad8,
+        minifb::Key::NumPadPlus => Key::NumPadPlus,
+        minifb::Key::NumPadMinus => Key::NumPadMinus,
+        _ => return None,
+    })
+}
+
+impl Display for WindowDisplay {
+    fn update_with_buffer(&mut self, buffer: &[u32], width: usize, height: usize) {
+        self.frame.clear();
+        self.frame.extend_from_slice(buffer);
+        self.width = width;
+        self.height = height;
+        // Only a buffer too small for its size fails, and ZPC hands over whole frames.
+        let _ = self.window.update_with_buffer(buffer, width, height);
+    }
+
+    fn pressed_keys(&mut self) -> Vec<Key> {
+        self.window
+            .get_keys()
+            .into_iter()
+            .filter_map(host_key)
+            .collect()
+    }
+
+    fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    /// Reopens the window at `scale`, clamped to the screen, keeping its position and
+    /// frame. The old window and scale stay if the new window can't be opened.
+    fn set_scale(&mut self, scale: Scale) -> Scale {
+        let scale = scale.clamp_to(self.width, self.height, self.screen.0, self.screen.1);
+        if scale == self.scale {
+            return scale;
+        }
+        let (x, y) = self.window.get_position();
+        match open(&self.title, self.width, self.height, minifb_scale(scale)) {
+            Ok(window) => self.window = window,
+            Err(_) => return self.scale,
+        }
+        self.window.set_position(x, y);
+        self.scale = scale;
+        let _ = self
+            .window
+            .update_with_buffer(&self.frame, self.width, self.height);
+        scale
+    }
+}
@@ -0,0 +1,2465 @@
+//! The machine: owns the chips and drives them from a single clock loop.
+
+use crate::audio;
+use crate::cia;
+use crate::clock;
+use crate::cpu;
+use crate::crt;
+use crate::display::{Display, HeadlessDisplay, Scale};
+use crate::drive;
+use crate::io;
+use crate::memory;
+use crate::reu;
+use crate::script;
+use crate::sid;
+use crate::snapshot::{self, StateReader, StateWriter};
+use crate::t64;
+use crate::tape;
+use crate::utils;
+use crate::utils::Region;
+use crate::vic;
+use std::collections::VecDeque;
+use std::io::Write;
+
+/// BASIC's main input loop; reaching it means the machine finished booting.
+const BASIC_READY_LOOP: u16 = 0xA480;
+/// BASIC's warm start vector, taken by RUN/STOP-RESTORE to break into a program.
+const BASIC_WARM_START_VECTOR: u16 = 0xA002;
+/// Frames a BASIC reload waits for the input loop before it is dropped.
+const RELOAD_TIMEOUT_FRAMES: u64 = 50;
+/// KERNAL LOAD, just after it has saved its arguments in zero page.
+const KERNAL_LOAD: u16 = 0xF4A5;
+/// KERNAL SAVE, once the start address has been copied to $C1/$C2.
+const KERNAL_SAVE: u16 = 0xF5ED;
+/// KERNAL zero page used by LOAD.
+const KERNAL_STATUS: u16 = 0x0090;
+const KERNAL_VERIFY_FLAG: u16 = 0x0093;
+const KERNAL_END_ADDRESS: u16 = 0x00AE;
+const KERNAL_FILENAME_LEN: u16 = 0x00B7;
+const KERNAL_SECONDARY_ADDRESS: u16 = 0x00B9;
+const KERNAL_DEVICE: u16 = 0x00BA;
+const KERNAL_FILENAME: u16 = 0x00BB;
+const KERNAL_LOAD_ADDRESS: u16 = 0x00C3;
+const KERNAL_SAVE_START: u16 = 0x00C1;
+/// Error for PRG data without room for a load address.
+const PRG_TOO_SHORT: &str = "too short for a PRG";
+
+/// Default start of BASIC program text.
+const BASIC_START: u16 = 0x0801;
+/// KERNAL keyboard buffer and its fill count.
+const KEYBOARD_BUFFER: u16 = 0x0277;
+const KEYBOARD_BUFFER_LEN: u16 = 0x00C6;
+const KEYBOARD_BUFFER_SIZE: usize = 10;
+
+/// RAM vectors the ROMs install at cold start, for `ZPC::fast_boot`.
+const FAST_BOOT_BASIC_VECTORS: [u16; 6] = [0xE38B, 0xA483, 0xA57C, 0xA71A, 0xA7E4, 0xAE86];
+const FAST_BOOT_KERNAL_VECTORS: [u16; 16] = [
+    0xEA31, 0xFE66, 0xFE47, 0xF34A, 0xF291, 0xF20E, 0xF250, 0xF333, 0xF157, 0xF1CA, 0xF6ED, 0xF13E,
+    0xF32F, 0xFE66, 0xF4A5, 0xF5ED,
+];
+/// Screen rows and text printed by the cold start.
+const FAST_BOOT_BANNER: [(u16, &str); 3] = [
+    (1, "    **** COMMODORE 64 BASIC V2 ****"),
+    (3, " 64K RAM SYSTEM  38911 BASIC BYTES FREE"),
+    (5, "READY."),
+];
+
+/// Screen code of an upper-case ASCII character in the default character set.
+fn screen_code(c: u8) -> u8 {
+    match c {
+        b'@'..=b'Z' => c - 0x40,
+        _ => c,
+    }
+}
+
+/// Host files holding the system ROMs. ROMs left as `None` are not installed.
+#[derive(Clone, Debug, Default)]
+pub struct RomPaths {
+    pub kernal: Option<String>,
+    pub basic: Option<String>,
+    pub chargen: Option<String>,
+}
+
+/// Everything `ZPC::with_config` sets up a machine from.
+#[derive(Clone, Debug)]
+pub struct ZpcConfig {
+    pub region: Region,
+    pub roms: RomPaths,
+    /// Zoom hint for the display.
+    pub scale: Scale,
+    pub palette: utils::Palette,
+    pub border_size: vic::BorderSize,
+    /// Start without real-time pacing.
+    pub warp: bool,
+    /// File to autostart, as taken by `ZPC::with_program`; empty for none.
+    pub autoload: String,
+    /// What RAM holds at power-on.
+    pub ram_pattern: memory::RamPattern,
+    /// Seed for everything pseudo-random in the machine. Two machines with the same
+    /// seed, ROMs and input run bit-for-bit identically.
+    pub seed: u64,
+    /// Instructions the CPU may run before it stops; `None` for no limit.
+    pub max_instructions: Option<u64>,
+}
+
+impl Default for ZpcConfig {
+    fn default() -> Self {
+        ZpcConfig {
+            region: Region::Pal,
+            roms: RomPaths::default(),
+            scale: Scale::X2,
+            palette: utils::Palette::default(),
+            border_size: vic::BorderSize::default(),
+            warp: false,
+            autoload: String::new(),
+            ram_pattern: memory::RamPattern::Patterned,
+            seed: utils::DEFAULT_SEED,
+            max_instructions: None,
+        }
+    }
+}
+
+/// Stop condition for `ZPC::run_until`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunUntil {
+    /// PC holds this address.
+    Pc(u16),
+    /// This many cycles have run.
+    Cycles(u64),
+    /// The CPU stops at one of its breakpoints.
+    Breakpoint,
+}
+
+/// Why `ZPC::run_until` returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    ReachedPc,
+    CyclesElapsed,
+    Breakpoint,
+    Halted,
+    /// The CPU ran out of instructions; see `CPU::set_instruction_limit`.
+    InstructionLimit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Power cycle: RAM is cleared and every chip starts over.
+    Cold,
+    /// Reset of the CPU alone; memory and chip state survive.
+    Warm,
+}
+
+pub struct ZPC<D: Display = HeadlessDisplay> {
+    pub mem_ref: memory::MemShared,
+    pub cpu_ref: cpu::CPUShared,
+    pub vic_ref: vic::VICShared,
+    pub cia1_ref: cia::CIAShared,
+    pub cia2_ref: cia::CIAShared,
+    pub sid_ref: sid::SIDShared,
+    /// RAM Expansion Unit in the expansion port, if one is attached.
+    pub reu_ref: Option<reu::ReuShared>,
+    pub region: Region,
+    pub clock: clock::Clock,
+    pub io: io::IO,
+    pub datasette: tape::Datasette,
+    pub drive: drive::Drive1541,
+    /// Receives each finished frame and supplies the keys held on the host.
+    pub display: D,
+    /// Copy of the last frame handed to the display, for `frame_buffer`, and its width
+    /// and height.
+    frame: Vec<u32>,
+    frame_size: (usize, usize),
+    /// Cycles emulated since the machine was created.
+    pub cycle_count: u64,
+    /// SID output waiting for `pull_audio`.
+    audio: audio::AudioRing,
+    /// Cycles run since the SID last rendered, in units of 1/sample rate, so the sample
+    /// count tracks the cycle count exactly.
+    audio_remainder: u64,
+    /// While paused, `update` leaves every chip untouched.
+    paused: bool,
+    /// PETSCII text waiting to be typed by `paste_text`.
+    paste_queue: VecDeque<u8>,
+    /// Scripted matrix key events from `type_matrix`, by the VIC frame they apply on.
+    key_events: Vec<(u64, io::KeyEvent)>,
+    /// Print a diagnostic when the CPU locks up on a JAM opcode.
+    pub report_halt: bool,
+    /// Where `run` reports breakpoints, watchpoints and halts; stdout by default.
+    debug_output: Box<dyn Write>,
+    /// PRG or T64 to load and autostart once BASIC reaches its ready loop, TAP or
+    /// D64 to start loading from at that point, or a CRT to plug in before the first
+    /// cycle runs.
+    file_to_load: String,
+    /// Host directory KERNAL SAVE writes PRG files to, when the SAVE trap is on.
+    save_dir: Option<std::path::PathBuf>,
+    /// Host directory the F8 hotkey writes screenshots to.
+    screenshot_dir: std::path::PathBuf,
+    /// Host keys held at the last frame, so hotkeys fire once per press.
+    held_hotkeys: Vec<io::Key>,
+    /// Zoom hint the display last settled on.
+    scale: Scale,
+    /// RAM as taken by `snapshot_ram`, for `diff_ram`.
+    ram_snapshot: Option<Vec<u8>>,
+    /// PRG queued by `reload_prg` until it can be swapped in.
+    pending_reload: Option<PendingReload>,
+}
+
+/// A PRG from `reload_prg` waiting for a safe point to be swapped in.
+struct PendingReload {
+    data: Vec<u8>,
+    /// Cycle count at which a BASIC program still short of the input loop is dropped.
+    deadline: u64,
+    /// Set once the running program has been broken into through the warm start.
+    broken_in: bool,
+}
+
+impl ZPC<HeadlessDisplay> {
+    /// A headless machine for running raw 6502 code: no ROMs, the processor port set so
+    /// all of $0002-$FFFF is RAM, `program` stored at `load_address`, and the CPU reset
+    /// through a vector pointing at it.
+    pub fn new_bare(program: &[u8], load_address: u16) -> ZPC {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        {
+            let mut mem = zpc.mem_ref.borrow_mut();
+            mem.write_byte(memory::PORT_DDR, 0x07);
+            mem.write_byte(memory::PORT_DATA, 0x00);
+            mem.load(load_address, program);
+            mem.load(cpu::RESET_VECTOR, &load_address.to_le_bytes());
+        }
+        zpc.reset(ResetKind::Warm);
+        zpc
+    }
+}
+
+impl<D: Display> ZPC<D> {
+    /// A machine with default settings, as from `ZpcConfig::default()`, at the given zoom
+    /// and region. No ROMs are installed.
+    pub fn new(scale: Scale, region: Region, display: D) -> ZPC<D> {
+        let config = ZpcConfig {
+            scale,
+            region,
+            ..ZpcConfig::default()
+        };
+        let mut zpc = ZPC::with_program(&config.autoload, config.region, display);
+        zpc.apply_config(&config);
+        zpc
+    }
+
+    /// A bare machine without ROMs that autostarts `prg_to_load`, as described for
+    /// `ZpcConfig::autoload`.
+    pub fn with_program(prg_to_load: &str, region: Region, display: D) -> ZPC<D> {
+        let mem_ref = memory::Memory::new_shared();
+        let cpu_ref = cpu::CPU::new_shared(mem_ref.clone());
+        let vic_ref = vic::VIC::new_shared(mem_ref.clone(), cpu_ref.clone());
+        let cia1_ref = cia::CIA::new_shared(cpu_ref.clone(), true);
+        let cia2_ref = cia::CIA::new_shared(cpu_ref.clone(), false);
+        let sid_ref = sid::SID::new_shared(region.clock_freq(), sid::DEFAULT_SAMPLE_RATE);
+        vic_ref.borrow_mut().set_region(region);
+        cia1_ref.borrow_mut().set_region(region);
+        cia2_ref.borrow_mut().set_region(region);
+        cia1_ref.borrow_mut().set_vic_reference(vic_ref.clone());
+        cia2_ref.borrow_mut().set_vic_reference(vic_ref.clone());
+        cpu_ref.borrow_mut().set_references(
+            vic_ref.clone(),
+            cia1_ref.clone(),
+            cia2_ref.clone(),
+            sid_ref.clone(),
+        );
+
+        ZPC {
+            mem_ref,
+            cpu_ref,
+            vic_ref,
+            cia1_ref,
+            cia2_ref,
+            sid_ref,
+            reu_ref: None,
+            region,
+            clock: clock::Clock::new(region.clock_freq()),
+            io: io::IO::new(),
+            datasette: tape::Datasette::new(),
+            drive: drive::Drive1541::default(),
+            display,
+            frame: vec![0; vic::SCREEN_WIDTH * vic::SCREEN_HEIGHT],
+            frame_size: (vic::SCREEN_WIDTH, vic::SCREEN_HEIGHT),
+            cycle_count: 0,
+            audio: audio::AudioRing::default(),
+            audio_remainder: 0,
+            paused: false,
+            paste_queue: VecDeque::new(),
+            key_events: Vec::new(),
+            report_halt: true,
+            debug_output: Box::new(std::io::stdout()),
+            file_to_load: String::from(prg_to_load),
+            save_dir: None,
+            screenshot_dir: std::path::PathBuf::from("."),
+            held_hotkeys: Vec::new(),
+            scale: Scale::X2,
+            ram_snapshot: None,
+            pending_reload: None,
+        }
+    }
+
+    /// Creates the machine and installs the ROMs named in `roms`.
+    pub fn with_roms(
+        prg_to_load: &str,
+        region: Region,
+        display: D,
+        roms: &RomPaths,
+    ) -> Result<ZPC<D>, String> {
+        let zpc = ZPC::with_program(prg_to_load, region, display);
+        let files = [
+            (memory::MemType::Kernal, &roms.kernal),
+            (memory::MemType::Basic, &roms.basic),
+            (memory::MemType::Chargen, &roms.chargen),
+        ];
+        for (rom, path) in files {
+            if let Some(path) = path {
+                let data = utils::open_file(path, 0).map_err(|e| format!("{}: {}", path, e))?;
+                zpc.mem_ref
+                    .borrow_mut()
+                    .load_rom(rom, &data)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+            }
+        }
+        Ok(zpc)
+    }
+
+    /// Creates the machine described by `config`, failing if a ROM can't be installed.
+    pub fn with_config(config: ZpcConfig, display: D) -> Result<ZPC<D>, String> {
+        let mut zpc = ZPC::with_roms(&config.autoload, config.region, display, &config.roms)?;
+        zpc.apply_config(&config);
+        Ok(zpc)
+    }
+
+    /// Applies everything in `config` but the ROMs, region and autoload file, which the
+    /// machine is built with, and cold-resets it.
+    fn apply_config(&mut self, config: &ZpcConfig) {
+        {
+            let mut mem = self.mem_ref.borrow_mut();
+            mem.set_ram_pattern(config.ram_pattern);
+            mem.set_seed(config.seed);
+        }
+        self.reset(ResetKind::Cold);
+        self.cpu_ref
+            .borrow_mut()
+            .set_instruction_limit(config.max_instructions);
+        self.set_scale(config.scale);
+        self.set_palette(config.palette);
+        self.set_border_size(config.border_size);
+        self.clock.set_warp(config.warp);
+    }
+
+    /// Resets the machine. A warm reset only re-vectors the CPU through $FFFC; a cold
+    /// reset clears RAM and resets every chip as well, as at power-on.
+    pub fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            self.cpu_ref.borrow_mut().reset();
+            return;
+        }
+        self.mem_ref.borrow_mut().reset();
+        self.vic_ref.borrow_mut().reset();
+        self.cia1_ref.borrow_mut().reset();
+        self.cia2_ref.borrow_mut().reset();
+        self.sid_ref.borrow_mut().reset();
+        if let Some(reu_ref) = &self.reu_ref {
+            reu_ref.borrow_mut().reset();
+        }
+        self.cpu_ref.borrow_mut().reset();
+    }
+
+    /// Cold-resets and then writes the state the KERNAL and BASIC cold start would leave
+    /// behind, with the CPU parked in BASIC's input loop. This skips the RAM test and is
+    /// not cycle-accurate: chip timing, the SID and the cursor blink phase all differ
+    /// from a real boot.
+    pub fn fast_boot(&mut self) {
+        self.reset(ResetKind::Cold);
+        let mut cpu = self.cpu_ref.borrow_mut();
+        let write_word = |cpu: &mut cpu::CPU, addr: u16, value: u16| {
+            cpu.write_byte(addr, value as u8);
+            cpu.write_byte(addr + 1, (value >> 8) as u8);
+        };
+
+        cpu.write_byte(0x0000, 0x2F);
+        cpu.write_byte(0x0001, 0x37);
+
+        // BASIC program pointers for an empty program, and the KERNAL memory bounds.
+        write_word(&mut cpu, 0x002B, BASIC_START);
+        for ptr in [0x2D, 0x2F, 0x31] {
+            write_word(&mut cpu, ptr, BASIC_START + 2);
+        }
+        write_word(&mut cpu, 0x0033, 0xA000);
+        write_word(&mut cpu, 0x0037, 0xA000);
+        write_word(&mut cpu, BASIC_START, 0);
+        write_word(&mut cpu, 0x0281, 0x0800);
+        write_word(&mut cpu, 0x0283, 0xA000);
+        cpu.write_byte(0x0288, 0x04);
+        cpu.write_byte(0x0286, 0x0E);
+
+        // BASIC and KERNAL RAM vectors, as copied from ROM at cold start.
+        for (i, &vector) in FAST_BOOT_BASIC_VECTORS.iter().enumerate() {
+            write_word(&mut cpu, 0x0300 + 2 * i as u16, vector);
+        }
+        for (i, &vector) in FAST_BOOT_KERNAL_VECTORS.iter().enumerate() {
+            write_word(&mut cpu, 0x0314 + 2 * i as u16, vector);
+        }
+
+        for (addr, value) in [
+            (0xD011, 0x1B),
+            (0xD016, 0xC8),
+            (0xD018, 0x14),
+            (0xD020, 0x0E),
+            (0xD021, 0x06),
+            (0xDD02, 0x3F),
+            (0xDD00, 0x97),
+        ] {
+            cpu.write_byte(addr, value);
+        }
+        let jiffy_latch: u16 = match self.region {
+            Region::Pal => 0x4025,
+            Region::Ntsc => 0x4295,
+        };
+        write_word(&mut cpu, 0xDC04, jiffy_latch);
+        cpu.write_byte(0xDC0D, 0x81);
+        cpu.write_byte(0xDC0E, 0x11);
+
+        // Screen: cleared, banner printed, cursor on the line after READY.
+        for i in 0..1000 {
+            cpu.write_byte(0x0400 + i, 0x20);
+            cpu.write_byte(0xD800 + i, 0x0E);
+        }
+        for row in 0..25u16 {
+            cpu.write_byte(0x00D9 + row, 0x80 | ((0x0400 + row * 40) >> 8) as u8);
+        }
+        for (row, text) in FAST_BOOT_BANNER {
+            for (col, c) in text.bytes().enumerate() {
+                cpu.write_byte(0x0400 + row * 40 + col as u16, screen_code(c));
+            }
+        }
+        let cursor_row = FAST_BOOT_BANNER[FAST_BOOT_BANNER.len() - 1].0 + 1;
+        cpu.write_byte(0x00D6, cursor_row as u8);
+        cpu.write_byte(0x00D3, 0);
+        write_word(&mut cpu, 0x00D1, 0x0400 + cursor_row * 40);
+        write_word(&mut cpu, 0x00F3, 0xD800 + cursor_row * 40);
+
+        cpu.sp = 0xFA;
+        cpu.p &= !(cpu::StatusFlag::InterruptDisable as u8);
+        cpu.pc = BASIC_READY_LOOP;
+    }
+
+    /// Snapshots the whole machine: CPU, RAM and ROM, both CIAs, the VIC, the SID, the
+    /// clock and the REU, cartridge banking, datasette and drive. Cartridge ROMs aren't
+    /// included, and IEC bus devices can't be saved, so this fails while any are attached.
+    pub fn save_state(&self) -> Result<Vec<u8>, String> {
+        if self.cia2_ref.borrow().has_iec_devices() {
+            return Err(String::from(
+                "IEC devices can't be saved; detach them before taking a snapshot",
+            ));
+        }
+        let mut w = StateWriter::new();
+        for &byte in snapshot::MAGIC {
+            w.u8(byte);
+        }
+        w.u8(snapshot::VERSION);
+        w.u8(self.region as u8);
+        w.section(|w| self.cpu_ref.borrow().save_state(w));
+        w.section(|w| self.mem_ref.borrow().save_state(w));
+        w.section(|w| self.vic_ref.borrow().save_state(w));
+        w.section(|w| self.cia1_ref.borrow().save_state(w));
+        w.section(|w| self.cia2_ref.borrow().save_state(w));
+        w.section(|w| self.sid_ref.borrow().save_state(w));
+        w.section(|w| {
+            w.bool(self.reu_ref.is_some());
+            if let Some(reu_ref) = &self.reu_ref {
+                let reu = reu_ref.borrow();
+                w.u32(reu.ram().len() as u32);
+                reu.save_state(w);
+            }
+        });
+        w.section(|w| self.datasette.save_state(w));
+        w.section(|w| self.drive.save_state(w));
+        w.section(|w| {
+            w.u64(self.cycle_count);
+            w.u64(self.clock.ticks());
+        });
+        Ok(w.into_bytes())
+    }
+
+    /// Restores a snapshot taken by `save_state`. Blobs from another version or region
+    /// are rejected before anything is changed; a corrupt blob can leave the machine
+    /// partly restored.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        for byte in magic.iter_mut() {
+            *byte = r.u8()?;
+        }
+        if &magic != snapshot::MAGIC {
+            return Err(String::from("not a snapshot"));
+        }
+        let version = r.u8()?;
+        if version != snapshot::VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+        if r.u8()? != self.region as u8 {
+            return Err(String::from("snapshot was taken in another region"));
+        }
+
+        r.section(|r| self.cpu_ref.borrow_mut().load_state(r))?;
+        r.section(|r| self.mem_ref.borrow_mut().load_state(r))?;
+        r.section(|r| self.vic_ref.borrow_mut().load_state(r))?;
+        r.section(|r| self.cia1_ref.borrow_mut().load_state(r))?;
+        r.section(|r| self.cia2_ref.borrow_mut().load_state(r))?;
+        r.section(|r| self.sid_ref.borrow_mut().load_state(r))?;
+        r.section(|r| {
+            if !r.bool()? {
+                self.detach_reu();
+                return Ok(());
+            }
+            let size = r.u32()? as usize;
+            if !size.is_power_of_two() || size > reu::MAX_SIZE {
+                return Err(format!("invalid REU size {}", size));
+            }
+            let attached = self.reu_ref.as_ref().map(|reu| reu.borrow().ram().len());
+            if attached != Some(size) {
+                self.attach_reu(size);
+            }
+            self.reu_ref.as_ref().unwrap().borrow_mut().load_state(r)
+        })?;
+        r.section(|r| self.datasette.load_state(r))?;
+        r.section(|r| self.drive.load_state(r))?;
+        r.section(|r| {
+            self.cycle_count = r.u64()?;
+            self.clock.set_ticks(r.u64()?);
+            Ok(())
+        })
+    }
+
+    /// Writes `len` bytes of raw RAM from `start` to a file.
+    pub fn dump_ram_to_file(&self, filename: &str, start: u16, len: usize) -> std::io::Result<()> {
+        std::fs::write(filename, self.mem_ref.borrow().dump_range(start, len))
+    }
+
+    /// Loads a whole file into raw RAM at `start`. Returns the number of bytes loaded.
+    pub fn load_ram_from_file(&mut self, filename: &str, start: u16) -> std::io::Result<usize> {
+        let data = utils::open_file(filename, 0)?;
+        self.mem_ref.borrow_mut().load_range(start, &data);
+        Ok(data.len())
+    }
+
+    /// Remembers the whole of RAM for a later `diff_ram`.
+    pub fn snapshot_ram(&mut self) {
+        self.ram_snapshot = Some(self.mem_ref.borrow().dump_range(0, memory::MEM_SIZE));
+    }
+
+    /// Bytes of RAM that changed since `snapshot_ram`, as (address, old, new), or `None`
+    /// if no snapshot was taken.
+    pub fn diff_ram(&self) -> Option<Vec<(u16, u8, u8)>> {
+        let snapshot = self.ram_snapshot.as_ref()?;
+        let diff = self.mem_ref.borrow().diff(snapshot);
+        Some(diff.expect("snapshot_ram saves all of RAM"))
+    }
+
+    /// Writes the last completed frame to a PNG, or a black image if no frame has been
+    /// drawn yet.
+    pub fn save_screenshot(&self, filename: &str) -> std::io::Result<()> {
+        let (width, height) = self.frame_dimensions();
+        utils::write_png(filename, width, height, &self.frame)
+    }
+
+    /// Sets where the F8 hotkey saves screenshots; the working directory by default.
+    pub fn set_screenshot_dir(&mut self, dir: impl Into<std::path::PathBuf>) {
+        self.screenshot_dir = dir.into();
+    }
+
+    /// Saves a screenshot named after the current time into the screenshot directory,
+    /// reporting where it went or why it failed on the debug output.
+    fn save_timestamped_screenshot(&mut self) {
+        let millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let path = self
+            .screenshot_dir
+            .join(format!("screenshot-{}.png", millis));
+        let path = path.to_string_lossy().into_owned();
+        let _ = match self.save_screenshot(&path) {
+            Ok(()) => writeln!(self.debug_output, "Screenshot saved to {}", path),
+            Err(e) => writeln!(self.debug_output, "Screenshot {} failed: {}", path, e),
+        };
+    }
+
+    /// The last completed frame as 0x00RRGGBB pixels, row by row, for displays that draw
+    /// it themselves. Black until the first frame is done; unchanged until the next one.
+    pub fn frame_buffer(&self) -> &[u32] {
+        &self.frame
+    }
+
+    /// Width and height of `frame_buffer` in pixels.
+    pub fn frame_dimensions(&self) -> (usize, usize) {
+        self.frame_size
+    }
+
+    /// Zooms the display and records the scale it settled on, which may be smaller if
+    /// the screen can't take `scale`.
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = self.display.set_scale(scale);
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    pub fn set_palette(&mut self, palette: utils::Palette) {
+        self.vic_ref.borrow_mut().set_palette(palette);
+    }
+
+    /// Changes how much border the frames show, from the next frame on.
+    pub fn set_border_size(&mut self, border_size: vic::BorderSize) {
+        self.vic_ref.borrow_mut().set_border_size(border_size);
+    }
+
+    /// Presses exactly the given host keys on the C64 keyboard.
+    pub fn set_keys(&mut self, pressed: &[io::Key]) {
+        self.io.update(pressed, &mut self.cia1_ref.borrow_mut());
+    }
+
+    /// Stops keys from showing up as joystick input and joystick input as keys. Off by
+    /// default, which keeps the hardware's cross-talk between the two.
+    pub fn set_joystick_keyboard_isolation(&mut self, isolated: bool) {
+        self.cia1_ref.borrow_mut().set_joystick_isolation(isolated);
+    }
+
+    /// Switches between real-time pacing and running as fast as the host allows.
+    pub fn toggle_warp(&mut self) {
+        self.clock.toggle_warp();
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.clock.restart();
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Runs the machine as fast as possible until `target` is met, the CPU halts, stops
+    /// at a breakpoint or runs out of instructions, or `max_cycles` have run, whichever
+    /// comes first.
+    pub fn run_until(&mut self, target: RunUntil, max_cycles: u64) -> RunOutcome {
+        let budget = match target {
+            RunUntil::Cycles(cycles) => cycles.min(max_cycles),
+            _ => max_cycles,
+        };
+        for _ in 0..budget {
+            if let RunUntil::Pc(pc) = target {
+                if self.cpu_ref.borrow().pc == pc {
+                    return RunOutcome::ReachedPc;
+                }
+            }
+            if self.cpu_ref.borrow().is_halted() {
+                return RunOutcome::Halted;
+            }
+            if self.cpu_ref.borrow().instruction_limit_reached() {
+                return RunOutcome::InstructionLimit;
+            }
+            if self.update() {
+                return RunOutcome::Breakpoint;
+            }
+        }
+        match target {
+            RunUntil::Pc(pc) if self.cpu_ref.borrow().pc == pc => RunOutcome::ReachedPc,
+            _ => RunOutcome::CyclesElapsed,
+        }
+    }
+
+    /// Runs the machine in real time until the CPU halts or reaches a breakpoint or
+    /// watchpoint.
+    pub fn run(&mut self) {
+        while !self.cpu_ref.borrow().is_halted() && self.display.is_open() {
+            if self.paused {
+                self.present_paused_frame();
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+
+            if !self.clock.tick() {
+                continue;
+            }
+
+            if self.update() {
+                let (pc, limited) = {
+                    let cpu = self.cpu_ref.borrow();
+                    (cpu.pc, cpu.instruction_limit_reached())
+                };
+                let reason = if limited {
+                    "Instruction limit reached"
+                } else {
+                    "Breakpoint"
+                };
+                self.report_stop(&format!("{} at ${:04X}", reason, pc));
+                return;
+            }
+
+            let hit = self.cpu_ref.borrow().take_watch_hit();
+            if let Some(hit) = hit {
+                let access = if hit.access == memory::WATCH_READ {
+                    "read"
+                } else {
+                    "write"
+                };
+                self.report_stop(&format!(
+                    "Watchpoint: {} ${:04X} = ${:02X}",
+                    access, hit.addr, hit.value
+                ));
+                return;
+            }
+        }
+
+        if self.report_halt {
+            let pc = self.cpu_ref.borrow().pc;
+            let _ = writeln!(self.debug_output, "CPU halted at ${:04X}", pc);
+        }
+    }
+
+    /// First half of a cycle, when the VIC has the bus: its fetches see memory as the
+    /// previous cycle left it, and RDY is settled before the CPU's half.
+    fn phi1(&mut self) {
+        self.vic_ref.borrow_mut().update();
+    }
+
+    /// Second half of a cycle, when the CPU and other bus masters have the bus. Returns
+    /// true if the CPU stopped at a breakpoint.
+    fn phi2(&mut self) -> bool {
+        // The CIAs count before the CPU runs, so a timer read sees this cycle's value,
+        // including the latch reloaded by an underflow.
+        self.cia1_ref.borrow_mut().update();
+        self.cia2_ref.borrow_mut().update();
+        // After the VIC so a running DMA transfer keeps RDY low.
+        if let Some(reu_ref) = &self.reu_ref {
+            reu_ref.borrow_mut().update();
+        }
+        self.datasette.update(
+            &mut self.mem_ref.borrow_mut(),
+            &mut self.cia1_ref.borrow_mut(),
+        );
+        self.cpu_ref.borrow_mut().update()
+    }
+
+    /// Moves buffered SID samples (mono, at the SID's sample rate) into `out` for an
+    /// audio backend and returns how many were written. About a quarter of a second is
+    /// kept; older samples are dropped if nobody pulls.
+    pub fn pull_audio(&mut self, out: &mut [i16]) -> usize {
+        self.audio.pull(out)
+    }
+
+    fn render_audio(&mut self, cycles: u64) {
+        let mut sid = self.sid_ref.borrow_mut();
+        self.audio_remainder += cycles * sid.sample_rate() as u64;
+        let clock_freq = self.region.clock_freq() as u64;
+        let mut count = (self.audio_remainder / clock_freq) as usize;
+        self.audio_remainder %= clock_freq;
+        let mut chunk = [0; 16];
+        while count > 0 {
+            let samples = &mut chunk[..count.min(16)];
+            sid.render(samples);
+            for &sample in samples.iter() {
+                self.audio.push(sample);
+            }
+            count -= samples.len();
+        }
+    }
+
+    /// Sends breakpoint, watchpoint and halt reports somewhere other than stdout, such as
+    /// a log file.
+    pub fn set_debug_output(&mut self, output: Box<dyn Write>) {
+        self.debug_output = output;
+    }
+
+    /// Writes why `run` stopped, followed by the instruction at PC.
+    fn report_stop(&mut self, reason: &str) {
+        let cpu = self.cpu_ref.borrow();
+        // A broken debug output isn't worth stopping the emulator over.
+        let _ = writeln!(self.debug_output, "{}", reason)
+            .and_then(|_| cpu.debug_instruction(&mut self.debug_output));
+    }
+
+    /// Advances every chip by one clock cycle. Returns true if the CPU stopped at a
+    /// breakpoint.
+    pub fn update(&mut self) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        self.cycle_count += 1;
+        self.phi1();
+        let breakpoint_hit = self.phi2();
+
+        // The SID renders a raster line of audio at a time, so register writes take
+        // effect at line granularity.
+        let cycles_per_line = self.region.cycles_per_line() as u64;
+        if self.cycle_count.is_multiple_of(cycles_per_line) {
+            self.render_audio(cycles_per_line);
+        }
+
+        if self.vic_ref.borrow().frame_ready {
+            self.present_frame();
+        }
+
+        if !self.paste_queue.is_empty()
+            && self
+                .cycle_count
+                .is_multiple_of(self.region.cycles_per_frame() as u64)
+        {
+            self.feed_paste_queue();
+        }
+
+        if self.pending_reload.is_some() {
+            self.apply_pending_reload();
+        }
+
+        if self.at_kernal_entry(KERNAL_LOAD) {
+            self.trap_load();
+        }
+
+        if self.save_dir.is_some() && self.at_kernal_entry(KERNAL_SAVE) {
+            self.trap_save();
+        }
+
+        if self.file_to_load.ends_with(".crt") {
+            let filename = std::mem::take(&mut self.file_to_load);
+            if let Err(e) = self.load_crt(&filename) {
+                let _ = writeln!(self.debug_output, "Couldn't load {}", e);
+            }
+        }
+
+        if !self.file_to_load.is_empty() && self.cpu_ref.borrow().pc == BASIC_READY_LOOP {
+            let filename = std::mem::take(&mut self.file_to_load);
+            if let Err(e) = self.autostart(&filename) {
+                let _ = writeln!(self.debug_output, "Couldn't load {}", e);
+            }
+        }
+
+        breakpoint_hit
+    }
+
+    /// True when the CPU is about to run the KERNAL routine at `entry`, rather than
+    /// whatever RAM or cartridge code happens to sit at that address.
+    fn at_kernal_entry(&self, entry: u16) -> bool {
+        self.cpu_ref.borrow().pc == entry
+            && self.mem_ref.borrow().bank_at(entry) == memory::BankSource::Kernal
+    }
+
+    /// Loads `filename` by its extension and types whatever starts it.
+    fn autostart(&mut self, filename: &str) -> Result<(), String> {
+        if filename.ends_with(".tap") {
+            self.insert_tape(filename)?;
+            self.datasette.press_play();
+            self.type_text("LOAD\r");
+        } else if filename.ends_with(".t64") {
+            self.load_t64(filename, 0)?;
+            self.type_text("RUN\r");
+        } else if filename.ends_with(".d64") {
+            self.insert_disk(filename)?;
+            self.paste_text("LOAD\"*\",8,1\rRUN\r");
+        } else {
+            self.load_prg(filename)?;
+            self.type_text("RUN\r");
+        }
+        Ok(())
+    }
+
+    /// Plugs in a CRT cartridge and power-cycles the machine, so the CPU starts through
+    /// the reset vector with the cartridge already mapped.
+    pub fn load_crt(&mut self, filename: &str) -> Result<(), String> {
+        let cart = crt::Crt::from_filename(filename)?;
+        self.mem_ref.borrow_mut().attach_cartridge(cart.mapper());
+        self.reset(ResetKind::Cold);
+        Ok(())
+    }
+
+    pub fn insert_tape(&mut self, filename: &str) -> Result<(), String> {
+        self.datasette.insert(tape::Tap::from_filename(filename)?);
+        Ok(())
+    }
+
+    /// Plugs an REU with `size` bytes of RAM into the expansion port.
+    pub fn attach_reu(&mut self, size: usize) {
+        let reu_ref = reu::Reu::new_shared(self.mem_ref.clone(), self.cpu_ref.clone(), size);
+        self.cpu_ref
+            .borrow_mut()
+            .set_reu_reference(Some(reu_ref.clone()));
+        self.reu_ref = Some(reu_ref);
+    }
+
+    pub fn detach_reu(&mut self) {
+        self.cpu_ref.borrow_mut().set_reu_reference(None);
+        self.reu_ref = None;
+    }
+
+    pub fn insert_disk(&mut self, filename: &str) -> Result<(), String> {
+        self.drive.insert(drive::D64::from_filename(filename)?);
+        Ok(())
+    }
+
+    /// Serves KERNAL LOAD calls for the drive's device number straight from the disk
+    /// image, then returns to the caller as LOAD would. Other devices, and VERIFY, go
+    /// through the KERNAL as usual.
+    fn trap_load(&mut self) {
+        let mut mem = self.mem_ref.borrow_mut();
+        if mem.read_byte(KERNAL_DEVICE) != self.drive.device
+            || self.drive.disk().is_none()
+            || mem.read_byte(KERNAL_VERIFY_FLAG) != 0
+        {
+            return;
+        }
+
+        let name_addr = mem.read_word_le(KERNAL_FILENAME);
+        let filename: Vec<u8> = (0..mem.read_byte(KERNAL_FILENAME_LEN) as u16)
+            .map(|i| mem.read_byte(name_addr.wrapping_add(i)))
+            .collect();
+        let mut cpu = self.cpu_ref.borrow_mut();
+        match self.drive.load(&filename) {
+            Ok(data) if data.len() >= 2 => {
+                // Secondary address 0 relocates the file to the caller's address.
+                let start = if mem.read_byte(KERNAL_SECONDARY_ADDRESS) == 0 {
+                    mem.read_word_le(KERNAL_LOAD_ADDRESS)
+                } else {
+                    u16::from_le_bytes([data[0], data[1]])
+                };
+                let payload = &data[2..data.len().min(2 + memory::MEM_SIZE - start as usize)];
+                mem.load(start, payload);
+                let end = start.wrapping_add(payload.len() as u16);
+                mem.write_byte(KERNAL_END_ADDRESS, end as u8);
+                mem.write_byte(KERNAL_END_ADDRESS + 1, (end >> 8) as u8);
+                mem.write_byte(KERNAL_STATUS, 0);
+                cpu.x = end as u8;
+                cpu.y = (end >> 8) as u8;
+                cpu.p &= !(cpu::StatusFlag::Carry as u8);
+            }
+            result => {
+                cpu.a = result.err().unwrap_or(drive::ERROR_FILE_NOT_FOUND);
+                cpu.p |= cpu::StatusFlag::Carry as u8;
+            }
+        }
+        drop(mem);
+        cpu.return_from_subroutine();
+    }
+
+    /// Makes KERNAL SAVE write a PRG named after the file to `dir` on the host, for any
+    /// device, instead of going out to tape or disk.
+    pub fn enable_save_trap(&mut self, dir: impl Into<std::path::PathBuf>) {
+        self.save_dir = Some(dir.into());
+    }
+
+    pub fn disable_save_trap(&mut self) {
+        self.save_dir = None;
+    }
+
+    /// Writes the range SAVE was given, from $C1/$C2 up to but not including $AE/$AF,
+    /// to the save directory and returns to the caller as a successful SAVE would.
+    fn trap_save(&mut self) {
+        let Some(dir) = &self.save_dir else {
+            return;
+        };
+        let mem = self.mem_ref.borrow();
+        let start = mem.read_word_le(KERNAL_SAVE_START);
+        let end = mem.read_word_le(KERNAL_END_ADDRESS);
+        let name_addr = mem.read_word_le(KERNAL_FILENAME);
+        let name: String = (0..mem.read_byte(KERNAL_FILENAME_LEN) as u16)
+            .map(|i| mem.read_byte(name_addr.wrapping_add(i)) as char)
+            .filter(|c| c.is_ascii_graphic() && *c != '/' && *c != '\\')
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let mut data = start.to_le_bytes().to_vec();
+        data.extend(mem.dump_range(start, end.wrapping_sub(start) as usize));
+        drop(mem);
+
+        let path = dir.join(format!(
+            "{}.prg",
+            if name.is_empty() { "noname" } else { &name }
+        ));
+        let mut cpu = self.cpu_ref.borrow_mut();
+        match std::fs::write(&path, data) {
+            Ok(()) => {
+                self.mem_ref.borrow_mut().write_byte(KERNAL_STATUS, 0);
+                cpu.p &= !(cpu::StatusFlag::Carry as u8);
+            }
+            Err(e) => {
+                let _ = writeln!(self.debug_output, "Couldn't save {}: {}", path.display(), e);
+                cpu.a = drive::ERROR_DEVICE_NOT_PRESENT;
+                cpu.p |= cpu::StatusFlag::Carry as u8;
+            }
+        }
+        cpu.return_from_subroutine();
+    }
+
+    /// Copies entry `index` of a T64 archive into memory like a PRG. Returns its load
+    /// address.
+    pub fn load_t64(&mut self, filename: &str, index: usize) -> Result<u16, String> {
+        let archive = t64::T64::from_filename(filename)?;
+        let entry = archive
+            .entries
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| format!("{}: no entry {}", filename, index))?;
+        self.load_t64_entry(&entry)
+            .map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// Copies a T64 entry into memory at its load address. Returns that address.
+    pub fn load_t64_entry(&mut self, entry: &t64::T64Entry) -> Result<u16, String> {
+        self.load_prg_data(&entry.to_prg())
+    }
+
+    /// Loads a PRG file into memory. Returns its load address.
+    pub fn load_prg(&mut self, filename: &str) -> Result<u16, String> {
+        let data = utils::open_file(filename, 0).map_err(|e| format!("{}: {}", filename, e))?;
+        self.load_prg_data(&data)
+            .map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// Reloads a rebuilt PRG into the running machine without resetting it. Nothing is
+    /// swapped until the CPU finishes its current instruction. Returns the load address.
+    ///
+    /// A BASIC program is re-run by typing RUN at BASIC's input loop. If another program
+    /// is running, it is first broken into through BASIC's warm start, as RUN/STOP-RESTORE
+    /// does. The reload is dropped, with a note on the debug output, if the input loop
+    /// isn't reached within `RELOAD_TIMEOUT_FRAMES`.
+    ///
+    /// Anything else isn't typed in with SYS, since it may bank BASIC out or never return
+    /// to the input loop. It is jumped to at its load address instead, with the stack and
+    /// interrupt mask reset, since the old program may have left them in any state.
+    pub fn reload_prg(&mut self, filename: &str) -> Result<u16, String> {
+        let data = utils::open_file(filename, 0).map_err(|e| format!("{}: {}", filename, e))?;
+        if data.len() < 2 {
+            return Err(format!("{}: {}", filename, PRG_TOO_SHORT));
+        }
+        let start = u16::from_le_bytes([data[0], data[1]]);
+        self.pending_reload = Some(PendingReload {
+            data,
+            deadline: self.cycle_count
+                + RELOAD_TIMEOUT_FRAMES * self.region.cycles_per_frame() as u64,
+            broken_in: false,
+        });
+        Ok(start)
+    }
+
+    /// Swaps in the PRG queued by `reload_prg` if the CPU is at a point where it can be,
+    /// breaking into a running BASIC program first.
+    fn apply_pending_reload(&mut self) {
+        let Some(reload) = &mut self.pending_reload else {
+            return;
+        };
+        let start = u16::from_le_bytes([reload.data[0], reload.data[1]]);
+        let mut cpu = self.cpu_ref.borrow_mut();
+        if !cpu.at_instruction_boundary() {
+            return;
+        }
+        if start == BASIC_START && cpu.pc != BASIC_READY_LOOP {
+            if self.cycle_count >= reload.deadline {
+                drop(cpu);
+                self.pending_reload = None;
+                let _ = writeln!(
+                    self.debug_output,
+                    "Reload dropped: BASIC didn't reach its input loop"
+                );
+            } else if !reload.broken_in {
+                reload.broken_in = true;
+                cpu.sp = 0xFA;
+                cpu.p &= !(cpu::StatusFlag::InterruptDisable as u8);
+                cpu.pc = self.mem_ref.borrow().read_word_le(BASIC_WARM_START_VECTOR);
+            }
+            return;
+        }
+        drop(cpu);
+
+        let data = self.pending_reload.take().unwrap().data;
+        if let Err(e) = self.load_prg_data(&data) {
+            let _ = writeln!(self.debug_output, "Couldn't reload: {}", e);
+            return;
+        }
+        if start == BASIC_START {
+            // What NEW leaves behind, less the program text: variables and strings of
+            // the old program are dropped.
+            let mut mem = self.mem_ref.borrow_mut();
+            mem.load(0x2B, &BASIC_START.to_le_bytes());
+            let memsiz = mem.read_word_le(0x37);
+            mem.load(0x33, &memsiz.to_le_bytes());
+            drop(mem);
+            self.type_text("RUN\r");
+        } else {
+            let mut cpu = self.cpu_ref.borrow_mut();
+            cpu.sp = 0xFA;
+            cpu.p &= !(cpu::StatusFlag::InterruptDisable as u8);
+            cpu.pc = start;
+        }
+    }
+
+    /// Runs the commands in a script file against the machine; see `script` for the
+    /// command set.
+    pub fn run_script(&mut self, filename: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))?;
+        script::run(self, &text)
+    }
+
+    /// Loads PRG data: a little-endian load address followed by the bytes to store there.
+    /// Returns the load address. Data that would run past $FFFF is dropped, with a
+    /// warning on the debug output.
+    pub fn load_prg_data(&mut self, data: &[u8]) -> Result<u16, String> {
+        if data.len() < 2 {
+            return Err(String::from(PRG_TOO_SHORT));
+        }
+
+        let start_address = u16::from_le_bytes([data[0], data[1]]);
+        let mut payload = &data[2..];
+        let room = memory::MEM_SIZE - start_address as usize;
+        if payload.len() > room {
+            let _ = writeln!(
+                self.debug_output,
+                "Warning: PRG at ${:04X} overflows $FFFF, truncating {} bytes",
+                start_address,
+                payload.len() - room
+            );
+            payload = &payload[..room];
+        }
+
+        let mut mem = self.mem_ref.borrow_mut();
+        for (i, byte) in payload.iter().enumerate() {
+            mem.write_byte(start_address + i as u16, *byte);
+        }
+
+        // A BASIC program also needs the end-of-program pointers moved past it,
+        // otherwise RUN sees an empty program.
+        if start_address == BASIC_START {
+            let end = (start_address as usize + payload.len()) as u16;
+            for ptr in [0x2D, 0x2F, 0x31, 0xAE] {
+                mem.write_byte(ptr, end as u8);
+                mem.write_byte(ptr + 1, (end >> 8) as u8);
+            }
+        }
+
+        Ok(start_address)
+    }
+
+    /// Hands the finished frame to the display and picks up the keys it reports.
+    fn present_frame(&mut self) {
+        {
+            let mut vic = self.vic_ref.borrow_mut();
+            vic.frame_ready = false;
+            self.frame.clone_from(&vic.window_buffer);
+            self.frame_size = vic.frame_size();
+            let (width, height) = self.frame_size;
+            self.display
+                .update_with_buffer(&vic.window_buffer, width, height);
+        }
+        let frame = self.vic_ref.borrow().frame_count;
+        let (due, pending) = std::mem::take(&mut self.key_events)
+            .into_iter()
+            .partition(|&(at, _)| at <= frame);
+        self.key_events = pending;
+        for (_, event) in due {
+            self.io.set_matrix_key(event.pos, event.pressed);
+        }
+
+        let keys = self.display.pressed_keys();
+        self.handle_hotkeys(&keys);
+        self.set_keys(&keys);
+        // RESTORE pulls NMI for one frame per press. RUN/STOP is already in the matrix
+        // by then for the KERNAL's NMI handler to see.
+        let restore = self.io.check_restore_key(&keys);
+        self.cpu_ref
+            .borrow_mut()
+            .set_nmi(cpu::NmiSource::Restore, restore);
+    }
+
+    /// Shows the last frame again while paused, so the window stays responsive, and
+    /// handles hotkeys. The machine itself doesn't see the keys.
+    fn present_paused_frame(&mut self) {
+        let (width, height) = self.frame_size;
+        self.display.update_with_buffer(&self.frame, width, height);
+        let keys = self.display.pressed_keys();
+        self.handle_hotkeys(&keys);
+    }
+
+    /// Acts on the host-only hotkeys among `keys` that weren't already held.
+    fn handle_hotkeys(&mut self, keys: &[io::Key]) {
+        let fresh: Vec<io::Key> = keys
+            .iter()
+            .filter(|key| !self.held_hotkeys.contains(key))
+            .copied()
+            .collect();
+        for key in fresh {
+            match key {
+                io::Key::F12 => self.reset(ResetKind::Cold),
+                io::Key::F11 => self.reset(ResetKind::Warm),
+                io::Key::F8 => self.save_timestamped_screenshot(),
+                io::Key::F9 => self.toggle_pause(),
+                io::Key::F10 => self.clock.toggle_warp(),
+                io::Key::NumPadPlus => self.set_scale(self.scale.larger()),
+                io::Key::NumPadMinus => self.set_scale(self.scale.smaller()),
+                _ => {}
+            }
+        }
+        self.held_hotkeys = keys.to_vec();
+    }
+
+    /// Queues matrix key presses and releases, held on top of the host keys. An event's
+    /// `frame` counts presented frames from now; 0 applies with the next one.
+    pub fn type_matrix(&mut self, events: Vec<io::KeyEvent>) {
+        let next_frame = self.vic_ref.borrow().frame_count + 1;
+        self.key_events.extend(
+            events
+                .into_iter()
+                .map(|event| (next_frame + event.frame, event)),
+        );
+        // Stable, so events on the same frame keep their order.
+        self.key_events.sort_by_key(|&(at, _)| at);
+    }
+
+    /// Queues text to be typed into the machine. Characters without a PETSCII
+    /// equivalent are dropped.
+    pub fn paste_text(&mut self, text: &str) {
+        self.paste_queue
+            .extend(text.chars().filter_map(io::ascii_to_petscii));
+    }
+
+    /// Refills the KERNAL keyboard buffer from the paste queue once the KERNAL has
+    /// drained it, so nothing is dropped and the buffer is never modified while the
+    /// KERNAL is shifting it.
+    fn feed_paste_queue(&mut self) {
+        let mut mem = self.mem_ref.borrow_mut();
+        if mem.read_byte(KEYBOARD_BUFFER_LEN) != 0 {
+            return;
+        }
+
+        let count = self.paste_queue.len().min(KEYBOARD_BUFFER_SIZE);
+        for (i, byte) in self.paste_queue.drain(..count).enumerate() {
+            mem.write_byte(KEYBOARD_BUFFER + i as u16, byte);
+        }
+        mem.write_byte(KEYBOARD_BUFFER_LEN, count as u8);
+    }
+
+    /// Stuffs PETSCII text into the KERNAL keyboard buffer as if it had been typed.
+    fn type_text(&mut self, text: &str) {
+        let mut mem = self.mem_ref.borrow_mut();
+        for (i, byte) in text.bytes().take(KEYBOARD_BUFFER_SIZE).enumerate() {
+            mem.write_byte(KEYBOARD_BUFFER + i as u16, byte);
+        }
+        mem.write_byte(
+            KEYBOARD_BUFFER_LEN,
+            text.len().min(KEYBOARD_BUFFER_SIZE) as u8,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn prg_overflowing_ffff_is_truncated() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let output = SharedOutput::default();
+        zpc.set_debug_output(Box::new(output.clone()));
+        let data = [0xFE, 0xFF, 0x11, 0x22, 0x33, 0x44];
+
+        assert_eq!(zpc.load_prg_data(&data), Ok(0xFFFE));
+        assert!(output.text().contains("truncating 2 bytes"));
+        let mem = zpc.mem_ref.borrow();
+        assert_eq!(mem.read_byte(0xFFFE), 0x11);
+        assert_eq!(mem.read_byte(0xFFFF), 0x22);
+        assert_eq!(mem.read_byte(0x0000), 0x00);
+        drop(mem);
+        assert_eq!(zpc.load_prg_data(&[0x01]), Err(String::from(PRG_TOO_SHORT)));
+    }
+
+    #[test]
+    fn paused_machine_does_not_advance() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.update();
+        zpc.pause();
+        let raster_line = zpc.vic_ref.borrow().raster_line;
+        for _ in 0..1000 {
+            zpc.update();
+        }
+        assert_eq!(zpc.cycle_count, 1);
+        assert_eq!(zpc.vic_ref.borrow().raster_line, raster_line);
+
+        zpc.toggle_pause();
+        for _ in 0..100 {
+            zpc.update();
+        }
+        assert_eq!(zpc.cycle_count, 101);
+        assert_eq!(zpc.vic_ref.borrow().raster_line, 1);
+    }
+
+    /// Debug output that a test can read back.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.borrow()).into_owned()
+        }
+    }
+
+    #[test]
+    fn f8_saves_a_timestamped_screenshot() {
+        let dir = std::env::temp_dir().join(format!("zpc-shots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let output = SharedOutput::default();
+        zpc.set_debug_output(Box::new(output.clone()));
+        zpc.set_screenshot_dir(&dir);
+        zpc.display.hold_keys(&[io::Key::F8], 1);
+        zpc.present_frame();
+
+        let shots: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(shots.len(), 1);
+        let name = shots[0].as_ref().unwrap().file_name();
+        let name = name.to_string_lossy();
+        assert!(
+            name.starts_with("screenshot-") && name.ends_with(".png"),
+            "{}",
+            name
+        );
+        assert!(output.text().starts_with("Screenshot saved to "));
+
+        // The directory is gone now, so the next one fails and says so.
+        zpc.display.hold_keys(&[], 1);
+        zpc.display.hold_keys(&[io::Key::F8], 1);
+        zpc.present_frame();
+        zpc.present_frame();
+        assert!(output.text().contains("failed"), "{}", output.text());
+    }
+
+    #[test]
+    fn f10_toggles_warp() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.display.hold_keys(&[io::Key::F10], 2);
+        zpc.display.hold_keys(&[], 1);
+        zpc.display.hold_keys(&[io::Key::F10], 1);
+        zpc.present_frame();
+        assert!(zpc.clock.is_warp());
+        zpc.present_frame();
+        assert!(zpc.clock.is_warp());
+        zpc.present_frame();
+        zpc.present_frame();
+        assert!(!zpc.clock.is_warp());
+    }
+
+    #[test]
+    fn f9_toggles_pause_and_the_paused_display_is_still_served() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.display.hold_keys(&[io::Key::F9], 1);
+        zpc.present_frame();
+        assert!(zpc.is_paused());
+
+        // Still presenting frames and reading keys: the release and a second press of
+        // F9 come through and resume the machine.
+        let frames = zpc.display.frame_count;
+        zpc.display.hold_keys(&[], 1);
+        zpc.display.hold_keys(&[io::Key::F9], 1);
+        zpc.present_paused_frame();
+        assert!(zpc.is_paused());
+        zpc.present_paused_frame();
+        assert!(!zpc.is_paused());
+        assert_eq!(zpc.display.frame_count, frames + 2);
+    }
+
+    fn run_frame(zpc: &mut ZPC) {
+        for _ in 0..zpc.region.cycles_per_frame() {
+            zpc.update();
+        }
+    }
+
+    fn keyboard_buffer(zpc: &ZPC) -> Vec<u8> {
+        let mem = zpc.mem_ref.borrow();
+        let len = mem.read_byte(KEYBOARD_BUFFER_LEN) as u16;
+        (0..len)
+            .map(|i| mem.read_byte(KEYBOARD_BUFFER + i))
+            .collect()
+    }
+
+    #[test]
+    fn pasted_text_reaches_keyboard_buffer() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.paste_text("10 print\n20 goto 10\n");
+
+        run_frame(&mut zpc);
+        assert_eq!(keyboard_buffer(&zpc), b"10 PRINT\r2");
+
+        // Nothing more is fed until the KERNAL empties the buffer.
+        run_frame(&mut zpc);
+        assert_eq!(keyboard_buffer(&zpc), b"10 PRINT\r2");
+
+        zpc.mem_ref.borrow_mut().write_byte(KEYBOARD_BUFFER_LEN, 0);
+        run_frame(&mut zpc);
+        assert_eq!(keyboard_buffer(&zpc), b"0 GOTO 10\r");
+    }
+
+    #[test]
+    fn snapshot_restores_deterministically() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        // Loop: INC $0400; INX; ADC $0400,X; JMP $C000
+        zpc.mem_ref.borrow_mut().load(
+            0xC000,
+            &[0xEE, 0x00, 0x04, 0xE8, 0x7D, 0x00, 0x04, 0x4C, 0x00, 0xC0],
+        );
+        zpc.mem_ref.borrow_mut().load(0xFFFC, &[0x00, 0xC0]);
+        zpc.reset(ResetKind::Warm);
+        // Keep CIA1 timer A running so timer state is part of the comparison.
+        zpc.cpu_ref.borrow_mut().write_byte(0xDC04, 0x10);
+        zpc.cpu_ref.borrow_mut().write_byte(0xDC05, 0x00);
+        zpc.cpu_ref.borrow_mut().write_byte(0xDC0E, 0x11);
+
+        for _ in 0..5_000 {
+            zpc.update();
+        }
+        let saved = zpc.save_state().unwrap();
+        let cpu_cycles = zpc.cpu_ref.borrow().cycles();
+        for _ in 0..20_000 {
+            zpc.update();
+        }
+        let expected = zpc.save_state().unwrap();
+        assert_ne!(saved, expected);
+
+        zpc.load_state(&saved).unwrap();
+        assert_eq!(zpc.cycle_count, 5_000);
+        assert_eq!(zpc.cpu_ref.borrow().cycles(), cpu_cycles);
+        for _ in 0..20_000 {
+            zpc.update();
+        }
+        assert_eq!(zpc.save_state().unwrap(), expected);
+    }
+
+    #[test]
+    fn snapshot_header_is_checked() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let mut data = zpc.save_state().unwrap();
+        assert!(zpc.load_state(&data[..100]).is_err());
+
+        data[4] = snapshot::VERSION + 1;
+        assert_eq!(
+            zpc.load_state(&data),
+            Err(format!(
+                "unsupported snapshot version {}",
+                snapshot::VERSION + 1
+            ))
+        );
+
+        let ntsc = ZPC::with_program("", Region::Ntsc, HeadlessDisplay::new())
+            .save_state()
+            .unwrap();
+        assert!(zpc.load_state(&ntsc).is_err());
+        assert!(zpc.load_state(b"nonsense").is_err());
+    }
+
+    #[test]
+    fn snapshot_carries_the_peripherals() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let mut image = crt::tests::build_crt(crt::HW_TYPE_OCEAN, 0, 0, 0x8000, &[0x11; 0x2000]);
+        crt::tests::add_chip(&mut image, 1, 0x8000, &[0x22; 0x2000]);
+        let cart = crt::Crt::from_bytes(&image).unwrap();
+        zpc.mem_ref.borrow_mut().attach_cartridge(cart.mapper());
+        zpc.cpu_ref.borrow_mut().write_byte(0xDE00, 1);
+        zpc.attach_reu(128 * 1024);
+        zpc.reu_ref
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .write_register(2, 0x34);
+        zpc.datasette.insert(tape::Tap {
+            version: 1,
+            pulses: vec![0x180, 0x1000, 0x158],
+        });
+        zpc.datasette.press_play();
+        zpc.drive
+            .insert(drive::D64::parse(drive::tests::sample_d64()).unwrap());
+        for _ in 0..1_000 {
+            zpc.update();
+        }
+        let saved = zpc.save_state().unwrap();
+
+        zpc.cpu_ref.borrow_mut().write_byte(0xDE00, 0);
+        zpc.detach_reu();
+        zpc.datasette.eject();
+        zpc.drive.eject();
+        zpc.load_state(&saved).unwrap();
+
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0x8000), 0x22);
+        assert!(zpc.reu_ref.is_some());
+        assert!(zpc.drive.disk().is_some());
+        assert_eq!(zpc.save_state().unwrap(), saved);
+
+        // The cartridge ROM isn't in the snapshot, so it can't be restored without one.
+        zpc.mem_ref.borrow_mut().detach_cartridge();
+        assert!(zpc.load_state(&saved).is_err());
+    }
+
+    struct IdleIecDevice;
+
+    impl crate::iec::IecDevice for IdleIecDevice {
+        fn host_lines_changed(&mut self, _lines: crate::iec::IecLines) {}
+
+        fn device_lines(&self) -> crate::iec::IecLines {
+            crate::iec::IecLines::default()
+        }
+    }
+
+    #[test]
+    fn snapshot_refuses_attached_iec_devices() {
+        let zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.cia2_ref
+            .borrow_mut()
+            .attach_iec_device(Box::new(IdleIecDevice));
+        assert!(zpc.save_state().is_err());
+    }
+
+    fn read_png(filename: &str) -> (png::OutputInfo, Vec<u8>) {
+        let decoder = png::Decoder::new(std::fs::File::open(filename).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut rgba = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgba).unwrap();
+        (info, rgba)
+    }
+
+    #[test]
+    fn screenshot_has_screen_size_and_colors() {
+        let path = std::env::temp_dir().join(format!("zpc-shot-{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+
+        zpc.save_screenshot(path).unwrap();
+        let (info, rgba) = read_png(path);
+        assert_eq!((info.width, info.height), (384, 272));
+        assert!(rgba.chunks(4).all(|p| p == [0, 0, 0, 0xFF]));
+
+        zpc.vic_ref.borrow_mut().write_register(0xD020, 0x0E);
+        run_frame(&mut zpc);
+        zpc.save_screenshot(path).unwrap();
+        let (_, rgba) = read_png(path);
+        std::fs::remove_file(path).unwrap();
+        // Top-left corner is border, light blue.
+        assert_eq!(&rgba[..4], &[0x6C, 0x5E, 0xB5, 0xFF]);
+    }
+
+    #[test]
+    fn frame_buffer_holds_the_last_frame() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        assert_eq!(
+            zpc.frame_dimensions(),
+            (vic::SCREEN_WIDTH, vic::SCREEN_HEIGHT)
+        );
+        assert!(zpc.frame_buffer().iter().all(|&pixel| pixel == 0));
+
+        zpc.vic_ref.borrow_mut().write_register(0xD020, 0x0E);
+        run_frame(&mut zpc);
+        let frame = zpc.frame_buffer();
+        assert_eq!(frame.len(), vic::SCREEN_WIDTH * vic::SCREEN_HEIGHT);
+        assert_eq!(frame[0], utils::fetch_c64_color_rgba(0x0E));
+
+        // Lines drawn since then don't show until the frame completes.
+        zpc.vic_ref.borrow_mut().write_register(0xD020, 0x02);
+        zpc.run_until(RunUntil::Cycles(10_000), 10_000);
+        assert_eq!(zpc.frame_buffer()[0], utils::fetch_c64_color_rgba(0x0E));
+    }
+
+    #[test]
+    fn border_size_sets_the_frame_size() {
+        let config = ZpcConfig {
+            border_size: vic::BorderSize::None,
+            ..ZpcConfig::default()
+        };
+        let mut zpc = ZPC::with_config(config, HeadlessDisplay::new()).unwrap();
+        {
+            let mut vic = zpc.vic_ref.borrow_mut();
+            vic.write_register(0xD011, 0x1B);
+            vic.write_register(0xD016, 0x08);
+            vic.write_register(0xD020, 0x0E);
+        }
+        let light_blue = utils::fetch_c64_color_rgba(0x0E);
+        run_frame(&mut zpc);
+        assert_eq!(zpc.frame_dimensions(), (320, 200));
+        assert_eq!(zpc.frame_buffer().len(), 320 * 200);
+        assert!(!zpc.display.frames[0].contains(&light_blue));
+
+        zpc.set_border_size(vic::BorderSize::Full);
+        run_frame(&mut zpc);
+        assert_eq!(zpc.frame_dimensions(), (504, 312));
+        assert_eq!(zpc.display.frames[0].len(), 504 * 312);
+        assert_eq!(zpc.frame_buffer()[0], light_blue);
+    }
+
+    #[test]
+    fn headless_machine_takes_scripted_input() {
+        let mut display = HeadlessDisplay::new();
+        display.hold_keys(&[], 2);
+        display.hold_keys(&[io::Key::Enter], 1);
+        let mut zpc = ZPC::with_program("", Region::Pal, display);
+
+        // Wait for RETURN on the keyboard matrix, then print "READY." on the screen.
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0xFE, 0x8D, 0x00, 0xDC, 0xA9, 0xFF, 0x8D, 0x02, 0xDC, // select row 0
+            0xAD, 0x01, 0xDC, 0x29, 0x02, 0xD0, 0xF9,                   // wait for RETURN
+            0xA2, 0x05, 0xBD, 0x1F, 0xC0, 0x9D, 0x00, 0x04, 0xCA, 0x10, 0xF7,
+            0x4C, 0x1C, 0xC0,
+            0x12, 0x05, 0x01, 0x04, 0x19, 0x2E,                         // READY.
+        ];
+        zpc.mem_ref.borrow_mut().load(0xC000, &program);
+        zpc.mem_ref.borrow_mut().load(0xFFFC, &[0x00, 0xC0]);
+        zpc.reset(ResetKind::Warm);
+
+        let screen = |zpc: &ZPC| -> Vec<u8> {
+            let mem = zpc.mem_ref.borrow();
+            (0..6).map(|i| mem.read_byte(0x0400 + i)).collect()
+        };
+        for _ in 0..2 {
+            run_frame(&mut zpc);
+        }
+        assert_eq!(screen(&zpc), [0; 6]);
+
+        for _ in 0..2 {
+            run_frame(&mut zpc);
+        }
+        assert_eq!(screen(&zpc), [0x12, 0x05, 0x01, 0x04, 0x19, 0x2E]);
+        assert_eq!(zpc.display.frame_count, 4);
+        assert_eq!(zpc.display.frames.len(), 1);
+    }
+
+    #[test]
+    fn region_reaches_the_vic() {
+        let zpc = ZPC::with_program("", Region::Ntsc, HeadlessDisplay::new());
+        assert_eq!(zpc.vic_ref.borrow().region(), Region::Ntsc);
+    }
+
+    #[test]
+    fn basic_prg_sets_end_of_program() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.load_prg_data(&[0x01, 0x08, 0x00, 0x00, 0x00]).unwrap();
+
+        assert_eq!(zpc.mem_ref.borrow().read_word_le(0x2D), 0x0804);
+    }
+
+    #[test]
+    fn ultimax_crt_is_plugged_in_before_the_cpu_resets() {
+        let mut image = b"C64 CARTRIDGE   ".to_vec();
+        image.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, 0, 1, 0]);
+        image.resize(0x40, 0);
+        image.extend_from_slice(b"CHIP");
+        image.extend_from_slice(&0x2010u32.to_be_bytes());
+        image.extend_from_slice(&[0, 0, 0, 0, 0xE0, 0x00, 0x20, 0x00]);
+        let mut rom = vec![0xEA; 0x2000];
+        rom[0x1FFC..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0]);
+        image.extend_from_slice(&rom);
+        let path = std::env::temp_dir().join(format!("zpc-ultimax-{}.crt", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+
+        let mut zpc =
+            ZPC::with_program(path.to_str().unwrap(), Region::Pal, HeadlessDisplay::new());
+        let mut kernal = vec![0xEA; memory::KERNAL_SIZE];
+        kernal[0x1FFC..].copy_from_slice(&[0xE2, 0xFC, 0x48, 0xFF]);
+        zpc.mem_ref
+            .borrow_mut()
+            .load_rom(memory::MemType::Kernal, &kernal)
+            .unwrap();
+        zpc.update();
+        std::fs::remove_file(&path).unwrap();
+
+        // The cartridge's ROMH hides the KERNAL and supplies the reset vector.
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0xE000);
+        assert_eq!(
+            zpc.mem_ref.borrow().bank_at(0xFFFC),
+            memory::BankSource::Cartridge
+        );
+    }
+
+    #[test]
+    fn with_roms_installs_rom_files() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            let path = dir.join(format!("zpc-{}-{}.rom", name, std::process::id()));
+            path.to_str().unwrap().to_string()
+        };
+        let (kernal, basic, chargen) = (path("kernal"), path("basic"), path("chargen"));
+        let mut kernal_data = vec![0xEA; memory::KERNAL_SIZE];
+        kernal_data[0x1FFC..].copy_from_slice(&[0xE2, 0xFC, 0x48, 0xFF]);
+        std::fs::write(&kernal, &kernal_data).unwrap();
+        std::fs::write(&basic, vec![0x94; memory::BASIC_SIZE]).unwrap();
+        std::fs::write(&chargen, vec![0x3C; memory::BASIC_SIZE]).unwrap();
+
+        let mut roms = RomPaths {
+            kernal: Some(kernal.clone()),
+            basic: Some(basic.clone()),
+            chargen: Some(chargen.clone()),
+        };
+        let result = ZPC::with_roms("", Region::Pal, HeadlessDisplay::new(), &roms);
+        assert!(result.is_err_and(|e| e.contains("Chargen")));
+
+        roms.chargen = None;
+        let mut zpc = ZPC::with_roms("", Region::Pal, HeadlessDisplay::new(), &roms).unwrap();
+        for path in [kernal, basic, chargen] {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0xA000), 0x94);
+        zpc.reset(ResetKind::Cold);
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0xFCE2);
+    }
+
+    #[test]
+    fn save_trap_writes_a_prg() {
+        let dir = std::env::temp_dir().join(format!("zpc-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.enable_save_trap(&dir);
+        {
+            let mut mem = zpc.mem_ref.borrow_mut();
+            mem.load(0x0340, b"DEMO");
+            mem.load(KERNAL_FILENAME, &[0x40, 0x03]);
+            mem.write_byte(KERNAL_FILENAME_LEN, 4);
+            mem.load(KERNAL_SAVE_START, &[0x01, 0x08]);
+            mem.load(KERNAL_END_ADDRESS, &[0x05, 0x08]);
+            mem.load(0x0801, &[0x0A, 0x0B, 0x0C, 0x0D, 0xFF]);
+            mem.load(0x01FE, &[0x33, 0x12]);
+        }
+        {
+            let mut cpu = zpc.cpu_ref.borrow_mut();
+            cpu.sp = 0xFD;
+            cpu.pc = KERNAL_SAVE;
+        }
+        zpc.trap_save();
+
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0x1234);
+        assert_eq!(zpc.cpu_ref.borrow().p & cpu::StatusFlag::Carry as u8, 0);
+        let path = dir.join("demo.prg");
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(saved, [0x01, 0x08, 0x0A, 0x0B, 0x0C, 0x0D]);
+    }
+
+    #[test]
+    fn load_trap_reads_from_the_disk() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.drive
+            .insert(drive::D64::parse(drive::tests::sample_d64()).unwrap());
+        {
+            let mut mem = zpc.mem_ref.borrow_mut();
+            mem.load(0x0340, b"HELLO");
+            mem.load(KERNAL_FILENAME, &[0x40, 0x03]);
+            mem.write_byte(KERNAL_FILENAME_LEN, 5);
+            mem.write_byte(KERNAL_DEVICE, 8);
+            mem.write_byte(KERNAL_SECONDARY_ADDRESS, 1);
+            // Return address $1233 on the stack, as left by JSR at $1231.
+            mem.load(0x01FE, &[0x33, 0x12]);
+        }
+        {
+            let mut cpu = zpc.cpu_ref.borrow_mut();
+            cpu.sp = 0xFD;
+            cpu.pc = KERNAL_LOAD;
+        }
+        zpc.trap_load();
+
+        let cpu = zpc.cpu_ref.borrow();
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cpu.p & cpu::StatusFlag::Carry as u8, 0);
+        assert_eq!((cpu.x, cpu.y), (0xFF, 0xC0));
+        let mem = zpc.mem_ref.borrow();
+        assert_eq!(mem.read_byte(0xC000), 4);
+        assert_eq!(mem.read_byte(0xC0FE), 0xCC);
+        assert_eq!(mem.read_word_le(KERNAL_END_ADDRESS), 0xC0FF);
+    }
+
+    #[test]
+    fn autostart_failures_go_to_the_debug_output() {
+        let mut zpc = ZPC::with_program("no-such-disk.d64", Region::Pal, HeadlessDisplay::new());
+        let output = SharedOutput::default();
+        zpc.set_debug_output(Box::new(output.clone()));
+        // BASIC's input loop, standing in for the ROM.
+        zpc.mem_ref
+            .borrow_mut()
+            .load(BASIC_READY_LOOP, &[0x4C, 0x80, 0xA4]);
+        zpc.cpu_ref.borrow_mut().pc = BASIC_READY_LOOP;
+        zpc.update();
+
+        assert!(output
+            .text()
+            .starts_with("Couldn't load no-such-disk.d64: "));
+        assert!(zpc.paste_queue.is_empty());
+        assert!(zpc.drive.disk().is_none());
+        assert!(zpc.insert_tape("no-such-tape.tap").is_err());
+        assert!(zpc.load_crt("no-such-cart.crt").is_err());
+    }
+
+    #[test]
+    fn load_trap_needs_the_kernal_banked_in() {
+        // INX; JMP $F4A5
+        let code = [0xE8, 0x4C, 0xA5, 0xF4];
+        let trapped = |kernal_visible: bool| {
+            let mut zpc = ZPC::new_bare(&code, KERNAL_LOAD);
+            if kernal_visible {
+                let mut kernal = vec![0; memory::KERNAL_SIZE];
+                let at = (KERNAL_LOAD - 0xE000) as usize;
+                kernal[at..at + code.len()].copy_from_slice(&code);
+                let mut mem = zpc.mem_ref.borrow_mut();
+                mem.load_rom(memory::MemType::Kernal, &kernal).unwrap();
+                mem.write_byte(memory::PORT_DATA, 0x02);
+            }
+            zpc.drive
+                .insert(drive::D64::parse(drive::tests::sample_d64()).unwrap());
+            {
+                let mut mem = zpc.mem_ref.borrow_mut();
+                mem.load(0x0340, b"HELLO");
+                mem.load(KERNAL_FILENAME, &[0x40, 0x03]);
+                mem.write_byte(KERNAL_FILENAME_LEN, 5);
+                mem.write_byte(KERNAL_DEVICE, 8);
+                mem.write_byte(KERNAL_SECONDARY_ADDRESS, 1);
+            }
+            for _ in 0..100 {
+                zpc.update();
+            }
+            let loaded = zpc.mem_ref.borrow().read_byte(0xC000) == 4;
+            loaded
+        };
+        assert!(trapped(true));
+        assert!(!trapped(false));
+    }
+
+    #[test]
+    fn t64_entry_lands_at_its_load_address() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let archive = t64::T64::parse(&t64::tests::sample_t64(0xC3C6, &[0xA9, 0x01])).unwrap();
+
+        assert_eq!(zpc.load_t64_entry(&archive.entries[0]), Ok(0xC000));
+        let mem = zpc.mem_ref.borrow();
+        assert_eq!((mem.read_byte(0xC000), mem.read_byte(0xC001)), (0xA9, 0x01));
+        assert_eq!(mem.read_byte(0xC002), 0x00);
+    }
+
+    #[test]
+    fn one_frame_is_presented_per_frame_of_cycles() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        for _ in 0..3 * Region::Pal.cycles_per_frame() - 1 {
+            zpc.update();
+        }
+        assert_eq!(zpc.display.frame_count, 2);
+        zpc.update();
+        assert_eq!(zpc.display.frame_count, 3);
+        assert!(!zpc.vic_ref.borrow().frame_ready);
+    }
+
+    #[test]
+    fn new_wires_the_chips_together() {
+        let zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let mut cpu = zpc.cpu_ref.borrow_mut();
+
+        cpu.write_byte(0xD020, 0x05);
+        assert_eq!(zpc.vic_ref.borrow_mut().read_register(0xD020) & 0x0F, 0x05);
+
+        // CIA2 port A selects the VIC bank, inverted.
+        cpu.write_byte(0xDD02, 0x03);
+        cpu.write_byte(0xDD00, 0x01);
+        assert_eq!(zpc.vic_ref.borrow().bank_base(), 0x8000);
+
+        // Chip registers never reach the RAM underneath.
+        cpu.write_byte(0xD418, 0x0F);
+        assert_eq!(zpc.mem_ref.borrow().read_ram(0xD418), 0);
+        cpu.write_byte(0xDC0D, 0x7F);
+        assert_eq!(zpc.cia1_ref.borrow().icr_mask, 0);
+    }
+
+    #[test]
+    fn warm_reset_keeps_ram_and_cold_reset_clears_it() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.mem_ref
+            .borrow_mut()
+            .load(0xC000, &[0xEA, 0x4C, 0x00, 0xC0]);
+        zpc.mem_ref.borrow_mut().load(0xFFFC, &[0x00, 0xC0]);
+        zpc.mem_ref.borrow_mut().write_byte(0xD800, 0x0E);
+
+        zpc.mem_ref
+            .borrow_mut()
+            .set_ram_pattern(memory::RamPattern::Zeroed);
+
+        zpc.reset(ResetKind::Warm);
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0xC000);
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0xC000), 0xEA);
+
+        // F12 is a cold reset, fired once however long it is held.
+        zpc.display.hold_keys(&[io::Key::F12], 2);
+        zpc.present_frame();
+        let mem = zpc.mem_ref.borrow();
+        assert_eq!(mem.read_byte(0xC000), 0);
+        assert_eq!(mem.read_color_ram(0), 0);
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0);
+        drop(mem);
+
+        zpc.cpu_ref.borrow_mut().pc = 0x1234;
+        zpc.present_frame();
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0x1234);
+    }
+
+    #[test]
+    fn held_restore_fires_a_single_nmi() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        // JMP $C000, with an NMI handler that counts into $02: INC $02; RTI
+        zpc.mem_ref
+            .borrow_mut()
+            .load(0xC000, &[0x4C, 0x00, 0xC0, 0xE6, 0x02, 0x40]);
+        zpc.mem_ref
+            .borrow_mut()
+            .load(0xFFFA, &[0x03, 0xC0, 0x00, 0xC0]);
+        zpc.reset(ResetKind::Warm);
+
+        zpc.display.hold_keys(&[io::Key::PageUp], 3);
+        zpc.display.hold_keys(&[], 1);
+        zpc.display.hold_keys(&[io::Key::PageUp], 1);
+        for _ in 0..4 * Region::Pal.cycles_per_frame() {
+            zpc.update();
+        }
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0x02), 1);
+
+        for _ in 0..2 * Region::Pal.cycles_per_frame() {
+            zpc.update();
+        }
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0x02), 2);
+    }
+
+    #[test]
+    fn run_until_stops_at_the_first_condition_met() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        // LDX #$10; loop: DEX; BNE loop; done: JMP done
+        zpc.mem_ref
+            .borrow_mut()
+            .load(0xC000, &[0xA2, 0x10, 0xCA, 0xD0, 0xFD, 0x4C, 0x05, 0xC0]);
+        zpc.mem_ref.borrow_mut().load(0xFFFC, &[0x00, 0xC0]);
+        zpc.reset(ResetKind::Warm);
+
+        let cap = 10_000;
+        let outcome = zpc.run_until(RunUntil::Pc(0xC005), cap);
+        assert_eq!(outcome, RunOutcome::ReachedPc);
+        assert_eq!(zpc.cpu_ref.borrow().x, 0);
+        assert!(zpc.cycle_count < cap);
+
+        let start = zpc.cycle_count;
+        assert_eq!(
+            zpc.run_until(RunUntil::Pc(0xC000), 500),
+            RunOutcome::CyclesElapsed
+        );
+        assert_eq!(zpc.cycle_count - start, 500);
+
+        zpc.cpu_ref.borrow_mut().add_breakpoint(0xC005);
+        assert_eq!(
+            zpc.run_until(RunUntil::Breakpoint, cap),
+            RunOutcome::Breakpoint
+        );
+    }
+
+    #[test]
+    fn ram_dump_round_trips_through_a_file() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.mem_ref.borrow_mut().load(0xD000, &[1, 2, 3, 4]);
+        let path = std::env::temp_dir().join(format!("zpc-ram-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        zpc.dump_ram_to_file(path, 0xD000, 4).unwrap();
+
+        zpc.mem_ref.borrow_mut().load_range(0xD000, &[0; 4]);
+        assert_eq!(zpc.load_ram_from_file(path, 0xD000).unwrap(), 4);
+        std::fs::remove_file(path).unwrap();
+        // The bytes went to RAM under the IO area, not to the VIC.
+        assert_eq!(zpc.mem_ref.borrow().dump_range(0xD000, 4), [1, 2, 3, 4]);
+        assert_eq!(zpc.vic_ref.borrow_mut().read_register(0xD000), 0);
+    }
+
+    #[test]
+    fn ram_diff_lists_the_changed_bytes() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        assert_eq!(zpc.diff_ram(), None);
+
+        zpc.snapshot_ram();
+        let mut mem = zpc.mem_ref.borrow_mut();
+        let old = mem.read_ram(0xC000);
+        mem.load_range(0xC000, &[old ^ 0xFF]);
+        mem.load_range(0x0400, &[0x41]);
+        // Writing a byte's own value back is not a change.
+        let same = mem.read_ram(0x2000);
+        mem.load_range(0x2000, &[same]);
+        drop(mem);
+
+        let old_screen = zpc.ram_snapshot.as_ref().unwrap()[0x0400];
+        assert_eq!(
+            zpc.diff_ram(),
+            Some(vec![(0x0400, old_screen, 0x41), (0xC000, old, old ^ 0xFF)])
+        );
+    }
+
+    #[test]
+    fn keypad_plus_and_minus_step_the_scale() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        assert_eq!(zpc.scale(), Scale::X2);
+
+        zpc.display.hold_keys(&[io::Key::NumPadPlus], 2);
+        zpc.display.hold_keys(&[], 1);
+        zpc.display.hold_keys(&[io::Key::NumPadPlus], 1);
+        for _ in 0..4 {
+            zpc.present_frame();
+        }
+        assert_eq!(zpc.scale(), Scale::X8);
+
+        zpc.display.hold_keys(&[io::Key::NumPadMinus], 1);
+        zpc.present_frame();
+        assert_eq!(zpc.scale(), Scale::X4);
+        assert_eq!(
+            Scale::X8.clamp_to(vic::SCREEN_WIDTH, vic::SCREEN_HEIGHT, 1920, 1080),
+            Scale::X2
+        );
+    }
+
+    #[test]
+    fn queued_matrix_events_apply_on_their_frames() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let a = (1, 2);
+        zpc.type_matrix(vec![
+            io::KeyEvent {
+                frame: 0,
+                pos: a,
+                pressed: true,
+            },
+            io::KeyEvent {
+                frame: 1,
+                pos: a,
+                pressed: false,
+            },
+        ]);
+        // Live host input still reaches the matrix alongside the events.
+        zpc.display.hold_keys(&[io::Key::Space], 2);
+
+        let a_held = |zpc: &ZPC| zpc.cia1_ref.borrow().key_matrix[1] & (1 << 2) == 0;
+        let space_held = |zpc: &ZPC| zpc.cia1_ref.borrow().key_matrix[7] & (1 << 4) == 0;
+        zpc.run_until(
+            RunUntil::Cycles(Region::Pal.cycles_per_frame() as u64),
+            u64::MAX,
+        );
+        assert!(a_held(&zpc) && space_held(&zpc));
+        zpc.run_until(
+            RunUntil::Cycles(Region::Pal.cycles_per_frame() as u64),
+            u64::MAX,
+        );
+        assert!(!a_held(&zpc) && space_held(&zpc));
+    }
+
+    #[test]
+    fn fast_boot_leaves_ready_on_screen() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.fast_boot();
+        let mem = zpc.mem_ref.borrow();
+        let row: Vec<u8> = (0..6).map(|i| mem.read_byte(0x0400 + 5 * 40 + i)).collect();
+        assert_eq!(row, [18, 5, 1, 4, 25, 46]);
+        assert_eq!(mem.read_byte(0x00D6), 6);
+        assert_eq!(mem.read_byte(0x0314), 0x31);
+        drop(mem);
+        assert_eq!(zpc.cpu_ref.borrow().pc, BASIC_READY_LOOP);
+        assert_eq!(zpc.cia1_ref.borrow().icr_mask, 0x01);
+    }
+
+    #[test]
+    fn audio_keeps_pace_with_the_cycle_count() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        zpc.mem_ref.borrow_mut().load(0xC000, &[0x4C, 0x00, 0xC0]);
+        zpc.cpu_ref.borrow_mut().pc = 0xC000;
+        let cycles = 5 * zpc.region.cycles_per_frame() as u64;
+        for _ in 0..cycles {
+            zpc.update();
+        }
+
+        let expected = cycles * sid::DEFAULT_SAMPLE_RATE as u64 / zpc.region.clock_freq() as u64;
+        let mut out = vec![0; audio::DEFAULT_CAPACITY];
+        let pulled = zpc.pull_audio(&mut out) as u64;
+        assert!(pulled.abs_diff(expected) <= 1, "{} vs {}", pulled, expected);
+        assert_eq!(zpc.pull_audio(&mut out), 0);
+    }
+
+    #[test]
+    fn isolation_keeps_keys_out_of_joystick_reads() {
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        let read_joystick_2 = |zpc: &ZPC| {
+            let mut cpu = zpc.cpu_ref.borrow_mut();
+            // Port B drives column 6 low, where Q sits, while port A is read.
+            cpu.write_byte(0xDC02, 0x00);
+            cpu.write_byte(0xDC03, 0xFF);
+            cpu.write_byte(0xDC01, 0xBF);
+            cpu.read_byte(0xDC00)
+        };
+        zpc.mem_ref.borrow_mut().write_byte(0x0001, 0x37);
+        zpc.set_keys(&[io::Key::Q, io::Key::NumPad0]);
+        let joystick_2 = zpc.cia1_ref.borrow().joystick_2;
+        assert_ne!(read_joystick_2(&zpc), joystick_2);
+
+        zpc.set_joystick_keyboard_isolation(true);
+        assert_eq!(zpc.cia1_ref.borrow().joystick_2, joystick_2);
+        assert_eq!(read_joystick_2(&zpc), joystick_2);
+    }
+
+    #[test]
+    fn vic_fetches_before_the_cpu_within_a_cycle() {
+        struct Log(Rc<RefCell<Vec<(&'static str, u16)>>>);
+        impl memory::BusHook for Log {
+            fn on_read(&mut self, addr: u16, _value: u8) {
+                self.0.borrow_mut().push(("cpu", addr));
+            }
+            fn on_write(&mut self, _addr: u16, _value: u8) {}
+            fn on_vic_read(&mut self, addr: u16, _value: u8) {
+                self.0.borrow_mut().push(("vic", addr));
+            }
+        }
+
+        let mut zpc = ZPC::with_program("", Region::Pal, HeadlessDisplay::new());
+        // LDA $0400, the first byte of the video matrix.
+        zpc.mem_ref
+            .borrow_mut()
+            .load(0xC000, &[0xAD, 0x00, 0x04, 0x4C, 0x00, 0xC0]);
+        zpc.mem_ref.borrow_mut().load(0xFFFC, &[0x00, 0xC0]);
+        zpc.vic_ref.borrow_mut().write_register(0xD011, 0x1B);
+        zpc.vic_ref.borrow_mut().write_register(0xD018, 0x14);
+
+        // Line 51 shows the first character row. Its last cycle is the 7th after a
+        // reset, which is when the CPU fetches its first instruction.
+        let last_cycle = zpc.region.cycles_per_line() as u8 - 1;
+        while (
+            zpc.vic_ref.borrow().raster_line(),
+            zpc.vic_ref.borrow().raster_cycle(),
+        ) != (51, last_cycle - 6)
+        {
+            zpc.update();
+        }
+        zpc.reset(ResetKind::Warm);
+        for _ in 0..6 {
+            zpc.update();
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        zpc.mem_ref
+            .borrow_mut()
+            .set_bus_hook(Box::new(Log(log.clone())));
+        zpc.update();
+        let log = log.borrow();
+        let vic = log.iter().position(|&access| access == ("vic", 0x0400));
+        let cpu = log.iter().position(|&access| access == ("cpu", 0x0400));
+        assert!(vic.is_some() && cpu.is_some());
+        assert!(vic < cpu);
+    }
+
+    #[test]
+    fn bare_machine_runs_raw_code() {
+        // CLC; LDA #$02; ADC #$03; STA $D000; JMP *
+        let program = [
+            0x18, 0xA9, 0x02, 0x69, 0x03, 0x8D, 0x00, 0xD0, 0x4C, 0x08, 0x10,
+        ];
+        let mut zpc = ZPC::new_bare(&program, 0x1000);
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0x1000);
+        let outcome = zpc.run_until(RunUntil::Pc(0x1008), 1_000);
+        assert_eq!(outcome, RunOutcome::ReachedPc);
+        assert_eq!(zpc.cpu_ref.borrow().a, 5);
+        // $D000 is RAM, not the VIC.
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0xD000), 5);
+    }
+
+    /// Runs Klaus Dormann's 6502 functional test, built with its default options, from
+    /// `6502_functional_test.bin` in the crate root or wherever `KLAUS_FUNCTIONAL_TEST`
+    /// points. The test is skipped when the binary is missing. Every failure in the suite
+    /// is a branch or jump to itself, so it stops at the first instruction that leaves
+    /// PC unchanged and checks that this is the success trap.
+    #[test]
+    fn klaus_dormann_functional_test() {
+        const ENTRY: u16 = 0x0400;
+        const SUCCESS_TRAP: u16 = 0x3469;
+
+        let path = std::env::var("KLAUS_FUNCTIONAL_TEST")
+            .unwrap_or_else(|_| format!("{}/6502_functional_test.bin", env!("CARGO_MANIFEST_DIR")));
+        let Ok(image) = std::fs::read(&path) else {
+            eprintln!("{} not found, skipping the functional test", path);
+            return;
+        };
+        assert_eq!(image.len(), memory::MEM_SIZE, "expected a full 64K image");
+
+        // $0000/$0001 are the processor port; the suite's zero page starts above them.
+        let zpc = ZPC::new_bare(&image[2..], 0x0002);
+        let mut cpu = zpc.cpu_ref.borrow_mut();
+        cpu.pc = ENTRY;
+        for _ in 0..100_000_000u64 {
+            let pc = cpu.pc;
+            cpu.step();
+            if cpu.pc == pc {
+                assert_eq!(pc, SUCCESS_TRAP, "trapped at ${:04X}", pc);
+                return;
+            }
+        }
+        panic!("no trap reached, PC at ${:04X}", cpu.pc);
+    }
+
+    /// Screen RAM as text, one line per row, assuming the upper-case character set.
+    fn screen_text(zpc: &ZPC) -> Vec<String> {
+        let mem = zpc.mem_ref.borrow();
+        (0..25)
+            .map(|row| {
+                (0..40)
+                    .map(|col| match mem.read_byte(0x0400 + row * 40 + col) & 0x7F {
+                        code @ 0x00..=0x1F => (code + 0x40) as char,
+                        code => code as char,
+                    })
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Boots the real ROMs to READY. and runs a line of BASIC. The ROMs are looked up in
+    /// `$C64_ROMS`, or `roms/` next to the manifest, as `kernal.rom`, `basic.rom` and
+    /// `chargen.rom`. The ROMs can't ship with the crate, so run it with `--ignored`.
+    #[test]
+    #[ignore = "needs the C64 ROMs in $C64_ROMS or roms/"]
+    fn real_roms_boot_to_ready_and_run_basic() {
+        let dir = std::env::var("C64_ROMS")
+            .unwrap_or_else(|_| format!("{}/roms", env!("CARGO_MANIFEST_DIR")));
+        let path = |name: &str| format!("{}/{}.rom", dir, name);
+        let roms = RomPaths {
+            kernal: Some(path("kernal")),
+            basic: Some(path("basic")),
+            chargen: Some(path("chargen")),
+        };
+        let mut zpc = match ZPC::with_roms("", Region::Pal, HeadlessDisplay::new(), &roms) {
+            Ok(zpc) => zpc,
+            Err(e) => panic!("can't install the ROMs from {}: {}", dir, e),
+        };
+        zpc.reset(ResetKind::Cold);
+
+        let mut frames = 0;
+        while !screen_text(&zpc).iter().any(|line| line == "READY.") {
+            assert!(frames < 300, "no READY. after {} frames", frames);
+            run_frame(&mut zpc);
+            frames += 1;
+        }
+        assert_eq!(screen_text(&zpc)[1], "    **** COMMODORE 64 BASIC V2 ****");
+
+        zpc.paste_text("PRINT 6*7\r");
+        for _ in 0..50 {
+            run_frame(&mut zpc);
+        }
+        let screen = screen_text(&zpc);
+        let typed = screen.iter().position(|line| line == "PRINT 6*7");
+        let Some(row) = typed else {
+            panic!("typed line missing from the screen: {:#?}", screen);
+        };
+        assert_eq!(screen[row + 1], " 42");
+        assert_eq!(screen[row + 3], "READY.");
+    }
+
+    #[test]
+    fn reload_prg_replaces_the_running_program() {
+        let path = std::env::temp_dir().join(format!("zpc-reload-{}.prg", std::process::id()));
+        let filename = path.to_str().unwrap();
+        // INC $D020; JMP $C000
+        let mut zpc = ZPC::new_bare(&[0xEE, 0x20, 0xD0, 0x4C, 0x00, 0xC0], 0xC000);
+        // BASIC's input loop, standing in for the ROM.
+        zpc.mem_ref
+            .borrow_mut()
+            .load(BASIC_READY_LOOP, &[0x4C, 0x80, 0xA4]);
+        let apply = |zpc: &mut ZPC| {
+            for _ in 0..10 {
+                if zpc.pending_reload.is_none() {
+                    return;
+                }
+                zpc.update();
+            }
+            panic!("reload not applied, PC at ${:04X}", zpc.cpu_ref.borrow().pc);
+        };
+
+        // The rebuilt program moved. It only lands once the CPU is between instructions.
+        // LDA #$07; JMP $C102
+        std::fs::write(&path, [0x00, 0xC1, 0xA9, 0x07, 0x4C, 0x02, 0xC1]).unwrap();
+        zpc.update();
+        assert!(!zpc.cpu_ref.borrow().at_instruction_boundary());
+        assert_eq!(zpc.reload_prg(filename), Ok(0xC100));
+        assert_eq!(zpc.mem_ref.borrow().read_byte(0xC100), 0x00);
+        apply(&mut zpc);
+        assert_eq!(zpc.cpu_ref.borrow().pc, 0xC100);
+        assert_eq!(zpc.mem_ref.borrow().dump_range(0xC100, 2), [0xA9, 0x07]);
+
+        // A BASIC program breaks in through the warm start, and is dropped if that never
+        // reaches the input loop.
+        let output = SharedOutput::default();
+        zpc.set_debug_output(Box::new(output.clone()));
+        zpc.mem_ref
+            .borrow_mut()
+            .load(BASIC_WARM_START_VECTOR, &[0x02, 0xC1]);
+        std::fs::write(&path, [0x01, 0x08, 0x00, 0x00]).unwrap();
+        assert_eq!(zpc.reload_prg(filename), Ok(BASIC_START));
+        let frame = zpc.region.cycles_per_frame() as u64;
+        for _ in 0..(RELOAD_TIMEOUT_FRAMES - 1) * frame {
+            zpc.update();
+        }
+        assert!(zpc.pending_reload.is_some());
+        assert!(keyboard_buffer(&zpc).is_empty());
+        // The deadline is only checked between instructions, so let the JMP finish.
+        for _ in 0..frame + 2 {
+            zpc.update();
+        }
+        assert!(zpc.pending_reload.is_none());
+        assert!(output.text().contains("Reload dropped"));
+        assert_eq!(zpc.mem_ref.borrow().read_word_le(0x2D), 0x0000);
+
+        zpc.mem_ref
+            .borrow_mut()
+            .load(BASIC_WARM_START_VECTOR, &[0x80, 0xA4]);
+        zpc.cpu_ref.borrow_mut().p |= cpu::StatusFlag::InterruptDisable as u8;
+        assert_eq!(zpc.reload_prg(filename), Ok(BASIC_START));
+        std::fs::remove_file(&path).unwrap();
+        apply(&mut zpc);
+        assert_eq!(zpc.cpu_ref.borrow().pc, BASIC_READY_LOOP);
+        assert_eq!(
+            zpc.cpu_ref.borrow().p & cpu::StatusFlag::InterruptDisable as u8,
+            0
+        );
+        assert_eq!(keyboard_buffer(&zpc), b"RUN\r");
+        let mem = zpc.mem_ref.borrow();
+        assert_eq!(mem.read_word_le(0x2B), BASIC_START);
+        assert_eq!(mem.read_word_le(0x2D), 0x0803);
+    }
+
+    #[test]
+    fn same_seed_runs_identically() {
+        let run = |seed: u64| {
+            let config = ZpcConfig {
+                ram_pattern: memory::RamPattern::Random,
+                seed,
+                ..ZpcConfig::default()
+            };
+            let mut zpc = ZPC::with_config(config, HeadlessDisplay::new()).unwrap();
+            // Fold the power-on contents of $2000-$20FF into A, over and over.
+            #[rustfmt::skip]
+            zpc.mem_ref.borrow_mut().load(0xC000, &[
+                0x78,             // SEI
+                0xA2, 0x00,       // LDX #0
+                0x7D, 0x00, 0x20, // ADC $2000,X
+                0x2A,             // ROL A
+                0xE8,             // INX
+                0xD0, 0xF9,       // BNE $C003
+                0xA8,             // TAY
+                0x4C, 0x01, 0xC0, // JMP $C001
+            ]);
+            zpc.cpu_ref.borrow_mut().pc = 0xC000;
+            zpc.set_keys(&[io::Key::A]);
+            zpc.run_until(RunUntil::Cycles(50_000), 50_000);
+            let cpu = zpc.cpu_ref.borrow();
+            let ram = zpc.mem_ref.borrow().dump_range(0, 0x10000);
+            ((cpu.pc, cpu.a, cpu.x, cpu.y, cpu.sp, cpu.p), ram)
+        };
+
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn timer_a_irq_is_recorded_as_its_source() {
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x81,       // LDA #$81
+            0x8D, 0x0D, 0xDC, // STA $DC0D: enable timer A interrupts
+            0xA9, 0x20,       // LDA #$20
+            0x8D, 0x04, 0xDC, // STA $DC04
+            0xA9, 0x00,       // LDA #$00
+            0x8D, 0x05, 0xDC, // STA $DC05
+            0xA9, 0x19,       // LDA #$19
+            0x8D, 0x0E, 0xDC, // STA $DC0E: start, one-shot, force load
+            0x58,             // CLI
+            0x4C, 0x15, 0xC0, // JMP *
+        ];
+        let mut zpc = ZPC::new_bare(&program, 0xC000);
+        zpc.mem_ref.borrow_mut().load(0xFFFE, &[0x00, 0xC1]);
+        // The IO area is banked out, so enable it for the CIA registers.
+        zpc.mem_ref.borrow_mut().write_byte(0x0001, 0x05);
+        assert_eq!(
+            zpc.run_until(RunUntil::Pc(0xC100), 1_000),
+            RunOutcome::ReachedPc
+        );
+        assert_eq!(
+            zpc.cpu_ref.borrow().last_irq_source(),
+            Some(cpu::InterruptCause::TimerA)
+        );
+        assert_eq!(zpc.cpu_ref.borrow().last_nmi_source(), None);
+    }
+
+    #[test]
+    fn instruction_limit_stops_a_runaway_loop() {
+        let config = ZpcConfig {
+            max_instructions: Some(100),
+            ..ZpcConfig::default()
+        };
+        let mut zpc = ZPC::with_config(config, HeadlessDisplay::new()).unwrap();
+        // loop: JMP loop
+        zpc.mem_ref.borrow_mut().load(0xC000, &[0x4C, 0x00, 0xC0]);
+        zpc.mem_ref.borrow_mut().load(0xFFFC, &[0x00, 0xC0]);
+        zpc.reset(ResetKind::Warm);
+
+        assert_eq!(
+            zpc.run_until(RunUntil::Breakpoint, 1_000_000),
+            RunOutcome::InstructionLimit
+        );
+        // The reset sequence, then 100 JMPs of 3 cycles each.
+        assert_eq!(zpc.cycle_count, 6 + 300);
+
+        zpc.cpu_ref.borrow_mut().set_instruction_limit(None);
+        assert_eq!(
+            zpc.run_until(RunUntil::Cycles(1_000), 1_000),
+            RunOutcome::CyclesElapsed
+        );
+    }
+
+    #[test]
+    fn config_picks_the_region_and_options() {
+        let config = ZpcConfig {
+            region: Region::Ntsc,
+            palette: utils::Palette::Colodore,
+            ..ZpcConfig::default()
+        };
+        let zpc = ZPC::with_config(config, HeadlessDisplay::new()).unwrap();
+        let vic = zpc.vic_ref.borrow();
+        assert_eq!(vic.region().raster_lines(), 263);
+        assert_eq!(vic.palette(), utils::Palette::Colodore);
+        assert_eq!(zpc.scale(), Scale::X2);
+        drop(vic);
+
+        let zpc = ZPC::new(Scale::X4, Region::Ntsc, HeadlessDisplay::new());
+        assert_eq!(zpc.scale(), Scale::X4);
+        assert_eq!(zpc.vic_ref.borrow().region().raster_lines(), 263);
+    }
+}